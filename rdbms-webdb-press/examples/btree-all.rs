@@ -7,12 +7,12 @@ use rdbms_from_scratch::disk::{DiskManager, PageId};
 fn main() -> Result<()> {
     let disk = DiskManager::open("test.btr")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
-    let mut iter = btree.search(&mut bufmgr, SearchMode::Start)?;
+    let btree = BTree::new(PageId(1));
+    let mut iter = btree.search(&bufmgr, SearchMode::Start)?;
 
-    while let Some((key, value)) = iter.next(&mut bufmgr)? {
+    while let Some((key, value)) = iter.next(&bufmgr)? {
         println!("{:02x?} = {:02x?}", key, value);
     }
     Ok(())
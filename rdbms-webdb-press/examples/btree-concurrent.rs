@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+
+use anyhow::{ensure, Result};
+use md5::{Digest, Md5};
+
+use rdbms_from_scratch::btree::{BTree, SearchMode};
+use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
+use rdbms_from_scratch::disk::DiskManager;
+
+const NUM_THREADS: u32 = 8;
+const NUM_PAIRS: u32 = 1_000_000;
+
+fn main() -> Result<()> {
+    let disk = DiskManager::open("concurrent.btr")?;
+    let pool = BufferPool::new(100);
+    let bufmgr = BufferPoolManager::new(disk, pool);
+
+    // 1つの木に複数のスレッドから同時に挿入しながら、読み出す
+    let btree = BTree::create(&bufmgr)?;
+    let num_running = AtomicU32::new(NUM_THREADS);
+    thread::scope(|s| -> Result<()> {
+        let writers: Vec<_> = (0..NUM_THREADS)
+            .map(|n| {
+                let (btree, bufmgr, num_running) = (&btree, &bufmgr, &num_running);
+                s.spawn(move || -> Result<()> {
+                    let pkeys = (1u32..=NUM_PAIRS).skip(n as usize);
+                    let result = pkeys.step_by(NUM_THREADS as usize).try_for_each(|i| {
+                        let pkey = i.to_be_bytes();
+                        let md5 = Md5::digest(&pkey);
+                        btree.insert(bufmgr, &md5[..], &pkey[..])
+                    });
+                    num_running.fetch_sub(1, Ordering::Release);
+                    Ok(result?)
+                })
+            })
+            .collect();
+        let reader = s.spawn(|| -> Result<()> {
+            while num_running.load(Ordering::Acquire) > 0 {
+                let mut iter = btree.search(&bufmgr, SearchMode::Start)?;
+                let mut prev_key = vec![];
+                while let Some((key, _)) = iter.next(&bufmgr)? {
+                    ensure!(prev_key < key, "keys must be sorted");
+                    prev_key = key;
+                }
+            }
+            Ok(())
+        });
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        reader.join().unwrap()
+    })?;
+    bufmgr.flush()?;
+
+    let mut iter = btree.search(&bufmgr, SearchMode::Start)?;
+    let mut count = 0;
+    while iter.next(&bufmgr)?.is_some() {
+        count += 1;
+    }
+    ensure!(count == NUM_PAIRS, "{} pairs found", count);
+    println!("{} pairs inserted by {} threads", count, NUM_THREADS);
+
+    Ok(())
+}
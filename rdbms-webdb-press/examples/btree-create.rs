@@ -7,16 +7,16 @@ use rdbms_from_scratch::disk::DiskManager;
 fn main() -> Result<()> {
     let disk = DiskManager::open("test.btr")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::create(&mut bufmgr)?;
+    let btree = BTree::create(&bufmgr)?;
 
-    btree.insert(&mut bufmgr, b"Kanagawa", b"Yokohama")?;
-    btree.insert(&mut bufmgr, b"Osaka", b"Osaka")?;
-    btree.insert(&mut bufmgr, b"Aichi", b"Nagoya")?;
-    btree.insert(&mut bufmgr, b"Hokkaido", b"Sapporo")?;
-    btree.insert(&mut bufmgr, b"Fukuoka", b"Fukuoka")?;
-    btree.insert(&mut bufmgr, b"Hyogo", b"Kobe")?;
+    btree.insert(&bufmgr, b"Kanagawa", b"Yokohama")?;
+    btree.insert(&bufmgr, b"Osaka", b"Osaka")?;
+    btree.insert(&bufmgr, b"Aichi", b"Nagoya")?;
+    btree.insert(&bufmgr, b"Hokkaido", b"Sapporo")?;
+    btree.insert(&bufmgr, b"Fukuoka", b"Fukuoka")?;
+    btree.insert(&bufmgr, b"Hyogo", b"Kobe")?;
 
     bufmgr.flush()?;
 
@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use anyhow::Result;
+use md5::{Digest, Md5};
+
+use rdbms_from_scratch::btree::BTree;
+use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
+use rdbms_from_scratch::check::Checker;
+use rdbms_from_scratch::disk::{DiskManager, PageId};
+
+const NUM_PAIRS: u32 = 1_000_000;
+
+// btree-large と同じペアを、並べ替えてから bulk_load で書き込む
+// メタページは btree-large と同じ PageId(1) になるので、btree-large-query などでも読める
+fn main() -> Result<()> {
+    {
+        let disk = DiskManager::open("large-bulk.btr")?;
+        let pool = BufferPool::new(100);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+
+        let mut pairs: Vec<_> = (1u32..=NUM_PAIRS)
+            .map(|i| {
+                let pkey = i.to_be_bytes();
+                let md5 = Md5::digest(&pkey);
+                (md5.to_vec(), pkey.to_vec())
+            })
+            .collect();
+        pairs.sort_unstable();
+        BTree::bulk_load(&bufmgr, pairs, 1.0)?;
+        bufmgr.flush()?;
+    }
+
+    // btree-large で作ったファイルがあれば、木の大きさを比べる
+    for path in &["large.btr", "large-bulk.btr"] {
+        if !Path::new(path).exists() {
+            continue;
+        }
+        let disk = DiskManager::open(path)?;
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(100));
+        let mut checker = Checker::new(u64::MAX);
+        let report = BTree::new(PageId(1)).check(&bufmgr, path, &mut checker);
+        println!(
+            "{:>14}: height {}, {} nodes, {} pairs",
+            path, report.height, report.num_nodes, report.num_pairs
+        );
+    }
+    Ok(())
+}
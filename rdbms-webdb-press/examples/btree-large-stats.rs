@@ -0,0 +1,78 @@
+use anyhow::Result;
+use md5::{Digest, Md5};
+
+use rdbms_from_scratch::btree::{BTree, SearchMode};
+use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager, ClockSweep, LruK, TwoQ};
+use rdbms_from_scratch::disk::{DiskManager, PageId};
+
+// btree-large で作った large.btr を使う
+const NUM_PAIRS: u32 = 1_000_000;
+const NUM_LOOKUPS: u32 = 10_000;
+
+// 主キーを引きながら、時々全体をスキャンする
+fn run(bufmgr: &BufferPoolManager) -> Result<()> {
+    let btree = BTree::new(PageId(1));
+    for i in 1..=NUM_LOOKUPS {
+        let pkey = (i * 7919 % NUM_PAIRS + 1).to_be_bytes();
+        let md5 = Md5::digest(&pkey);
+        let mut iter = btree.search(bufmgr, SearchMode::Key(md5.to_vec()))?;
+        let (_, value) = iter.next(bufmgr)?.unwrap();
+        assert_eq!(pkey.to_vec(), value);
+        if i % (NUM_LOOKUPS / 2) == 0 {
+            let mut iter = btree.search(bufmgr, SearchMode::Start)?;
+            while iter.next(bufmgr)?.is_some() {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    println!(
+        "{:>6} {:>12} {:>10} {:>10} {:>10} {:>10} {:>8}",
+        "pool", "policy", "hits", "misses", "evictions", "write-back", "hit %"
+    );
+    for &pool_size in &[10, 100, 1000] {
+        let pools: Vec<(&str, BufferPool)> = vec![
+            (
+                "clock-sweep",
+                BufferPool::with_policy(pool_size, ClockSweep::default()),
+            ),
+            ("lru-2", BufferPool::with_policy(pool_size, LruK::new(2))),
+            ("2q", BufferPool::with_policy(pool_size, TwoQ::default())),
+        ];
+        for (name, pool) in pools {
+            let disk = DiskManager::open("large.btr")?;
+            let bufmgr = BufferPoolManager::new(disk, pool);
+            run(&bufmgr)?;
+            let stats = bufmgr.stats();
+            println!(
+                "{:>6} {:>12} {:>10} {:>10} {:>10} {:>10} {:>7.1}%",
+                stats.pool_size,
+                name,
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.dirty_write_backs,
+                stats.hit_ratio() * 100.0
+            );
+        }
+    }
+
+    // プールが 10 フレームの時に、どのフレームにどのページが残るか
+    let disk = DiskManager::open("large.btr")?;
+    let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+    run(&bufmgr)?;
+    println!();
+    for frame in bufmgr.frames() {
+        println!(
+            "buffer {:>2}: page {:>6} dirty={} pins={}",
+            frame.buffer_id.to_usize(),
+            frame
+                .page_id
+                .map_or("-".to_string(), |id| id.to_u64().to_string()),
+            frame.is_dirty,
+            frame.pin_count
+        );
+    }
+    Ok(())
+}
@@ -8,12 +8,12 @@ use rdbms_from_scratch::tuple;
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
-    let mut iter = btree.search(&mut bufmgr, SearchMode::Start)?;
+    let btree = BTree::new(PageId(1));
+    let mut iter = btree.search(&bufmgr, SearchMode::Start)?;
 
-    while let Some((key, value)) = iter.next(&mut bufmgr)? {
+    while let Some((key, value)) = iter.next(&bufmgr)? {
         let mut record = vec![];
         tuple::decode(&key, &mut record);
         tuple::decode(&value, &mut record);
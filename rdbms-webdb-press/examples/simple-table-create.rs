@@ -7,19 +7,19 @@ use rdbms_from_scratch::table::SimpleTable;
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
     let mut table = SimpleTable {
         meta_page_id: PageId(0),
         num_key_elems: 1,
     };
-    table.create(&mut bufmgr)?;
+    table.create(&bufmgr)?;
     dbg!(&table);
-    table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
-    table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;
-    table.insert(&mut bufmgr, &[b"y", b"Charlie", b"Williams"])?;
-    table.insert(&mut bufmgr, &[b"w", b"Dave", b"Miller"])?;
-    table.insert(&mut bufmgr, &[b"v", b"Eve", b"Brown"])?;
+    table.insert(&bufmgr, &["z".into(), "Alice".into(), "Smith".into()])?;
+    table.insert(&bufmgr, &["x".into(), "Bob".into(), "Johnson".into()])?;
+    table.insert(&bufmgr, &["y".into(), "Charlie".into(), "Williams".into()])?;
+    table.insert(&bufmgr, &["w".into(), "Dave".into(), "Miller".into()])?;
+    table.insert(&bufmgr, &["v".into(), "Eve".into(), "Brown".into()])?;
 
     bufmgr.flush()?;
     Ok(())
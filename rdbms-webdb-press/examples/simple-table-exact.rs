@@ -4,21 +4,22 @@ use rdbms_from_scratch::btree::{BTree, SearchMode};
 use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
 use rdbms_from_scratch::disk::{DiskManager, PageId};
 use rdbms_from_scratch::tuple;
+use rdbms_from_scratch::value::Value;
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let btree = BTree::new(PageId(1));
     let mut search_key = vec![];
-    tuple::encode([b"y"].iter(), &mut search_key);
-    let mut iter = btree.search(&mut bufmgr, SearchMode::Key(search_key))?;
+    tuple::encode([Value::from("y")].iter(), &mut search_key);
+    let mut iter = btree.search(&bufmgr, SearchMode::Key(search_key))?;
 
-    while let Some((key, value)) = iter.next(&mut bufmgr)? {
+    while let Some((key, value)) = iter.next(&bufmgr)? {
         let mut record = vec![];
         tuple::decode(&key, &mut record);
-        if record[0] != b"y" {
+        if record[0] != "y".into() {
             break;
         }
         tuple::decode(&value, &mut record);
@@ -1,27 +1,38 @@
+use std::ops::Bound;
+
 use anyhow::Result;
 
 use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
 use rdbms_from_scratch::disk::{DiskManager, PageId};
-use rdbms_from_scratch::query::{Filter, PlanNode, SeqScan, TupleSearchMode};
+use rdbms_from_scratch::query::{
+    Filter, PlanNode, Predicate, SeqScan, TupleRange, TupleSearchMode, TupleSlice,
+};
 use rdbms_from_scratch::tuple;
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
     let plan = Filter {
-        cond: &|record| record[1].as_slice() < b"Dave",
-        inner_plan: &SeqScan {
-            table_meta_page_id: PageId(0),
-            search_mode: TupleSearchMode::Key(&[b"w"]),
-            while_cond: &|pkey| pkey[0].as_slice() < b"z",
-        },
+        cond: Predicate::new("#1 < 'Dave'", |record: TupleSlice| {
+            record[1] < "Dave".into()
+        }),
+        inner_plan: Box::new(SeqScan {
+            table_meta_page_id: PageId(1),
+            search_mode: TupleSearchMode::Start,
+            range: TupleRange {
+                lower: Bound::Included(vec!["w".into()]),
+                upper: Bound::Excluded(vec!["z".into()]),
+            },
+            snapshot: None,
+        }),
     };
-    let mut exec = plan.start(&mut bufmgr)?;
+    let mut exec = plan.start(&bufmgr)?;
 
-    while let Some(record) = exec.next(&mut bufmgr)? {
+    while let Some(record) = exec.next(&bufmgr)? {
         println!("{:?}", tuple::Pretty(&record));
     }
+    println!("{}", plan.explain());
     Ok(())
 }
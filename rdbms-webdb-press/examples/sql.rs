@@ -0,0 +1,46 @@
+use anyhow::Result;
+use tempfile::tempfile;
+
+use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
+use rdbms_from_scratch::disk::DiskManager;
+use rdbms_from_scratch::sql::{Database, QueryResult};
+use rdbms_from_scratch::tuple;
+
+fn main() -> Result<()> {
+    let disk = DiskManager::new(tempfile()?)?;
+    let pool = BufferPool::new(10);
+    let bufmgr = BufferPoolManager::new(disk, pool);
+    let db = Database::create(&bufmgr)?;
+
+    let statements = [
+        "CREATE TABLE users (id TEXT PRIMARY KEY, first_name TEXT, last_name TEXT)",
+        "CREATE UNIQUE INDEX users_last_name ON users (last_name)",
+        "INSERT INTO users VALUES
+            ('z', 'Alice', 'Smith'),
+            ('x', 'Bob', 'Johnson'),
+            ('y', 'Charlie', 'Williams'),
+            ('w', 'Dave', 'Miller'),
+            ('v', 'Eve', 'Brown')",
+        "SELECT * FROM users WHERE last_name = 'Smith'",
+        "SELECT first_name, last_name FROM users WHERE last_name >= 'J' ORDER BY last_name",
+        "UPDATE users SET first_name = 'Alicia' WHERE id = 'z'",
+        "DELETE FROM users WHERE first_name = 'Bob' OR last_name = 'Brown'",
+        "SELECT * FROM users",
+        "ANALYZE users",
+        "EXPLAIN ANALYZE SELECT * FROM users WHERE last_name > 'A'",
+    ];
+    for sql in &statements {
+        println!("> {}", sql);
+        match db.execute(&bufmgr, sql)? {
+            QueryResult::Rows { columns, rows } => {
+                let names: Vec<_> = columns.iter().map(|column| &column.name).collect();
+                println!("{:?}", names);
+                for row in &rows {
+                    println!("{:?}", tuple::Pretty(row));
+                }
+            }
+            result => println!("{:?}", result),
+        }
+    }
+    Ok(())
+}
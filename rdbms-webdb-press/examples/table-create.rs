@@ -1,8 +1,9 @@
 use anyhow::Result;
 
 use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
-use rdbms_from_scratch::disk::{DiskManager, PageId};
-use rdbms_from_scratch::table::{Table, UniqueIndex};
+use rdbms_from_scratch::catalog::{Catalog, Column};
+use rdbms_from_scratch::disk::DiskManager;
+use rdbms_from_scratch::value::DataType;
 
 /* CREATE TABLE
   |id    |first_name|last_name|
@@ -16,23 +17,25 @@ use rdbms_from_scratch::table::{Table, UniqueIndex};
 fn main() -> Result<()> {
     let disk = DiskManager::open("table.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
-    let mut table = Table {
-        meta_page_id: PageId::INVALID_PAGE_ID,
-        num_key_elems: 1,
-        unique_indices: vec![UniqueIndex {
-            meta_page_id: PageId::INVALID_PAGE_ID,
-            skey: vec![2],
-        }],
-    };
-    table.create(&mut bufmgr)?;
-    dbg!(&table);
-    table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
-    table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;
-    table.insert(&mut bufmgr, &[b"y", b"Charlie", b"Williams"])?;
-    table.insert(&mut bufmgr, &[b"w", b"Dave", b"Miller"])?;
-    table.insert(&mut bufmgr, &[b"v", b"Eve", b"Brown"])?;
+    let catalog = Catalog::create(&bufmgr)?;
+    let columns = ["id", "first_name", "last_name"]
+        .iter()
+        .map(|name| Column {
+            name: name.to_string(),
+            data_type: DataType::Text,
+        })
+        .collect();
+    catalog.create_table(&bufmgr, "users", columns, 1)?;
+    let schema = catalog.create_index(&bufmgr, "users", "users_last_name", &["last_name"], None)?;
+    dbg!(&schema);
+    let table = schema.table;
+    table.insert(&bufmgr, &["z".into(), "Alice".into(), "Smith".into()])?;
+    table.insert(&bufmgr, &["x".into(), "Bob".into(), "Johnson".into()])?;
+    table.insert(&bufmgr, &["y".into(), "Charlie".into(), "Williams".into()])?;
+    table.insert(&bufmgr, &["w".into(), "Dave".into(), "Miller".into()])?;
+    table.insert(&bufmgr, &["v".into(), "Eve".into(), "Brown".into()])?;
 
     bufmgr.flush()?;
     Ok(())
@@ -0,0 +1,133 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use tempfile::NamedTempFile;
+
+use rdbms_from_scratch::buffer::{self, BufferPool, BufferPoolManager};
+use rdbms_from_scratch::catalog::{Catalog, Column};
+use rdbms_from_scratch::disk::{DiskManager, PageId};
+use rdbms_from_scratch::hash_index::HashIndex;
+use rdbms_from_scratch::query::{HashIndexScan, IndexScan, PlanNode, TupleRange, TupleSearchMode};
+use rdbms_from_scratch::value::{DataType, Value};
+
+const NUM_ROWS: u32 = 100_000;
+const NUM_LOOKUPS: u32 = 10_000;
+
+// table-large と同じ形の users テーブルを作り、last_name の B+Tree とハッシュインデックスを張る
+// テーブル、B+Tree のインデックス、ハッシュインデックスのメタページIDを返す
+fn build(path: &std::path::Path) -> Result<(PageId, PageId, PageId)> {
+    let disk = DiskManager::open(path)?;
+    let bufmgr = BufferPoolManager::new(disk, BufferPool::new(100_000));
+    let catalog = Catalog::create(&bufmgr)?;
+    let columns = ["id", "first_name", "last_name"]
+        .iter()
+        .map(|name| Column {
+            name: name.to_string(),
+            data_type: DataType::Bytes,
+        })
+        .collect();
+    catalog.create_table(&bufmgr, "users", columns, 1)?;
+    catalog.create_index(&bufmgr, "users", "users_last_name", &["last_name"], None)?;
+    // ハッシュインデックスもカタログに登録し、行を入れる時にテーブルが一緒に書く
+    let table = catalog
+        .create_hash_index(
+            &bufmgr,
+            "users",
+            "users_last_name_hash",
+            &["last_name"],
+            None,
+        )?
+        .table;
+    for i in 0u32..NUM_ROWS {
+        let pkey = i.to_be_bytes();
+        let md5 = Md5::digest(&pkey);
+        let sha1 = Sha1::digest(&pkey);
+        let record: Vec<Value> = [&pkey[..], &md5[..], &sha1[..]]
+            .iter()
+            .map(|&elem| elem.into())
+            .collect();
+        table.insert(&bufmgr, &record)?;
+    }
+    let hash_meta_page_id = table.hash_indices[0].meta_page_id;
+    println!(
+        "{} rows, {} hash buckets",
+        NUM_ROWS,
+        HashIndex::new(hash_meta_page_id).num_buckets(&bufmgr)?
+    );
+    bufmgr.flush()?;
+    Ok((
+        table.meta_page_id,
+        table.unique_indices[0].meta_page_id,
+        hash_meta_page_id,
+    ))
+}
+
+// index で last_name = key の行を引く実行計画
+fn plan(index: &str, meta_page_ids: (PageId, PageId, PageId), key: Value) -> Box<dyn PlanNode> {
+    let (table_meta_page_id, btree_meta_page_id, hash_meta_page_id) = meta_page_ids;
+    match index {
+        "btree" => Box::new(IndexScan {
+            table_meta_page_id,
+            index_meta_page_id: btree_meta_page_id,
+            search_mode: TupleSearchMode::Start,
+            range: TupleRange::prefix(vec![key]),
+            snapshot: None,
+        }),
+        _ => Box::new(HashIndexScan {
+            table_meta_page_id,
+            index_meta_page_id: hash_meta_page_id,
+            key: vec![key],
+            snapshot: None,
+        }),
+    }
+}
+
+// SELECT * WHERE last_name = sha1(id) を、id を変えながら繰り返す
+fn run(
+    bufmgr: &BufferPoolManager,
+    index: &str,
+    meta_page_ids: (PageId, PageId, PageId),
+) -> Result<()> {
+    for i in 0..NUM_LOOKUPS {
+        let pkey = (i * 7919 % NUM_ROWS).to_be_bytes();
+        let sha1 = Sha1::digest(&pkey);
+        let plan = plan(index, meta_page_ids, sha1[..].into());
+        let mut exec = plan.start(bufmgr)?;
+        let record = exec.next(bufmgr)?.unwrap();
+        assert_eq!(Value::from(&pkey[..]), record[0]);
+        assert!(exec.next(bufmgr)?.is_none());
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let (_file, path) = NamedTempFile::new()?.into_parts();
+    let meta_page_ids = build(&path)?;
+
+    println!(
+        "{:>6} {:>6} {:>12} {:>12} {:>12}",
+        "pool", "index", "us/lookup", "pages/lookup", "misses"
+    );
+    for &pool_size in &[10, 1000, 100_000] {
+        for &index in &["btree", "hash"] {
+            let disk = DiskManager::open(&path)?;
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(pool_size));
+            let fetches = buffer::thread_fetches();
+            let start = Instant::now();
+            run(&bufmgr, index, meta_page_ids)?;
+            let elapsed = start.elapsed();
+            let fetches = buffer::thread_fetches() - fetches;
+            println!(
+                "{:>6} {:>6} {:>12.2} {:>12.2} {:>12}",
+                pool_size,
+                index,
+                elapsed.as_secs_f64() * 1e6 / NUM_LOOKUPS as f64,
+                fetches as f64 / NUM_LOOKUPS as f64,
+                bufmgr.stats().misses
+            );
+        }
+    }
+    Ok(())
+}
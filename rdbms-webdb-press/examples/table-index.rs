@@ -1,8 +1,9 @@
 use anyhow::Result;
 
 use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
-use rdbms_from_scratch::disk::{DiskManager, PageId};
-use rdbms_from_scratch::query::{IndexScan, PlanNode, TupleSearchMode};
+use rdbms_from_scratch::catalog::Catalog;
+use rdbms_from_scratch::disk::DiskManager;
+use rdbms_from_scratch::query::{IndexScan, PlanNode, TupleRange, TupleSearchMode};
 use rdbms_from_scratch::tuple;
 
 // SELECT * WHERE last_name = 'Smith'
@@ -10,17 +11,19 @@ use rdbms_from_scratch::tuple;
 fn main() -> Result<()> {
     let disk = DiskManager::open("table.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
+    let table = Catalog::open().open_table(&bufmgr, "users")?;
     let plan = IndexScan {
-        table_meta_page_id: PageId(0),
-        index_meta_page_id: PageId(2),
-        search_mode: TupleSearchMode::Key(&[b"Smith"]),
-        while_cond: &|skey| skey[0].as_slice() == b"Smith",
+        table_meta_page_id: table.meta_page_id,
+        index_meta_page_id: table.unique_indices[0].meta_page_id,
+        search_mode: TupleSearchMode::Start,
+        range: TupleRange::prefix(vec!["Smith".into()]),
+        snapshot: None,
     };
-    let mut exec = plan.start(&mut bufmgr)?;
+    let mut exec = plan.start(&bufmgr)?;
 
-    while let Some(record) = exec.next(&mut bufmgr)? {
+    while let Some(record) = exec.next(&bufmgr)? {
         println!("{:?}", tuple::Pretty(&record));
     }
     Ok(())
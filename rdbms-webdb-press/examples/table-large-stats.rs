@@ -0,0 +1,26 @@
+use anyhow::Result;
+
+use rdbms_from_scratch::check;
+
+// table-large で作った table.rly の B+Tree ごとに、木の高さとページ数を出す
+// リーフの形式を変えた前後で table-large を実行して、出力を比べる
+fn main() -> Result<()> {
+    let report = check::check_file("table.rly")?;
+    println!(
+        "{:<24} {:>6} {:>10} {:>10} {:>12}",
+        "tree", "height", "pages", "pairs", "pairs/page"
+    );
+    for tree in &report.trees {
+        let num_pages = tree.num_nodes + tree.num_overflow_pages;
+        println!(
+            "{:<24} {:>6} {:>10} {:>10} {:>12.1}",
+            tree.name,
+            tree.height,
+            num_pages,
+            tree.num_pairs,
+            tree.num_pairs as f64 / num_pages as f64
+        );
+    }
+    println!("total pages: {}", report.num_pages);
+    Ok(())
+}
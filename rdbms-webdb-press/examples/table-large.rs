@@ -1,8 +1,9 @@
 use anyhow::Result;
 use md5::Md5;
 use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
-use rdbms_from_scratch::disk::{DiskManager, PageId};
-use rdbms_from_scratch::table::{Table, UniqueIndex};
+use rdbms_from_scratch::catalog::{Catalog, Column};
+use rdbms_from_scratch::disk::DiskManager;
+use rdbms_from_scratch::value::{DataType, Value};
 use sha1::{Digest, Sha1};
 
 const NUM_ROWS: u32 = 10_000_000;
@@ -18,30 +19,36 @@ const NUM_ROWS: u32 = 10_000_000;
   |...   |          |         |
   |BE i32|md5(id)   |sha1(id) |
 */
+fn bytes(elems: &[&[u8]]) -> Vec<Value> {
+    elems.iter().map(|&elem| elem.into()).collect()
+}
+
 fn main() -> Result<()> {
     let disk = DiskManager::open("table.rly")?;
     let pool = BufferPool::new(1_000_000);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
-    let mut table = Table {
-        meta_page_id: PageId(0),
-        num_key_elems: 1,
-        unique_indices: vec![UniqueIndex {
-            meta_page_id: PageId::INVALID_PAGE_ID,
-            skey: vec![2],
-        }],
-    };
-    table.create(&mut bufmgr)?;
-    dbg!(&table);
-    table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
-    table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;
-    table.insert(&mut bufmgr, &[b"y", b"Charlie", b"Williams"])?;
-    table.insert(&mut bufmgr, &[b"w", b"Dave", b"Miller"])?;
-    table.insert(&mut bufmgr, &[b"v", b"Eve", b"Brown"])?;
+    let bufmgr = BufferPoolManager::new(disk, pool);
+    let catalog = Catalog::create(&bufmgr)?;
+    let columns = ["id", "first_name", "last_name"]
+        .iter()
+        .map(|name| Column {
+            name: name.to_string(),
+            data_type: DataType::Bytes,
+        })
+        .collect();
+    catalog.create_table(&bufmgr, "users", columns, 1)?;
+    let schema = catalog.create_index(&bufmgr, "users", "users_last_name", &["last_name"], None)?;
+    dbg!(&schema);
+    let table = schema.table;
+    table.insert(&bufmgr, &bytes(&[b"z", b"Alice", b"Smith"]))?;
+    table.insert(&bufmgr, &bytes(&[b"x", b"Bob", b"Johnson"]))?;
+    table.insert(&bufmgr, &bytes(&[b"y", b"Charlie", b"Williams"]))?;
+    table.insert(&bufmgr, &bytes(&[b"w", b"Dave", b"Miller"]))?;
+    table.insert(&bufmgr, &bytes(&[b"v", b"Eve", b"Brown"]))?;
     for i in 0u32..NUM_ROWS {
         let pkey = i.to_be_bytes();
         let md5 = Md5::digest(&pkey);
         let sha1 = Sha1::digest(&pkey);
-        table.insert(&mut bufmgr, &[&pkey[..], &md5[..], &sha1[..]])?;
+        table.insert(&bufmgr, &bytes(&[&pkey[..], &md5[..], &sha1[..]]))?;
     }
     bufmgr.flush()?;
     Ok(())
@@ -0,0 +1,27 @@
+use std::env;
+use std::process;
+
+use rdbms_from_scratch::check;
+
+// ヒープファイルの整合性を調べ、結果を JSON で出力する
+// 問題がなければ 0、問題があれば 1、調べられなければ 2 で終わる
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: rdbms-check <heap file>");
+            process::exit(2);
+        }
+    };
+    let report = match check::check_file(&path) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("rdbms-check: {}: {:#}", path, err);
+            process::exit(2);
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    if !report.is_ok() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,48 @@
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::process;
+
+use anyhow::Result;
+
+use rdbms_from_scratch::buffer::{BufferPool, BufferPoolManager};
+use rdbms_from_scratch::disk::DiskManager;
+use rdbms_from_scratch::server::Server;
+use rdbms_from_scratch::sql::Database;
+
+const POOL_SIZE: usize = 256;
+const DEFAULT_ADDR: &str = "127.0.0.1:5432";
+
+// ヒープファイルを開き、PostgreSQL のプロトコルで SQL を受け付ける
+// 空のヒープファイルならカタログを作る
+// psql -h 127.0.0.1 -p 5432 で接続できる
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = match args.next() {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: rdbms-server <heap file> [address]");
+            process::exit(2);
+        }
+    };
+    let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    if let Err(err) = run(&path, &addr) {
+        eprintln!("rdbms-server: {:#}", err);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str, addr: &str) -> Result<()> {
+    let is_new = fs::metadata(path).map_or(true, |metadata| metadata.len() == 0);
+    let disk = DiskManager::open(path)?;
+    let pool = BufferPool::new(POOL_SIZE);
+    let bufmgr = BufferPoolManager::new(disk, pool);
+    let db = if is_new {
+        Database::create(&bufmgr)?
+    } else {
+        Database::open(&bufmgr)?
+    };
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("listening on {}", listener.local_addr()?);
+    Server::new(&bufmgr, db).serve(&listener)
+}
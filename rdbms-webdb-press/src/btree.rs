@@ -1,19 +1,25 @@
-use std::cell::{Ref, RefMut};
 use std::convert::identity;
-use std::rc::Rc;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
 
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use zerocopy::{AsBytes, ByteSlice};
+use zerocopy::{ByteSlice, ByteSliceMut};
 
-use crate::buffer::{self, Buffer, BufferPoolManager};
-use crate::disk::PageId;
+use crate::buffer::{self, Buffer, BufferPoolManager, PageLatch};
+use crate::disk::{PageId, PAGE_SIZE};
+use crate::transaction::UndoRecord;
 
 mod branch;
+mod bulk;
+mod check;
 mod leaf;
 mod meta;
 mod node;
+pub(crate) mod overflow;
 
 #[derive(Serialize, Deserialize)]
 pub struct Pair<'a> {
@@ -22,27 +28,46 @@ pub struct Pair<'a> {
 }
 
 impl<'a> Pair<'a> {
-    fn to_bytes(&self) -> Vec<u8> {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
         bincode::options().serialize(self).unwrap()
     }
 
-    fn from_bytes(bytes: &'a [u8]) -> Self {
+    pub(crate) fn from_bytes(bytes: &'a [u8]) -> Self {
         bincode::options().deserialize(bytes).unwrap()
     }
+
+    fn try_from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        bincode::options().deserialize(bytes).ok()
+    }
+
+    fn size(&self) -> usize {
+        bincode::options().serialized_size(self).unwrap() as usize
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("duplicate key")]
     DuplicateKey,
+    #[error("key not found")]
+    KeyNotFound,
+    #[error("key too large: {0} bytes")]
+    KeyTooLarge(usize),
+    #[error("keys must be sorted in ascending order")]
+    UnsortedKeys,
     #[error(transparent)]
     Buffer(#[from] buffer::Error),
 }
 
+// これより大きなペアは、値をオーバーフローページに移してリーフに置く
+// キーはリーフに置くしかないので、値を移しても収まらなければ挿入できない
+const MAX_INLINE_PAIR_SIZE: usize = PAGE_SIZE / 4;
+
 #[derive(Debug, Clone)]
 pub enum SearchMode {
     Start,
     Key(Vec<u8>),
+    End,
 }
 
 impl SearchMode {
@@ -50,14 +75,30 @@ impl SearchMode {
         match self {
             SearchMode::Start => branch.child_at(0),
             SearchMode::Key(key) => branch.search_child(key),
+            SearchMode::End => branch.child_at(branch.num_pairs()),
         }
     }
+}
 
-    fn tuple_slot_id(&self, leaf: &leaf::Leaf<impl ByteSlice>) -> Result<usize, usize> {
-        match self {
-            SearchMode::Start => Err(0),
-            SearchMode::Key(key) => leaf.search_slot_id(key),
-        }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InsertMode {
+    Insert,
+    Update,
+    Upsert,
+}
+
+fn root_page_id(meta_latch: &PageLatch) -> PageId {
+    let body = meta_latch.body();
+    meta::Meta::new(&body[..]).header.root_page_id
+}
+
+// ブランチなら探しているキーを含む子のページID、リーフなら None
+fn child_page_id(latch: &PageLatch, search_mode: &SearchMode) -> Option<PageId> {
+    let body = latch.body();
+    let node = node::Node::new(&body[..]);
+    match node::Body::new(node.header.node_type, node.body) {
+        node::Body::Leaf(_) => None,
+        node::Body::Branch(branch) => Some(search_mode.child_page_id(&branch)),
     }
 }
 
@@ -66,182 +107,823 @@ pub struct BTree {
 }
 
 impl BTree {
-    pub fn create(bufmgr: &mut BufferPoolManager) -> Result<Self, Error> {
-        let meta_buffer = bufmgr.create_page()?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
-        let root_buffer = bufmgr.create_page()?;
-        let mut root = node::Node::new(root_buffer.page.borrow_mut() as RefMut<[_]>);
-        root.initialize_as_leaf();
-        let mut leaf = leaf::Leaf::new(root.body);
-        leaf.initialize();
-        meta.header.root_page_id = root_buffer.page_id;
-        Ok(Self::new(meta_buffer.page_id))
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self, Error> {
+        bufmgr.with_mtr(|bufmgr| {
+            let meta_buffer = bufmgr.create_page()?;
+            let mut meta_body = meta_buffer.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            let root_buffer = bufmgr.create_page()?;
+            let mut root_body = root_buffer.body_mut();
+            let mut root = node::Node::new(&mut root_body[..]);
+            root.initialize_as_leaf();
+            let mut leaf = root.into_leaf();
+            leaf.initialize();
+            meta.header.root_page_id = root_buffer.page_id;
+            Ok(Self::new(meta_buffer.page_id))
+        })
     }
 
     pub fn new(meta_page_id: PageId) -> Self {
         Self { meta_page_id }
     }
 
-    fn fetch_root_page(&self, bufmgr: &mut BufferPoolManager) -> Result<Rc<Buffer>, Error> {
-        let root_page_id = {
-            let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-            let meta = meta::Meta::new(meta_buffer.page.borrow() as Ref<[_]>);
-            meta.header.root_page_id
-        };
-        Ok(bufmgr.fetch_page(root_page_id)?)
+    #[cfg(test)]
+    fn fetch_root_page(&self, bufmgr: &BufferPoolManager) -> Result<Arc<Buffer>, Error> {
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        Ok(bufmgr.fetch_page(root_page_id(&meta_latch))?)
     }
 
-    fn search_internal(
+    // 親の共有ラッチを持ったまま子の共有ラッチを取りながら降りていき、リーフのラッチを返す
+    fn find_leaf(
         &self,
-        bufmgr: &mut BufferPoolManager,
-        node_buffer: Rc<Buffer>,
-        search_mode: SearchMode,
-    ) -> Result<Iter, Error> {
-        let node = node::Node::new(node_buffer.page.borrow() as Ref<[_]>);
-        match node::Body::new(node.header.node_type, node.body.as_bytes()) {
-            node::Body::Leaf(leaf) => {
-                let slot_id = search_mode.tuple_slot_id(&leaf).unwrap_or_else(identity);
-                drop(node);
-                Ok(Iter {
-                    buffer: node_buffer,
-                    slot_id,
-                })
-            }
-            node::Body::Branch(branch) => {
-                let child_page_id = search_mode.child_page_id(&branch);
-                drop(node);
-                drop(node_buffer);
-                let child_node_page = bufmgr.fetch_page(child_page_id)?;
-                self.search_internal(bufmgr, child_node_page, search_mode)
-            }
+        bufmgr: &BufferPoolManager,
+        search_mode: &SearchMode,
+    ) -> Result<PageLatch, Error> {
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let mut latch = bufmgr.fetch_page_shared(root_page_id(&meta_latch))?;
+        drop(meta_latch);
+        while let Some(child_page_id) = child_page_id(&latch, search_mode) {
+            latch = bufmgr.fetch_page_shared(child_page_id)?;
         }
+        Ok(latch)
     }
 
     pub fn search(
         &self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
+        search_mode: SearchMode,
+    ) -> Result<Iter, Error> {
+        self.range(bufmgr, search_mode, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    // lower から upper までのキーだけを返すイテレータ。search_mode は範囲の中で読み始める位置で、
+    // Start なら範囲の先頭から next で、End なら範囲の末尾から prev で読む
+    pub fn range(
+        &self,
+        bufmgr: &BufferPoolManager,
         search_mode: SearchMode,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
     ) -> Result<Iter, Error> {
-        let root_page = self.fetch_root_page(bufmgr)?;
-        self.search_internal(bufmgr, root_page, search_mode)
+        let position = match search_mode {
+            SearchMode::Key(key) if !below(&key, &lower) && !above(&key, &upper) => {
+                Position::Before(key)
+            }
+            SearchMode::Key(key) if above(&key, &upper) => Position::upper(&upper),
+            SearchMode::Start | SearchMode::Key(_) => Position::lower(&lower),
+            SearchMode::End => Position::upper(&upper),
+        };
+        let leaf_latch = self.find_leaf(bufmgr, &position.search_mode())?;
+        let mut iter = Iter {
+            meta_page_id: self.meta_page_id,
+            buffer: leaf_latch.buffer().clone(),
+            version: leaf_latch.version(),
+            slot_id: 0,
+            position,
+            lower,
+            upper,
+        };
+        iter.enter(&leaf_latch);
+        Ok(iter)
     }
 
-    fn insert_internal(
+    // キーに完全一致するペアの値を取り出す
+    fn get(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let leaf_latch = self.find_leaf(bufmgr, &SearchMode::Key(key.to_vec()))?;
+        let body = leaf_latch.body();
+        let leaf_node = node::Node::new(&body[..]);
+        let leaf = leaf_node.into_leaf();
+        leaf.search_slot_id(key)
+            .ok()
+            .map(|slot_id| load_value(bufmgr, leaf.value_at(slot_id)))
+            .transpose()
+    }
+
+    // トランザクション中なら、変更を取り消すための UNDO レコードを先に作っておく
+    fn undo_record(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+    ) -> Result<Option<UndoRecord>, Error> {
+        if !bufmgr.in_transaction() {
+            return Ok(None);
+        }
+        let meta_page_id = self.meta_page_id;
+        let key = key.to_vec();
+        Ok(Some(match self.get(bufmgr, &key)? {
+            Some(value) => UndoRecord::Update {
+                meta_page_id,
+                key,
+                value,
+            },
+            None => UndoRecord::Insert { meta_page_id, key },
+        }))
+    }
+
+    // 分割が起きない場合の挿入
+    // 共有ラッチで降りていき、親の共有ラッチを持ったままリーフだけを排他ラッチに取り直して書き換える
+    // リーフに収まらなければ何もせずに false を返す
+    fn insert_optimistic(
         &self,
-        bufmgr: &mut BufferPoolManager,
-        buffer: Rc<Buffer>,
+        bufmgr: &BufferPoolManager,
         key: &[u8],
         value: &[u8],
-    ) -> Result<Option<(Vec<u8>, PageId)>, Error> {
-        let node = node::Node::new(buffer.page.borrow_mut() as RefMut<[_]>);
-        match node::Body::new(node.header.node_type, node.body) {
-            node::Body::Leaf(mut leaf) => {
-                let slot_id = match leaf.search_slot_id(key) {
-                    Ok(_) => return Err(Error::DuplicateKey),
-                    Err(slot_id) => slot_id,
-                };
-                if leaf.insert(slot_id, key, value).is_some() {
-                    buffer.is_dirty.set(true);
-                    Ok(None)
-                } else {
-                    let prev_leaf_page_id = leaf.prev_page_id();
-                    let prev_leaf_buffer = prev_leaf_page_id
-                        .map(|next_leaf_page_id| bufmgr.fetch_page(next_leaf_page_id))
-                        .transpose()?;
-
-                    let new_leaf_buffer = bufmgr.create_page()?;
-
-                    if let Some(prev_leaf_buffer) = prev_leaf_buffer {
-                        let node =
-                            node::Node::new(prev_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                        let mut prev_leaf = leaf::Leaf::new(node.body);
-                        prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
-                        prev_leaf_buffer.is_dirty.set(true);
-                    }
-                    leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
-
-                    let mut new_leaf_node =
-                        node::Node::new(new_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                    new_leaf_node.initialize_as_leaf();
-                    let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
-                    new_leaf.initialize();
-                    let overflow_key = leaf.split_insert(&mut new_leaf, key, value);
-                    new_leaf.set_next_page_id(Some(buffer.page_id));
-                    new_leaf.set_prev_page_id(prev_leaf_page_id);
-                    buffer.is_dirty.set(true);
-                    Ok(Some((overflow_key, new_leaf_buffer.page_id)))
+        mode: InsertMode,
+    ) -> Result<bool, Error> {
+        let search_mode = SearchMode::Key(key.to_vec());
+        let mut parent_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let mut latch = bufmgr.fetch_page_shared(root_page_id(&parent_latch))?;
+        while let Some(child_page_id) = child_page_id(&latch, &search_mode) {
+            let child_latch = bufmgr.fetch_page_shared(child_page_id)?;
+            parent_latch = mem::replace(&mut latch, child_latch);
+        }
+        // 親のラッチを持っているので、取り直す間にリーフが分割されたり併合されたりすることはない
+        let leaf_buffer = latch.buffer().clone();
+        drop(latch);
+        let leaf_latch = bufmgr.latch_exclusive(&leaf_buffer)?;
+        drop(parent_latch);
+
+        let mut body = leaf_latch.body_mut();
+        let leaf_node = node::Node::new(&mut body[..]);
+        let mut leaf = leaf_node.into_leaf();
+        let done = match (leaf.search_slot_id(key), mode) {
+            (Ok(_), InsertMode::Insert) => return Err(Error::DuplicateKey),
+            (Err(_), InsertMode::Update) => return Err(Error::KeyNotFound),
+            (Ok(slot_id), _) => {
+                let old_page_ids = overflow_page_ids(bufmgr, leaf.value_at(slot_id))?;
+                let done = leaf.update(slot_id, value).is_some();
+                if done {
+                    free_pages(bufmgr, old_page_ids)?;
                 }
+                done
             }
-            node::Body::Branch(mut branch) => {
-                let child_idx = branch.search_child_idx(key);
-                let child_page_id = branch.child_at(child_idx);
-                let child_node_buffer = bufmgr.fetch_page(child_page_id)?;
-                if let Some((overflow_key_from_child, overflow_child_page_id)) =
-                    self.insert_internal(bufmgr, child_node_buffer, key, value)?
-                {
-                    if branch
-                        .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
-                        .is_some()
-                    {
-                        buffer.is_dirty.set(true);
-                        Ok(None)
-                    } else {
-                        let new_branch_buffer = bufmgr.create_page()?;
-                        let mut new_branch_node =
-                            node::Node::new(new_branch_buffer.page.borrow_mut() as RefMut<[_]>);
-                        new_branch_node.initialize_as_branch();
-                        let mut new_branch = branch::Branch::new(new_branch_node.body);
-                        let overflow_key = branch.split_insert(
-                            &mut new_branch,
-                            &overflow_key_from_child,
-                            overflow_child_page_id,
-                        );
-                        buffer.is_dirty.set(true);
-                        new_branch_buffer.is_dirty.set(true);
-                        Ok(Some((overflow_key, new_branch_buffer.page_id)))
+            (Err(slot_id), _) => leaf.insert(slot_id, key, value).is_some(),
+        };
+        if done {
+            bufmgr.mark_dirty(&leaf_latch);
+        }
+        Ok(done)
+    }
+
+    // 分割が起きる場合の挿入
+    // メタページから排他ラッチで降りていき、分割が伝わらないと分かったノードより上のラッチを外す
+    // 左隣のリーフのラッチが取れなければ、何も変更せずに false を返す
+    fn insert_pessimistic(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        mode: InsertMode,
+    ) -> Result<bool, Error> {
+        let search_mode = SearchMode::Key(key.to_vec());
+        let mut meta_latch = Some(bufmgr.fetch_page_exclusive(self.meta_page_id)?);
+        let root_page_id = root_page_id(meta_latch.as_ref().unwrap());
+        let mut latches = vec![bufmgr.fetch_page_exclusive(root_page_id)?];
+        loop {
+            let latch = latches.last().unwrap();
+            let is_safe = {
+                let body = latch.body();
+                let node = node::Node::new(&body[..]);
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Leaf(leaf) => leaf.has_room_for(key, value),
+                    node::Body::Branch(branch) => branch.is_safe_for_insert(),
+                }
+            };
+            let child_page_id = child_page_id(latch, &search_mode);
+            if is_safe {
+                meta_latch = None;
+                latches.drain(..latches.len() - 1);
+            }
+            match child_page_id {
+                Some(child_page_id) => latches.push(bufmgr.fetch_page_exclusive(child_page_id)?),
+                None => break,
+            }
+        }
+
+        let leaf_latch = latches.pop().unwrap();
+        let mut overflow = {
+            let mut body = leaf_latch.body_mut();
+            let leaf_node = node::Node::new(&mut body[..]);
+            let mut leaf = leaf_node.into_leaf();
+            let slot_id = match (leaf.search_slot_id(key), mode) {
+                (Ok(_), InsertMode::Insert) => return Err(Error::DuplicateKey),
+                (Err(_), InsertMode::Update) => return Err(Error::KeyNotFound),
+                (Ok(slot_id), _) => {
+                    let old_page_ids = overflow_page_ids(bufmgr, leaf.value_at(slot_id))?;
+                    if leaf.update(slot_id, value).is_some() {
+                        free_pages(bufmgr, old_page_ids)?;
+                        bufmgr.mark_dirty(&leaf_latch);
+                        return Ok(true);
                     }
-                } else {
-                    Ok(None)
+                    Ok((slot_id, old_page_ids))
                 }
+                (Err(slot_id), _) => {
+                    if leaf.insert(slot_id, key, value).is_some() {
+                        bufmgr.mark_dirty(&leaf_latch);
+                        return Ok(true);
+                    }
+                    Err(slot_id)
+                }
+            };
+
+            // 左から右へラッチを取るスレッドとデッドロックしないよう、左隣は待たずに取る
+            let prev_leaf_page_id = leaf.prev_page_id();
+            let prev_leaf_latch = match prev_leaf_page_id {
+                Some(prev_leaf_page_id) => {
+                    match bufmgr.try_fetch_page_exclusive(prev_leaf_page_id)? {
+                        Some(prev_leaf_latch) => Some(prev_leaf_latch),
+                        None => return Ok(false),
+                    }
+                }
+                None => None,
+            };
+            // 失敗しうる処理を済ませてからリーフを書き換える
+            let new_leaf_buffer = bufmgr.create_page()?;
+            let new_leaf_latch = bufmgr.latch_exclusive(&new_leaf_buffer)?;
+            // 新しい値が収まらない場合は、一度取り除いてから挿入し直す
+            if let Ok((slot_id, old_page_ids)) = slot_id {
+                leaf.remove(slot_id);
+                free_pages(bufmgr, old_page_ids)?;
+            }
+            if let Some(prev_leaf_latch) = prev_leaf_latch {
+                let mut body = prev_leaf_latch.body_mut();
+                let node = node::Node::new(&mut body[..]);
+                let mut prev_leaf = node.into_leaf();
+                prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
+                bufmgr.mark_dirty(&prev_leaf_latch);
             }
+            leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
+
+            let mut new_leaf_body = new_leaf_latch.body_mut();
+            let mut new_leaf_node = node::Node::new(&mut new_leaf_body[..]);
+            new_leaf_node.initialize_as_leaf();
+            let mut new_leaf = new_leaf_node.into_leaf();
+            new_leaf.initialize();
+            let overflow_key = leaf.split_insert(&mut new_leaf, key, value);
+            new_leaf.set_next_page_id(Some(leaf_latch.page_id));
+            new_leaf.set_prev_page_id(prev_leaf_page_id);
+            bufmgr.mark_dirty(&leaf_latch);
+            bufmgr.mark_dirty(&new_leaf_latch);
+            Some((overflow_key, new_leaf_buffer.page_id))
+        };
+
+        // 分割を親へ伝える。ラッチを持っているノードのどれかで止まる
+        while let Some((overflow_key_from_child, overflow_child_page_id)) = overflow.take() {
+            let latch = match latches.pop() {
+                Some(latch) => latch,
+                None => {
+                    // ルートまで分割されたので、新しいルートを作る
+                    let meta_latch = meta_latch.as_ref().expect("meta page must be latched");
+                    let new_root_buffer = bufmgr.create_page()?;
+                    let mut body = new_root_buffer.body_mut();
+                    let mut node = node::Node::new(&mut body[..]);
+                    node.initialize_as_branch();
+                    let mut branch = branch::Branch::new(node.body);
+                    branch.initialize(
+                        &overflow_key_from_child,
+                        overflow_child_page_id,
+                        root_page_id,
+                    );
+                    let mut meta_body = meta_latch.body_mut();
+                    let mut meta = meta::Meta::new(&mut meta_body[..]);
+                    meta.header.root_page_id = new_root_buffer.page_id;
+                    bufmgr.mark_dirty(meta_latch);
+                    break;
+                }
+            };
+            let mut body = latch.body_mut();
+            let node = node::Node::new(&mut body[..]);
+            let mut branch = branch::Branch::new(node.body);
+            let child_idx = branch.search_child_idx(key);
+            if branch
+                .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
+                .is_none()
+            {
+                let new_branch_buffer = bufmgr.create_page()?;
+                let mut new_branch_body = new_branch_buffer.body_mut();
+                let mut new_branch_node = node::Node::new(&mut new_branch_body[..]);
+                new_branch_node.initialize_as_branch();
+                let mut new_branch = branch::Branch::new(new_branch_node.body);
+                let overflow_key = branch.split_insert(
+                    &mut new_branch,
+                    &overflow_key_from_child,
+                    overflow_child_page_id,
+                );
+                overflow = Some((overflow_key, new_branch_buffer.page_id));
+            }
+            bufmgr.mark_dirty(&latch);
         }
+        Ok(true)
     }
 
     pub fn insert(
         &self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
         key: &[u8],
         value: &[u8],
     ) -> Result<(), Error> {
-        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
-        let root_page_id = meta.header.root_page_id;
-        let root_buffer = bufmgr.fetch_page(root_page_id)?;
-        if let Some((key, child_page_id)) = self.insert_internal(bufmgr, root_buffer, key, value)? {
-            let new_root_buffer = bufmgr.create_page()?;
-            let mut node = node::Node::new(new_root_buffer.page.borrow_mut() as RefMut<[_]>);
-            node.initialize_as_branch();
-            let mut branch = branch::Branch::new(node.body);
-            branch.initialize(&key, child_page_id, root_page_id);
-            meta.header.root_page_id = new_root_buffer.page_id;
-            meta_buffer.is_dirty.set(true);
+        self.insert_with_mode(bufmgr, key, value, InsertMode::Insert)
+    }
+
+    // 既存のキーの値を書き換える
+    pub fn update(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.insert_with_mode(bufmgr, key, value, InsertMode::Update)
+    }
+
+    // キーがあれば値を書き換え、なければ挿入する
+    pub fn upsert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.insert_with_mode(bufmgr, key, value, InsertMode::Upsert)
+    }
+
+    fn insert_with_mode(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        mode: InsertMode,
+    ) -> Result<(), Error> {
+        let _lock = bufmgr.lock_key(self.meta_page_id, key)?;
+        bufmgr.with_mtr(|bufmgr| {
+            let undo_record = self.undo_record(bufmgr, key)?;
+            let value = store_value(bufmgr, key, value)?;
+            if let Err(err) = self.insert_stored(bufmgr, key, &value, mode) {
+                // 挿入できなかった値のオーバーフローページを解放する
+                free_value(bufmgr, &value)?;
+                return Err(err);
+            }
+            if let Some(undo_record) = undo_record {
+                bufmgr.push_undo(undo_record);
+            }
+            Ok(())
+        })
+    }
+
+    // value はリーフに置くバイト列
+    fn insert_stored(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        mode: InsertMode,
+    ) -> Result<(), Error> {
+        if !self.insert_optimistic(bufmgr, key, value, mode)? {
+            while !self.insert_pessimistic(bufmgr, key, value, mode)? {
+                thread::yield_now();
+            }
+        }
+        Ok(())
+    }
+
+    // 戻り値は子ノードが半分を下回ったかどうか
+    // メタページの排他ラッチを持った状態で呼ぶので、他に木の形を変えるスレッドはいない
+    fn delete_internal(
+        &self,
+        bufmgr: &BufferPoolManager,
+        latch: &PageLatch,
+        key: &[u8],
+        freed_page_ids: &mut Vec<PageId>,
+    ) -> Result<bool, Error> {
+        let mut body = latch.body_mut();
+        let node = node::Node::new(&mut body[..]);
+        match node::Body::new(node.header.node_type, node.body) {
+            node::Body::Leaf(mut leaf) => {
+                let slot_id = leaf.search_slot_id(key).map_err(|_| Error::KeyNotFound)?;
+                freed_page_ids.extend(overflow_page_ids(bufmgr, leaf.value_at(slot_id))?);
+                leaf.remove(slot_id);
+                bufmgr.mark_dirty(latch);
+                Ok(!leaf.is_half_full())
+            }
+            node::Body::Branch(mut branch) => {
+                let child_idx = branch.search_child_idx(key);
+                let child_latch = bufmgr.fetch_page_exclusive(branch.child_at(child_idx))?;
+                if !self.delete_internal(bufmgr, &child_latch, key, freed_page_ids)? {
+                    return Ok(false);
+                }
+                // 子が1つしかないので組む兄弟がいない。親がこのブランチを兄弟とまとめる
+                if branch.num_pairs() == 0 {
+                    return Ok(true);
+                }
+                // 右隣の兄弟があればそれと、なければ左隣の兄弟と組にする
+                let slot_id = child_idx.min(branch.num_pairs() - 1);
+                let left_page_id = branch.child_at(slot_id);
+                let right_page_id = branch.child_at(slot_id + 1);
+                let left_latch = bufmgr.fetch_page_exclusive(left_page_id)?;
+                let right_latch = bufmgr.fetch_page_exclusive(right_page_id)?;
+                let mut left_body = left_latch.body_mut();
+                let mut right_body = right_latch.body_mut();
+                let left_node = node::Node::new(&mut left_body[..]);
+                let right_node = node::Node::new(&mut right_body[..]);
+                match (
+                    node::Body::new(left_node.header.node_type, left_node.body),
+                    node::Body::new(right_node.header.node_type, right_node.body),
+                ) {
+                    (node::Body::Leaf(mut left), node::Body::Leaf(mut right)) => {
+                        if left.can_merge(&right) {
+                            // 左のリーフを右のリーフにまとめて、左のリーフのページを解放する
+                            let prev_leaf_page_id = left.prev_page_id();
+                            left.merge_into(&mut right);
+                            right.set_prev_page_id(prev_leaf_page_id);
+                            if let Some(prev_leaf_page_id) = prev_leaf_page_id {
+                                let prev_leaf_latch =
+                                    bufmgr.fetch_page_exclusive(prev_leaf_page_id)?;
+                                let mut body = prev_leaf_latch.body_mut();
+                                let node = node::Node::new(&mut body[..]);
+                                let mut prev_leaf = node.into_leaf();
+                                prev_leaf.set_next_page_id(Some(right_page_id));
+                                bufmgr.mark_dirty(&prev_leaf_latch);
+                            }
+                            branch.remove(slot_id);
+                            freed_page_ids.push(left_page_id);
+                        } else {
+                            Self::redistribute_leaves(&mut branch, slot_id, &mut left, &mut right);
+                        }
+                    }
+                    (node::Body::Branch(mut left), node::Body::Branch(mut right)) => {
+                        let sep_key = branch.pair_at(slot_id).key.to_vec();
+                        if left.can_merge(&right, &sep_key) {
+                            left.merge_into(&mut right, &sep_key);
+                            branch.remove(slot_id);
+                            freed_page_ids.push(left_page_id);
+                        } else {
+                            Self::redistribute_branches(
+                                &mut branch,
+                                slot_id,
+                                &mut left,
+                                &mut right,
+                            );
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                bufmgr.mark_dirty(&left_latch);
+                bufmgr.mark_dirty(&right_latch);
+                bufmgr.mark_dirty(latch);
+                Ok(!branch.is_half_full())
+            }
+        }
+    }
+
+    // 2つのリーフのペアを均等に分け直し、親の区切りキーを更新する
+    // 親に新しい区切りキーが収まらない場合は何もしない
+    fn redistribute_leaves(
+        branch: &mut branch::Branch<impl ByteSliceMut>,
+        slot_id: usize,
+        left: &mut leaf::Leaf<impl ByteSliceMut>,
+        right: &mut leaf::Leaf<impl ByteSliceMut>,
+    ) {
+        let mut pairs = left.pairs();
+        pairs.extend(right.pairs());
+        let mid = leaf::split_point(left, right, &pairs);
+        let sep_key = leaf::separator(&pairs[mid - 1].0, &pairs[mid].0);
+        if branch.set_key_at(slot_id, &sep_key).is_some() {
+            left.assign(&pairs[..mid]);
+            right.assign(&pairs[mid..]);
+        }
+    }
+
+    // 少ない方が半分を超えるまで子を移す
+    // 新しい区切りキーが親に収まらなければ、収まるまで移した子を1つずつ戻す
+    // 区切りキーを持たないブランチには、少なくとも1つは移せるよう最後まで試す
+    fn redistribute_branches(
+        branch: &mut branch::Branch<impl ByteSliceMut>,
+        slot_id: usize,
+        left: &mut branch::Branch<impl ByteSliceMut>,
+        right: &mut branch::Branch<impl ByteSliceMut>,
+    ) {
+        let mut sep_keys = vec![branch.pair_at(slot_id).key.to_vec()];
+        let to_right = !right.is_half_full();
+        if to_right {
+            while !right.is_half_full() && left.num_pairs() > 1 {
+                let sep_key = left.rotate_right(right, sep_keys.last().unwrap());
+                sep_keys.push(sep_key);
+            }
+        } else {
+            while !left.is_half_full() && right.num_pairs() > 1 {
+                let sep_key = right.rotate_left(left, sep_keys.last().unwrap());
+                sep_keys.push(sep_key);
+            }
+        }
+        while sep_keys.len() > 1 {
+            let sep_key = sep_keys.pop().unwrap();
+            if branch.set_key_at(slot_id, &sep_key).is_some() {
+                return;
+            }
+            if to_right {
+                right.rotate_left(left, &sep_key);
+            } else {
+                left.rotate_right(right, &sep_key);
+            }
+        }
+    }
+
+    // 木の形が変わることがあるので、メタページの排他ラッチを持ったまま削除する
+    // 併合で空になって解放したページのIDを返す。解放したページはコミット時にフリーリストへ返る
+    pub fn delete(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Vec<PageId>, Error> {
+        let _lock = bufmgr.lock_key(self.meta_page_id, key)?;
+        bufmgr.with_mtr(|bufmgr| {
+            let undo_record = match self.undo_record(bufmgr, key)? {
+                Some(UndoRecord::Update {
+                    meta_page_id,
+                    key,
+                    value,
+                }) => Some(UndoRecord::Delete {
+                    meta_page_id,
+                    key,
+                    value,
+                }),
+                Some(_) => return Err(Error::KeyNotFound),
+                None => None,
+            };
+            let mut freed_page_ids = vec![];
+            {
+                let meta_latch = bufmgr.fetch_page_exclusive(self.meta_page_id)?;
+                let root_page_id = root_page_id(&meta_latch);
+                let root_latch = bufmgr.fetch_page_exclusive(root_page_id)?;
+                self.delete_internal(bufmgr, &root_latch, key, &mut freed_page_ids)?;
+
+                // ルートのブランチが子を1つしか持たなくなったら、その子を新しいルートにして木を低くする
+                let body = root_latch.body();
+                let root_node = node::Node::new(&body[..]);
+                if let node::Body::Branch(branch) =
+                    node::Body::new(root_node.header.node_type, root_node.body)
+                {
+                    if branch.num_pairs() == 0 {
+                        let mut meta_body = meta_latch.body_mut();
+                        let mut meta = meta::Meta::new(&mut meta_body[..]);
+                        meta.header.root_page_id = branch.child_at(0);
+                        bufmgr.mark_dirty(&meta_latch);
+                        freed_page_ids.push(root_page_id);
+                    }
+                }
+            }
+            // ラッチを外してから、使われなくなったページを解放する
+            for &page_id in &freed_page_ids {
+                bufmgr.delete_page(page_id)?;
+            }
+            if let Some(undo_record) = undo_record {
+                bufmgr.push_undo(undo_record);
+            }
+            Ok(freed_page_ids)
+        })
+    }
+
+    // ルートから choose で選んだ子へ降りて、たどり着いたリーフのページIDとペアを返す
+    // choose は子の数を受け取って子の位置を返す。もう 1 つの値は通ったブランチの子の数の積で、
+    // 子を一様に選べば、そのリーフを選ぶ確率の逆数になる
+    #[allow(clippy::type_complexity)]
+    pub fn sample_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        mut choose: impl FnMut(usize) -> usize,
+    ) -> Result<(PageId, f64, Vec<(Vec<u8>, Vec<u8>)>), Error> {
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let mut latch = bufmgr.fetch_page_shared(root_page_id(&meta_latch))?;
+        drop(meta_latch);
+        let mut weight = 1.0;
+        loop {
+            let child_page_id = {
+                let body = latch.body();
+                let node = node::Node::new(&body[..]);
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Branch(branch) => {
+                        let num_children = branch.num_pairs() + 1;
+                        weight *= num_children as f64;
+                        branch.child_at(choose(num_children))
+                    }
+                    node::Body::Leaf(leaf) => {
+                        let pairs = (0..leaf.num_pairs())
+                            .map(|slot_id| {
+                                let value = load_value(bufmgr, leaf.value_at(slot_id))?;
+                                Ok((leaf.key_at(slot_id), value))
+                            })
+                            .collect::<Result<_, Error>>()?;
+                        return Ok((latch.buffer().page_id, weight, pairs));
+                    }
+                }
+            };
+            latch = bufmgr.fetch_page_shared(child_page_id)?;
+        }
+    }
+
+    // 木を構成する全てのページを解放する
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<(), Error> {
+        let mut page_ids = vec![self.meta_page_id];
+        {
+            let meta_latch = bufmgr.fetch_page_exclusive(self.meta_page_id)?;
+            let mut stack = vec![root_page_id(&meta_latch)];
+            while let Some(page_id) = stack.pop() {
+                page_ids.push(page_id);
+                let latch = bufmgr.fetch_page_shared(page_id)?;
+                let body = latch.body();
+                let node = node::Node::new(&body[..]);
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Branch(branch) => stack.extend(
+                        (0..=branch.num_pairs()).map(|child_idx| branch.child_at(child_idx)),
+                    ),
+                    node::Body::Leaf(leaf) => {
+                        for slot_id in 0..leaf.num_pairs() {
+                            let value = leaf.value_at(slot_id);
+                            page_ids.extend(overflow_page_ids(bufmgr, value)?);
+                        }
+                    }
+                }
+            }
+        }
+        for page_id in page_ids {
+            bufmgr.delete_page(page_id)?;
         }
         Ok(())
     }
 }
 
+// 大きな値はオーバーフローページに移して、リーフに置くバイト列を返す
+fn store_value(bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<Vec<u8>, Error> {
+    let inline = overflow::LeafValue::Inline(value).to_bytes();
+    let pair_size = |value: &[u8]| Pair { key, value }.to_bytes().len();
+    if pair_size(&inline) <= MAX_INLINE_PAIR_SIZE {
+        return Ok(inline);
+    }
+    let stub = overflow::LeafValue::Overflow {
+        first_page_id: PageId::INVALID_PAGE_ID,
+        len: value.len() as u64,
+    };
+    if pair_size(&stub.to_bytes()) > MAX_INLINE_PAIR_SIZE {
+        return Err(Error::KeyTooLarge(key.len()));
+    }
+    let first_page_id = overflow::write(bufmgr, value)?;
+    Ok(overflow::LeafValue::Overflow {
+        first_page_id,
+        len: value.len() as u64,
+    }
+    .to_bytes())
+}
+
+// リーフに置かれたバイト列から値を組み立てる。リーフのラッチを持ったまま呼ぶ
+fn load_value(bufmgr: &BufferPoolManager, stored: &[u8]) -> Result<Vec<u8>, Error> {
+    match overflow::LeafValue::from_bytes(stored) {
+        overflow::LeafValue::Inline(value) => Ok(value.to_vec()),
+        overflow::LeafValue::Overflow { first_page_id, len } => {
+            Ok(overflow::read(bufmgr, first_page_id, len)?)
+        }
+    }
+}
+
+fn overflow_page_ids(bufmgr: &BufferPoolManager, stored: &[u8]) -> Result<Vec<PageId>, Error> {
+    match overflow::LeafValue::from_bytes(stored) {
+        overflow::LeafValue::Inline(_) => Ok(vec![]),
+        overflow::LeafValue::Overflow { first_page_id, .. } => {
+            Ok(overflow::page_ids(bufmgr, first_page_id)?)
+        }
+    }
+}
+
+fn free_value(bufmgr: &BufferPoolManager, stored: &[u8]) -> Result<(), Error> {
+    free_pages(bufmgr, overflow_page_ids(bufmgr, stored)?)
+}
+
+fn free_pages(bufmgr: &BufferPoolManager, page_ids: Vec<PageId>) -> Result<(), Error> {
+    for page_id in page_ids {
+        bufmgr.delete_page(page_id)?;
+    }
+    Ok(())
+}
+
+// key が lower より前にあるかどうか
+fn below(key: &[u8], lower: &Bound<Vec<u8>>) -> bool {
+    match lower {
+        Bound::Unbounded => false,
+        Bound::Included(bound) => key < &bound[..],
+        Bound::Excluded(bound) => key <= &bound[..],
+    }
+}
+
+// key が upper より後にあるかどうか
+fn above(key: &[u8], upper: &Bound<Vec<u8>>) -> bool {
+    match upper {
+        Bound::Unbounded => false,
+        Bound::Included(bound) => key > &bound[..],
+        Bound::Excluded(bound) => key >= &bound[..],
+    }
+}
+
+// イテレータの位置。ペアとペアの間を指していて、next はその後ろの、prev はその前のペアを返す
+#[derive(Debug, Clone)]
+enum Position {
+    Start,
+    Before(Vec<u8>),
+    After(Vec<u8>),
+    End,
+}
+
+impl Position {
+    fn lower(lower: &Bound<Vec<u8>>) -> Self {
+        match lower {
+            Bound::Unbounded => Position::Start,
+            Bound::Included(key) => Position::Before(key.clone()),
+            Bound::Excluded(key) => Position::After(key.clone()),
+        }
+    }
+
+    fn upper(upper: &Bound<Vec<u8>>) -> Self {
+        match upper {
+            Bound::Unbounded => Position::End,
+            Bound::Included(key) => Position::After(key.clone()),
+            Bound::Excluded(key) => Position::Before(key.clone()),
+        }
+    }
+
+    fn search_mode(&self) -> SearchMode {
+        match self {
+            Position::Start => SearchMode::Start,
+            Position::Before(key) | Position::After(key) => SearchMode::Key(key.clone()),
+            Position::End => SearchMode::End,
+        }
+    }
+
+    // リーフの中で、この位置より前にあるペアの数
+    fn slot_id_in(&self, leaf: &leaf::Leaf<impl ByteSlice>) -> usize {
+        match self {
+            Position::Start => 0,
+            Position::Before(key) => leaf.search_slot_id(key).unwrap_or_else(identity),
+            Position::After(key) => match leaf.search_slot_id(key) {
+                Ok(slot_id) => slot_id + 1,
+                Err(slot_id) => slot_id,
+            },
+            Position::End => leaf.num_pairs(),
+        }
+    }
+}
+
+// 呼び出しの間はラッチを持たないので、他のスレッドが木を書き換えていても読み進められる
+// リーフが書き換えられていたら、最後に返したキーを手がかりに続きの位置を探し直す
 pub struct Iter {
-    buffer: Rc<Buffer>,
+    meta_page_id: PageId,
+    buffer: Arc<Buffer>,
+    // 最後に位置を確かめた時のリーフのバージョン
+    version: u64,
+    // リーフの中で position より前にあるペアの数
     slot_id: usize,
+    position: Position,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+}
+
+enum Step {
+    Found(Vec<u8>, Vec<u8>),
+    Sibling(Option<PageId>),
+    Seek,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
 }
 
 impl Iter {
-    fn get(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-        let leaf = leaf::Leaf::new(leaf_node.body);
-        if self.slot_id < leaf.num_pairs() {
-            let pair = leaf.pair_at(self.slot_id);
-            Some((pair.key.to_vec(), pair.value.to_vec()))
+    // ルートから探し直して、ラッチを取ったリーフを返す
+    fn seek(&mut self, bufmgr: &BufferPoolManager) -> Result<PageLatch, Error> {
+        let leaf_latch =
+            BTree::new(self.meta_page_id).find_leaf(bufmgr, &self.position.search_mode())?;
+        self.enter(&leaf_latch);
+        Ok(leaf_latch)
+    }
+
+    fn enter(&mut self, leaf_latch: &PageLatch) {
+        {
+            let body = leaf_latch.body();
+            let leaf_node = node::Node::new(&body[..]);
+            let leaf = leaf_node.into_leaf();
+            self.slot_id = self.position.slot_id_in(&leaf);
+        }
+        self.buffer = leaf_latch.buffer().clone();
+        self.version = leaf_latch.version();
+    }
+
+    #[cfg(test)]
+    fn get(&self, bufmgr: &BufferPoolManager) -> Option<(Vec<u8>, Vec<u8>)> {
+        let leaf_latch = self.buffer.latch_shared();
+        let body = leaf_latch.body();
+        let leaf_node = node::Node::new(&body[..]);
+        let leaf = leaf_node.into_leaf();
+        let slot_id = if leaf_latch.version() == self.version {
+            self.slot_id
+        } else {
+            self.position.slot_id_in(&leaf)
+        };
+        if slot_id < leaf.num_pairs() {
+            let value = load_value(bufmgr, leaf.value_at(slot_id)).unwrap();
+            Some((leaf.key_at(slot_id), value))
         } else {
             None
         }
@@ -250,62 +932,164 @@ impl Iter {
     #[allow(clippy::type_complexity)]
     pub fn next(
         &mut self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
     ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
-        let value = self.get();
-        self.slot_id += 1;
-        let next_page_id = {
-            let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-            let leaf = leaf::Leaf::new(leaf_node.body);
-            if self.slot_id < leaf.num_pairs() {
-                return Ok(value);
-            }
-            leaf.next_page_id()
-        };
-        if let Some(next_page_id) = next_page_id {
-            self.buffer = bufmgr.fetch_page(next_page_id)?;
-            self.slot_id = 0;
+        self.step(bufmgr, Direction::Forward)
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn prev(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.step(bufmgr, Direction::Backward)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn step(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        direction: Direction,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        let mut leaf_latch = self.buffer.latch_shared();
+        loop {
+            let step = {
+                let body = leaf_latch.body();
+                let leaf_node = node::Node::new(&body[..]);
+                let leaf = leaf_node.into_leaf();
+                let version = leaf_latch.version();
+                let relocated = version != self.version;
+                if relocated {
+                    self.version = version;
+                    self.slot_id = self.position.slot_id_in(&leaf);
+                }
+                match direction {
+                    // 分割で左のリーフに移されたペアがあるかもしれない
+                    Direction::Forward
+                        if relocated && self.slot_id == 0 && leaf.prev_page_id().is_some() =>
+                    {
+                        Step::Seek
+                    }
+                    Direction::Forward if self.slot_id < leaf.num_pairs() => {
+                        let value = load_value(bufmgr, leaf.value_at(self.slot_id))?;
+                        Step::Found(leaf.key_at(self.slot_id), value)
+                    }
+                    Direction::Forward => Step::Sibling(leaf.next_page_id()),
+                    // 併合で右のリーフに移されたペアがあるかもしれない
+                    Direction::Backward
+                        if relocated
+                            && self.slot_id == leaf.num_pairs()
+                            && leaf.next_page_id().is_some() =>
+                    {
+                        Step::Seek
+                    }
+                    Direction::Backward if self.slot_id > 0 => {
+                        let value = load_value(bufmgr, leaf.value_at(self.slot_id - 1))?;
+                        Step::Found(leaf.key_at(self.slot_id - 1), value)
+                    }
+                    Direction::Backward => Step::Sibling(leaf.prev_page_id()),
+                }
+            };
+            match step {
+                Step::Found(key, value) => {
+                    match direction {
+                        Direction::Forward => {
+                            if above(&key, &self.upper) {
+                                return Ok(None);
+                            }
+                            self.slot_id += 1;
+                            self.position = Position::After(key.clone());
+                        }
+                        Direction::Backward => {
+                            if below(&key, &self.lower) {
+                                return Ok(None);
+                            }
+                            self.slot_id -= 1;
+                            self.position = Position::Before(key.clone());
+                        }
+                    }
+                    return Ok(Some((key, value)));
+                }
+                Step::Sibling(None) => return Ok(None),
+                Step::Sibling(Some(sibling_page_id)) => {
+                    // 隣へは今のリーフのラッチを持ったまま移る。取れなければ探し直す
+                    // 左隣のラッチを待つと右に向かってラッチを取る削除とデッドロックするので、
+                    // どちら向きでも待たない
+                    let sibling_buffer = bufmgr.fetch_page(sibling_page_id)?;
+                    let linked = |sibling_latch: &PageLatch| match direction {
+                        Direction::Forward => prev_page_id(sibling_latch),
+                        Direction::Backward => next_page_id(sibling_latch),
+                    } == Some(self.buffer.page_id);
+                    match sibling_buffer.try_latch_shared() {
+                        Some(sibling_latch) if linked(&sibling_latch) => {
+                            leaf_latch = sibling_latch;
+                            self.enter(&leaf_latch);
+                        }
+                        _ => {
+                            drop(leaf_latch);
+                            leaf_latch = self.seek(bufmgr)?;
+                        }
+                    }
+                }
+                Step::Seek => {
+                    drop(leaf_latch);
+                    leaf_latch = self.seek(bufmgr)?;
+                }
+            }
         }
-        Ok(value)
     }
 }
 
+fn prev_page_id(leaf_latch: &PageLatch) -> Option<PageId> {
+    let body = leaf_latch.body();
+    let leaf_node = node::Node::new(&body[..]);
+    leaf_node.into_leaf().prev_page_id()
+}
+
+fn next_page_id(leaf_latch: &PageLatch) -> Option<PageId> {
+    let body = leaf_latch.body();
+    let leaf_node = node::Node::new(&body[..]);
+    leaf_node.into_leaf().next_page_id()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
     use tempfile::tempfile;
 
+    use crate::check::Checker;
     use crate::{buffer::BufferPool, disk::DiskManager};
 
     use super::*;
+
     #[test]
     fn test() {
         let disk = DiskManager::new(tempfile().unwrap()).unwrap();
         let pool = BufferPool::new(10);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
-        let btree = BTree::create(&mut bufmgr).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
         btree
-            .insert(&mut bufmgr, &6u64.to_be_bytes(), b"world")
+            .insert(&bufmgr, &6u64.to_be_bytes(), b"world")
             .unwrap();
         btree
-            .insert(&mut bufmgr, &3u64.to_be_bytes(), b"hello")
-            .unwrap();
-        btree
-            .insert(&mut bufmgr, &8u64.to_be_bytes(), b"!")
-            .unwrap();
-        btree
-            .insert(&mut bufmgr, &4u64.to_be_bytes(), b",")
+            .insert(&bufmgr, &3u64.to_be_bytes(), b"hello")
             .unwrap();
+        btree.insert(&bufmgr, &8u64.to_be_bytes(), b"!").unwrap();
+        btree.insert(&bufmgr, &4u64.to_be_bytes(), b",").unwrap();
 
         let (_, value) = btree
-            .search(&mut bufmgr, SearchMode::Key(3u64.to_be_bytes().to_vec()))
+            .search(&bufmgr, SearchMode::Key(3u64.to_be_bytes().to_vec()))
             .unwrap()
-            .get()
+            .get(&bufmgr)
             .unwrap();
         assert_eq!(b"hello", &value[..]);
         let (_, value) = btree
-            .search(&mut bufmgr, SearchMode::Key(8u64.to_be_bytes().to_vec()))
+            .search(&bufmgr, SearchMode::Key(8u64.to_be_bytes().to_vec()))
             .unwrap()
-            .get()
+            .get(&bufmgr)
             .unwrap();
         assert_eq!(b"!", &value[..]);
     }
@@ -314,9 +1098,9 @@ mod tests {
     fn test_split() {
         let disk = DiskManager::new(tempfile().unwrap()).unwrap();
         let pool = BufferPool::new(10);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
-        let btree = BTree::create(&mut bufmgr).unwrap();
-        let long_data_list = vec![
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let long_data_list = [
             vec![0xC0u8; 1000],
             vec![0x01u8; 1000],
             vec![0xCAu8; 1000],
@@ -327,16 +1111,632 @@ mod tests {
             vec![0xAEu8; 1000],
         ];
         for data in long_data_list.iter() {
-            btree.insert(&mut bufmgr, &data, &data).unwrap();
+            btree.insert(&bufmgr, data, data).unwrap();
         }
         for data in long_data_list.iter() {
             let (k, v) = btree
-                .search(&mut bufmgr, SearchMode::Key(data.clone()))
+                .search(&bufmgr, SearchMode::Key(data.clone()))
                 .unwrap()
-                .get()
+                .get(&bufmgr)
                 .unwrap();
             assert_eq!(data, &k);
             assert_eq!(data, &v);
         }
     }
+
+    #[test]
+    fn test_update() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..16 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0x01; 100])
+                .unwrap();
+        }
+        assert!(matches!(
+            btree.update(&bufmgr, &16u64.to_be_bytes(), b"hello"),
+            Err(Error::KeyNotFound)
+        ));
+
+        // 値が大きくなってリーフに収まらなくなると分割される
+        for i in 0u64..16 {
+            btree
+                .update(&bufmgr, &i.to_be_bytes(), &[i as u8; 1000])
+                .unwrap();
+        }
+        btree
+            .upsert(&bufmgr, &3u64.to_be_bytes(), b"hello")
+            .unwrap();
+        btree
+            .upsert(&bufmgr, &16u64.to_be_bytes(), b"world")
+            .unwrap();
+
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..=16 {
+            let (k, v) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), k.as_slice());
+            match i {
+                3 => assert_eq!(b"hello", v.as_slice()),
+                16 => assert_eq!(b"world", v.as_slice()),
+                _ => assert_eq!(vec![i as u8; 1000], v),
+            }
+        }
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_update_without_free_page() {
+        let fails = Arc::new(AtomicBool::new(false));
+        let mut disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        {
+            let fails = fails.clone();
+            disk.set_fault_hook(Box::new(move || match fails.load(Ordering::SeqCst) {
+                true => Err(std::io::Error::other("no space left")),
+                false => Ok(()),
+            }));
+        }
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..30 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[i as u8; 100])
+                .unwrap();
+        }
+
+        // 分割に使うページが作れなければ、元の値を残したまま失敗する
+        fails.store(true, Ordering::SeqCst);
+        assert!(btree
+            .update(&bufmgr, &3u64.to_be_bytes(), &[0xFF; 1000])
+            .is_err());
+        fails.store(false, Ordering::SeqCst);
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..30 {
+            let (k, v) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), k.as_slice());
+            assert_eq!(vec![i as u8; 100], v);
+        }
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+
+        btree
+            .update(&bufmgr, &3u64.to_be_bytes(), &[0xFF; 1000])
+            .unwrap();
+        assert_eq!(
+            Some(vec![0xFF; 1000]),
+            btree.get(&bufmgr, &3u64.to_be_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let key_of = |i: u64| i.to_be_bytes().repeat(8);
+        const NUM_KEYS: u64 = 2000;
+        for i in 0..NUM_KEYS {
+            let i = i * 7919 % NUM_KEYS;
+            btree.insert(&bufmgr, &key_of(i), &[0xAB; 200]).unwrap();
+        }
+
+        let mut freed_page_ids = vec![];
+        for i in (1..NUM_KEYS).step_by(2) {
+            freed_page_ids.extend(btree.delete(&bufmgr, &key_of(i)).unwrap());
+        }
+        // 削除で解放されたページが返り、再利用される
+        assert!(!freed_page_ids.is_empty());
+        let page_id = bufmgr.create_page().unwrap().page_id;
+        assert!(freed_page_ids.contains(&page_id), "{:?}", page_id);
+        assert!(matches!(
+            btree.delete(&bufmgr, &key_of(1)),
+            Err(Error::KeyNotFound)
+        ));
+
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in (0..NUM_KEYS).step_by(2) {
+            let (k, _) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(key_of(i), k);
+        }
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+        for i in (0..NUM_KEYS).step_by(2) {
+            let (k, _) = btree
+                .search(&bufmgr, SearchMode::Key(key_of(i)))
+                .unwrap()
+                .get(&bufmgr)
+                .unwrap();
+            assert_eq!(key_of(i), k);
+        }
+
+        for i in (0..NUM_KEYS).step_by(2) {
+            btree.delete(&bufmgr, &key_of(i)).unwrap();
+        }
+        let root_buffer = btree.fetch_root_page(&bufmgr).unwrap();
+        let body = root_buffer.body();
+        let root = node::Node::new(&body[..]);
+        match node::Body::new(root.header.node_type, root.body) {
+            node::Body::Leaf(leaf) => assert_eq!(0, leaf.num_pairs()),
+            node::Body::Branch(_) => panic!("root must collapse into a leaf"),
+        }
+    }
+
+    #[test]
+    fn test_delete_variable_keys() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(32);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        // 隣り合うキーが長い接頭辞を共有するので、区切りキーも長くなる
+        let key_of = |i: u64| {
+            let mut key = vec![(i % 16) as u8; 8 + (i * 37 % 400) as usize];
+            key.extend_from_slice(&i.to_be_bytes());
+            key
+        };
+        const NUM_KEYS: u64 = 3000;
+        for i in 0..NUM_KEYS {
+            btree.insert(&bufmgr, &key_of(i), b"").unwrap();
+        }
+        for (n, i) in (0..NUM_KEYS).map(|i| i * 7919 % NUM_KEYS).enumerate() {
+            btree.delete(&bufmgr, &key_of(i)).unwrap();
+            if n % 500 == 0 {
+                let mut checker = Checker::new(u64::MAX);
+                btree.check(&bufmgr, "test", &mut checker);
+                assert!(checker.violations().is_empty(), "{:?}", checker.violations());
+            }
+        }
+    }
+
+    #[test]
+    fn test_redistribute_branches() {
+        let mut parent_data = vec![0u8; 48];
+        let mut parent = branch::Branch::new(parent_data.as_mut_slice());
+        parent.initialize(b"m", PageId(100), PageId(101));
+        let mut left_data = vec![0u8; 256];
+        let mut left = branch::Branch::new(left_data.as_mut_slice());
+        left.initialize(&[b'a'; 40], PageId(1), PageId(2));
+        left.insert(1, &[b'b'; 40], PageId(3)).unwrap();
+        left.insert(2, &[b'c'; 40], PageId(4)).unwrap();
+        left.insert(3, b"d", PageId(5)).unwrap();
+        // 区切りキーを持たないブランチ
+        let mut right_data = vec![0u8; 256];
+        let mut right = branch::Branch::new(right_data.as_mut_slice());
+        right.initialize(b"x", PageId(6), PageId(7));
+        right.fill_right_child();
+        assert_eq!(0, right.num_pairs());
+
+        // 均等に分けた時の長い区切りキーは親に収まらないので、短いキーで区切れるところまで戻す
+        BTree::redistribute_branches(&mut parent, 0, &mut left, &mut right);
+        assert_eq!(b"d", parent.pair_at(0).key);
+        assert_eq!(3, left.num_pairs());
+        assert_eq!(1, right.num_pairs());
+        // 左の一番右の子が、右のブランチの先頭に移る
+        assert_eq!(PageId(2), right.search_child(b"d"));
+        assert_eq!(PageId(6), right.search_child(b"m"));
+    }
+
+    #[test]
+    fn test_range() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let key_of = |i: u64| i.to_be_bytes().repeat(8);
+        const NUM_KEYS: u64 = 1000;
+        for i in 0..NUM_KEYS {
+            let i = i * 7919 % NUM_KEYS;
+            btree.insert(&bufmgr, &key_of(i * 2), &[0xAB; 200]).unwrap();
+        }
+        let keys = |iter: &mut Iter, forward: bool| {
+            let mut keys = vec![];
+            loop {
+                let pair = if forward {
+                    iter.next(&bufmgr)
+                } else {
+                    iter.prev(&bufmgr)
+                };
+                match pair.unwrap() {
+                    Some((k, _)) => keys.push(k),
+                    None => return keys,
+                }
+            }
+        };
+
+        let mut iter = btree.search(&bufmgr, SearchMode::End).unwrap();
+        let expected: Vec<_> = (0..NUM_KEYS).rev().map(|i| key_of(i * 2)).collect();
+        assert_eq!(expected, keys(&mut iter, false));
+        assert!(iter.prev(&bufmgr).unwrap().is_none());
+        // 先頭まで戻ったら、また前に向かって読める
+        let (k, _) = iter.next(&bufmgr).unwrap().unwrap();
+        assert_eq!(key_of(0), k);
+
+        // next と prev は同じペアを返す
+        let mut iter = btree.search(&bufmgr, SearchMode::Key(key_of(501))).unwrap();
+        assert_eq!(key_of(502), iter.next(&bufmgr).unwrap().unwrap().0);
+        assert_eq!(key_of(502), iter.prev(&bufmgr).unwrap().unwrap().0);
+        assert_eq!(key_of(500), iter.prev(&bufmgr).unwrap().unwrap().0);
+
+        let (lower, upper) = (key_of(100), key_of(1500));
+        for (lower, upper, first, last) in [
+            (Bound::Included(&lower), Bound::Included(&upper), 100, 1500),
+            (Bound::Excluded(&lower), Bound::Excluded(&upper), 102, 1498),
+            (Bound::Unbounded, Bound::Excluded(&upper), 0, 1498),
+            (
+                Bound::Excluded(&lower),
+                Bound::Unbounded,
+                102,
+                NUM_KEYS * 2 - 2,
+            ),
+        ] {
+            let expected: Vec<_> = (first..=last).step_by(2).map(key_of).collect();
+            let mut iter = btree
+                .range(&bufmgr, SearchMode::Start, lower.cloned(), upper.cloned())
+                .unwrap();
+            assert_eq!(expected, keys(&mut iter, true));
+            assert!(iter.next(&bufmgr).unwrap().is_none());
+            assert_eq!(expected, {
+                let mut keys = keys(&mut iter, false);
+                keys.reverse();
+                keys
+            });
+            let mut iter = btree
+                .range(&bufmgr, SearchMode::End, lower.cloned(), upper.cloned())
+                .unwrap();
+            assert_eq!(expected.len(), keys(&mut iter, false).len());
+        }
+
+        // 範囲の外のキーから読み始めると、範囲の端から読む
+        let mut iter = btree
+            .range(
+                &bufmgr,
+                SearchMode::Key(key_of(0)),
+                Bound::Included(key_of(10)),
+                Bound::Included(key_of(12)),
+            )
+            .unwrap();
+        assert_eq!(vec![key_of(10), key_of(12)], keys(&mut iter, true));
+        let mut iter = btree
+            .range(
+                &bufmgr,
+                SearchMode::Key(key_of(3)),
+                Bound::Excluded(key_of(3)),
+                Bound::Excluded(key_of(4)),
+            )
+            .unwrap();
+        assert!(keys(&mut iter, true).is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(64);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let value_of = |i: u64, len: usize| -> Vec<u8> {
+            (0..len).map(|j| (i as usize * 31 + j) as u8).collect()
+        };
+        let last_page_id = bufmgr.create_page().unwrap().page_id;
+        for i in 0u64..20 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &value_of(i, 3000 * i as usize))
+                .unwrap();
+        }
+        for i in 0u64..20 {
+            assert_eq!(
+                Some(value_of(i, 3000 * i as usize)),
+                btree.get(&bufmgr, &i.to_be_bytes()).unwrap()
+            );
+        }
+        let mut iter = btree.search(&bufmgr, SearchMode::End).unwrap();
+        for i in (0u64..20).rev() {
+            let (k, v) = iter.prev(&bufmgr).unwrap().unwrap();
+            assert_eq!(i.to_be_bytes().to_vec(), k);
+            assert_eq!(value_of(i, 3000 * i as usize), v);
+        }
+
+        // 小さな値に書き換えるとリーフに戻り、大きな値に書き換えると新しい連鎖に移る
+        btree
+            .update(&bufmgr, &5u64.to_be_bytes(), b"small")
+            .unwrap();
+        btree
+            .update(&bufmgr, &1u64.to_be_bytes(), &value_of(1, 50_000))
+            .unwrap();
+        assert_eq!(
+            Some(b"small".to_vec()),
+            btree.get(&bufmgr, &5u64.to_be_bytes()).unwrap()
+        );
+        assert_eq!(
+            Some(value_of(1, 50_000)),
+            btree.get(&bufmgr, &1u64.to_be_bytes()).unwrap()
+        );
+        assert!(matches!(
+            btree.insert(&bufmgr, &1u64.to_be_bytes(), &value_of(1, 10_000)),
+            Err(Error::DuplicateKey)
+        ));
+        assert!(matches!(
+            btree.insert(&bufmgr, &[0xAB; 2000], b"value"),
+            Err(Error::KeyTooLarge(2000))
+        ));
+
+        // 削除すると連鎖のページも解放される
+        for i in 0u64..20 {
+            btree.delete(&bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        btree.destroy(&bufmgr).unwrap();
+        let page_id = bufmgr.create_page().unwrap().page_id;
+        assert!(page_id.to_u64() < last_page_id.to_u64());
+        let mut num_free_pages = 1;
+        while bufmgr.create_page().unwrap().page_id.to_u64() < last_page_id.to_u64() {
+            num_free_pages += 1;
+        }
+        assert_eq!(
+            last_page_id.to_u64() - btree.meta_page_id.to_u64(),
+            num_free_pages
+        );
+    }
+
+    #[test]
+    fn test_destroy() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..100 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0xAB; 500])
+                .unwrap();
+        }
+        let last_page_id = bufmgr.create_page().unwrap().page_id;
+        btree.destroy(&bufmgr).unwrap();
+
+        // 解放された全てのページが再利用されてから、新しいページが割り当てられる
+        let mut page_ids = vec![];
+        loop {
+            let page_id = bufmgr.create_page().unwrap().page_id;
+            if page_id.to_u64() > last_page_id.to_u64() {
+                break;
+            }
+            page_ids.push(page_id.to_u64());
+        }
+        page_ids.sort_unstable();
+        let expected: Vec<_> = (btree.meta_page_id.to_u64()..last_page_id.to_u64()).collect();
+        assert_eq!(expected, page_ids);
+    }
+
+    #[test]
+    fn test_prefix_compression() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(64);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        const NUM_KEYS: u64 = 3000;
+        // 長い接頭辞を共有するキーでも、接頭辞のないキーと同じくらいのページ数に収まる
+        let mut reports = vec![];
+        for prefix in [&b""[..], &b"database/schema/users/".repeat(4)] {
+            let btree = BTree::create(&bufmgr).unwrap();
+            for i in 0..NUM_KEYS {
+                let i = i * 7919 % NUM_KEYS;
+                let key = [prefix, &i.to_be_bytes()].concat();
+                btree.insert(&bufmgr, &key, &i.to_be_bytes()).unwrap();
+            }
+            for i in (0..NUM_KEYS).step_by(3) {
+                let key = [prefix, &i.to_be_bytes()].concat();
+                btree.delete(&bufmgr, &key).unwrap();
+            }
+            let mut checker = Checker::new(u64::MAX);
+            let report = btree.check(&bufmgr, "test", &mut checker);
+            assert!(
+                checker.violations().is_empty(),
+                "{:?}",
+                checker.violations()
+            );
+            assert_eq!(2000, report.num_pairs);
+            reports.push(report);
+        }
+        assert_eq!(reports[0].height, reports[1].height);
+        assert!(reports[1].num_nodes * 2 < reports[0].num_nodes * 3);
+    }
+
+    #[test]
+    fn test_uncompressed_leaf() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let key_of = |i: u64| [&b"users/"[..], &i.to_be_bytes()].concat();
+        // 接頭辞を圧縮する前の形式で書かれたリーフも読み書きできる
+        {
+            let root_buffer = btree.fetch_root_page(&bufmgr).unwrap();
+            let latch = bufmgr.latch_exclusive(&root_buffer).unwrap();
+            let mut body = latch.body_mut();
+            let mut root = node::Node::new(&mut body[..]);
+            root.header.node_type = node::NODE_TYPE_LEAF_V1;
+            let mut leaf = root.into_leaf();
+            leaf.initialize();
+            let value = overflow::LeafValue::Inline(b"old").to_bytes();
+            for i in 0..100 {
+                leaf.insert(i as usize, &key_of(i), &value).unwrap();
+            }
+            bufmgr.mark_dirty(&latch);
+        }
+        for i in 100..2000 {
+            btree.insert(&bufmgr, &key_of(i), b"new").unwrap();
+        }
+        for i in (0..2000).step_by(2) {
+            btree.delete(&bufmgr, &key_of(i)).unwrap();
+        }
+        let mut checker = Checker::new(u64::MAX);
+        let report = btree.check(&bufmgr, "test", &mut checker);
+        assert!(
+            checker.violations().is_empty(),
+            "{:?}",
+            checker.violations()
+        );
+        assert_eq!(1000, report.num_pairs);
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in (1..2000).step_by(2) {
+            let (k, v) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(key_of(i), k);
+            assert_eq!(if i < 100 { &b"old"[..] } else { &b"new"[..] }, &v[..]);
+        }
+    }
+
+    #[test]
+    fn test_concurrent() {
+        const NUM_THREADS: u64 = 4;
+        const NUM_KEYS: u64 = 2000;
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(64);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let key_of = |i: u64| (i * 7919 % NUM_KEYS).to_be_bytes().repeat(8);
+        // 奇数番目のキーは、他のスレッドが挿入している間に消す
+        for i in (1..NUM_KEYS).step_by(2) {
+            btree.insert(&bufmgr, &key_of(i), &[0xCD; 100]).unwrap();
+        }
+
+        thread::scope(|s| {
+            for n in 0..NUM_THREADS {
+                let (btree, bufmgr) = (&btree, &bufmgr);
+                s.spawn(move || {
+                    for i in (n * 2..NUM_KEYS).step_by(NUM_THREADS as usize * 2) {
+                        btree.insert(bufmgr, &key_of(i), &[0xAB; 100]).unwrap();
+                        assert_eq!(
+                            Some(vec![0xAB; 100]),
+                            btree.get(bufmgr, &key_of(i)).unwrap()
+                        );
+                    }
+                });
+            }
+            s.spawn(|| {
+                for i in (1..NUM_KEYS).step_by(2) {
+                    btree.delete(&bufmgr, &key_of(i)).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..10 {
+                    let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+                    let mut prev_key = vec![];
+                    while let Some((k, _)) = iter.next(&bufmgr).unwrap() {
+                        assert!(prev_key < k);
+                        prev_key = k;
+                    }
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..10 {
+                    let mut iter = btree.search(&bufmgr, SearchMode::End).unwrap();
+                    let mut next_key = vec![0xFF; 65];
+                    while let Some((k, _)) = iter.prev(&bufmgr).unwrap() {
+                        assert!(k < next_key);
+                        next_key = k;
+                    }
+                }
+            });
+        });
+
+        let mut expected: Vec<_> = (0..NUM_KEYS).step_by(2).map(key_of).collect();
+        expected.sort();
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for key in expected {
+            let (k, v) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(key, k);
+            assert_eq!(vec![0xAB; 100], v);
+        }
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+    }
+
+    const CRASH_TEST_PATH_ENV: &str = "RDBMS_CRASH_TEST_PATH";
+
+    fn crash_test_key(i: u64) -> Vec<u8> {
+        (i * 2654435761 % 1_000_003).to_be_bytes().repeat(4)
+    }
+
+    #[test]
+    fn test_recovery_after_crash_in_split() {
+        const NUM_FLUSHED: u64 = 300;
+        if let Ok(path) = std::env::var(CRASH_TEST_PATH_ENV) {
+            // 子プロセス: 途中まで書き込んでから分割の最中に落ちる
+            // 0 でない間は、ページを割り当てるたびに減らし、1 から 0 になったところでプロセスを落とす
+            let allocations_before_crash = Arc::new(AtomicUsize::new(0));
+            let mut disk = DiskManager::open(&path).unwrap();
+            {
+                let allocations_before_crash = allocations_before_crash.clone();
+                disk.set_fault_hook(Box::new(move || {
+                    let n = allocations_before_crash.load(Ordering::SeqCst);
+                    if n == 1 {
+                        std::process::abort();
+                    }
+                    if n > 1 {
+                        allocations_before_crash.store(n - 1, Ordering::SeqCst);
+                    }
+                    Ok(())
+                }));
+            }
+            let pool = BufferPool::new(10);
+            let bufmgr = BufferPoolManager::new(disk, pool);
+            let btree = BTree::create(&bufmgr).unwrap();
+            for i in 0..NUM_FLUSHED {
+                btree
+                    .insert(&bufmgr, &crash_test_key(i), &[0xAB; 100])
+                    .unwrap();
+            }
+            bufmgr.flush().unwrap();
+            allocations_before_crash.store(20, Ordering::SeqCst);
+            for i in NUM_FLUSHED.. {
+                btree
+                    .insert(&bufmgr, &crash_test_key(i), &[0xAB; 100])
+                    .unwrap();
+            }
+            unreachable!();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "btree::tests::test_recovery_after_crash_in_split",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env(CRASH_TEST_PATH_ENV, &path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!status.success());
+
+        let disk = DiskManager::open(&path).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::new(PageId(1));
+
+        // コミット済みの挿入だけが、先頭から途切れずに残っている
+        let mut keys = vec![];
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        while let Some((k, v)) = iter.next(&bufmgr).unwrap() {
+            assert_eq!(vec![0xAB; 100], v);
+            keys.push(k);
+        }
+        let num_keys = keys.len() as u64;
+        assert!(num_keys >= NUM_FLUSHED);
+        let mut expected: Vec<_> = (0..num_keys).map(crash_test_key).collect();
+        expected.sort();
+        assert_eq!(expected, keys);
+        for key in keys {
+            let (k, _) = btree
+                .search(&bufmgr, SearchMode::Key(key.clone()))
+                .unwrap()
+                .get(&bufmgr)
+                .unwrap();
+            assert_eq!(key, k);
+        }
+        let mut checker = Checker::new(u64::MAX);
+        btree.check(&bufmgr, "test", &mut checker);
+        assert!(checker.violations().is_empty(), "{:?}", checker.violations());
+        // 復旧した木にそのまま書き込める
+        btree
+            .insert(&bufmgr, &crash_test_key(num_keys), b"hello")
+            .unwrap();
+    }
 }
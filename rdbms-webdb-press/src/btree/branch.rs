@@ -32,7 +32,7 @@ impl<B: ByteSlice> Branch<B> {
 
     pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
         binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
+            self.pair_at(slot_id).key.cmp(key)
         })
     }
 
@@ -56,13 +56,59 @@ impl<B: ByteSlice> Branch<B> {
         }
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
+    pub fn pair_at(&self, slot_id: usize) -> Pair<'_> {
         Pair::from_bytes(&self.body[slot_id])
     }
 
+    // 壊れたページでもパニックしない。先に check_slots で確かめておく
+    pub fn try_pair_at(&self, slot_id: usize) -> Option<Pair<'_>> {
+        Pair::try_from_bytes(&self.body[slot_id])
+    }
+
+    pub fn check_slots(&self) -> Result<(), String> {
+        self.body.check()
+    }
+
+    pub fn right_child(&self) -> PageId {
+        self.header.right_child
+    }
+
     pub fn max_pair_size(&self) -> usize {
         self.body.capacity() / 2 - size_of::<slotted::Pointer>()
     }
+
+    pub fn is_half_full(&self) -> bool {
+        2 * self.body.free_space() < self.body.capacity()
+    }
+
+    // 半分以上空いていれば、子が分割されてもこのブランチは分割されない
+    pub fn is_safe_for_insert(&self) -> bool {
+        2 * self.body.free_space() >= self.body.capacity()
+    }
+
+    // push_child で key を足しても、使う領域が全体の fill_factor の割合に収まるかどうか
+    pub fn has_room_within(&self, key: &[u8], fill_factor: f64) -> bool {
+        let pair = Pair {
+            key,
+            value: self.header.right_child.as_bytes(),
+        };
+        let pair_size = pair.to_bytes().len() + size_of::<slotted::Pointer>();
+        (self.used_space() + pair_size) as f64 <= self.body.capacity() as f64 * fill_factor
+    }
+
+    fn used_space(&self) -> usize {
+        self.body.capacity() - self.body.free_space()
+    }
+
+    // 区切りキーを含めて、2つのブランチの中身が1ページに収まるかどうか
+    pub fn can_merge(&self, right: &Branch<impl ByteSlice>, sep_key: &[u8]) -> bool {
+        let sep_pair = Pair {
+            key: sep_key,
+            value: self.header.right_child.as_bytes(),
+        };
+        let sep_size = sep_pair.to_bytes().len() + size_of::<slotted::Pointer>();
+        self.used_space() + right.used_space() + sep_size <= self.body.capacity()
+    }
 }
 
 impl<B: ByteSliceMut> Branch<B> {
@@ -73,6 +119,21 @@ impl<B: ByteSliceMut> Branch<B> {
         self.header.right_child = right_child;
     }
 
+    // 子を1つだけ持つブランチにする。push_child で右に子を足していく
+    pub fn initialize_with_child(&mut self, child: PageId) {
+        self.body.initialize();
+        self.header.right_child = child;
+    }
+
+    // key 以上のキーを持つ子を右端に足す
+    #[must_use = "insertion may fail"]
+    pub fn push_child(&mut self, key: &[u8], child: PageId) -> Option<()> {
+        let right_child = self.header.right_child;
+        self.insert(self.num_pairs(), key, right_child)?;
+        self.header.right_child = child;
+        Some(())
+    }
+
     pub fn fill_right_child(&mut self) -> Vec<u8> {
         let last_id = self.num_pairs() - 1;
         let Pair { key, value } = self.pair_at(last_id);
@@ -96,8 +157,21 @@ impl<B: ByteSliceMut> Branch<B> {
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    pub fn remove(&mut self, slot_id: usize) {
+        self.body.remove(slot_id);
+    }
+
+    #[must_use = "update may fail"]
+    pub fn set_key_at(&mut self, slot_id: usize, key: &[u8]) -> Option<()> {
+        let child = self.child_at(slot_id);
+        let pair = Pair {
+            key,
+            value: child.as_bytes(),
+        };
+        let pair_bytes = pair.to_bytes();
+        self.body.resize(slot_id, pair_bytes.len())?;
+        self.body[slot_id].copy_from_slice(&pair_bytes);
+        Some(())
     }
 
     pub fn split_insert(
@@ -139,6 +213,45 @@ impl<B: ByteSliceMut> Branch<B> {
         dest.body[next_index].copy_from_slice(&self.body[0]);
         self.body.remove(0);
     }
+
+    // 右隣のブランチ dest に、区切りキーと一番右の子を含めて全ての子を移す
+    pub fn merge_into(&mut self, dest: &mut Branch<impl ByteSliceMut>, sep_key: &[u8]) {
+        dest.insert(0, sep_key, self.header.right_child)
+            .expect("no space in dest branch");
+        while self.num_pairs() > 0 {
+            let last_id = self.num_pairs() - 1;
+            dest.body
+                .insert(0, self.body[last_id].len())
+                .expect("no space in dest branch");
+            dest.body[0].copy_from_slice(&self.body[last_id]);
+            self.body.remove(last_id);
+        }
+    }
+
+    // 一番右の子を右隣のブランチ right の先頭に移し、新しい区切りキーを返す
+    pub fn rotate_right(
+        &mut self,
+        right: &mut Branch<impl ByteSliceMut>,
+        sep_key: &[u8],
+    ) -> Vec<u8> {
+        right
+            .insert(0, sep_key, self.header.right_child)
+            .expect("right branch must have space");
+        self.fill_right_child()
+    }
+
+    // 一番左の子を左隣のブランチ left の末尾に移し、新しい区切りキーを返す
+    pub fn rotate_left(&mut self, left: &mut Branch<impl ByteSliceMut>, sep_key: &[u8]) -> Vec<u8> {
+        let (new_sep_key, child) = {
+            let Pair { key, value } = self.pair_at(0);
+            (key.to_vec(), PageId::from(value))
+        };
+        left.insert(left.num_pairs(), sep_key, left.header.right_child)
+            .expect("left branch must have space");
+        left.header.right_child = child;
+        self.body.remove(0);
+        new_sep_key
+    }
 }
 
 #[cfg(test)]
@@ -186,4 +299,42 @@ mod tests {
         assert_eq!(PageId(2), branch.search_child(&11u64.to_be_bytes()));
         assert_eq!(PageId(2), branch.search_child(&12u64.to_be_bytes()));
     }
+
+    #[test]
+    fn test_merge_rotate() {
+        let mut left_data = vec![0u8; 100];
+        let mut left = Branch::new(left_data.as_mut_slice());
+        left.initialize(&5u64.to_be_bytes(), PageId(1), PageId(2));
+
+        let mut right_data = vec![0u8; 100];
+        let mut right = Branch::new(right_data.as_mut_slice());
+        right.initialize(&11u64.to_be_bytes(), PageId(3), PageId(4));
+
+        let sep_key = left.rotate_right(&mut right, &8u64.to_be_bytes());
+        assert_eq!(&5u64.to_be_bytes(), sep_key.as_slice());
+        assert_eq!(0, left.num_pairs());
+        assert_eq!(PageId(1), left.search_child(&1u64.to_be_bytes()));
+        assert_eq!(PageId(2), right.search_child(&6u64.to_be_bytes()));
+        assert_eq!(PageId(3), right.search_child(&9u64.to_be_bytes()));
+
+        let sep_key = right.rotate_left(&mut left, &sep_key);
+        assert_eq!(&8u64.to_be_bytes(), sep_key.as_slice());
+        assert_eq!(PageId(1), left.search_child(&1u64.to_be_bytes()));
+        assert_eq!(PageId(2), left.search_child(&6u64.to_be_bytes()));
+        assert_eq!(PageId(3), right.search_child(&9u64.to_be_bytes()));
+
+        assert!(left.can_merge(&right, &sep_key));
+        left.merge_into(&mut right, &sep_key);
+        assert_eq!(0, left.num_pairs());
+        assert_eq!(3, right.num_pairs());
+        assert_eq!(PageId(1), right.search_child(&1u64.to_be_bytes()));
+        assert_eq!(PageId(2), right.search_child(&6u64.to_be_bytes()));
+        assert_eq!(PageId(3), right.search_child(&9u64.to_be_bytes()));
+        assert_eq!(PageId(4), right.search_child(&12u64.to_be_bytes()));
+
+        assert!(right.set_key_at(1, &7u64.to_be_bytes()).is_some());
+        assert_eq!(PageId(3), right.search_child(&7u64.to_be_bytes()));
+        right.remove(0);
+        assert_eq!(PageId(2), right.search_child(&1u64.to_be_bytes()));
+    }
 }
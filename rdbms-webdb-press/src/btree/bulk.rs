@@ -0,0 +1,344 @@
+use std::ops::Range;
+
+use zerocopy::AsBytes;
+
+use super::{branch, leaf, meta, node, overflow_page_ids, store_value, BTree, Error};
+use crate::buffer::BufferPoolManager;
+use crate::disk::{PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+
+// ページに書き込む前にノードを組み立てておく領域
+// ヘッダーを LayoutVerified で読めるように、8バイト境界に揃える
+struct Scratch(Vec<u64>);
+
+impl Scratch {
+    fn new() -> Self {
+        Self(vec![0; (PAGE_SIZE - PAGE_HEADER_SIZE) / 8])
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.0[..].as_bytes()
+    }
+
+    fn leaf(&mut self) -> leaf::Leaf<&mut [u8]> {
+        let node = node::Node::new(self.0[..].as_bytes_mut());
+        node.into_leaf()
+    }
+
+    fn branch(&mut self) -> branch::Branch<&mut [u8]> {
+        let node = node::Node::new(self.0[..].as_bytes_mut());
+        branch::Branch::new(node.body)
+    }
+
+    fn initialize_as_leaf(&mut self, prev_page_id: Option<PageId>) {
+        node::Node::new(self.0[..].as_bytes_mut()).initialize_as_leaf();
+        let mut leaf = self.leaf();
+        leaf.initialize();
+        leaf.set_prev_page_id(prev_page_id);
+    }
+
+    // children の先頭から順に子を足していき、足した子の数を返す
+    // fill_factor を超えても、少なくとも2つの子を持たせる
+    fn build_branch(&mut self, children: &[(Vec<u8>, PageId)], fill_factor: f64) -> usize {
+        node::Node::new(self.0[..].as_bytes_mut()).initialize_as_branch();
+        let mut branch = self.branch();
+        branch.initialize_with_child(children[0].1);
+        let mut num_children = 1;
+        for (key, child) in &children[1..] {
+            if num_children >= 2 && !branch.has_room_within(key, fill_factor) {
+                break;
+            }
+            if branch.push_child(key, *child).is_none() {
+                break;
+            }
+            num_children += 1;
+        }
+        num_children
+    }
+}
+
+struct Loader<'a> {
+    bufmgr: &'a BufferPoolManager,
+    fill_factor: f64,
+    // 作ったページ。途中で失敗したら解放する
+    page_ids: Vec<PageId>,
+}
+
+impl<'a> Loader<'a> {
+    // リーフを左から順に作り、左隣のリーフとの区切りキーとページIDを返す
+    fn load_leaves(
+        &mut self,
+        pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Vec<(Vec<u8>, PageId)>, Error> {
+        let mut leaves = vec![];
+        let mut scratch = Scratch::new();
+        scratch.initialize_as_leaf(None);
+        let mut separator = vec![];
+        let mut prev_key: Option<Vec<u8>> = None;
+        for (key, value) in pairs {
+            match &prev_key {
+                Some(prev_key) if key == *prev_key => return Err(Error::DuplicateKey),
+                Some(prev_key) if key < *prev_key => return Err(Error::UnsortedKeys),
+                _ => {}
+            }
+            let stored = self
+                .bufmgr
+                .with_mtr(|bufmgr| store_value(bufmgr, &key, &value))?;
+            self.page_ids
+                .extend(overflow_page_ids(self.bufmgr, &stored)?);
+            let leaf = scratch.leaf();
+            if leaf.num_pairs() > 0 && !leaf.has_room_within(&key, &stored, self.fill_factor) {
+                let page_id = self.write_leaf(&mut scratch, separator, &mut leaves)?;
+                scratch.initialize_as_leaf(Some(page_id));
+                separator = leaf::separator(prev_key.as_deref().unwrap(), &key);
+            }
+            let mut leaf = scratch.leaf();
+            leaf.insert(leaf.num_pairs(), &key, &stored)
+                .expect("empty leaf must have space");
+            prev_key = Some(key);
+        }
+        self.write_leaf(&mut scratch, separator, &mut leaves)?;
+        Ok(leaves)
+    }
+
+    // 組み立てたリーフをページに書き込み、左隣のリーフとつなぐ
+    fn write_leaf(
+        &mut self,
+        scratch: &mut Scratch,
+        separator: Vec<u8>,
+        leaves: &mut Vec<(Vec<u8>, PageId)>,
+    ) -> Result<PageId, Error> {
+        let prev_page_id = leaves.last().map(|(_, page_id)| *page_id);
+        let page_id = self.bufmgr.with_mtr(|bufmgr| -> Result<PageId, Error> {
+            let buffer = bufmgr.create_page()?;
+            buffer.body_mut().copy_from_slice(scratch.bytes());
+            if let Some(prev_page_id) = prev_page_id {
+                let prev_latch = bufmgr.fetch_page_exclusive(prev_page_id)?;
+                let mut body = prev_latch.body_mut();
+                let node = node::Node::new(&mut body[..]);
+                node.into_leaf().set_next_page_id(Some(buffer.page_id));
+                bufmgr.mark_dirty(&prev_latch);
+            }
+            Ok(buffer.page_id)
+        })?;
+        self.page_ids.push(page_id);
+        leaves.push((separator, page_id));
+        Ok(page_id)
+    }
+
+    // 1つ下の段のノードをまとめて、ブランチの段を作る
+    fn load_branches(
+        &mut self,
+        children: &[(Vec<u8>, PageId)],
+    ) -> Result<Vec<(Vec<u8>, PageId)>, Error> {
+        let mut scratch = Scratch::new();
+        let mut groups: Vec<Range<usize>> = vec![];
+        let mut start = 0;
+        while start < children.len() {
+            let end = start + scratch.build_branch(&children[start..], self.fill_factor);
+            groups.push(start..end);
+            start = end;
+        }
+        // 子が1つだけ残ったら、左隣にまとめるか、左隣から子を1つもらう
+        if groups.len() > 1 && groups.last().unwrap().len() == 1 {
+            let last = groups.pop().unwrap();
+            let prev = groups.pop().unwrap();
+            let merged = prev.start..last.end;
+            if scratch.build_branch(&children[merged.clone()], 1.0) == merged.len() {
+                groups.push(merged);
+            } else {
+                groups.push(prev.start..prev.end - 1);
+                groups.push(prev.end - 1..last.end);
+            }
+        }
+
+        let mut branches = vec![];
+        for group in groups {
+            let num_children = scratch.build_branch(&children[group.clone()], 1.0);
+            assert_eq!(group.len(), num_children, "branch must have space");
+            let page_id = self.bufmgr.with_mtr(|bufmgr| -> Result<PageId, Error> {
+                let buffer = bufmgr.create_page()?;
+                buffer.body_mut().copy_from_slice(scratch.bytes());
+                Ok(buffer.page_id)
+            })?;
+            self.page_ids.push(page_id);
+            branches.push((children[group.start].0.clone(), page_id));
+        }
+        Ok(branches)
+    }
+
+    fn load(
+        &mut self,
+        pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<BTree, Error> {
+        // BTree::create と同じように、メタページを先に作っておく
+        let meta_page_id = self
+            .bufmgr
+            .with_mtr(|bufmgr| -> Result<PageId, Error> { Ok(bufmgr.create_page()?.page_id) })?;
+        self.page_ids.push(meta_page_id);
+        let mut nodes = self.load_leaves(pairs)?;
+        while nodes.len() > 1 {
+            nodes = self.load_branches(&nodes)?;
+        }
+        let root_page_id = nodes[0].1;
+        self.bufmgr.with_mtr(|bufmgr| -> Result<(), Error> {
+            let meta_latch = bufmgr.fetch_page_exclusive(meta_page_id)?;
+            let mut meta_body = meta_latch.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            meta.header.root_page_id = root_page_id;
+            bufmgr.mark_dirty(&meta_latch);
+            Ok(())
+        })?;
+        Ok(BTree::new(meta_page_id))
+    }
+}
+
+impl BTree {
+    // キーの昇順に並んだペアから、新しい B+Tree を作る
+    // リーフを左から順に詰めていき、その後でブランチを下の段から作るので、1つずつ挿入するより速く、ページも少なくて済む
+    // fill_factor はリーフとブランチをどこまで埋めるかの割合で、後から挿入する余地を残したい時は 1.0 より小さくする
+    // ノードごとにミニトランザクションを分けるので、トランザクションの外で呼ぶ
+    pub fn bulk_load(
+        bufmgr: &BufferPoolManager,
+        pairs: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        fill_factor: f64,
+    ) -> Result<Self, Error> {
+        assert!(
+            fill_factor > 0.0 && fill_factor <= 1.0,
+            "fill factor must be in (0, 1]"
+        );
+        let mut loader = Loader {
+            bufmgr,
+            fill_factor,
+            page_ids: vec![],
+        };
+        match loader.load(pairs) {
+            Ok(btree) => Ok(btree),
+            Err(err) => {
+                // 作りかけの木はどこからも指されていないので、そのまま解放する
+                for page_id in loader.page_ids {
+                    bufmgr.delete_page(page_id)?;
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::btree::SearchMode;
+    use crate::buffer::BufferPool;
+    use crate::check::Checker;
+    use crate::disk::DiskManager;
+
+    fn pairs(num_pairs: u64) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        (0..num_pairs).map(|i| {
+            let value = vec![i as u8; if i % 1000 == 0 { 10000 } else { 10 }];
+            (i.to_be_bytes().to_vec(), value)
+        })
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let btree = BTree::bulk_load(&bufmgr, pairs(20000), 1.0).unwrap();
+        assert_eq!(PageId(1), btree.meta_page_id);
+        let mut checker = Checker::new(u64::MAX);
+        let report = btree.check(&bufmgr, "bulk", &mut checker);
+        assert!(
+            checker.violations().is_empty(),
+            "{:?}",
+            checker.violations()
+        );
+        assert_eq!(20000, report.num_pairs);
+        assert_eq!(60, report.num_overflow_pages);
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        let mut expected = pairs(20000);
+        while let Some(pair) = iter.next(&bufmgr).unwrap() {
+            assert_eq!(expected.next(), Some(pair));
+        }
+        assert_eq!(None, expected.next());
+
+        // 1つずつ挿入するとリーフが半分ほどしか埋まらない
+        let inserted = BTree::create(&bufmgr).unwrap();
+        for (key, value) in pairs(20000) {
+            inserted.insert(&bufmgr, &key, &value).unwrap();
+        }
+        let inserted_report = inserted.check(&bufmgr, "insert", &mut Checker::new(u64::MAX));
+        assert!(report.num_nodes * 3 < inserted_report.num_nodes * 2);
+
+        // 後から挿入や削除もできる
+        btree
+            .insert(&bufmgr, &20000u64.to_be_bytes(), b"last")
+            .unwrap();
+        for i in (0..20000u64).step_by(3) {
+            btree.delete(&bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        let mut checker = Checker::new(u64::MAX);
+        let report = btree.check(&bufmgr, "bulk", &mut checker);
+        assert!(
+            checker.violations().is_empty(),
+            "{:?}",
+            checker.violations()
+        );
+        assert_eq!(20000 - 6667 + 1, report.num_pairs);
+    }
+
+    #[test]
+    fn test_fill_factor() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let mut num_nodes = vec![];
+        for &fill_factor in &[1.0, 0.5, 0.01] {
+            let btree = BTree::bulk_load(&bufmgr, pairs(5000), fill_factor).unwrap();
+            let mut checker = Checker::new(u64::MAX);
+            let report = btree.check(&bufmgr, "bulk", &mut checker);
+            assert!(
+                checker.violations().is_empty(),
+                "{:?}",
+                checker.violations()
+            );
+            assert_eq!(5000, report.num_pairs);
+            num_nodes.push(report.num_nodes);
+        }
+        assert!(num_nodes[0] < num_nodes[1] && num_nodes[1] < num_nodes[2]);
+
+        let btree = BTree::bulk_load(&bufmgr, pairs(0), 1.0).unwrap();
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        assert_eq!(None, iter.next(&bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_unsorted() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let mut unsorted: Vec<_> = pairs(5000).collect();
+        unsorted.swap(3000, 4000);
+        assert!(matches!(
+            BTree::bulk_load(&bufmgr, unsorted, 1.0),
+            Err(Error::UnsortedKeys)
+        ));
+        let mut duplicated: Vec<_> = pairs(5000).collect();
+        duplicated[4000].0 = duplicated[3999].0.clone();
+        assert!(matches!(
+            BTree::bulk_load(&bufmgr, duplicated, 1.0),
+            Err(Error::DuplicateKey)
+        ));
+        // 作りかけの木のページは解放され、次に作る木で使い回される
+        let btree = BTree::bulk_load(&bufmgr, pairs(5000), 1.0).unwrap();
+        let mut checker = Checker::new(u64::MAX);
+        let report = btree.check(&bufmgr, "bulk", &mut checker);
+        assert!(
+            checker.violations().is_empty(),
+            "{:?}",
+            checker.violations()
+        );
+        // ヘッダーページとメタページの分を足す
+        let num_pages = report.num_nodes + report.num_overflow_pages + 2;
+        assert!(btree.meta_page_id.to_u64() < num_pages as u64);
+    }
+}
@@ -0,0 +1,419 @@
+use std::mem::size_of;
+
+use super::{meta, node, overflow, BTree};
+use crate::buffer::BufferPoolManager;
+use crate::check::{Checker, TreeReport, ViolationKind};
+use crate::disk::PageId;
+
+// 区切りキーから決まる、ノードに入ってよいキーの範囲。下限は含み、上限は含まない
+#[derive(Clone)]
+struct KeyRange {
+    lower: Option<Vec<u8>>,
+    upper: Option<Vec<u8>>,
+}
+
+impl KeyRange {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.lower.as_deref().is_none_or(|lower| lower <= key)
+            && self.upper.as_deref().is_none_or(|upper| key < upper)
+    }
+}
+
+struct LeafInfo {
+    page_id: PageId,
+    prev_page_id: Option<PageId>,
+    next_page_id: Option<PageId>,
+    first_key: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
+}
+
+struct Walk<'a> {
+    bufmgr: &'a BufferPoolManager,
+    checker: &'a mut Checker,
+    report: TreeReport,
+    // キーの順に並べたリーフ
+    leaves: Vec<LeafInfo>,
+    leaf_depth: Option<usize>,
+}
+
+impl BTree {
+    // 木が壊れていてもパニックせずに、見つけた問題を checker に残す
+    // 他のスレッドが書き換えていないことを前提にする
+    pub fn check(
+        &self,
+        bufmgr: &BufferPoolManager,
+        name: &str,
+        checker: &mut Checker,
+    ) -> TreeReport {
+        let mut walk = Walk {
+            bufmgr,
+            checker,
+            report: TreeReport {
+                name: name.to_string(),
+                meta_page_id: self.meta_page_id,
+                height: 0,
+                num_nodes: 0,
+                num_pairs: 0,
+                num_overflow_pages: 0,
+            },
+            leaves: vec![],
+            leaf_depth: None,
+        };
+        if let Some(root_page_id) = walk.meta(self.meta_page_id) {
+            let range = KeyRange {
+                lower: None,
+                upper: None,
+            };
+            walk.node(root_page_id, 0, range);
+            walk.sibling_links();
+        }
+        walk.report.height = walk.leaf_depth.map_or(0, |depth| depth + 1);
+        walk.report
+    }
+}
+
+impl Walk<'_> {
+    fn meta(&mut self, page_id: PageId) -> Option<PageId> {
+        if !self.checker.visit(page_id) {
+            return None;
+        }
+        let latch = match self.bufmgr.fetch_page_shared(page_id) {
+            Ok(latch) => latch,
+            Err(err) => {
+                self.checker
+                    .report(ViolationKind::UnreadablePage, page_id, err.to_string());
+                return None;
+            }
+        };
+        let body = latch.body();
+        Some(meta::Meta::new(&body[..]).header.root_page_id)
+    }
+
+    fn node(&mut self, page_id: PageId, depth: usize, range: KeyRange) {
+        if !self.checker.visit(page_id) {
+            return;
+        }
+        self.report.num_nodes += 1;
+        let latch = match self.bufmgr.fetch_page_shared(page_id) {
+            Ok(latch) => latch,
+            Err(err) => {
+                self.checker
+                    .report(ViolationKind::UnreadablePage, page_id, err.to_string());
+                return;
+            }
+        };
+        let body = latch.body();
+        let node = node::Node::new(&body[..]);
+        let node_type = node.header.node_type;
+        if !node.is_leaf() && node_type != node::NODE_TYPE_BRANCH {
+            self.checker.report(
+                ViolationKind::InvalidNodeType,
+                page_id,
+                format!("unknown node type {:?}", node_type),
+            );
+            return;
+        }
+        let children = match node::Body::new(node_type, node.body) {
+            node::Body::Leaf(leaf) => {
+                if let Err(message) = leaf.check_slots() {
+                    self.checker
+                        .report(ViolationKind::InvalidSlottedPage, page_id, message);
+                    return;
+                }
+                let mut info = LeafInfo {
+                    page_id,
+                    prev_page_id: leaf.prev_page_id(),
+                    next_page_id: leaf.next_page_id(),
+                    first_key: None,
+                    last_key: None,
+                };
+                for slot_id in 0..leaf.num_pairs() {
+                    let (key, value) = match leaf.try_pair_at(slot_id) {
+                        Some(pair) => pair,
+                        None => {
+                            self.checker.report(
+                                ViolationKind::InvalidPair,
+                                page_id,
+                                format!("slot {} cannot be decoded", slot_id),
+                            );
+                            return;
+                        }
+                    };
+                    self.key(page_id, info.last_key.as_deref(), &key, &range);
+                    self.value(page_id, slot_id, value);
+                    info.first_key.get_or_insert_with(|| key.clone());
+                    info.last_key = Some(key);
+                }
+                self.report.num_pairs += leaf.num_pairs();
+                self.leaves.push(info);
+                match self.leaf_depth {
+                    Some(leaf_depth) if leaf_depth != depth => self.checker.report(
+                        ViolationKind::UnbalancedTree,
+                        page_id,
+                        format!("leaf at depth {}, others at depth {}", depth, leaf_depth),
+                    ),
+                    _ => self.leaf_depth = Some(depth),
+                }
+                vec![]
+            }
+            node::Body::Branch(branch) => {
+                if let Err(message) = branch.check_slots() {
+                    self.checker
+                        .report(ViolationKind::InvalidSlottedPage, page_id, message);
+                    return;
+                }
+                if depth > 0 && branch.num_pairs() == 0 {
+                    self.checker.report(
+                        ViolationKind::EmptyBranch,
+                        page_id,
+                        "non-root branch has a single child",
+                    );
+                }
+                let mut children = vec![];
+                let mut lower = range.lower.clone();
+                for slot_id in 0..branch.num_pairs() {
+                    let pair = match branch.try_pair_at(slot_id) {
+                        Some(pair) if pair.value.len() == size_of::<PageId>() => pair,
+                        _ => {
+                            self.checker.report(
+                                ViolationKind::InvalidPair,
+                                page_id,
+                                format!("slot {} cannot be decoded", slot_id),
+                            );
+                            return;
+                        }
+                    };
+                    if !range.contains(pair.key)
+                        || lower.as_deref().is_some_and(|lower| pair.key < lower)
+                        || (slot_id > 0 && lower.as_deref() == Some(pair.key))
+                    {
+                        self.checker.report(
+                            ViolationKind::SeparatorOutOfRange,
+                            page_id,
+                            format!("separator key in slot {} is out of order", slot_id),
+                        );
+                    }
+                    let upper = Some(pair.key.to_vec());
+                    children.push((PageId::from(pair.value), KeyRange { lower, upper }));
+                    lower = Some(pair.key.to_vec());
+                }
+                let upper = range.upper.clone();
+                children.push((branch.right_child(), KeyRange { lower, upper }));
+                children
+            }
+        };
+        // 子を調べる間は親のラッチを外しておく
+        drop(body);
+        drop(latch);
+        for (child_page_id, range) in children {
+            self.node(child_page_id, depth + 1, range);
+        }
+    }
+
+    fn key(&mut self, page_id: PageId, prev_key: Option<&[u8]>, key: &[u8], range: &KeyRange) {
+        if prev_key.is_some_and(|prev_key| prev_key >= key) {
+            self.checker.report(
+                ViolationKind::UnsortedKeys,
+                page_id,
+                "keys are not sorted within the leaf",
+            );
+        } else if !range.contains(key) {
+            self.checker.report(
+                ViolationKind::KeyOutOfRange,
+                page_id,
+                "key is outside the range given by the parent's separator keys",
+            );
+        }
+    }
+
+    // オーバーフローページの連鎖をたどって、長さが合うかを調べる
+    fn value(&mut self, page_id: PageId, slot_id: usize, value: &[u8]) {
+        let (first_page_id, len) = match overflow::LeafValue::try_from_bytes(value) {
+            Some(overflow::LeafValue::Inline(_)) => return,
+            Some(overflow::LeafValue::Overflow { first_page_id, len }) => (first_page_id, len),
+            None => {
+                self.checker.report(
+                    ViolationKind::InvalidPair,
+                    page_id,
+                    format!("value in slot {} cannot be decoded", slot_id),
+                );
+                return;
+            }
+        };
+        let mut total_len = 0;
+        let mut next_page_id = Some(first_page_id);
+        while let Some(overflow_page_id) = next_page_id {
+            if !self.checker.visit(overflow_page_id) {
+                return;
+            }
+            self.report.num_overflow_pages += 1;
+            let latch = match self.bufmgr.fetch_page_shared(overflow_page_id) {
+                Ok(latch) => latch,
+                Err(err) => {
+                    self.checker.report(
+                        ViolationKind::UnreadablePage,
+                        overflow_page_id,
+                        err.to_string(),
+                    );
+                    return;
+                }
+            };
+            let body = latch.body();
+            let page = overflow::Overflow::new(&body[..]);
+            if page.data_len() > overflow::CAPACITY {
+                self.checker.report(
+                    ViolationKind::BrokenOverflowChain,
+                    overflow_page_id,
+                    format!("length {} exceeds the page", page.data_len()),
+                );
+                return;
+            }
+            total_len += page.data_len() as u64;
+            next_page_id = page.next_page_id();
+        }
+        if total_len != len {
+            self.checker.report(
+                ViolationKind::BrokenOverflowChain,
+                page_id,
+                format!(
+                    "value in slot {} has {} bytes but its chain holds {}",
+                    slot_id, len, total_len
+                ),
+            );
+        }
+    }
+
+    // 木をたどった順に並んだリーフ同士が、互いを指しているかを調べる
+    fn sibling_links(&mut self) {
+        for (i, leaf) in self.leaves.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|i| &self.leaves[i]);
+            let next = self.leaves.get(i + 1);
+            if leaf.prev_page_id != prev.map(|prev| prev.page_id) {
+                self.checker.report(
+                    ViolationKind::BrokenSiblingLink,
+                    leaf.page_id,
+                    format!(
+                        "prev_page_id is {}, expected {}",
+                        fmt_page_id(leaf.prev_page_id),
+                        fmt_page_id(prev.map(|prev| prev.page_id))
+                    ),
+                );
+            }
+            if leaf.next_page_id != next.map(|next| next.page_id) {
+                self.checker.report(
+                    ViolationKind::BrokenSiblingLink,
+                    leaf.page_id,
+                    format!(
+                        "next_page_id is {}, expected {}",
+                        fmt_page_id(leaf.next_page_id),
+                        fmt_page_id(next.map(|next| next.page_id))
+                    ),
+                );
+            }
+            if let (Some(last_key), Some(next_first_key)) = (
+                &leaf.last_key,
+                next.and_then(|next| next.first_key.as_ref()),
+            ) {
+                if last_key >= next_first_key {
+                    self.checker.report(
+                        ViolationKind::UnsortedKeys,
+                        leaf.page_id,
+                        "last key is not smaller than the first key of the next leaf",
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn fmt_page_id(page_id: Option<PageId>) -> String {
+    page_id.map_or("none".to_string(), |page_id| page_id.to_u64().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::btree::leaf::Leaf;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+
+    fn kinds(checker: &Checker) -> Vec<ViolationKind> {
+        checker
+            .violations()
+            .iter()
+            .map(|violation| violation.kind)
+            .collect()
+    }
+
+    // 根の一番左の子であるリーフを書き換える
+    fn modify_first_leaf(
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        f: impl FnOnce(&mut Leaf<&mut [u8]>),
+    ) {
+        let root_page_id = {
+            let latch = bufmgr.fetch_page_shared(btree.meta_page_id).unwrap();
+            let body = latch.body();
+            meta::Meta::new(&body[..]).header.root_page_id
+        };
+        let leaf_page_id = {
+            let latch = bufmgr.fetch_page_shared(root_page_id).unwrap();
+            let body = latch.body();
+            let node = node::Node::new(&body[..]);
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Branch(branch) => branch.child_at(0),
+                node::Body::Leaf(_) => unreachable!(),
+            }
+        };
+        let latch = bufmgr.fetch_page_exclusive(leaf_page_id).unwrap();
+        {
+            let mut body = latch.body_mut();
+            let node = node::Node::new(&mut body[..]);
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Leaf(mut leaf) => f(&mut leaf),
+                node::Body::Branch(_) => unreachable!(),
+            }
+        }
+        bufmgr.mark_dirty(&latch);
+    }
+
+    #[test]
+    fn test_check() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(64);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..1000 {
+            let value = vec![i as u8; if i % 100 == 0 { 10000 } else { 10 }];
+            btree.insert(&bufmgr, &i.to_be_bytes(), &value).unwrap();
+        }
+        let mut checker = Checker::new(u64::MAX);
+        let report = btree.check(&bufmgr, "test", &mut checker);
+        assert_eq!(Vec::<ViolationKind>::new(), kinds(&checker));
+        assert_eq!(1000, report.num_pairs);
+        assert_eq!(2, report.height);
+        assert_eq!(30, report.num_overflow_pages);
+
+        // 次のリーフへのリンクを切る
+        let mut next_page_id = None;
+        modify_first_leaf(&bufmgr, &btree, |leaf| {
+            next_page_id = leaf.next_page_id();
+            leaf.set_next_page_id(None);
+        });
+        let mut checker = Checker::new(u64::MAX);
+        btree.check(&bufmgr, "test", &mut checker);
+        assert_eq!(vec![ViolationKind::BrokenSiblingLink], kinds(&checker));
+        modify_first_leaf(&bufmgr, &btree, |leaf| leaf.set_next_page_id(next_page_id));
+
+        // 先頭に大きなキーを入れる
+        modify_first_leaf(&bufmgr, &btree, |leaf| {
+            let value = overflow::LeafValue::Inline(b"").to_bytes();
+            leaf.insert(0, &5u64.to_be_bytes(), &value).unwrap();
+        });
+        let mut checker = Checker::new(u64::MAX);
+        btree.check(&bufmgr, "test", &mut checker);
+        assert_eq!(vec![ViolationKind::UnsortedKeys], kinds(&checker));
+    }
+}
@@ -14,17 +14,72 @@ pub struct Header {
     next_page_id: PageId,
 }
 
+// 圧縮したリーフは、先頭のスロットに全てのキーに共通する接頭辞を置き、
+// 以降のスロットに接頭辞を取り除いたキーと値のペアを置く
 pub struct Leaf<B> {
     header: LayoutVerified<B, Header>,
     body: Slotted<B>,
+    compressed: bool,
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+// 左のリーフの最大のキーより大きく、右のリーフの最小のキー以下になる最も短いキー
+pub fn separator(left_max: &[u8], right_min: &[u8]) -> Vec<u8> {
+    debug_assert!(left_max < right_min);
+    right_min[..common_prefix_len(left_max, right_min) + 1].to_vec()
+}
+
+// pairs を left と right に分ける位置
+// 使う領域がなるべく半々になる位置から順に、両方に収まる位置を探す
+pub fn split_point(
+    left: &Leaf<impl ByteSlice>,
+    right: &Leaf<impl ByteSlice>,
+    pairs: &[(Vec<u8>, Vec<u8>)],
+) -> usize {
+    let total: usize = pairs.iter().map(|(key, value)| pair_size(key, value)).sum();
+    let mut middle = 1;
+    let mut left_size = pair_size(&pairs[0].0, &pairs[0].1);
+    while middle < pairs.len() - 1 && 2 * left_size < total {
+        left_size += pair_size(&pairs[middle].0, &pairs[middle].1);
+        middle += 1;
+    }
+    let fits = |mid: usize| {
+        (1..pairs.len()).contains(&mid)
+            && left.space_for(&pairs[..mid]) <= left.body.capacity()
+            && right.space_for(&pairs[mid..]) <= right.body.capacity()
+    };
+    (0..pairs.len())
+        .flat_map(|distance| vec![middle + distance, middle.wrapping_sub(distance)])
+        .find(|&mid| fits(mid))
+        .expect("pairs must fit in two leaves")
+}
+
+fn pair_size(key: &[u8], value: &[u8]) -> usize {
+    Pair { key, value }.size() + size_of::<slotted::Pointer>()
 }
 
 impl<B: ByteSlice> Leaf<B> {
     pub fn new(bytes: B) -> Self {
+        Self::with_format(bytes, true)
+    }
+
+    // 接頭辞を圧縮する前の形式のリーフ
+    pub fn new_uncompressed(bytes: B) -> Self {
+        Self::with_format(bytes, false)
+    }
+
+    fn with_format(bytes: B, compressed: bool) -> Self {
         let (header, body) =
             LayoutVerified::new_from_prefix(bytes).expect("leaf header must be aligned");
         let body = Slotted::new(body);
-        Self { header, body }
+        Self {
+            header,
+            body,
+            compressed,
+        }
     }
 
     pub fn prev_page_id(&self) -> Option<PageId> {
@@ -35,36 +90,149 @@ impl<B: ByteSlice> Leaf<B> {
         self.header.next_page_id.valid()
     }
 
+    // 接頭辞のスロットの分だけずらしたスロット番号
+    fn slot(&self, slot_id: usize) -> usize {
+        slot_id + self.compressed as usize
+    }
+
     pub fn num_pairs(&self) -> usize {
-        self.body.num_slots()
+        self.body.num_slots() - self.compressed as usize
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        if self.compressed {
+            &self.body[0]
+        } else {
+            &[]
+        }
     }
 
     pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
-        binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
-        })
+        let prefix = self.prefix();
+        match key.strip_prefix(prefix) {
+            Some(suffix) => binary_search_by(self.num_pairs(), |slot_id| {
+                self.stored_pair_at(slot_id).key.cmp(suffix)
+            }),
+            // 接頭辞で始まらないキーは、全てのキーより小さいか大きい
+            None if key < prefix => Err(0),
+            None => Err(self.num_pairs()),
+        }
     }
 
     #[cfg(test)]
-    pub fn search_pair(&self, key: &[u8]) -> Option<Pair> {
+    pub fn search_value(&self, key: &[u8]) -> Option<&[u8]> {
         let slot_id = self.search_slot_id(key).ok()?;
-        Some(self.pair_at(slot_id))
+        Some(self.value_at(slot_id))
+    }
+
+    // 接頭辞を取り除いたキーと値のペア
+    fn stored_pair_at(&self, slot_id: usize) -> Pair<'_> {
+        Pair::from_bytes(&self.body[self.slot(slot_id)])
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
-        Pair::from_bytes(&self.body[slot_id])
+    pub fn key_at(&self, slot_id: usize) -> Vec<u8> {
+        [self.prefix(), self.stored_pair_at(slot_id).key].concat()
+    }
+
+    pub fn value_at(&self, slot_id: usize) -> &[u8] {
+        self.stored_pair_at(slot_id).value
+    }
+
+    pub fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..self.num_pairs())
+            .map(|slot_id| (self.key_at(slot_id), self.value_at(slot_id).to_vec()))
+            .collect()
+    }
+
+    // 壊れたページでもパニックしない。先に check_slots で確かめておく
+    pub fn try_pair_at(&self, slot_id: usize) -> Option<(Vec<u8>, &[u8])> {
+        let pair = Pair::try_from_bytes(&self.body[self.slot(slot_id)])?;
+        Some(([self.prefix(), pair.key].concat(), pair.value))
+    }
+
+    pub fn check_slots(&self) -> Result<(), String> {
+        self.body.check()?;
+        if self.compressed && self.body.num_slots() == 0 {
+            return Err("prefix slot is missing".to_string());
+        }
+        Ok(())
     }
 
     pub fn max_pair_size(&self) -> usize {
         self.body.capacity() / 2 - size_of::<slotted::Pointer>()
     }
+
+    pub fn is_half_full(&self) -> bool {
+        2 * self.body.free_space() < self.body.capacity()
+    }
+
+    // 分割せずにペアを挿入 (または値を書き換え) できるかどうか
+    pub fn has_room_for(&self, key: &[u8], value: &[u8]) -> bool {
+        self.required_space(key, value) <= self.body.free_space()
+    }
+
+    // 挿入しても、使う領域が全体の fill_factor の割合に収まるかどうか
+    pub fn has_room_within(&self, key: &[u8], value: &[u8], fill_factor: f64) -> bool {
+        (self.used_space() + self.required_space(key, value)) as f64
+            <= self.body.capacity() as f64 * fill_factor
+    }
+
+    // 接頭辞が縮む場合は、他のペアのキーが伸びる分も含める
+    fn required_space(&self, key: &[u8], value: &[u8]) -> usize {
+        if self.needs_new_prefix(key) {
+            let mut pairs = self.pairs();
+            pairs.push((key.to_vec(), value.to_vec()));
+            return self.space_for(&pairs).saturating_sub(self.used_space());
+        }
+        pair_size(&key[self.prefix().len()..], value)
+    }
+
+    // 空のリーフではキー全体を、接頭辞で始まらないキーなら共通する部分を新しい接頭辞にする
+    fn needs_new_prefix(&self, key: &[u8]) -> bool {
+        self.compressed && (self.num_pairs() == 0 || !key.starts_with(self.prefix()))
+    }
+
+    // pairs だけを並べたときに使う領域
+    fn space_for(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> usize {
+        let prefix_len = self.prefix_len_for(pairs);
+        let pairs_size: usize = pairs
+            .iter()
+            .map(|(key, value)| pair_size(&key[prefix_len..], value))
+            .sum();
+        match self.compressed {
+            true => prefix_len + size_of::<slotted::Pointer>() + pairs_size,
+            false => pairs_size,
+        }
+    }
+
+    fn prefix_len_for(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> usize {
+        match (self.compressed, pairs.first()) {
+            (true, Some((first, _))) => pairs
+                .iter()
+                .map(|(key, _)| common_prefix_len(first, key))
+                .min()
+                .unwrap(),
+            _ => 0,
+        }
+    }
+
+    fn used_space(&self) -> usize {
+        self.body.capacity() - self.body.free_space()
+    }
+
+    // 2つのリーフの中身が1ページに収まるかどうか
+    pub fn can_merge(&self, right: &Leaf<impl ByteSlice>) -> bool {
+        let mut pairs = self.pairs();
+        pairs.extend(right.pairs());
+        right.space_for(&pairs) <= right.body.capacity()
+    }
 }
 
 impl<B: ByteSliceMut> Leaf<B> {
     pub fn initialize(&mut self) {
         self.header.prev_page_id = PageId::INVALID_PAGE_ID;
         self.header.next_page_id = PageId::INVALID_PAGE_ID;
-        self.body.initialize();
+        self.assign(&[]);
     }
 
     pub fn set_prev_page_id(&mut self, prev_page_id: Option<PageId>) {
@@ -75,20 +243,71 @@ impl<B: ByteSliceMut> Leaf<B> {
         self.header.next_page_id = next_page_id.into()
     }
 
+    // 中身を pairs で置き換え、接頭辞を付け直す。前後のリーフへのリンクはそのまま
+    pub fn assign(&mut self, pairs: &[(Vec<u8>, Vec<u8>)]) {
+        self.body.initialize();
+        let prefix_len = self.prefix_len_for(pairs);
+        if self.compressed {
+            let prefix = pairs.first().map_or(&[][..], |(key, _)| &key[..prefix_len]);
+            self.body.insert(0, prefix.len()).expect("prefix must fit");
+            self.body[0].copy_from_slice(prefix);
+        }
+        for (slot_id, (key, value)) in pairs.iter().enumerate() {
+            let pair_bytes = Pair {
+                key: &key[prefix_len..],
+                value,
+            }
+            .to_bytes();
+            let slot = self.slot(slot_id);
+            self.body
+                .insert(slot, pair_bytes.len())
+                .expect("pairs must fit");
+            self.body[slot].copy_from_slice(&pair_bytes);
+        }
+    }
+
     #[must_use = "insertion may fail"]
     pub fn insert(&mut self, slot_id: usize, key: &[u8], value: &[u8]) -> Option<()> {
-        let pair = Pair { key, value };
+        if self.needs_new_prefix(key) {
+            let mut pairs = self.pairs();
+            pairs.insert(slot_id, (key.to_vec(), value.to_vec()));
+            if self.space_for(&pairs) > self.body.capacity() {
+                return None;
+            }
+            self.assign(&pairs);
+            return Some(());
+        }
+        let pair = Pair {
+            key: &key[self.prefix().len()..],
+            value,
+        };
         let pair_bytes = pair.to_bytes();
         assert!(pair_bytes.len() <= self.max_pair_size());
-        self.body.insert(slot_id, pair_bytes.len())?;
-        self.body[slot_id].copy_from_slice(&pair_bytes);
+        let slot = self.slot(slot_id);
+        self.body.insert(slot, pair_bytes.len())?;
+        self.body[slot].copy_from_slice(&pair_bytes);
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    // ペアの値を書き換える。サイズが変わる場合はスロットの大きさを変更する
+    #[must_use = "update may fail"]
+    pub fn update(&mut self, slot_id: usize, value: &[u8]) -> Option<()> {
+        let key = self.stored_pair_at(slot_id).key.to_vec();
+        let pair = Pair { key: &key, value };
+        let pair_bytes = pair.to_bytes();
+        assert!(pair_bytes.len() <= self.max_pair_size());
+        let slot = self.slot(slot_id);
+        self.body.resize(slot, pair_bytes.len())?;
+        self.body[slot].copy_from_slice(&pair_bytes);
+        Some(())
     }
 
+    pub fn remove(&mut self, slot_id: usize) {
+        let slot = self.slot(slot_id);
+        self.body.remove(slot);
+    }
+
+    // 小さい方のキーを new_leaf に移し、親に置く区切りキーを返す
     pub fn split_insert(
         &mut self,
         new_leaf: &mut Leaf<impl ByteSliceMut>,
@@ -96,35 +315,23 @@ impl<B: ByteSliceMut> Leaf<B> {
         new_value: &[u8],
     ) -> Vec<u8> {
         new_leaf.initialize();
-        loop {
-            if new_leaf.is_half_full() {
-                let index = self
-                    .search_slot_id(new_key)
-                    .expect_err("key must be unique");
-                self.insert(index, new_key, new_value)
-                    .expect("old leaf must have space");
-                break;
-            }
-            if self.pair_at(0).key < new_key {
-                self.transfer(new_leaf);
-            } else {
-                new_leaf
-                    .insert(new_leaf.num_pairs(), new_key, new_value)
-                    .expect("new leaf must have space");
-                while !new_leaf.is_half_full() {
-                    self.transfer(new_leaf);
-                }
-                break;
-            }
-        }
-        self.pair_at(0).key.to_vec()
+        let slot_id = self
+            .search_slot_id(new_key)
+            .expect_err("key must be unique");
+        let mut pairs = self.pairs();
+        pairs.insert(slot_id, (new_key.to_vec(), new_value.to_vec()));
+        let mid = split_point(new_leaf, self, &pairs);
+        new_leaf.assign(&pairs[..mid]);
+        self.assign(&pairs[mid..]);
+        separator(&pairs[mid - 1].0, &pairs[mid].0)
     }
 
-    pub fn transfer(&mut self, dest: &mut Leaf<impl ByteSliceMut>) {
-        let next_index = dest.num_pairs();
-        assert!(dest.body.insert(next_index, self.body[0].len()).is_some());
-        dest.body[next_index].copy_from_slice(&self.body[0]);
-        self.body.remove(0);
+    // 全てのペアを右隣のリーフ dest の先頭に移す
+    pub fn merge_into(&mut self, dest: &mut Leaf<impl ByteSliceMut>) {
+        let mut pairs = self.pairs();
+        pairs.extend(dest.pairs());
+        dest.assign(&pairs);
+        self.assign(&[]);
     }
 }
 
@@ -141,29 +348,26 @@ mod tests {
         let id = leaf_page.search_slot_id(b"deadbeef").unwrap_err();
         assert_eq!(0, id);
         leaf_page.insert(id, b"deadbeef", b"world").unwrap();
-        assert_eq!(b"deadbeef", leaf_page.pair_at(0).key);
+        assert_eq!(b"deadbeef", &leaf_page.key_at(0)[..]);
 
         let id = leaf_page.search_slot_id(b"facebook").unwrap_err();
         assert_eq!(1, id);
         leaf_page.insert(id, b"facebook", b"!").unwrap();
-        assert_eq!(b"deadbeef", leaf_page.pair_at(0).key);
-        assert_eq!(b"facebook", leaf_page.pair_at(1).key);
+        assert_eq!(b"deadbeef", &leaf_page.key_at(0)[..]);
+        assert_eq!(b"facebook", &leaf_page.key_at(1)[..]);
 
         let id = leaf_page.search_slot_id(b"beefdead").unwrap_err();
         assert_eq!(0, id);
         leaf_page.insert(id, b"beefdead", b"hello").unwrap();
-        assert_eq!(b"beefdead", leaf_page.pair_at(0).key);
-        assert_eq!(b"deadbeef", leaf_page.pair_at(1).key);
-        assert_eq!(b"facebook", leaf_page.pair_at(2).key);
-        assert_eq!(
-            &b"hello"[..],
-            leaf_page.search_pair(b"beefdead").unwrap().value
-        );
+        assert_eq!(b"beefdead", &leaf_page.key_at(0)[..]);
+        assert_eq!(b"deadbeef", &leaf_page.key_at(1)[..]);
+        assert_eq!(b"facebook", &leaf_page.key_at(2)[..]);
+        assert_eq!(&b"hello"[..], leaf_page.search_value(b"beefdead").unwrap());
     }
 
     #[test]
     fn test_leaf_split_insert() {
-        let mut page_data = vec![0; 62];
+        let mut page_data = vec![0; 66];
         let mut leaf_page = Leaf::new(page_data.as_mut_slice());
         leaf_page.initialize();
         let id = leaf_page.search_slot_id(b"deadbeef").unwrap_err();
@@ -174,12 +378,90 @@ mod tests {
         assert!(leaf_page.insert(id, b"beefdead", b"hello").is_none());
 
         let mut leaf_page = Leaf::new(page_data.as_mut_slice());
-        let mut new_page_data = vec![0; 62];
+        let mut new_page_data = vec![0; 66];
         let mut new_leaf_page = Leaf::new(new_page_data.as_mut_slice());
-        leaf_page.split_insert(&mut new_leaf_page, b"beefdead", b"hello");
+        let sep_key = leaf_page.split_insert(&mut new_leaf_page, b"beefdead", b"hello");
         assert_eq!(
             &b"world"[..],
-            new_leaf_page.search_pair(b"deadbeef").unwrap().value
+            new_leaf_page.search_value(b"deadbeef").unwrap()
         );
+        assert_eq!(b"f", &sep_key[..]);
+    }
+
+    #[test]
+    fn test_leaf_prefix() {
+        let mut page_data = vec![0; 128];
+        let mut leaf = Leaf::new(page_data.as_mut_slice());
+        leaf.initialize();
+        leaf.insert(0, b"user:0001", b"alice").unwrap();
+        assert_eq!(b"user:0001", leaf.prefix());
+        leaf.insert(1, b"user:0002", b"bob").unwrap();
+        leaf.insert(2, b"user:0010", b"carol").unwrap();
+        assert_eq!(b"user:00", leaf.prefix());
+        assert_eq!(b"user:0010", &leaf.key_at(2)[..]);
+        assert_eq!(Err(0), leaf.search_slot_id(b"user"));
+        assert_eq!(Err(3), leaf.search_slot_id(b"users"));
+        assert_eq!(Ok(1), leaf.search_slot_id(b"user:0002"));
+
+        // 接頭辞で始まらないキーを入れると、接頭辞が縮む
+        leaf.insert(0, b"group:1", b"admin").unwrap();
+        assert_eq!(b"", leaf.prefix());
+        assert_eq!(b"group:1", &leaf.key_at(0)[..]);
+        assert_eq!(&b"carol"[..], leaf.search_value(b"user:0010").unwrap());
+
+        // 接頭辞を取り除いて置くので、同じキーを圧縮しないリーフより多く入る
+        let mut compressed_data = vec![0; 200];
+        let mut compressed = Leaf::new(compressed_data.as_mut_slice());
+        compressed.initialize();
+        let mut plain_data = vec![0; 200];
+        let mut plain = Leaf::new_uncompressed(plain_data.as_mut_slice());
+        plain.initialize();
+        let key = |i: usize| format!("user:{:08}", i).into_bytes();
+        let count = |leaf: &mut Leaf<&mut [u8]>| {
+            (0..)
+                .take_while(|&i| leaf.insert(i, &key(i), b"").is_some())
+                .count()
+        };
+        assert!(count(&mut compressed) > count(&mut plain));
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!(b"f", &separator(b"deadbeef", b"facebook")[..]);
+        assert_eq!(b"user:0002", &separator(b"user:0001", b"user:0002")[..]);
+        assert_eq!(b"ab", &separator(b"a", b"abc")[..]);
+    }
+
+    #[test]
+    fn test_leaf_merge() {
+        let mut left_data = vec![0; 100];
+        let mut left = Leaf::new(left_data.as_mut_slice());
+        left.initialize();
+        left.insert(0, b"beefdead", b"hello").unwrap();
+        left.insert(1, b"deadbeef", b"world").unwrap();
+
+        let mut right_data = vec![0; 100];
+        let mut right = Leaf::new(right_data.as_mut_slice());
+        right.initialize();
+        right.insert(0, b"facebook", b"!").unwrap();
+
+        assert!(left.can_merge(&right));
+        left.merge_into(&mut right);
+        assert_eq!(0, left.num_pairs());
+        assert_eq!(3, right.num_pairs());
+        assert_eq!(b"beefdead", &right.key_at(0)[..]);
+        assert_eq!(b"deadbeef", &right.key_at(1)[..]);
+        assert_eq!(b"facebook", &right.key_at(2)[..]);
+
+        assert!(right.update(0, b"hello, world").is_some());
+        assert_eq!(&b"hello, world"[..], right.value_at(0));
+        assert!(right.update(2, b"").is_some());
+        assert_eq!(&b""[..], right.value_at(2));
+        assert_eq!(&b"world"[..], right.value_at(1));
+        assert!(right.update(1, &[0; 24]).is_none());
+
+        right.remove(1);
+        assert_eq!(2, right.num_pairs());
+        assert!(right.search_value(b"deadbeef").is_none());
     }
 }
@@ -3,7 +3,10 @@ use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 use super::branch::Branch;
 use super::leaf::Leaf;
 
-pub const NODE_TYPE_LEAF: [u8; 8] = *b"LEAF    ";
+// ページの形式を変えたら種別の末尾の版を上げ、古い版のページも読めるようにしておく
+// v2 のリーフはキーの共通接頭辞を圧縮する
+pub const NODE_TYPE_LEAF: [u8; 8] = *b"LEAF  v2";
+pub const NODE_TYPE_LEAF_V1: [u8; 8] = *b"LEAF    ";
 pub const NODE_TYPE_BRANCH: [u8; 8] = *b"BRANCH  ";
 
 #[derive(Debug, FromBytes, AsBytes)]
@@ -22,6 +25,17 @@ impl<B: ByteSlice> Node<B> {
         let (header, body) = LayoutVerified::new_from_prefix(bytes).expect("node must be aligned");
         Self { header, body }
     }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.header.node_type, NODE_TYPE_LEAF | NODE_TYPE_LEAF_V1)
+    }
+
+    pub fn into_leaf(self) -> Leaf<B> {
+        match Body::new(self.header.node_type, self.body) {
+            Body::Leaf(leaf) => leaf,
+            Body::Branch(_) => panic!("node must be a leaf"),
+        }
+    }
 }
 
 impl<B: ByteSliceMut> Node<B> {
@@ -43,6 +57,7 @@ impl<B: ByteSlice> Body<B> {
     pub fn new(node_type: [u8; 8], bytes: B) -> Body<B> {
         match node_type {
             NODE_TYPE_LEAF => Body::Leaf(Leaf::new(bytes)),
+            NODE_TYPE_LEAF_V1 => Body::Leaf(Leaf::new_uncompressed(bytes)),
             NODE_TYPE_BRANCH => Body::Branch(Branch::new(bytes)),
             _ => unreachable!(),
         }
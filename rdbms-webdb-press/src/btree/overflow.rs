@@ -0,0 +1,158 @@
+use std::mem::size_of;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
+
+use crate::buffer::{self, BufferPoolManager};
+use crate::disk::{PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+
+// リーフに置く値。大きな値はオーバーフローページの連鎖に移し、先頭のページIDと長さだけを置く
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LeafValue<'a> {
+    Inline(&'a [u8]),
+    Overflow { first_page_id: PageId, len: u64 },
+}
+
+impl<'a> LeafValue<'a> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::options().serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        bincode::options().deserialize(bytes).unwrap()
+    }
+
+    pub fn try_from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        bincode::options().deserialize(bytes).ok()
+    }
+}
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    next_page_id: PageId,
+    // このページに入っている値の長さ
+    len: u64,
+}
+
+// 値を先頭から順に詰めたページ。次のページへのリンクでつながる
+pub struct Overflow<B> {
+    header: LayoutVerified<B, Header>,
+    body: B,
+}
+
+// 1ページに入る値の長さ
+pub const CAPACITY: usize = PAGE_SIZE - PAGE_HEADER_SIZE - size_of::<Header>();
+
+impl<B: ByteSlice> Overflow<B> {
+    pub fn new(bytes: B) -> Self {
+        let (header, body) =
+            LayoutVerified::new_from_prefix(bytes).expect("overflow header must be aligned");
+        Self { header, body }
+    }
+
+    pub fn next_page_id(&self) -> Option<PageId> {
+        self.header.next_page_id.valid()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.body[..self.header.len as usize]
+    }
+
+    pub fn data_len(&self) -> usize {
+        self.header.len as usize
+    }
+}
+
+impl<B: ByteSliceMut> Overflow<B> {
+    pub fn initialize(&mut self, data: &[u8]) {
+        self.header.next_page_id = PageId::INVALID_PAGE_ID;
+        self.header.len = data.len() as u64;
+        self.body[..data.len()].copy_from_slice(data);
+    }
+
+    pub fn set_next_page_id(&mut self, next_page_id: Option<PageId>) {
+        self.header.next_page_id = next_page_id.into()
+    }
+}
+
+// 値をオーバーフローページの連鎖に書き込み、先頭のページIDを返す
+// ミニトランザクションの中で呼ぶので、作ったページはコミットするまで他のスレッドから見えない
+// その間は全てのページがバッファプールに残るので、バッファプールより大きな値は書き込めない
+pub fn write(bufmgr: &BufferPoolManager, value: &[u8]) -> Result<PageId, buffer::Error> {
+    let mut first_page_id = None;
+    let mut prev_buffer = None;
+    for chunk in value.chunks(CAPACITY) {
+        let buffer = bufmgr.create_page()?;
+        {
+            let mut body = buffer.body_mut();
+            Overflow::new(&mut body[..]).initialize(chunk);
+        }
+        match prev_buffer.replace(buffer.clone()) {
+            Some(prev_buffer) => {
+                let mut body = prev_buffer.body_mut();
+                Overflow::new(&mut body[..]).set_next_page_id(Some(buffer.page_id));
+            }
+            None => first_page_id = Some(buffer.page_id),
+        }
+    }
+    Ok(first_page_id.expect("overflow value must not be empty"))
+}
+
+// 連鎖をたどって値を組み立てる。値を指すリーフのラッチを持ったまま呼ぶ
+pub fn read(
+    bufmgr: &BufferPoolManager,
+    first_page_id: PageId,
+    len: u64,
+) -> Result<Vec<u8>, buffer::Error> {
+    let mut value = Vec::with_capacity(len as usize);
+    let mut page_id = Some(first_page_id);
+    while let Some(current_page_id) = page_id {
+        let latch = bufmgr.fetch_page_shared(current_page_id)?;
+        let body = latch.body();
+        let overflow = Overflow::new(&body[..]);
+        value.extend_from_slice(overflow.data());
+        page_id = overflow.next_page_id();
+    }
+    assert_eq!(
+        len as usize,
+        value.len(),
+        "overflow chain must hold the whole value"
+    );
+    Ok(value)
+}
+
+// 連鎖を構成するページのID
+pub fn page_ids(
+    bufmgr: &BufferPoolManager,
+    first_page_id: PageId,
+) -> Result<Vec<PageId>, buffer::Error> {
+    let mut page_ids = vec![];
+    let mut page_id = Some(first_page_id);
+    while let Some(current_page_id) = page_id {
+        page_ids.push(current_page_id);
+        let latch = bufmgr.fetch_page_shared(current_page_id)?;
+        let body = latch.body();
+        page_id = Overflow::new(&body[..]).next_page_id();
+    }
+    Ok(page_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaf_value() {
+        let inline = LeafValue::Inline(b"hello");
+        assert_eq!(inline, LeafValue::from_bytes(&inline.to_bytes()));
+        let overflow = LeafValue::Overflow {
+            first_page_id: PageId(42),
+            len: 100_000,
+        };
+        assert_eq!(overflow, LeafValue::from_bytes(&overflow.to_bytes()));
+        // インラインの値に余分にかかるのはタグと長さだけ
+        assert_eq!(7, inline.to_bytes().len());
+    }
+}
@@ -1,70 +1,378 @@
 use std::{
-    cell::{Cell, RefCell},
-    collections::HashMap,
+    cell::Cell,
+    collections::{hash_map::Entry, HashMap, HashSet},
     io,
-    ops::{Index, IndexMut},
-    rc::Rc,
+    ops::{Deref, DerefMut, Index},
     result::Result,
-    u64,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread::{self, ThreadId},
 };
 
-use crate::disk::{DiskManager, PageId, PAGE_SIZE};
+use crate::disk::{self, DiskManager, PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::latch::{self, Latch};
+use crate::transaction::{self, UndoRecord};
+use crate::wal;
+
+mod policy;
+
+pub use policy::{ClockSweep, LruK, ReplacementPolicy, TwoQ};
+
+// ログがこの大きさを超えたら、全てのページを書き出してログを空にする
+const CHECKPOINT_WAL_SIZE: u64 = 16 * 1024 * 1024;
+// ページテーブルを分割する数。ページIDの剰余でどこに入れるかを決める
+const NUM_PAGE_TABLE_SHARDS: usize = 16;
 
 pub type Page = [u8; PAGE_SIZE];
 
+// ノードのヘッダーを LayoutVerified で読めるように、ページを8バイト境界に揃えて置く
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct AlignedPage(Page);
+
+impl Deref for AlignedPage {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        &self.0
+    }
+}
+
+impl DerefMut for AlignedPage {
+    fn deref_mut(&mut self) -> &mut Page {
+        &mut self.0
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error("no free buffer available in buffer pool")]
     NoFreeBuffer,
+    #[error("page {} is corrupted", .page_id.to_u64())]
+    Corrupted { page_id: PageId },
+    #[error("transaction already in progress")]
+    TransactionInProgress,
+    #[error("key is locked by another transaction")]
+    KeyLocked,
+    #[error("page {} is latched shared by this thread and cannot be latched exclusively", .page_id.to_u64())]
+    LatchUpgrade { page_id: PageId },
 }
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct BufferId(usize);
 
+impl BufferId {
+    pub fn to_usize(self) -> usize {
+        self.0
+    }
+}
+
+// BufferPoolManager を作ってから、または reset_stats してからの回数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub pool_size: usize,
+    // fetch_page でページがバッファプールにあった回数と、なかった回数
+    pub hits: u64,
+    pub misses: u64,
+    // 別のページを入れるためにページを追い出した回数
+    pub evictions: u64,
+    // 変更されたページをディスクに書き出した回数。追い出す時と flush の両方を数える
+    pub dirty_write_backs: u64,
+    pub no_free_buffer_failures: u64,
+    // 今貸し出し中のフレームの数
+    pub pinned_frames: usize,
+}
+
+impl Stats {
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+// フレームに今入っているページ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub buffer_id: BufferId,
+    pub page_id: Option<PageId>,
+    pub is_dirty: bool,
+    // 貸し出している数
+    pub pin_count: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    dirty_write_backs: AtomicU64,
+    no_free_buffer_failures: AtomicU64,
+}
+
+impl Counters {
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    // このスレッドが fetch_page でページを取り出した回数。EXPLAIN ANALYZE で使う
+    static THREAD_FETCHES: Cell<u64> = const { Cell::new(0) };
+}
+
+// このスレッドがどの BufferPoolManager からでもページを取り出した回数の合計
+// stats と違い、フレームをロックしないので安く読める
+pub fn thread_fetches() -> u64 {
+    THREAD_FETCHES.with(Cell::get)
+}
+
 #[derive(Debug)]
 pub struct Buffer {
     pub page_id: PageId,
-    pub page: RefCell<Page>,
-    pub is_dirty: Cell<bool>,
+    pub page: RwLock<AlignedPage>,
+    pub is_dirty: AtomicBool,
+    // 変更されるたびに増える。イテレータが読んだ後に書き換えられたかを調べるのに使う
+    version: AtomicU64,
+    latch: Latch,
+}
+
+impl Buffer {
+    // ページヘッダーを除いた部分
+    pub fn body(&self) -> BodyRef<'_> {
+        BodyRef(self.page.read().unwrap())
+    }
+
+    pub fn body_mut(&self) -> BodyMut<'_> {
+        BodyMut(self.page.write().unwrap())
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn latch_shared(self: &Arc<Self>) -> PageLatch {
+        let mode = self
+            .latch
+            .lock(latch::Mode::Shared)
+            .expect("shared latch is never refused");
+        PageLatch {
+            buffer: self.clone(),
+            mode,
+        }
+    }
+
+    pub fn try_latch_shared(self: &Arc<Self>) -> Option<PageLatch> {
+        let mode = self.latch.try_lock(latch::Mode::Shared)?;
+        Some(PageLatch {
+            buffer: self.clone(),
+            mode,
+        })
+    }
+
+    // 排他ラッチはミニトランザクションに記録するため、BufferPoolManager を通して取る
+    // 共有ラッチを持ったまま取り直そうとするとデッドロックしかねないので、待たずにエラーを返す
+    fn latch_exclusive(self: &Arc<Self>) -> Result<PageLatch, Error> {
+        let mode = self
+            .latch
+            .lock(latch::Mode::Exclusive)
+            .ok_or(Error::LatchUpgrade {
+                page_id: self.page_id,
+            })?;
+        Ok(PageLatch {
+            buffer: self.clone(),
+            mode,
+        })
+    }
+
+    fn try_latch_exclusive(self: &Arc<Self>) -> Option<PageLatch> {
+        let mode = self.latch.try_lock(latch::Mode::Exclusive)?;
+        Some(PageLatch {
+            buffer: self.clone(),
+            mode,
+        })
+    }
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Self {
             page_id: Default::default(),
-            page: RefCell::new([0u8; PAGE_SIZE]),
-            is_dirty: Cell::new(false),
+            page: RwLock::new(AlignedPage([0u8; PAGE_SIZE])),
+            is_dirty: AtomicBool::new(false),
+            version: AtomicU64::new(0),
+            latch: Latch::default(),
         }
     }
 }
 
+pub struct BodyRef<'a>(RwLockReadGuard<'a, AlignedPage>);
+
+impl Deref for BodyRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[PAGE_HEADER_SIZE..]
+    }
+}
+
+pub struct BodyMut<'a>(RwLockWriteGuard<'a, AlignedPage>);
+
+impl Deref for BodyMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[PAGE_HEADER_SIZE..]
+    }
+}
+
+impl DerefMut for BodyMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0[PAGE_HEADER_SIZE..]
+    }
+}
+
+// ラッチを取ったまま貸し出しているページ。drop するとラッチを外す
+pub struct PageLatch {
+    buffer: Arc<Buffer>,
+    mode: latch::Mode,
+}
+
+impl PageLatch {
+    pub fn buffer(&self) -> &Arc<Buffer> {
+        &self.buffer
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.mode == latch::Mode::Exclusive
+    }
+}
+
+impl Deref for PageLatch {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+impl Drop for PageLatch {
+    fn drop(&mut self) {
+        self.buffer.latch.unlock(self.mode);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Frame {
-    usage_count: u64,
-    buffer: Rc<Buffer>,
+    buffer: Mutex<Arc<Buffer>>,
 }
 
 pub struct BufferPool {
     buffers: Vec<Frame>,
-    next_victim_id: BufferId,
+    policy: Box<dyn ReplacementPolicy>,
+}
+
+// 複数ページへの変更をまとめてログに残す単位 (ミニトランザクション)
+// スレッドごとに持つ。変更したページのラッチは使い終わったら外すが、
+// ログに残すまでは retain しておき、他のスレッドには読ませても書き換えさせない
+#[derive(Default)]
+struct Mtr {
+    // 入れ子になったミニトランザクション。最後が一番内側
+    savepoints: Vec<Savepoint>,
+    // 変更したページと新しく作ったページ
+    pages: HashMap<PageId, Arc<Buffer>>,
+    // 新しく作ったページは、中身を書き終えるまで見えないよう排他ラッチを持っておく
+    created: Vec<PageLatch>,
+    freed_page_ids: Vec<PageId>,
+    // この中で行った操作を取り消すための UNDO レコード
+    undo_records: Vec<UndoRecord>,
+    // この中で取り消し終えた UNDO レコードの数
+    num_compensated: usize,
+}
+
+// 入れ子になったミニトランザクションが失敗した時に、始めた時点へ戻すための情報
+#[derive(Default)]
+struct Savepoint {
+    // この中で排他ラッチを取った時点のページの内容。新しく作ったページは含まない
+    before_images: HashMap<PageId, Box<Page>>,
+    // この中で pages に加えたページ
+    page_ids: Vec<PageId>,
+    created_page_ids: HashSet<PageId>,
+    num_freed_page_ids: usize,
+    num_undo_records: usize,
+    num_compensated: usize,
+}
+
+// 書き換え中のキーと、書き換えているスレッド
+// トランザクションで書き換えたキーはコミットかロールバックまで持ち続ける
+// そうでなければ書き換える間だけ持つので、他の書き換えは待たせる
+#[derive(Default)]
+struct KeyLocks {
+    locked: Mutex<HashMap<(PageId, Vec<u8>), KeyOwner>>,
+    unlocked: Condvar,
+}
+
+#[derive(Clone, Copy)]
+struct KeyOwner {
+    thread_id: ThreadId,
+    in_transaction: bool,
+}
+
+// トランザクションの外で書き換える間だけ持つロック
+pub(crate) struct KeyLock<'a> {
+    locks: &'a KeyLocks,
+    key: Option<(PageId, Vec<u8>)>,
+}
+
+impl Drop for KeyLock<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.locks.locked.lock().unwrap().remove(&key);
+            self.locks.unlocked.notify_all();
+        }
+    }
+}
+
+// スレッドごとの、実行中のトランザクションの状態
+struct UndoLog {
+    txn_id: u64,
+    records: Vec<UndoRecord>,
+    // ロールバック中の操作には UNDO レコードを作らない
+    rolling_back: bool,
 }
 
 pub struct BufferPoolManager {
-    disk: DiskManager,
+    disk: Mutex<DiskManager>,
+    has_wal: bool,
     pool: BufferPool,
-    page_table: HashMap<PageId, BufferId>,
+    page_table: Vec<Mutex<HashMap<PageId, BufferId>>>,
+    mtrs: Mutex<HashMap<ThreadId, Mtr>>,
+    // 実行中のトランザクションの UNDO レコード
+    undo_logs: Mutex<HashMap<ThreadId, UndoLog>>,
+    key_locks: KeyLocks,
+    next_txn_id: AtomicU64,
+    counters: Counters,
 }
 
 impl BufferPool {
+    // Clock-sweepというアルゴリズムでPoolを管理する
     pub fn new(pool_size: usize) -> Self {
+        Self::with_policy(pool_size, ClockSweep::default())
+    }
+
+    pub fn with_policy(pool_size: usize, policy: impl ReplacementPolicy + 'static) -> Self {
         let mut buffers = vec![];
         buffers.resize_with(pool_size, Default::default);
-        let next_victim_id = BufferId::default();
         Self {
             buffers,
-            next_victim_id,
+            policy: Box::new(policy),
         }
     }
 
@@ -72,40 +380,27 @@ impl BufferPool {
         self.buffers.len()
     }
 
-    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
-        BufferId((buffer_id.0 + 1) % self.size())
-    }
-
-    // Clock-sweepというアルゴリズムでPoolを管理する
     // 新しいBufferの空き領域があるかどうかを探す
-    fn evict(&mut self) -> Option<BufferId> {
-        let pool_size = self.size();
-        // 参照されているバッファの数をカウント
-        let mut consecutive_pinned = 0;
-        let victim_id = loop {
-            let next_victim_id = self.next_victim_id;
-            let frame = &mut self[next_victim_id];
-
-            // bufferの利用回数が0になるものを探す
-            if frame.usage_count == 0 {
-                break self.next_victim_id;
+    // 見つかったフレームはロックしたまま返す
+    fn evict(&self) -> Option<(BufferId, MutexGuard<'_, Arc<Buffer>>)> {
+        let mut victim = None;
+        let buffer_id = self.policy.evict(self.size(), &mut |buffer_id| {
+            // 前に試したフレームのロックを外してから取る
+            victim = None;
+            let buffer = self[buffer_id].buffer.lock().unwrap();
+            // 貸し出し中のバッファは追い出せない
+            if Arc::strong_count(&buffer) > 1 {
+                return false;
             }
-
-            // bufferが参照されているか
-            if Rc::get_mut(&mut frame.buffer).is_some() {
-                // されていない場合
-                frame.usage_count -= 1;
-                consecutive_pinned = 0;
-            } else {
-                // 参照されている場合
-                consecutive_pinned += 1;
-                if consecutive_pinned >= pool_size {
-                    return None;
-                }
-            }
-            self.next_victim_id = self.increment_id(self.next_victim_id)
-        };
-        Some(victim_id)
+            victim = Some((buffer_id, buffer));
+            true
+        })?;
+        let (victim_id, buffer) = victim.expect("policy must choose an unpinned buffer");
+        assert_eq!(
+            buffer_id, victim_id,
+            "policy must return the last buffer tried"
+        );
+        Some((buffer_id, buffer))
     }
 }
 
@@ -118,152 +413,879 @@ impl Index<BufferId> for BufferPool {
     }
 }
 
-impl IndexMut<BufferId> for BufferPool {
-    fn index_mut(&mut self, index: BufferId) -> &mut Self::Output {
-        &mut self.buffers[index.0]
-    }
-}
-
 impl BufferPoolManager {
+    // 前回終わらないまま落ちたトランザクションがあれば、ここで取り消す
+    // 取り消せないのは B+Tree が壊れている場合なので panic する
     pub fn new(disk: DiskManager, pool: BufferPool) -> Self {
-        let page_table = HashMap::new();
-        Self {
-            disk,
+        let has_wal = disk.has_wal();
+        let unfinished = disk.unfinished_transactions();
+        let next_txn_id = unfinished.last().map_or(1, |&(txn_id, _)| txn_id + 1);
+        let mut page_table = vec![];
+        page_table.resize_with(NUM_PAGE_TABLE_SHARDS, Default::default);
+        let bufmgr = Self {
+            disk: Mutex::new(disk),
+            has_wal,
             pool,
             page_table,
+            mtrs: Mutex::new(HashMap::new()),
+            undo_logs: Mutex::new(HashMap::new()),
+            key_locks: KeyLocks::default(),
+            next_txn_id: AtomicU64::new(next_txn_id),
+            counters: Counters::default(),
+        };
+        for (txn_id, records) in unfinished {
+            let records = records
+                .iter()
+                .map(|data| UndoRecord::from_bytes(data))
+                .collect();
+            bufmgr.undo_logs.lock().unwrap().insert(
+                thread::current().id(),
+                UndoLog {
+                    txn_id,
+                    records,
+                    rolling_back: true,
+                },
+            );
+            transaction::rollback_current(&bufmgr)
+                .expect("failed to roll back an unfinished transaction");
         }
+        bufmgr
+    }
+
+    fn page_table(&self, page_id: PageId) -> MutexGuard<'_, HashMap<PageId, BufferId>> {
+        let shard = page_id.to_u64() as usize % NUM_PAGE_TABLE_SHARDS;
+        self.page_table[shard].lock().unwrap()
+    }
+
+    fn disk(&self) -> MutexGuard<'_, DiskManager> {
+        self.disk.lock().unwrap()
     }
 
     // ページの貸し出し処理
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<Buffer>, Error> {
-        // dbg!(page_id);
-        if let Some(&buffer_id) = self.page_table.get(&page_id) {
-            let frame = &mut self.pool[buffer_id];
-            frame.usage_count += 1;
-            return Ok(frame.buffer.clone());
+    // ロックは ページテーブル -> 置換ポリシー -> フレーム -> ディスク の順に取る
+    // 置換ポリシーにはフレームのロックを外してから知らせる
+    pub fn fetch_page(&self, page_id: PageId) -> Result<Arc<Buffer>, Error> {
+        THREAD_FETCHES.with(|fetches| fetches.set(fetches.get() + 1));
+        let mut page_table = self.page_table(page_id);
+        if let Some(&buffer_id) = page_table.get(&page_id) {
+            let buffer = self.pool[buffer_id].buffer.lock().unwrap().clone();
+            if buffer.page_id == page_id {
+                self.pool.policy.record_access(buffer_id);
+                Counters::increment(&self.counters.hits);
+                return Ok(buffer);
+            }
+            // 追い出されて別のページが入っている
+            page_table.remove(&page_id);
         }
 
         // ページがバッファープールにない場合
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
-        let evict_page_id = frame.buffer.page_id;
+        Counters::increment(&self.counters.misses);
+        let (buffer_id, mut frame_buffer) = self.evict()?;
         {
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
+            let buffer = Arc::get_mut(&mut frame_buffer).unwrap();
+            let mut disk = self.disk();
 
             // is_dirty: バッファは更新されているが、ディスク内容が古いことを示す
-            if buffer.is_dirty.get() {
-                // ページIDを上書きする前にディスクを更新
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
-            }
+            // ページIDを上書きする前にディスクを更新
+            self.write_back(&mut disk, buffer)?;
             // 新しいページIDをセット
             buffer.page_id = page_id;
-            buffer.is_dirty.set(false);
+            *buffer.is_dirty.get_mut() = false;
 
             // ページの読み込み
-            self.disk.read_page_data(page_id, buffer.page.get_mut())?;
-            frame.usage_count = 1;
+            let page = &mut buffer.page.get_mut().unwrap()[..];
+            disk.read_page_data(page_id, page)?;
+            if !disk::verify_page(page) {
+                // 壊れた内容を他のページIDで使い回さないよう、空きフレームに戻す
+                buffer.page_id = PageId::INVALID_PAGE_ID;
+                return Err(Error::Corrupted { page_id });
+            }
         }
+        let buffer = frame_buffer.clone();
+        drop(frame_buffer);
+        self.pool.policy.record_load(buffer_id, page_id);
 
         // ページテーブルの更新
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
-        self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+        // 追い出したページの古いエントリは、次に引かれた時に取り除く
+        page_table.insert(page_id, buffer_id);
+        Ok(buffer)
     }
 
-    // ページの作成処理
-    pub fn create_page(&mut self) -> Result<Rc<Buffer>, Error> {
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
-        let evict_page_id = frame.buffer.page_id;
-        let page_id = {
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
-            if buffer.is_dirty.get() {
-                // ページIDを上書きする前にディスクを更新
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
+    // 追い出すフレームを選んで、ロックしたまま返す
+    fn evict(&self) -> Result<(BufferId, MutexGuard<'_, Arc<Buffer>>), Error> {
+        let (buffer_id, buffer) = match self.pool.evict() {
+            Some(victim) => victim,
+            None => {
+                Counters::increment(&self.counters.no_free_buffer_failures);
+                return Err(Error::NoFreeBuffer);
             }
-            // ページテーブルから削除
-            self.page_table.remove(&evict_page_id);
+        };
+        if buffer.page_id.valid().is_some() {
+            Counters::increment(&self.counters.evictions);
+        }
+        Ok((buffer_id, buffer))
+    }
+
+    // 追い出すページが変更されていれば書き出す
+    fn write_back(&self, disk: &mut DiskManager, buffer: &mut Buffer) -> Result<(), Error> {
+        if *buffer.is_dirty.get_mut() {
+            disk.write_page_data(buffer.page_id, &buffer.page.get_mut().unwrap()[..])?;
+            Counters::increment(&self.counters.dirty_write_backs);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let pinned_frames = self
+            .pool
+            .buffers
+            .iter()
+            .filter(|frame| Arc::strong_count(&frame.buffer.lock().unwrap()) > 1)
+            .count();
+        Stats {
+            pool_size: self.pool.size(),
+            hits: load(&self.counters.hits),
+            misses: load(&self.counters.misses),
+            evictions: load(&self.counters.evictions),
+            dirty_write_backs: load(&self.counters.dirty_write_backs),
+            no_free_buffer_failures: load(&self.counters.no_free_buffer_failures),
+            pinned_frames,
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        for counter in [
+            &self.counters.hits,
+            &self.counters.misses,
+            &self.counters.evictions,
+            &self.counters.dirty_write_backs,
+            &self.counters.no_free_buffer_failures,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // どのフレームにどのページが入っているか。フレームを一つずつ見るので、全体が同じ時点のものとは限らない
+    pub fn frames(&self) -> Vec<FrameInfo> {
+        self.pool
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let buffer = frame.buffer.lock().unwrap();
+                FrameInfo {
+                    buffer_id: BufferId(i),
+                    page_id: buffer.page_id.valid(),
+                    is_dirty: buffer.is_dirty.load(Ordering::Acquire),
+                    pin_count: Arc::strong_count(&buffer) - 1,
+                }
+            })
+            .collect()
+    }
+
+    pub fn fetch_page_shared(&self, page_id: PageId) -> Result<PageLatch, Error> {
+        Ok(self.fetch_page(page_id)?.latch_shared())
+    }
+
+    pub fn fetch_page_exclusive(&self, page_id: PageId) -> Result<PageLatch, Error> {
+        let buffer = self.fetch_page(page_id)?;
+        self.latch_exclusive(&buffer)
+    }
+
+    // ラッチが取れなければ待たずに None を返す
+    pub fn try_fetch_page_exclusive(&self, page_id: PageId) -> Result<Option<PageLatch>, Error> {
+        let buffer = self.fetch_page(page_id)?;
+        let latch = match buffer.try_latch_exclusive() {
+            Some(latch) => latch,
+            None => return Ok(None),
+        };
+        self.track(&latch);
+        Ok(Some(latch))
+    }
+
+    // ページの作成処理
+    pub fn create_page(&self) -> Result<Arc<Buffer>, Error> {
+        let buffer = {
+            let (buffer_id, mut frame_buffer) = self.evict()?;
+            let buffer = Arc::get_mut(&mut frame_buffer).unwrap();
+            let mut disk = self.disk();
+            // ページIDを上書きする前にディスクを更新
+            self.write_back(&mut disk, buffer)?;
 
             // バッファーの新規作成
-            let page_id = self.disk.allocate_page();
+            let page_id = disk.allocate_page()?;
             *buffer = Buffer::default();
             buffer.page_id = page_id;
-            buffer.is_dirty.set(true);
-            frame.usage_count = 1;
-            page_id
+            *buffer.is_dirty.get_mut() = true;
+            (buffer_id, frame_buffer.clone())
         };
+        let (buffer_id, buffer) = buffer;
+        self.pool.policy.record_load(buffer_id, buffer.page_id);
 
         // テーブルの更新
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
-        self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+        self.page_table(buffer.page_id)
+            .insert(buffer.page_id, buffer_id);
+
+        // ミニトランザクションの途中なら、コミットするまで他のスレッドから見えないようにする
+        let mut mtrs = self.mtrs.lock().unwrap();
+        if let Some(mtr) = mtrs.get_mut(&thread::current().id()) {
+            let latch = buffer.latch_exclusive()?;
+            latch.latch.retain();
+            mtr.created.push(latch);
+            mtr.pages.insert(buffer.page_id, buffer.clone());
+            let savepoint = mtr.savepoints.last_mut().unwrap();
+            savepoint.page_ids.push(buffer.page_id);
+            savepoint.created_page_ids.insert(buffer.page_id);
+        }
+        Ok(buffer)
+    }
+
+    // ページの解放処理
+    pub fn delete_page(&self, page_id: PageId) -> Result<(), Error> {
+        // ミニトランザクションの途中なら、変更をログに残した後で解放する
+        if let Some(mtr) = self.mtrs.lock().unwrap().get_mut(&thread::current().id()) {
+            mtr.freed_page_ids.push(page_id);
+            return Ok(());
+        }
+        let buffer_id = self.page_table(page_id).remove(&page_id);
+        if let Some(buffer_id) = buffer_id {
+            let mut buffer = self.pool[buffer_id].buffer.lock().unwrap();
+            if buffer.page_id == page_id {
+                // 解放するページの内容は書き出さずに捨てる
+                // 貸し出し中なら、返ってきた後で追い出されるのを待つ
+                match Arc::get_mut(&mut buffer) {
+                    Some(buffer) => *buffer = Buffer::default(),
+                    None => buffer.is_dirty.store(false, Ordering::Release),
+                }
+                drop(buffer);
+                self.pool.policy.record_free(buffer_id);
+            }
+        }
+        self.disk().deallocate_page(page_id)?;
+        Ok(())
+    }
+
+    pub fn latch_exclusive(&self, buffer: &Arc<Buffer>) -> Result<PageLatch, Error> {
+        let latch = buffer.latch_exclusive()?;
+        self.track(&latch);
+        Ok(latch)
+    }
+
+    // ミニトランザクション中に排他ラッチを取ったページの、変更前の内容を覚えておく
+    // まだ変更していないページは他のスレッドが書き換えているかもしれないので、取るたびに覚え直す
+    fn track(&self, latch: &PageLatch) {
+        let mut mtrs = self.mtrs.lock().unwrap();
+        let mtr = match mtrs.get_mut(&thread::current().id()) {
+            Some(mtr) => mtr,
+            None => return,
+        };
+        if mtr.pages.contains_key(&latch.page_id) {
+            let savepoint = mtr.savepoints.last_mut().unwrap();
+            savepoint
+                .before_images
+                .entry(latch.page_id)
+                .or_insert_with(|| Box::new(**latch.page.read().unwrap()));
+            return;
+        }
+        for savepoint in mtr.savepoints.iter_mut() {
+            savepoint.before_images.remove(&latch.page_id);
+        }
+        let before = Box::new(**latch.page.read().unwrap());
+        let savepoint = mtr.savepoints.last_mut().unwrap();
+        savepoint.before_images.insert(latch.page_id, before);
+    }
+
+    // ページを変更したことを記録する。排他ラッチを持っている間に呼ぶ
+    // ミニトランザクションの中なら、ラッチを外した後もコミットするまで他のスレッドに書き換えさせない
+    pub fn mark_dirty(&self, latch: &PageLatch) {
+        assert!(latch.is_exclusive(), "page must be latched exclusively");
+        latch.is_dirty.store(true, Ordering::Release);
+        latch.version.fetch_add(1, Ordering::AcqRel);
+        let mut mtrs = self.mtrs.lock().unwrap();
+        if let Some(mtr) = mtrs.get_mut(&thread::current().id()) {
+            if let Entry::Vacant(entry) = mtr.pages.entry(latch.page_id) {
+                latch.latch.retain();
+                entry.insert(latch.buffer().clone());
+                let savepoint = mtr.savepoints.last_mut().unwrap();
+                savepoint.page_ids.push(latch.page_id);
+            }
+        }
+    }
+
+    pub fn begin_mtr(&self) {
+        let mut mtrs = self.mtrs.lock().unwrap();
+        let mtr = mtrs.entry(thread::current().id()).or_default();
+        let savepoint = Savepoint {
+            num_freed_page_ids: mtr.freed_page_ids.len(),
+            num_undo_records: mtr.undo_records.len(),
+            num_compensated: mtr.num_compensated,
+            ..Default::default()
+        };
+        mtr.savepoints.push(savepoint);
+    }
+
+    // 入れ子になったミニトランザクションは、一番外側が終わる時にまとめてログに残す
+    pub fn commit_mtr(&self) -> Result<(), Error> {
+        let mtr = {
+            let mut mtrs = self.mtrs.lock().unwrap();
+            let me = thread::current().id();
+            let mtr = mtrs.get_mut(&me).expect("no mini-transaction in progress");
+            let savepoint = mtr.savepoints.pop().unwrap();
+            if let Some(outer) = mtr.savepoints.last_mut() {
+                for (page_id, before) in savepoint.before_images {
+                    outer.before_images.entry(page_id).or_insert(before);
+                }
+                outer.page_ids.extend(savepoint.page_ids);
+                outer.created_page_ids.extend(savepoint.created_page_ids);
+                return Ok(());
+            }
+            let mut mtr = mtrs.remove(&me).unwrap();
+            mtr.savepoints.push(savepoint);
+            mtr
+        };
+        {
+            let mut disk = self.disk();
+            let mut logged = false;
+            if self.has_wal {
+                let before_images = &mtr.savepoints[0].before_images;
+                for (page_id, buffer) in mtr.pages.iter() {
+                    let mut page = buffer.page.write().unwrap();
+                    let runs = match before_images.get(page_id) {
+                        Some(before) => wal::diff(before.as_ref(), page.as_ref()),
+                        None => vec![wal::Run {
+                            offset: PAGE_HEADER_SIZE as u16,
+                            data: page[PAGE_HEADER_SIZE..].to_vec(),
+                        }],
+                    };
+                    if runs.is_empty() {
+                        continue;
+                    }
+                    if let Some(lsn) = disk.log_redo(*page_id, runs) {
+                        disk::set_page_lsn(page.as_mut(), lsn);
+                        logged = true;
+                    }
+                }
+            }
+            // UNDO レコードは、その操作の REDO レコードと一緒にログに残す
+            let mut undo_logs = self.undo_logs.lock().unwrap();
+            let undo_log = undo_logs.get_mut(&thread::current().id());
+            let in_transaction = undo_log.is_some();
+            if let Some(undo_log) = undo_log {
+                logged |= self.has_wal && !mtr.undo_records.is_empty();
+                for record in mtr.undo_records {
+                    disk.log_undo(undo_log.txn_id, record.to_bytes());
+                    undo_log.records.push(record);
+                }
+                logged |= self.has_wal && mtr.num_compensated > 0;
+                for _ in 0..mtr.num_compensated {
+                    disk.log_compensate(undo_log.txn_id);
+                    undo_log.records.pop();
+                }
+            }
+            drop(undo_logs);
+            // トランザクションの中ではコミットする時にまとめて永続化する
+            if logged {
+                disk.log_commit();
+                if !in_transaction {
+                    disk.flush_wal()?;
+                }
+            }
+        }
+        // ログに残したので、他のスレッドにも書き換えさせる
+        drop(mtr.created);
+        for buffer in mtr.pages.values() {
+            buffer.latch.release();
+        }
+
+        for page_id in mtr.freed_page_ids {
+            self.delete_page(page_id)?;
+        }
+        if self.disk().wal_size() > CHECKPOINT_WAL_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // 一番内側のミニトランザクションで変更したページを始めた時点の内容に戻し、ログには何も残さない
+    // 解放するはずだったページはそのまま残し、新しく作ったページは解放する
+    pub fn abort_mtr(&self) -> Result<(), Error> {
+        let created_page_ids = {
+            let mut mtrs = self.mtrs.lock().unwrap();
+            let me = thread::current().id();
+            let mtr = mtrs.get_mut(&me).expect("no mini-transaction in progress");
+            let savepoint = mtr.savepoints.pop().unwrap();
+            for (page_id, before) in savepoint.before_images {
+                if let Some(buffer) = mtr.pages.get(&page_id) {
+                    buffer
+                        .page
+                        .write()
+                        .unwrap()
+                        .copy_from_slice(before.as_ref());
+                    buffer.version.fetch_add(1, Ordering::AcqRel);
+                }
+            }
+            let created_page_ids = savepoint.created_page_ids;
+            mtr.created
+                .retain(|latch| !created_page_ids.contains(&latch.page_id));
+            for page_id in savepoint.page_ids {
+                let buffer = mtr.pages.remove(&page_id).unwrap();
+                buffer.latch.release();
+            }
+            mtr.freed_page_ids.truncate(savepoint.num_freed_page_ids);
+            mtr.undo_records.truncate(savepoint.num_undo_records);
+            mtr.num_compensated = savepoint.num_compensated;
+            if mtr.savepoints.is_empty() {
+                mtrs.remove(&me);
+            }
+            created_page_ids
+        };
+        // 外側のミニトランザクションがあれば、その終わりに解放される
+        for page_id in created_page_ids {
+            self.delete_page(page_id)?;
+        }
+        Ok(())
+    }
+
+    // f の中で行ったページの変更を、まとめてログに残す
+    // f が失敗したら、変更を取り消してからエラーを返す
+    pub fn with_mtr<T, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.begin_mtr();
+        match f(self) {
+            Ok(value) => {
+                self.commit_mtr()?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.abort_mtr()?;
+                Err(err)
+            }
+        }
+    }
+
+    pub(crate) fn begin_transaction(&self) -> Result<(), Error> {
+        let mut undo_logs = self.undo_logs.lock().unwrap();
+        let me = thread::current().id();
+        if undo_logs.contains_key(&me) {
+            return Err(Error::TransactionInProgress);
+        }
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::Relaxed);
+        undo_logs.insert(
+            me,
+            UndoLog {
+                txn_id,
+                records: vec![],
+                rolling_back: false,
+            },
+        );
+        Ok(())
+    }
+
+    // トランザクションが終わったことをログに残して永続化する
+    // 永続化してから、書き換えたキーのロックを外す
+    pub(crate) fn end_transaction(&self) -> Result<(), Error> {
+        let mut disk = self.disk();
+        let undo_log = self
+            .undo_logs
+            .lock()
+            .unwrap()
+            .remove(&thread::current().id())
+            .expect("no transaction in progress");
+        disk.log_end(undo_log.txn_id);
+        disk.log_commit();
+        let result = disk.flush_wal();
+        drop(disk);
+        self.unlock_keys();
+        result?;
+        Ok(())
+    }
+
+    // ロールバックに失敗したトランザクションを諦める
+    // ログに残した UNDO レコードは、次に開いた時に改めて取り消す
+    pub(crate) fn abandon_transaction(&self) {
+        let mut undo_logs = self.undo_logs.lock().unwrap();
+        undo_logs
+            .remove(&thread::current().id())
+            .expect("no transaction in progress");
+        drop(undo_logs);
+        self.unlock_keys();
+    }
+
+    // キーを書き換える前に呼ぶ。他のトランザクションが書き換えたキーならエラーにする
+    // トランザクションの中なら、終わるまでロックを持ち続けるので何も返さない
+    // ロールバック中は、取り消すキーのロックを既に持っている
+    pub(crate) fn lock_key(
+        &self,
+        meta_page_id: PageId,
+        key: &[u8],
+    ) -> Result<Option<KeyLock<'_>>, Error> {
+        let owner = KeyOwner {
+            thread_id: thread::current().id(),
+            in_transaction: self.in_transaction(),
+        };
+        let key = (meta_page_id, key.to_vec());
+        let mut locked = self.key_locks.locked.lock().unwrap();
+        loop {
+            match locked.get(&key) {
+                Some(holder) if holder.thread_id == owner.thread_id => return Ok(None),
+                Some(holder) if holder.in_transaction => return Err(Error::KeyLocked),
+                Some(_) => locked = self.key_locks.unlocked.wait(locked).unwrap(),
+                None => break,
+            }
+        }
+        locked.insert(key.clone(), owner);
+        if owner.in_transaction {
+            return Ok(None);
+        }
+        Ok(Some(KeyLock {
+            locks: &self.key_locks,
+            key: Some(key),
+        }))
+    }
+
+    fn unlock_keys(&self) {
+        let me = thread::current().id();
+        self.key_locks
+            .locked
+            .lock()
+            .unwrap()
+            .retain(|_, holder| holder.thread_id != me);
+        self.key_locks.unlocked.notify_all();
+    }
+
+    pub(crate) fn begin_rollback(&self) {
+        let mut undo_logs = self.undo_logs.lock().unwrap();
+        let undo_log = undo_logs
+            .get_mut(&thread::current().id())
+            .expect("no transaction in progress");
+        undo_log.rolling_back = true;
+    }
+
+    // ロールバック中は false を返す
+    pub fn in_transaction(&self) -> bool {
+        let undo_logs = self.undo_logs.lock().unwrap();
+        undo_logs
+            .get(&thread::current().id())
+            .is_some_and(|undo_log| !undo_log.rolling_back)
+    }
+
+    // トランザクションの外では何もしない
+    // ミニトランザクションの中で呼び、コミットする時にログに残す
+    pub(crate) fn push_undo(&self, record: UndoRecord) {
+        if !self.in_transaction() {
+            return;
+        }
+        let mut mtrs = self.mtrs.lock().unwrap();
+        let mtr = mtrs
+            .get_mut(&thread::current().id())
+            .expect("no mini-transaction in progress");
+        mtr.undo_records.push(record);
+    }
+
+    // まだ取り消していない一番新しい UNDO レコード
+    pub(crate) fn last_undo(&self) -> Option<UndoRecord> {
+        let undo_logs = self.undo_logs.lock().unwrap();
+        undo_logs
+            .get(&thread::current().id())
+            .and_then(|undo_log| undo_log.records.last().cloned())
+    }
+
+    // last_undo を取り消したことを、取り消した変更と一緒にログに残す
+    pub(crate) fn compensate(&self) {
+        let mut mtrs = self.mtrs.lock().unwrap();
+        let mtr = mtrs
+            .get_mut(&thread::current().id())
+            .expect("no mini-transaction in progress");
+        mtr.num_compensated += 1;
+    }
+
+    // ログを永続化して、ここまでの変更がクラッシュしても失われないようにする
+    pub fn flush_wal(&self) -> Result<(), Error> {
+        self.disk().flush_wal()?;
+        Ok(())
     }
 
     // ディスクの更新
-    pub fn flush(&mut self) -> Result<(), Error> {
-        for (&page_id, &buffer_id) in self.page_table.iter() {
-            let frame = &self.pool[buffer_id];
-            let mut page = frame.buffer.page.borrow_mut();
-            self.disk.write_page_data(page_id, page.as_mut())?;
-            frame.buffer.is_dirty.set(false);
-        }
-        self.disk.sync()?;
+    // 書き出している間は、他のスレッドのコミットを止めておく
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut disk = self.disk();
+        // ミニトランザクションの途中のページがあれば、ログを空にできない
+        let mut skipped = false;
+        for frame in self.pool.buffers.iter() {
+            // ロックの順序が逆になるので、待たずに諦める
+            let buffer = match frame.buffer.try_lock() {
+                Ok(buffer) => buffer.clone(),
+                Err(_) => {
+                    skipped = true;
+                    continue;
+                }
+            };
+            if !buffer.is_dirty.load(Ordering::Acquire) {
+                continue;
+            }
+            // まだログに残していない変更は書き出さない
+            if !buffer.latch.try_lock_shared_strict() {
+                skipped = true;
+                continue;
+            }
+            let result = disk.write_page_data(buffer.page_id, buffer.page.read().unwrap().as_ref());
+            if result.is_ok() {
+                buffer.is_dirty.store(false, Ordering::Release);
+                Counters::increment(&self.counters.dirty_write_backs);
+            }
+            buffer.latch.unlock(latch::Mode::Shared);
+            result?;
+        }
+        if skipped {
+            disk.sync()?;
+        } else {
+            disk.checkpoint()?;
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
     use super::*;
-    use tempfile::tempfile;
+    use tempfile::{tempfile, NamedTempFile};
 
     #[test]
     fn test() {
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
+        let mut hello = vec![0; PAGE_HEADER_SIZE];
         hello.extend_from_slice(b"hello");
         hello.resize(PAGE_SIZE, 0);
-        let mut world = Vec::with_capacity(PAGE_SIZE);
+        let mut world = vec![0; PAGE_HEADER_SIZE];
         world.extend_from_slice(b"world");
         world.resize(PAGE_SIZE, 0);
 
         let disk = DiskManager::new(tempfile().unwrap()).unwrap();
         let pool = BufferPool::new(1);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let bufmgr = BufferPoolManager::new(disk, pool);
 
         // BufferPoolManagerを利用したページの作成
         let page1_id = {
             let buffer = bufmgr.create_page().unwrap();
             assert!(bufmgr.create_page().is_err());
-            let mut page = buffer.page.borrow_mut();
+            let mut page = buffer.page.write().unwrap();
             page.copy_from_slice(&hello);
-            buffer.is_dirty.set(true);
+            buffer.is_dirty.store(true, Ordering::Release);
             buffer.page_id
         };
         {
             let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&hello, page.as_ref());
+            let page = buffer.page.read().unwrap();
+            assert_eq!(hello[PAGE_HEADER_SIZE..], page[PAGE_HEADER_SIZE..]);
         }
         let page2_id = {
             let buffer = bufmgr.create_page().unwrap();
-            let mut page = buffer.page.borrow_mut();
+            let mut page = buffer.page.write().unwrap();
             page.copy_from_slice(&world);
-            buffer.is_dirty.set(true);
+            buffer.is_dirty.store(true, Ordering::Release);
             buffer.page_id
         };
         {
             let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&hello, page.as_ref());
+            let page = buffer.page.read().unwrap();
+            assert_eq!(hello[PAGE_HEADER_SIZE..], page[PAGE_HEADER_SIZE..]);
         }
         {
             let buffer = bufmgr.fetch_page(page2_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&world, page.as_ref());
+            let page = buffer.page.read().unwrap();
+            assert_eq!(world[PAGE_HEADER_SIZE..], page[PAGE_HEADER_SIZE..]);
+        }
+    }
+
+    #[test]
+    fn test_abort_mtr() {
+        let disk = DiskManager::with_wal(tempfile().unwrap(), tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let page_id = bufmgr
+            .with_mtr(|bufmgr| -> Result<PageId, Error> {
+                let buffer = bufmgr.create_page()?;
+                buffer.body_mut()[..5].copy_from_slice(b"hello");
+                Ok(buffer.page_id)
+            })
+            .unwrap();
+        let page_lsn = |bufmgr: &BufferPoolManager| {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            let page = buffer.page.read().unwrap();
+            disk::page_lsn(page.as_ref())
+        };
+        let lsn = page_lsn(&bufmgr);
+
+        // 変更の途中で失敗したら、ページを元に戻してログには何も残さない
+        let mut new_page_id = None;
+        let result = bufmgr.with_mtr(|bufmgr| -> Result<(), Error> {
+            let latch = bufmgr.fetch_page_exclusive(page_id)?;
+            latch.body_mut()[..5].copy_from_slice(b"world");
+            bufmgr.mark_dirty(&latch);
+            drop(latch);
+            // 内側のミニトランザクションが成功しても、外側が失敗すれば取り消す
+            bufmgr.with_mtr(|bufmgr| -> Result<(), Error> {
+                new_page_id = Some(bufmgr.create_page()?.page_id);
+                Ok(())
+            })?;
+            bufmgr.delete_page(page_id)?;
+            Err(Error::NoFreeBuffer)
+        });
+        assert!(matches!(result, Err(Error::NoFreeBuffer)));
+        assert_eq!(lsn, page_lsn(&bufmgr));
+        let latch = bufmgr.fetch_page_exclusive(page_id).unwrap();
+        assert_eq!(b"hello", &latch.body()[..5]);
+        drop(latch);
+        // 解放するはずだったページは残り、新しく作ったページは解放されて使い回される
+        let buffer = bufmgr.create_page().unwrap();
+        assert_eq!(new_page_id, Some(buffer.page_id));
+        assert_ne!(page_id, buffer.page_id);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let (file, path) = NamedTempFile::new().unwrap().into_parts();
+        let disk = DiskManager::new(file).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap()
+        };
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.body_mut()[..5].copy_from_slice(b"hello");
+                buffer.page_id
+            })
+            .collect();
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        // 1つ目のページは1バイトだけ、2つ目のページは後半だけが書き換わったことにする
+        let mut file = open();
+        let offset =
+            |page_id: PageId, pos: usize| (page_id.to_u64() as usize * PAGE_SIZE + pos) as u64;
+        file.seek(SeekFrom::Start(offset(page_ids[0], PAGE_HEADER_SIZE)))
+            .unwrap();
+        file.write_all(b"j").unwrap();
+        file.seek(SeekFrom::Start(offset(page_ids[1], PAGE_SIZE / 2)))
+            .unwrap();
+        file.write_all(&[0xAB; PAGE_SIZE / 2]).unwrap();
+        drop(file);
+
+        let disk = DiskManager::new(open()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        for page_id in page_ids {
+            assert!(matches!(
+                bufmgr.fetch_page(page_id),
+                Err(Error::Corrupted { page_id: id }) if id == page_id
+            ));
         }
+        // 一度も書き出されずに 0 のままのページは読める
+        let file = open();
+        let page_id = PageId(file.metadata().unwrap().len() / PAGE_SIZE as u64);
+        file.set_len(offset(page_id, PAGE_SIZE)).unwrap();
+        assert!(bufmgr.fetch_page(page_id).is_ok());
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        let is_resident = |bufmgr: &BufferPoolManager, page_id: PageId| {
+            let buffer_id = bufmgr.page_table(page_id).get(&page_id).copied();
+            buffer_id.is_some_and(|buffer_id| {
+                bufmgr.pool[buffer_id].buffer.lock().unwrap().page_id == page_id
+            })
+        };
+        // ページ 1 から 4 を何度も読みながら他のページも読み、最後にまだ読んでいないページを一度ずつ読む
+        let run = |pool: BufferPool| {
+            let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, pool);
+            let page_ids: Vec<_> = (0..64)
+                .map(|_| bufmgr.create_page().unwrap().page_id)
+                .collect();
+            let (hot, cold) = page_ids.split_at(4);
+            for round in 0..3 {
+                for page_id in hot.iter().chain(hot) {
+                    bufmgr.fetch_page(*page_id).unwrap();
+                }
+                for page_id in &cold[round * 8..(round + 1) * 8] {
+                    bufmgr.fetch_page(*page_id).unwrap();
+                }
+            }
+            for page_id in &cold[24..] {
+                bufmgr.fetch_page(*page_id).unwrap();
+            }
+            hot.iter()
+                .filter(|page_id| is_resident(&bufmgr, **page_id))
+                .count()
+        };
+        assert!(run(BufferPool::new(8)) < 4);
+        assert_eq!(4, run(BufferPool::with_policy(8, LruK::new(2))));
+        assert_eq!(4, run(BufferPool::with_policy(8, TwoQ::default())));
+    }
+
+    #[test]
+    fn test_stats() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        // 3 つ目のページを作る時に、変更された 1 つ目のページを書き出して追い出す
+        let stats = bufmgr.stats();
+        assert_eq!(
+            (0, 0, 1, 1),
+            (
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.dirty_write_backs
+            )
+        );
+
+        let page3 = bufmgr.fetch_page(page_ids[2]).unwrap();
+        let page1 = bufmgr.fetch_page(page_ids[0]).unwrap();
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[1]),
+            Err(Error::NoFreeBuffer)
+        ));
+        let stats = bufmgr.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(2, stats.misses);
+        assert_eq!(2, stats.evictions);
+        assert_eq!(1, stats.no_free_buffer_failures);
+        assert_eq!(2, stats.pinned_frames);
+
+        let mut frames = bufmgr.frames();
+        frames.sort_by_key(|frame| frame.page_id.map(PageId::to_u64));
+        let pages: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.page_id, frame.pin_count))
+            .collect();
+        assert_eq!(
+            vec![(Some(page1.page_id), 1), (Some(page3.page_id), 1)],
+            pages
+        );
+        drop((page1, page3));
+
+        bufmgr.reset_stats();
+        bufmgr.fetch_page(page_ids[1]).unwrap();
+        let stats = bufmgr.stats();
+        assert_eq!(
+            (0, 1, 1, 0),
+            (
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.pinned_frames
+            )
+        );
     }
 }
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::BufferId;
+use crate::disk::PageId;
+
+// バッファプールが満杯の時に、どのフレームのページを追い出すかを決める
+// フレームのロックを持ったまま呼ばれることはないので、内部でロックを取ってよい
+pub trait ReplacementPolicy: Send + Sync {
+    // バッファプールにあったページを貸し出した
+    fn record_access(&self, buffer_id: BufferId);
+    // 追い出したフレームに新しいページを読み込んだ
+    fn record_load(&self, buffer_id: BufferId, page_id: PageId);
+    // ページを解放して、フレームが空いた
+    fn record_free(&self, buffer_id: BufferId);
+    // 追い出すフレームを選ぶ。try_evict は貸し出し中のフレームなら false を返す
+    // 最後に true を返したフレームを返さなければならない
+    fn evict(
+        &self,
+        num_frames: usize,
+        try_evict: &mut dyn FnMut(BufferId) -> bool,
+    ) -> Option<BufferId>;
+}
+
+// フレームの番号で引けるように、足りなければ伸ばす
+fn frame_mut<T: Default>(frames: &mut Vec<T>, buffer_id: BufferId) -> &mut T {
+    if frames.len() <= buffer_id.0 {
+        frames.resize_with(buffer_id.0 + 1, Default::default);
+    }
+    &mut frames[buffer_id.0]
+}
+
+#[derive(Default)]
+struct Clock {
+    next_victim_id: BufferId,
+    usage_counts: Vec<u64>,
+}
+
+// Clock-sweep。針が指すフレームの利用回数を減らしていき、0 になったものを追い出す
+#[derive(Default)]
+pub struct ClockSweep {
+    clock: Mutex<Clock>,
+}
+
+impl ReplacementPolicy for ClockSweep {
+    fn record_access(&self, buffer_id: BufferId) {
+        let mut clock = self.clock.lock().unwrap();
+        *frame_mut(&mut clock.usage_counts, buffer_id) += 1;
+    }
+
+    fn record_load(&self, buffer_id: BufferId, _page_id: PageId) {
+        let mut clock = self.clock.lock().unwrap();
+        *frame_mut(&mut clock.usage_counts, buffer_id) = 1;
+    }
+
+    fn record_free(&self, buffer_id: BufferId) {
+        let mut clock = self.clock.lock().unwrap();
+        *frame_mut(&mut clock.usage_counts, buffer_id) = 0;
+    }
+
+    fn evict(
+        &self,
+        num_frames: usize,
+        try_evict: &mut dyn FnMut(BufferId) -> bool,
+    ) -> Option<BufferId> {
+        let mut clock = self.clock.lock().unwrap();
+        // 参照されているバッファの数をカウント
+        let mut consecutive_pinned = 0;
+        loop {
+            let buffer_id = clock.next_victim_id;
+            // bufferが参照されているか
+            if try_evict(buffer_id) {
+                // bufferの利用回数が0になるものを探す
+                let usage_count = frame_mut(&mut clock.usage_counts, buffer_id);
+                if *usage_count == 0 {
+                    return Some(buffer_id);
+                }
+                // されていない場合
+                *usage_count -= 1;
+                consecutive_pinned = 0;
+            } else {
+                // 参照されている場合
+                consecutive_pinned += 1;
+                if consecutive_pinned >= num_frames {
+                    return None;
+                }
+            }
+            clock.next_victim_id = BufferId((buffer_id.0 + 1) % num_frames);
+        }
+    }
+}
+
+#[derive(Default)]
+struct History {
+    // 参照した時刻。新しいものから最大 k 個
+    times: VecDeque<u64>,
+}
+
+struct LruKState {
+    now: u64,
+    histories: Vec<History>,
+}
+
+// LRU-K。k 回前に参照された時刻が最も古いページを追い出す
+// 参照が k 回に満たないページはそれより先に追い出すので、一度しか読まないページが
+// 何度も参照されるページを押し出さない
+pub struct LruK {
+    k: usize,
+    state: Mutex<LruKState>,
+}
+
+impl LruK {
+    pub fn new(k: usize) -> Self {
+        assert!(k > 0, "k must be positive");
+        Self {
+            k,
+            state: Mutex::new(LruKState {
+                now: 0,
+                histories: vec![],
+            }),
+        }
+    }
+}
+
+impl LruKState {
+    fn access(&mut self, k: usize, buffer_id: BufferId) {
+        self.now += 1;
+        let now = self.now;
+        let history = frame_mut(&mut self.histories, buffer_id);
+        history.times.push_front(now);
+        history.times.truncate(k);
+    }
+}
+
+impl ReplacementPolicy for LruK {
+    fn record_access(&self, buffer_id: BufferId) {
+        self.state.lock().unwrap().access(self.k, buffer_id);
+    }
+
+    // 追い出したページの履歴は引き継がない
+    fn record_load(&self, buffer_id: BufferId, _page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        frame_mut(&mut state.histories, buffer_id).times.clear();
+        state.access(self.k, buffer_id);
+    }
+
+    fn record_free(&self, buffer_id: BufferId) {
+        let mut state = self.state.lock().unwrap();
+        frame_mut(&mut state.histories, buffer_id).times.clear();
+    }
+
+    fn evict(
+        &self,
+        num_frames: usize,
+        try_evict: &mut dyn FnMut(BufferId) -> bool,
+    ) -> Option<BufferId> {
+        let mut state = self.state.lock().unwrap();
+        // 空いているフレーム、参照が k 回に満たないフレーム、それ以外の順に、古いものから試す
+        let mut candidates: Vec<_> = (0..num_frames)
+            .map(|i| {
+                let times = &frame_mut(&mut state.histories, BufferId(i)).times;
+                let key = match times.len() {
+                    0 => (0, 0),
+                    len if len < self.k => (1, times[0]),
+                    _ => (2, times[self.k - 1]),
+                };
+                (key, BufferId(i))
+            })
+            .collect();
+        candidates.sort_by_key(|(key, _)| *key);
+        candidates
+            .into_iter()
+            .map(|(_, buffer_id)| buffer_id)
+            .find(|&buffer_id| try_evict(buffer_id))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    A1in,
+    Am,
+}
+
+#[derive(Default)]
+struct TwoQState {
+    num_frames: usize,
+    // フレームに入っているページと、そのフレームがどちらのキューにいるか
+    frames: Vec<Option<(PageId, Queue)>>,
+    // 初めて読んだページの FIFO
+    a1in: VecDeque<BufferId>,
+    // A1in から追い出したページのID。ここにあるページをもう一度読むと Am に入れる
+    a1out: VecDeque<PageId>,
+    // 何度も参照されるページの LRU。末尾が最近使ったもの
+    am: VecDeque<BufferId>,
+}
+
+impl TwoQState {
+    fn remove(&mut self, buffer_id: BufferId) -> Option<(PageId, Queue)> {
+        let (page_id, queue) = frame_mut(&mut self.frames, buffer_id).take()?;
+        let list = match queue {
+            Queue::A1in => &mut self.a1in,
+            Queue::Am => &mut self.am,
+        };
+        if let Some(pos) = list.iter().position(|&id| id == buffer_id) {
+            list.remove(pos);
+        }
+        Some((page_id, queue))
+    }
+}
+
+// 2Q。初めて読んだページは A1in に入れ、A1in から追い出された後でもう一度読まれたページだけを Am に入れる
+// A1in が Kin を超えている間は A1in から追い出すので、一度きりのスキャンは Am のページを押し出さない
+#[derive(Default)]
+pub struct TwoQ {
+    state: Mutex<TwoQState>,
+}
+
+impl TwoQ {
+    // Kin はフレーム数の 1/4、Kout は 1/2 にする
+    fn kin(num_frames: usize) -> usize {
+        (num_frames / 4).max(1)
+    }
+
+    fn kout(num_frames: usize) -> usize {
+        (num_frames / 2).max(1)
+    }
+}
+
+impl ReplacementPolicy for TwoQ {
+    fn record_access(&self, buffer_id: BufferId) {
+        let mut state = self.state.lock().unwrap();
+        // A1in にいる間の参照は数えない
+        if let Some((_, Queue::Am)) = *frame_mut(&mut state.frames, buffer_id) {
+            if let Some(pos) = state.am.iter().position(|&id| id == buffer_id) {
+                state.am.remove(pos);
+            }
+            state.am.push_back(buffer_id);
+        }
+    }
+
+    fn record_load(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        state.remove(buffer_id);
+        let queue = match state.a1out.iter().position(|&id| id == page_id) {
+            Some(pos) => {
+                state.a1out.remove(pos);
+                state.am.push_back(buffer_id);
+                Queue::Am
+            }
+            None => {
+                state.a1in.push_back(buffer_id);
+                Queue::A1in
+            }
+        };
+        *frame_mut(&mut state.frames, buffer_id) = Some((page_id, queue));
+        // 読み込むページを探し終わってから A1out を縮める
+        while state.a1out.len() > Self::kout(state.num_frames) {
+            state.a1out.pop_front();
+        }
+    }
+
+    fn record_free(&self, buffer_id: BufferId) {
+        self.state.lock().unwrap().remove(buffer_id);
+    }
+
+    fn evict(
+        &self,
+        num_frames: usize,
+        try_evict: &mut dyn FnMut(BufferId) -> bool,
+    ) -> Option<BufferId> {
+        let mut state = self.state.lock().unwrap();
+        // 空いているフレームを先に使う
+        let mut candidates: Vec<_> = (0..num_frames)
+            .map(BufferId)
+            .filter(|&buffer_id| frame_mut(&mut state.frames, buffer_id).is_none())
+            .collect();
+        let (first, second) = if state.a1in.len() > Self::kin(num_frames) {
+            (&state.a1in, &state.am)
+        } else {
+            (&state.am, &state.a1in)
+        };
+        candidates.extend(first.iter().chain(second));
+        let buffer_id = candidates
+            .into_iter()
+            .find(|&buffer_id| try_evict(buffer_id))?;
+        if let Some((page_id, Queue::A1in)) = state.remove(buffer_id) {
+            state.a1out.push_back(page_id);
+        }
+        state.num_frames = num_frames;
+        Some(buffer_id)
+    }
+}
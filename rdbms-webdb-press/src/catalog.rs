@@ -0,0 +1,879 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::btree::{self, BTree, SearchMode};
+use crate::buffer::BufferPoolManager;
+use crate::disk::PageId;
+use crate::hash_index::HashIndex;
+use crate::mvcc::{self, Snapshot, Version, VersionStore};
+use crate::stats::{self, TableStats};
+use crate::table::{NonUniqueIndex, Table, UniqueHashIndex, UniqueIndex};
+use crate::tuple;
+use crate::value::{DataType, Value};
+
+mod legacy;
+
+// カタログの B+Tree のメタページ
+// 空のヒープファイルで最初に作る B+Tree なので、ヘッダーページの次のページになる
+pub const CATALOG_META_PAGE_ID: PageId = PageId(1);
+
+// TableSchema の前に置き、後に続く版の形式で書かれていることを示す
+const FORMAT_TAG: u8 = 0xff;
+// TableSchema の形を変えたら上げて、古い版も読めるようにする
+const FORMAT_VERSION: u8 = 5;
+
+// コミットログの B+Tree のメタページIDを入れておくキー。テーブル名には使えない
+const COMMIT_LOG_KEY: &[u8] = b"";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("catalog must be created in an empty database")]
+    NotEmpty,
+    #[error("table {0:?} does not exist")]
+    TableNotFound(String),
+    #[error("table {0:?} already exists")]
+    TableExists(String),
+    #[error("index {0:?} already exists")]
+    IndexExists(String),
+    #[error("column {0:?} does not exist")]
+    ColumnNotFound(String),
+    #[error("{0}")]
+    InvalidSchema(String),
+    #[error("column {column:?} is of type {data_type} but got {value}")]
+    TypeMismatch {
+        column: String,
+        data_type: DataType,
+        value: Value,
+    },
+    #[error("primary key column {0:?} must not be null")]
+    NullPrimaryKey(String),
+    #[error("unsupported catalog format version {0}")]
+    UnsupportedFormat(u8),
+    #[error("catalog entry is corrupted")]
+    Corrupted,
+    #[error("table {0:?} has versioned rows and must be read through a snapshot")]
+    SnapshotRequired(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+// カタログに記録するテーブルの定義
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableSchema {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub table: Table,
+    // table.unique_indices と同じ順に並ぶインデックスの名前
+    pub index_names: Vec<String>,
+    // table.non_unique_indices と同じ順に並ぶインデックスの名前
+    pub non_unique_index_names: Vec<String>,
+    // 行とインデックスのエントリに mvcc の版を付けて書くテーブル
+    pub versioned: bool,
+    // 最後に ANALYZE した時の統計情報。その後の変更は反映しない
+    pub stats: Option<TableStats>,
+    // table.hash_indices と同じ順に並ぶインデックスの名前
+    pub hash_index_names: Vec<String>,
+}
+
+impl TableSchema {
+    pub fn column_index(&self, name: &str) -> Result<usize, Error> {
+        self.columns
+            .iter()
+            .position(|column| column.name == name)
+            .ok_or_else(|| Error::ColumnNotFound(name.to_string()))
+    }
+
+    pub fn column_indices(&self, names: &[impl AsRef<str>]) -> Result<Vec<usize>, Error> {
+        names
+            .iter()
+            .map(|name| self.column_index(name.as_ref()))
+            .collect()
+    }
+
+    // 行の値をそれぞれの列の型に合わせる。主キーに NULL は入れられない
+    pub fn check_record(&self, record: Vec<Value>) -> Result<Vec<Value>, Error> {
+        if record.len() != self.columns.len() {
+            return Err(Error::InvalidSchema(format!(
+                "expected {} values, got {}",
+                self.columns.len(),
+                record.len()
+            )));
+        }
+        record
+            .into_iter()
+            .zip(&self.columns)
+            .enumerate()
+            .map(|(i, (value, column))| {
+                if i < self.table.num_key_elems && value.is_null() {
+                    return Err(Error::NullPrimaryKey(column.name.clone()));
+                }
+                value
+                    .clone()
+                    .cast(column.data_type)
+                    .ok_or_else(|| Error::TypeMismatch {
+                        column: column.name.clone(),
+                        data_type: column.data_type,
+                        value,
+                    })
+            })
+            .collect()
+    }
+
+    // 版を付けたテーブルはスナップショットで読み、それ以外は値をそのまま読む
+    pub fn snapshot<'a>(
+        &self,
+        snapshot: Option<&'a Snapshot>,
+    ) -> Result<Option<&'a Snapshot>, Error> {
+        match (self.versioned, snapshot) {
+            (true, None) => Err(Error::SnapshotRequired(self.name.clone())),
+            (true, snapshot) => Ok(snapshot),
+            (false, _) => Ok(None),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![FORMAT_TAG, FORMAT_VERSION];
+        bincode::options().serialize_into(&mut bytes, self).unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [FORMAT_TAG, FORMAT_VERSION, body @ ..] => Ok(bincode::options().deserialize(body)?),
+            [FORMAT_TAG, 4, body @ ..] => Ok(legacy::decode_v4(body)?),
+            [FORMAT_TAG, 3, body @ ..] => Ok(legacy::decode_v3(body)?),
+            [FORMAT_TAG, 2, body @ ..] => Ok(legacy::decode_v2(body)?),
+            [FORMAT_TAG, 1, body @ ..] => Ok(legacy::decode_v1(body)?),
+            [FORMAT_TAG, version, ..] => Err(Error::UnsupportedFormat(*version).into()),
+            _ => Err(Error::Corrupted.into()),
+        }
+    }
+}
+
+// テーブル名をキー、TableSchema を値とする B+Tree
+pub struct Catalog {
+    btree: BTree,
+    // TableSchema を読んで書き戻す間に、他のスレッドの変更を上書きしないように1つずつ行う
+    ddl_lock: Mutex<()>,
+}
+
+impl Catalog {
+    // 空のヒープファイルにカタログを作る
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self> {
+        let btree = BTree::create(bufmgr)?;
+        if btree.meta_page_id != CATALOG_META_PAGE_ID {
+            btree.destroy(bufmgr)?;
+            return Err(Error::NotEmpty.into());
+        }
+        Ok(Self::new(btree))
+    }
+
+    // Catalog::create したヒープファイルのカタログを開く
+    pub fn open() -> Self {
+        Self::new(BTree::new(CATALOG_META_PAGE_ID))
+    }
+
+    fn new(btree: BTree) -> Self {
+        Self {
+            btree,
+            ddl_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn create_table(
+        &self,
+        bufmgr: &BufferPoolManager,
+        name: &str,
+        columns: Vec<Column>,
+        num_key_elems: usize,
+    ) -> Result<TableSchema> {
+        self.add_table(bufmgr, name, columns, num_key_elems, false)
+    }
+
+    // 行を mvcc::Txn で書き、スナップショットで読むテーブルを作る
+    pub fn create_versioned_table(
+        &self,
+        bufmgr: &BufferPoolManager,
+        name: &str,
+        columns: Vec<Column>,
+        num_key_elems: usize,
+    ) -> Result<TableSchema> {
+        self.add_table(bufmgr, name, columns, num_key_elems, true)
+    }
+
+    fn add_table(
+        &self,
+        bufmgr: &BufferPoolManager,
+        name: &str,
+        columns: Vec<Column>,
+        num_key_elems: usize,
+        versioned: bool,
+    ) -> Result<TableSchema> {
+        if name.as_bytes() == COMMIT_LOG_KEY {
+            return Err(Error::InvalidSchema("table name must not be empty".into()).into());
+        }
+        let _guard = self.ddl_lock.lock().unwrap();
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].iter().any(|c| c.name == column.name) {
+                return Err(
+                    Error::InvalidSchema(format!("duplicate column {:?}", column.name)).into(),
+                );
+            }
+        }
+        if num_key_elems == 0 || num_key_elems > columns.len() {
+            return Err(Error::InvalidSchema("primary key is required".into()).into());
+        }
+        if self.fetch(bufmgr, name)?.is_some() {
+            return Err(Error::TableExists(name.to_string()).into());
+        }
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(bufmgr)?;
+        let schema = TableSchema {
+            name: name.to_string(),
+            columns,
+            table,
+            index_names: vec![],
+            non_unique_index_names: vec![],
+            versioned,
+            stats: None,
+            hash_index_names: vec![],
+        };
+        self.btree
+            .insert(bufmgr, name.as_bytes(), &schema.to_bytes())?;
+        Ok(schema)
+    }
+
+    // ユニークインデックスを作り、既にある行を入れる
+    // 版を付けたテーブルなら、snapshot を取った時点で取り消されていた版は入れない
+    pub fn create_index(
+        &self,
+        bufmgr: &BufferPoolManager,
+        table_name: &str,
+        index_name: &str,
+        columns: &[impl AsRef<str>],
+        snapshot: Option<&Snapshot>,
+    ) -> Result<TableSchema> {
+        let _guard = self.ddl_lock.lock().unwrap();
+        self.check_index_name(bufmgr, index_name)?;
+        let mut schema = self.table_schema(bufmgr, table_name)?;
+        let snapshot = schema.snapshot(snapshot)?;
+        let mut unique_index = UniqueIndex {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            skey: schema.column_indices(columns)?,
+        };
+        unique_index.create(bufmgr)?;
+
+        // 重複していたら作りかけのインデックスを捨てる
+        let result = fill_index(
+            bufmgr,
+            &schema.table,
+            &BTree::new(unique_index.meta_page_id),
+            snapshot,
+            true,
+            |_, record| unique_index.encode_skey(record),
+        );
+        if let Err(err) = result {
+            unique_index.destroy(bufmgr)?;
+            return Err(err);
+        }
+        schema.table.unique_indices.push(unique_index);
+        schema.index_names.push(index_name.to_string());
+        self.btree
+            .update(bufmgr, table_name.as_bytes(), &schema.to_bytes())?;
+        Ok(schema)
+    }
+
+    // 一意でないインデックスを作り、既にある行を入れる
+    pub fn create_non_unique_index(
+        &self,
+        bufmgr: &BufferPoolManager,
+        table_name: &str,
+        index_name: &str,
+        columns: &[impl AsRef<str>],
+        snapshot: Option<&Snapshot>,
+    ) -> Result<TableSchema> {
+        let _guard = self.ddl_lock.lock().unwrap();
+        self.check_index_name(bufmgr, index_name)?;
+        let mut schema = self.table_schema(bufmgr, table_name)?;
+        let snapshot = schema.snapshot(snapshot)?;
+        let mut non_unique_index = NonUniqueIndex {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            skey: schema.column_indices(columns)?,
+        };
+        non_unique_index.create(bufmgr)?;
+
+        let result = fill_index(
+            bufmgr,
+            &schema.table,
+            &BTree::new(non_unique_index.meta_page_id),
+            snapshot,
+            false,
+            |pkey, record| non_unique_index.encode_key(pkey, record),
+        );
+        if let Err(err) = result {
+            non_unique_index.destroy(bufmgr)?;
+            return Err(err);
+        }
+        schema.table.non_unique_indices.push(non_unique_index);
+        schema.non_unique_index_names.push(index_name.to_string());
+        self.btree
+            .update(bufmgr, table_name.as_bytes(), &schema.to_bytes())?;
+        Ok(schema)
+    }
+
+    // ハッシュインデックスによるユニークインデックスを作り、既にある行を入れる
+    pub fn create_hash_index(
+        &self,
+        bufmgr: &BufferPoolManager,
+        table_name: &str,
+        index_name: &str,
+        columns: &[impl AsRef<str>],
+        snapshot: Option<&Snapshot>,
+    ) -> Result<TableSchema> {
+        let _guard = self.ddl_lock.lock().unwrap();
+        self.check_index_name(bufmgr, index_name)?;
+        let mut schema = self.table_schema(bufmgr, table_name)?;
+        let snapshot = schema.snapshot(snapshot)?;
+        let mut hash_index = UniqueHashIndex {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            skey: schema.column_indices(columns)?,
+        };
+        hash_index.create(bufmgr)?;
+
+        // 重複していたら作りかけのインデックスを捨てる
+        let result = fill_index(
+            bufmgr,
+            &schema.table,
+            &HashIndex::new(hash_index.meta_page_id),
+            snapshot,
+            true,
+            |_, record| hash_index.encode_skey(record),
+        );
+        if let Err(err) = result {
+            hash_index.destroy(bufmgr)?;
+            return Err(err);
+        }
+        schema.table.hash_indices.push(hash_index);
+        schema.hash_index_names.push(index_name.to_string());
+        self.btree
+            .update(bufmgr, table_name.as_bytes(), &schema.to_bytes())?;
+        Ok(schema)
+    }
+
+    // テーブルの統計情報を集め直して保存する
+    // 版を付けたテーブルなら、snapshot から見える行で集める
+    pub fn analyze(
+        &self,
+        bufmgr: &BufferPoolManager,
+        table_name: &str,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<TableSchema> {
+        let _guard = self.ddl_lock.lock().unwrap();
+        let mut schema = self.table_schema(bufmgr, table_name)?;
+        let snapshot = schema.snapshot(snapshot)?;
+        schema.stats = Some(stats::analyze(
+            bufmgr,
+            &schema.table,
+            schema.columns.len(),
+            snapshot,
+        )?);
+        self.btree
+            .update(bufmgr, table_name.as_bytes(), &schema.to_bytes())?;
+        Ok(schema)
+    }
+
+    // インデックスの名前はテーブルをまたいで一意にする
+    fn check_index_name(&self, bufmgr: &BufferPoolManager, index_name: &str) -> Result<()> {
+        for schema in self.tables(bufmgr)? {
+            let mut names = schema
+                .index_names
+                .iter()
+                .chain(&schema.non_unique_index_names)
+                .chain(&schema.hash_index_names);
+            if names.any(|name| name == index_name) {
+                return Err(Error::IndexExists(index_name.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn table_schema(&self, bufmgr: &BufferPoolManager, name: &str) -> Result<TableSchema> {
+        self.fetch(bufmgr, name)?
+            .ok_or_else(|| Error::TableNotFound(name.to_string()).into())
+    }
+
+    pub fn open_table(&self, bufmgr: &BufferPoolManager, name: &str) -> Result<Table> {
+        Ok(self.table_schema(bufmgr, name)?.table)
+    }
+
+    // 全てのテーブルを名前の順に返す
+    pub fn tables(&self, bufmgr: &BufferPoolManager) -> Result<Vec<TableSchema>> {
+        let mut iter = self.btree.search(bufmgr, SearchMode::Start)?;
+        let mut schemas = vec![];
+        while let Some((key, value)) = iter.next(bufmgr)? {
+            if key != COMMIT_LOG_KEY {
+                schemas.push(TableSchema::from_bytes(&value)?);
+            }
+        }
+        Ok(schemas)
+    }
+
+    // 版を付けたテーブルのトランザクションを記録する、コミットログのメタページID
+    pub fn commit_log(&self, bufmgr: &BufferPoolManager) -> Result<Option<PageId>> {
+        let mut iter = self
+            .btree
+            .search(bufmgr, SearchMode::Key(COMMIT_LOG_KEY.to_vec()))?;
+        match iter.next(bufmgr)? {
+            Some((key, value)) if key == COMMIT_LOG_KEY => {
+                let bytes = value[..].try_into().map_err(|_| Error::Corrupted)?;
+                Ok(Some(PageId(u64::from_be_bytes(bytes))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set_commit_log(&self, bufmgr: &BufferPoolManager, meta_page_id: PageId) -> Result<()> {
+        let _guard = self.ddl_lock.lock().unwrap();
+        self.btree
+            .upsert(bufmgr, COMMIT_LOG_KEY, &meta_page_id.to_u64().to_be_bytes())?;
+        Ok(())
+    }
+
+    fn fetch(&self, bufmgr: &BufferPoolManager, name: &str) -> Result<Option<TableSchema>> {
+        let key = name.as_bytes();
+        if key == COMMIT_LOG_KEY {
+            return Ok(None);
+        }
+        let mut iter = self.btree.search(bufmgr, SearchMode::Key(key.to_vec()))?;
+        match iter.next(bufmgr)? {
+            Some((name_bytes, value)) if name_bytes == key => {
+                Ok(Some(TableSchema::from_bytes(&value)?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// 既にある行のエントリをインデックスに入れる。encode_key は主キーと行からインデックスのキーを作る
+// 版を付けたテーブルなら、取り消されていない行の版ごとに、同じ範囲のスナップショットから見えるエントリの版を作る
+fn fill_index(
+    bufmgr: &BufferPoolManager,
+    table: &Table,
+    index: &dyn VersionStore,
+    snapshot: Option<&Snapshot>,
+    unique: bool,
+    encode_key: impl Fn(&[u8], &[Value]) -> Vec<u8>,
+) -> Result<()> {
+    let btree = BTree::new(table.meta_page_id);
+    let mut iter = btree.search(bufmgr, SearchMode::Start)?;
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => {
+            while let Some((pkey_bytes, tuple_bytes)) = iter.next(bufmgr)? {
+                let record = decode_record(&pkey_bytes, &tuple_bytes);
+                index.insert(bufmgr, &encode_key(&pkey_bytes, &record), &pkey_bytes)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let mut entries: BTreeMap<Vec<u8>, Vec<Version>> = BTreeMap::new();
+    while let Some((pkey_bytes, value)) = iter.next(bufmgr)? {
+        for version in mvcc::decode_versions(&value)? {
+            if snapshot.is_aborted(version.xmin) {
+                continue;
+            }
+            let record = decode_record(&pkey_bytes, &version.data);
+            entries
+                .entry(encode_key(&pkey_bytes, &record))
+                .or_default()
+                .push(Version {
+                    xmin: version.xmin,
+                    xmax: version.xmax.filter(|&xmax| !snapshot.is_aborted(xmax)),
+                    data: pkey_bytes.clone(),
+                });
+        }
+    }
+    for (key, mut versions) in entries {
+        // 削除されていない版が 2 つあれば、セカンダリキーが重複している
+        if unique
+            && versions
+                .iter()
+                .filter(|version| version.xmax.is_none())
+                .count()
+                > 1
+        {
+            return Err(btree::Error::DuplicateKey.into());
+        }
+        versions.sort_by_key(|version| std::cmp::Reverse(version.xmin));
+        index.insert(bufmgr, &key, &mvcc::encode_versions(&versions))?;
+    }
+    Ok(())
+}
+
+fn decode_record(pkey_bytes: &[u8], tuple_bytes: &[u8]) -> Vec<Value> {
+    let mut record = vec![];
+    tuple::decode(pkey_bytes, &mut record);
+    tuple::decode(tuple_bytes, &mut record);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempfile, NamedTempFile};
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+    use crate::hash_index::HashIndex;
+
+    fn text(name: &str) -> Column {
+        Column {
+            name: name.into(),
+            data_type: DataType::Text,
+        }
+    }
+
+    fn row(elems: &[&str]) -> Vec<Value> {
+        elems.iter().map(|&elem| elem.into()).collect()
+    }
+
+    #[test]
+    fn test_reopen() {
+        let file = NamedTempFile::new().unwrap();
+        {
+            let disk = DiskManager::open(file.path()).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+            let catalog = Catalog::create(&bufmgr).unwrap();
+            let columns = vec![text("id"), text("first_name"), text("last_name")];
+            let schema = catalog
+                .create_table(&bufmgr, "users", columns.clone(), 1)
+                .unwrap();
+            schema
+                .table
+                .insert(&bufmgr, &row(&["z", "Alice", "Smith"]))
+                .unwrap();
+            catalog
+                .create_index(&bufmgr, "users", "users_last_name", &["last_name"], None)
+                .unwrap();
+            assert!(matches!(
+                catalog
+                    .create_table(&bufmgr, "users", columns, 1)
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::TableExists(_))
+            ));
+            assert!(matches!(
+                catalog
+                    .create_index(&bufmgr, "users", "users_last_name", &["first_name"], None)
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::IndexExists(_))
+            ));
+            catalog
+                .create_non_unique_index(
+                    &bufmgr,
+                    "users",
+                    "users_first_name",
+                    &["first_name"],
+                    None,
+                )
+                .unwrap();
+            assert!(matches!(
+                catalog
+                    .create_non_unique_index(
+                        &bufmgr,
+                        "users",
+                        "users_last_name",
+                        &["first_name"],
+                        None
+                    )
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::IndexExists(_))
+            ));
+            catalog
+                .create_hash_index(
+                    &bufmgr,
+                    "users",
+                    "users_full_name",
+                    &["first_name", "last_name"],
+                    None,
+                )
+                .unwrap();
+            assert!(matches!(
+                catalog
+                    .create_hash_index(&bufmgr, "users", "users_last_name", &["id"], None)
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::IndexExists(_))
+            ));
+            // 版を付けたテーブルには、見える行を決めるスナップショットが要る
+            catalog
+                .create_versioned_table(&bufmgr, "events", vec![text("id")], 1)
+                .unwrap();
+            assert!(matches!(
+                catalog
+                    .create_hash_index(&bufmgr, "events", "events_id", &["id"], None)
+                    .unwrap_err()
+                    .downcast_ref::<Error>(),
+                Some(Error::SnapshotRequired(_))
+            ));
+            let schema = catalog.analyze(&bufmgr, "users", None).unwrap();
+            assert_eq!(1, schema.stats.unwrap().row_count);
+            bufmgr.flush().unwrap();
+        }
+
+        let disk = DiskManager::open(file.path()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let catalog = Catalog::open();
+        let schema = catalog.table_schema(&bufmgr, "users").unwrap();
+        assert_eq!(vec!["users_last_name".to_string()], schema.index_names);
+        assert_eq!(2, schema.column_index("last_name").unwrap());
+        let table = catalog.open_table(&bufmgr, "users").unwrap();
+        assert_eq!(
+            vec!["users_first_name".to_string()],
+            schema.non_unique_index_names
+        );
+        assert_eq!(vec![2], table.unique_indices[0].skey);
+        assert_eq!(vec![1], table.non_unique_indices[0].skey);
+        assert_eq!(vec!["users_full_name".to_string()], schema.hash_index_names);
+        assert_eq!(vec![1, 2], table.hash_indices[0].skey);
+        // 統計情報もインデックスも同じ TableSchema に入っている
+        let stats = schema.stats.unwrap();
+        assert_eq!(3, stats.columns.len());
+        assert_eq!(1, stats.columns[2].distinct_count);
+        // 作る前に入っていた行もインデックスに入っている
+        assert!(table.insert(&bufmgr, &row(&["y", "Eve", "Smith"])).is_err());
+        let hash_index = &table.hash_indices[0];
+        let skey = hash_index.encode_skey(&row(&["z", "Alice", "Smith"]));
+        let pkey = HashIndex::new(hash_index.meta_page_id)
+            .lookup(&bufmgr, &skey)
+            .unwrap();
+        let mut z = vec![];
+        tuple::encode(row(&["z"]).iter(), &mut z);
+        assert_eq!(Some(z), pkey);
+        table
+            .insert(&bufmgr, &row(&["x", "Bob", "Johnson"]))
+            .unwrap();
+        table
+            .insert(&bufmgr, &row(&["w", "Alice", "Brown"]))
+            .unwrap();
+        assert!(matches!(
+            catalog
+                .open_table(&bufmgr, "posts")
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_format_version() {
+        let schema = TableSchema {
+            name: "users".into(),
+            columns: vec![text("id"), text("name")],
+            table: Table {
+                meta_page_id: PageId(2),
+                num_key_elems: 1,
+                unique_indices: vec![UniqueIndex {
+                    meta_page_id: PageId(3),
+                    skey: vec![1],
+                }],
+                non_unique_indices: vec![NonUniqueIndex {
+                    meta_page_id: PageId(4),
+                    skey: vec![1],
+                }],
+                hash_indices: vec![UniqueHashIndex {
+                    meta_page_id: PageId(5),
+                    skey: vec![0, 1],
+                }],
+            },
+            index_names: vec!["users_name".into()],
+            non_unique_index_names: vec!["users_name_dup".into()],
+            stats: None,
+            versioned: true,
+            hash_index_names: vec!["users_hash".into()],
+        };
+        let bytes = schema.to_bytes();
+        assert_eq!(&[FORMAT_TAG, FORMAT_VERSION], &bytes[..2]);
+        let decoded = TableSchema::from_bytes(&bytes).unwrap();
+        assert!(decoded.versioned);
+        assert_eq!(schema.hash_index_names, decoded.hash_index_names);
+        assert_eq!(PageId(5), decoded.table.hash_indices[0].meta_page_id);
+        assert_eq!(schema.columns, decoded.columns);
+        assert_eq!(schema.index_names, decoded.index_names);
+        assert_eq!(
+            schema.non_unique_index_names,
+            decoded.non_unique_index_names
+        );
+
+        // 版 1 のテーブルには重複を許すインデックスがない
+        let v1 = legacy::TableSchemaV1 {
+            name: "users".into(),
+            columns: schema.columns.clone(),
+            table: legacy::TableV1 {
+                meta_page_id: PageId(2),
+                num_key_elems: 1,
+                unique_indices: vec![UniqueIndex {
+                    meta_page_id: PageId(3),
+                    skey: vec![1],
+                }],
+            },
+            index_names: vec!["users_name".into()],
+        };
+        let mut bytes_v1 = vec![FORMAT_TAG, 1];
+        bincode::options()
+            .serialize_into(&mut bytes_v1, &v1)
+            .unwrap();
+        let decoded = TableSchema::from_bytes(&bytes_v1).unwrap();
+        assert_eq!(schema.index_names, decoded.index_names);
+        assert_eq!(PageId(3), decoded.table.unique_indices[0].meta_page_id);
+        assert!(decoded.table.non_unique_indices.is_empty());
+        assert!(decoded.non_unique_index_names.is_empty());
+        assert!(!decoded.versioned);
+
+        // 版 2 のテーブルには版が付いていない
+        let v2 = legacy::TableSchemaV2 {
+            name: "users".into(),
+            columns: schema.columns.clone(),
+            table: legacy::TableV2 {
+                meta_page_id: PageId(2),
+                num_key_elems: 1,
+                unique_indices: vec![],
+                non_unique_indices: vec![NonUniqueIndex {
+                    meta_page_id: PageId(4),
+                    skey: vec![1],
+                }],
+            },
+            index_names: vec![],
+            non_unique_index_names: vec!["users_name_dup".into()],
+        };
+        let mut bytes_v2 = vec![FORMAT_TAG, 2];
+        bincode::options()
+            .serialize_into(&mut bytes_v2, &v2)
+            .unwrap();
+        let decoded = TableSchema::from_bytes(&bytes_v2).unwrap();
+        assert_eq!(PageId(4), decoded.table.non_unique_indices[0].meta_page_id);
+        assert!(!decoded.versioned);
+
+        // 版 3 のテーブルには統計情報がない
+        let v3 = legacy::TableSchemaV3 {
+            name: "users".into(),
+            columns: schema.columns.clone(),
+            table: v2.table,
+            index_names: vec![],
+            non_unique_index_names: vec!["users_name_dup".into()],
+            versioned: true,
+        };
+        let mut bytes_v3 = vec![FORMAT_TAG, 3];
+        bincode::options()
+            .serialize_into(&mut bytes_v3, &v3)
+            .unwrap();
+        let decoded = TableSchema::from_bytes(&bytes_v3).unwrap();
+        assert_eq!(PageId(4), decoded.table.non_unique_indices[0].meta_page_id);
+        assert!(decoded.versioned);
+        assert!(decoded.stats.is_none());
+
+        // 版 4 のテーブルにはハッシュインデックスがない
+        let v4 = legacy::TableSchemaV4 {
+            name: "users".into(),
+            columns: schema.columns.clone(),
+            table: v3.table,
+            index_names: vec![],
+            non_unique_index_names: vec!["users_name_dup".into()],
+            versioned: true,
+            stats: Some(TableStats {
+                row_count: 3,
+                columns: vec![],
+            }),
+        };
+        let mut bytes_v4 = vec![FORMAT_TAG, 4];
+        bincode::options()
+            .serialize_into(&mut bytes_v4, &v4)
+            .unwrap();
+        let decoded = TableSchema::from_bytes(&bytes_v4).unwrap();
+        assert_eq!(3, decoded.stats.unwrap().row_count);
+        assert!(decoded.table.hash_indices.is_empty());
+        assert!(decoded.hash_index_names.is_empty());
+
+        // 知らない版は読まない
+        let mut bytes = bytes;
+        bytes[1] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            TableSchema::from_bytes(&bytes)
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::UnsupportedFormat(_))
+        ));
+        // 印のないものは壊れている
+        assert!(matches!(
+            TableSchema::from_bytes(&bytes[1..])
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::Corrupted)
+        ));
+    }
+
+    #[test]
+    fn test_create_in_non_empty_database() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        BTree::create(&bufmgr).unwrap();
+        let err = Catalog::create(&bufmgr).err().unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::NotEmpty)));
+    }
+
+    #[test]
+    fn test_check_record() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let catalog = Catalog::create(&bufmgr).unwrap();
+        let columns = vec![
+            Column {
+                name: "id".into(),
+                data_type: DataType::Int64,
+            },
+            Column {
+                name: "score".into(),
+                data_type: DataType::Float64,
+            },
+            text("name"),
+        ];
+        let schema = catalog.create_table(&bufmgr, "scores", columns, 1).unwrap();
+        assert_eq!(
+            vec![Value::Int64(1), Value::Float64(2.0), Value::Null],
+            schema
+                .check_record(vec![1.into(), 2.into(), Value::Null])
+                .unwrap()
+        );
+        assert!(matches!(
+            schema.check_record(vec![Value::Null, 2.0.into(), "a".into()]),
+            Err(Error::NullPrimaryKey(_))
+        ));
+        assert!(matches!(
+            schema.check_record(vec![1.into(), "a".into(), "a".into()]),
+            Err(Error::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            schema.check_record(vec![1.into()]),
+            Err(Error::InvalidSchema(_))
+        ));
+    }
+}
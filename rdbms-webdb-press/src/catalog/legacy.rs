@@ -0,0 +1,165 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::disk::PageId;
+use crate::stats::TableStats;
+use crate::table::{NonUniqueIndex, Table, UniqueIndex};
+
+use super::{Column, TableSchema};
+
+// 古い版で書かれた TableSchema の形式
+// 読んだものは、後から足した項目を空にして今の形式に直す
+
+// 版 1 は最初の形式
+pub fn decode_v1(body: &[u8]) -> Result<TableSchema, bincode::Error> {
+    Ok(bincode::options()
+        .deserialize::<TableSchemaV1>(body)?
+        .into())
+}
+
+// 版 2 は、重複を許すインデックスを足した形式
+pub fn decode_v2(body: &[u8]) -> Result<TableSchema, bincode::Error> {
+    Ok(bincode::options()
+        .deserialize::<TableSchemaV2>(body)?
+        .into())
+}
+
+// 版 3 は、テーブルに版を付けるかどうかを足した形式
+pub fn decode_v3(body: &[u8]) -> Result<TableSchema, bincode::Error> {
+    Ok(bincode::options()
+        .deserialize::<TableSchemaV3>(body)?
+        .into())
+}
+
+// 版 4 は、統計情報を足した形式
+pub fn decode_v4(body: &[u8]) -> Result<TableSchema, bincode::Error> {
+    Ok(bincode::options()
+        .deserialize::<TableSchemaV4>(body)?
+        .into())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableSchemaV1 {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub table: TableV1,
+    pub index_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableV1 {
+    pub meta_page_id: PageId,
+    pub num_key_elems: usize,
+    pub unique_indices: Vec<UniqueIndex>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableSchemaV2 {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub table: TableV2,
+    pub index_names: Vec<String>,
+    pub non_unique_index_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TableV2 {
+    pub meta_page_id: PageId,
+    pub num_key_elems: usize,
+    pub unique_indices: Vec<UniqueIndex>,
+    pub non_unique_indices: Vec<NonUniqueIndex>,
+}
+
+// 版 3 の Table は版 2 と同じ
+#[derive(Serialize, Deserialize)]
+pub struct TableSchemaV3 {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub table: TableV2,
+    pub index_names: Vec<String>,
+    pub non_unique_index_names: Vec<String>,
+    pub versioned: bool,
+}
+
+// 版 4 の Table も版 2 と同じ
+#[derive(Serialize, Deserialize)]
+pub struct TableSchemaV4 {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub table: TableV2,
+    pub index_names: Vec<String>,
+    pub non_unique_index_names: Vec<String>,
+    pub versioned: bool,
+    pub stats: Option<TableStats>,
+}
+
+impl From<TableSchemaV1> for TableSchema {
+    fn from(schema: TableSchemaV1) -> Self {
+        TableSchemaV2 {
+            name: schema.name,
+            columns: schema.columns,
+            table: TableV2 {
+                meta_page_id: schema.table.meta_page_id,
+                num_key_elems: schema.table.num_key_elems,
+                unique_indices: schema.table.unique_indices,
+                non_unique_indices: vec![],
+            },
+            index_names: schema.index_names,
+            non_unique_index_names: vec![],
+        }
+        .into()
+    }
+}
+
+// 版 3 より前のテーブルには版が付いていない
+impl From<TableSchemaV2> for TableSchema {
+    fn from(schema: TableSchemaV2) -> Self {
+        TableSchemaV3 {
+            name: schema.name,
+            columns: schema.columns,
+            table: schema.table,
+            index_names: schema.index_names,
+            non_unique_index_names: schema.non_unique_index_names,
+            versioned: false,
+        }
+        .into()
+    }
+}
+
+// 版 4 より前のテーブルには統計情報がない
+impl From<TableSchemaV3> for TableSchema {
+    fn from(schema: TableSchemaV3) -> Self {
+        TableSchemaV4 {
+            name: schema.name,
+            columns: schema.columns,
+            table: schema.table,
+            index_names: schema.index_names,
+            non_unique_index_names: schema.non_unique_index_names,
+            versioned: schema.versioned,
+            stats: None,
+        }
+        .into()
+    }
+}
+
+// 版 5 より前のテーブルにはハッシュインデックスがない
+impl From<TableSchemaV4> for TableSchema {
+    fn from(schema: TableSchemaV4) -> Self {
+        TableSchema {
+            name: schema.name,
+            columns: schema.columns,
+            table: Table {
+                meta_page_id: schema.table.meta_page_id,
+                num_key_elems: schema.table.num_key_elems,
+                unique_indices: schema.table.unique_indices,
+                non_unique_indices: schema.table.non_unique_indices,
+                hash_indices: vec![],
+            },
+            index_names: schema.index_names,
+            non_unique_index_names: schema.non_unique_index_names,
+            versioned: schema.versioned,
+            stats: schema.stats,
+            hash_index_names: vec![],
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::btree::BTree;
+use crate::buffer::{BufferPool, BufferPoolManager};
+use crate::catalog::{Catalog, CATALOG_META_PAGE_ID};
+use crate::disk::{DiskManager, PageId, HEADER_PAGE_ID};
+use crate::hash_index::HashIndex;
+
+const POOL_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    // チェックサムが合わないか、ファイルの外を指している
+    UnreadablePage,
+    InvalidNodeType,
+    InvalidSlottedPage,
+    InvalidPair,
+    UnsortedKeys,
+    // 親の区切りキーが決める範囲の外にキーがある
+    KeyOutOfRange,
+    SeparatorOutOfRange,
+    BrokenSiblingLink,
+    // リーフの深さがそろっていない
+    UnbalancedTree,
+    // 根でないブランチが区切りキーを持たず、子が1つしかない
+    EmptyBranch,
+    BrokenOverflowChain,
+    PageReachableTwice,
+    BrokenFreeList,
+    BrokenCatalog,
+    // どの B+Tree からも空きページのリストからもたどれない
+    UnreachablePage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub page_id: Option<PageId>,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TreeReport {
+    pub name: String,
+    pub meta_page_id: PageId,
+    pub height: usize,
+    pub num_nodes: usize,
+    pub num_pairs: usize,
+    pub num_overflow_pages: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub num_pages: u64,
+    pub num_free_pages: usize,
+    pub trees: Vec<TreeReport>,
+    pub violations: Vec<Violation>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+// たどったページと見つけた問題を集める
+pub struct Checker {
+    num_pages: u64,
+    reachable: HashSet<PageId>,
+    violations: Vec<Violation>,
+}
+
+impl Checker {
+    pub fn new(num_pages: u64) -> Self {
+        Self {
+            num_pages,
+            reachable: HashSet::new(),
+            violations: vec![],
+        }
+    }
+
+    // 初めてたどったページなら true を返す。ファイルの外や二度目なら問題として残す
+    pub fn visit(&mut self, page_id: PageId) -> bool {
+        if page_id == HEADER_PAGE_ID || page_id.to_u64() >= self.num_pages {
+            self.report(
+                ViolationKind::UnreadablePage,
+                page_id,
+                format!("page {} is out of range", page_id.to_u64()),
+            );
+            return false;
+        }
+        if !self.reachable.insert(page_id) {
+            self.report(
+                ViolationKind::PageReachableTwice,
+                page_id,
+                format!("page {} is reachable twice", page_id.to_u64()),
+            );
+            return false;
+        }
+        true
+    }
+
+    pub fn report(&mut self, kind: ViolationKind, page_id: PageId, message: impl Into<String>) {
+        self.violations.push(Violation {
+            kind,
+            page_id: page_id.valid(),
+            message: message.into(),
+        });
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+// ヒープファイルの全ての B+Tree をたどって整合性を確かめる
+// 他のプロセスが開いていないファイルに対して使う
+pub fn check_file(path: impl AsRef<Path>) -> Result<Report> {
+    let mut disk = DiskManager::open(path)?;
+    let num_pages = disk.num_pages();
+    let mut checker = Checker::new(num_pages);
+    let free_page_ids = match disk.free_page_ids() {
+        Ok(free_page_ids) => free_page_ids,
+        Err(err) => {
+            checker.report(
+                ViolationKind::BrokenFreeList,
+                PageId::INVALID_PAGE_ID,
+                err.to_string(),
+            );
+            vec![]
+        }
+    };
+    let bufmgr = BufferPoolManager::new(disk, BufferPool::new(POOL_SIZE));
+    let mut trees = vec![];
+    if CATALOG_META_PAGE_ID.to_u64() < num_pages {
+        let catalog = BTree::new(CATALOG_META_PAGE_ID);
+        trees.push(catalog.check(&bufmgr, "catalog", &mut checker));
+        if checker.violations().is_empty() {
+            check_tables(&bufmgr, &mut checker, &mut trees);
+        }
+    }
+    for page_id in &free_page_ids {
+        checker.visit(*page_id);
+    }
+    for page_id in 1..num_pages {
+        if !checker.reachable.contains(&PageId(page_id)) {
+            checker.report(
+                ViolationKind::UnreachablePage,
+                PageId(page_id),
+                format!("page {} is not reachable", page_id),
+            );
+        }
+    }
+    Ok(Report {
+        num_pages,
+        num_free_pages: free_page_ids.len(),
+        trees,
+        violations: checker.violations,
+    })
+}
+
+fn check_tables(bufmgr: &BufferPoolManager, checker: &mut Checker, trees: &mut Vec<TreeReport>) {
+    let catalog = Catalog::open();
+    match catalog.commit_log(bufmgr) {
+        Ok(Some(meta_page_id)) => {
+            trees.push(BTree::new(meta_page_id).check(bufmgr, "commit log", checker));
+        }
+        Ok(None) => {}
+        Err(err) => checker.report(
+            ViolationKind::BrokenCatalog,
+            CATALOG_META_PAGE_ID,
+            err.to_string(),
+        ),
+    }
+    let schemas = match catalog.tables(bufmgr) {
+        Ok(schemas) => schemas,
+        Err(err) => {
+            checker.report(
+                ViolationKind::BrokenCatalog,
+                CATALOG_META_PAGE_ID,
+                err.to_string(),
+            );
+            return;
+        }
+    };
+    for schema in schemas {
+        let table = BTree::new(schema.table.meta_page_id);
+        trees.push(table.check(bufmgr, &schema.name, checker));
+        let unique_indices = schema
+            .table
+            .unique_indices
+            .iter()
+            .map(|index| index.meta_page_id)
+            .zip(&schema.index_names);
+        let non_unique_indices = schema
+            .table
+            .non_unique_indices
+            .iter()
+            .map(|index| index.meta_page_id)
+            .zip(&schema.non_unique_index_names);
+        for (meta_page_id, name) in unique_indices.chain(non_unique_indices) {
+            let index = BTree::new(meta_page_id);
+            trees.push(index.check(bufmgr, &format!("{}.{}", schema.name, name), checker));
+        }
+        // ハッシュインデックスは中身を確かめず、使っているページをたどったことにする
+        for hash_index in &schema.table.hash_indices {
+            let meta_page_id = hash_index.meta_page_id;
+            match HashIndex::new(meta_page_id).page_ids(bufmgr) {
+                Ok(page_ids) => {
+                    for page_id in page_ids {
+                        checker.visit(page_id);
+                    }
+                }
+                Err(err) => {
+                    checker.report(ViolationKind::UnreadablePage, meta_page_id, err.to_string())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::catalog::Column;
+    use crate::disk::PAGE_SIZE;
+    use crate::value::DataType;
+
+    #[test]
+    fn test_check_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.rly");
+        {
+            let disk = DiskManager::open(&path).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(POOL_SIZE));
+            let catalog = Catalog::create(&bufmgr).unwrap();
+            let columns = vec![
+                Column {
+                    name: "id".to_string(),
+                    data_type: DataType::Int64,
+                },
+                Column {
+                    name: "name".to_string(),
+                    data_type: DataType::Text,
+                },
+                Column {
+                    name: "bio".to_string(),
+                    data_type: DataType::Text,
+                },
+                Column {
+                    name: "team".to_string(),
+                    data_type: DataType::Int64,
+                },
+            ];
+            catalog.create_table(&bufmgr, "users", columns, 1).unwrap();
+            catalog
+                .create_index(&bufmgr, "users", "users_name", &["name"], None)
+                .unwrap();
+            catalog
+                .create_non_unique_index(&bufmgr, "users", "users_team", &["team"], None)
+                .unwrap();
+            // ハッシュインデックスのページも、どこかからたどれるページとして数える
+            catalog
+                .create_hash_index(&bufmgr, "users", "users_name_hash", &["name"], None)
+                .unwrap();
+            let table = catalog.open_table(&bufmgr, "users").unwrap();
+            for i in 0..500i64 {
+                let name = format!("user{:04}", i);
+                let bio = "x".repeat(if i == 7 { 20000 } else { 10 });
+                table
+                    .insert(
+                        &bufmgr,
+                        &[i.into(), name.into(), bio.into(), (i % 10).into()],
+                    )
+                    .unwrap();
+            }
+            table.delete(&bufmgr, &[3i64.into()]).unwrap();
+            bufmgr.flush().unwrap();
+        }
+
+        let report = check_file(&path).unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        let names: Vec<_> = report.trees.iter().map(|tree| tree.name.as_str()).collect();
+        assert_eq!(
+            vec!["catalog", "users", "users.users_name", "users.users_team"],
+            names
+        );
+        assert_eq!(499, report.trees[1].num_pairs);
+        assert!(report.trees[1].height >= 2);
+        assert!(report.trees[1].num_overflow_pages > 0);
+
+        // 最後のページの中身を 1 バイト壊す
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = (report.num_pages - 1) * PAGE_SIZE as u64 + 100;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xAA]).unwrap();
+        drop(file);
+        let report = check_file(&path).unwrap();
+        assert!(!report.is_ok());
+        assert!(report
+            .violations
+            .iter()
+            .any(|violation| violation.kind == ViolationKind::UnreadablePage));
+    }
+}
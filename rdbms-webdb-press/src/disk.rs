@@ -1,15 +1,19 @@
 use std::{
+    collections::BTreeMap,
     convert::TryInto,
     fs::{File, OpenOptions},
-    io::{Read, Result, Seek, SeekFrom, Write},
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    mem::size_of,
     path::Path,
-    u64,
 };
 
-use zerocopy::{AsBytes, FromBytes};
+use serde::{Deserialize, Serialize};
+use zerocopy::{AsBytes, FromBytes, LayoutVerified};
+
+use crate::wal::{self, Lsn, Wal};
 
 pub const PAGE_SIZE: usize = 4096;
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, AsBytes, FromBytes)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, AsBytes, FromBytes, Serialize, Deserialize)]
 #[repr(C)]
 pub struct PageId(pub u64);
 impl PageId {
@@ -48,19 +52,158 @@ impl From<&[u8]> for PageId {
     }
 }
 
+// 全てのページの先頭に置くヘッダー
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PageHeader {
+    // このページに最後に反映したログレコードの LSN
+    pub lsn: Lsn,
+    // 書き出す時に計算する、checksum 自身を除いたページ全体の CRC32C
+    pub checksum: u32,
+    _reserved: u32,
+}
+
+const CHECKSUM_OFFSET: usize = size_of::<Lsn>();
+const CHECKSUM_END: usize = CHECKSUM_OFFSET + size_of::<u32>();
+
+pub const PAGE_HEADER_SIZE: usize = size_of::<PageHeader>();
+
+pub fn page_lsn(page: &[u8]) -> Lsn {
+    let (header, _) = LayoutVerified::<_, PageHeader>::new_from_prefix(page)
+        .expect("page header must be aligned");
+    header.lsn
+}
+
+pub fn set_page_lsn(page: &mut [u8], lsn: Lsn) {
+    let (mut header, _) = LayoutVerified::<_, PageHeader>::new_from_prefix(page)
+        .expect("page header must be aligned");
+    header.lsn = lsn;
+}
+
+fn page_checksum(page: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&page[..CHECKSUM_OFFSET]);
+    crc32c::crc32c_append(crc, &page[CHECKSUM_END..])
+}
+
+// 書き込みが途中で途切れたページや、壊れたページなら false
+// 一度も書き出されずに 0 のままのページは正しいとみなす
+pub fn verify_page(page: &[u8]) -> bool {
+    let (header, _) = LayoutVerified::<_, PageHeader>::new_from_prefix(page)
+        .expect("page header must be aligned");
+    header.checksum == page_checksum(page) || page.iter().all(|&b| b == 0)
+}
+
+fn set_page_checksum(page: &mut [u8]) {
+    let checksum = page_checksum(page);
+    let (mut header, _) = LayoutVerified::<_, PageHeader>::new_from_prefix(page)
+        .expect("page header must be aligned");
+    header.checksum = checksum;
+}
+
+fn corrupted(page_id: PageId) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("page {} is corrupted", page_id.to_u64()),
+    )
+}
+
+// ヒープファイルの先頭ページ (ヘッダーページ) の、ページヘッダーに続く内容
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 8],
+    version: u32,
+    _reserved: u32,
+    next_page_id: u64,
+    // 解放されたページの連結リストの先頭
+    free_page_id: PageId,
+    // ログを空にした時点で次に割り当てる LSN
+    next_lsn: Lsn,
+}
+
+// 解放されたページには、次に解放されたページの ID を書いておく
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct FreePage {
+    header: PageHeader,
+    next_free_page_id: PageId,
+}
+
+pub const HEADER_PAGE_ID: PageId = PageId(0);
+
+// ヘッダーページの先頭に置き、このファイルがヒープファイルであることを示す
+const MAGIC: [u8; 8] = *b"RDBMSHF\0";
+// ページの形式を変えたら上げる
+pub const FORMAT_VERSION: u32 = 1;
+
+fn unrecognized(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
 pub struct DiskManager {
     heap_file: File,
-    next_page_id: u64,
+    header: Header,
+    // ヘッダーはメモリに置き、sync や checkpoint の時にまとめて書き出す
+    header_dirty: bool,
+    // ヘッダーの変更を最後に記録したログレコードの LSN
+    header_lsn: Lsn,
+    // ページの変更を最後にコミットしたログレコードの LSN
+    commit_lsn: Lsn,
+    wal: Option<Wal>,
+    // 終わっていないトランザクションの UNDO レコード
+    // ログを空にしても失われないように、チェックポイントの後に書き直す
+    transactions: BTreeMap<u64, Vec<Vec<u8>>>,
+    fault_hook: Option<FaultHook>,
 }
 
+// ページを割り当てる直前に呼ぶ関数。テストで書き込みの途中に障害を起こすのに使う
+pub type FaultHook = Box<dyn FnMut() -> Result<()> + Send>;
+
 impl DiskManager {
     pub fn new(heap_file: File) -> Result<Self> {
         let heap_file_size = heap_file.metadata()?.len();
-        let next_page_id = heap_file_size / PAGE_SIZE as u64;
-        Ok(Self {
+        let mut disk = Self {
             heap_file,
-            next_page_id,
-        })
+            header: Header {
+                magic: MAGIC,
+                version: FORMAT_VERSION,
+                _reserved: 0,
+                next_page_id: HEADER_PAGE_ID.to_u64() + 1,
+                free_page_id: PageId::INVALID_PAGE_ID,
+                next_lsn: 1,
+            },
+            header_dirty: false,
+            header_lsn: 0,
+            commit_lsn: 0,
+            wal: None,
+            transactions: BTreeMap::new(),
+            fault_hook: None,
+        };
+        if heap_file_size == 0 {
+            disk.write_header()?;
+        } else {
+            let mut buf = vec![0; PAGE_SIZE];
+            disk.read_page_data(HEADER_PAGE_ID, &mut buf)?;
+            if !verify_page(&buf) {
+                return Err(corrupted(HEADER_PAGE_ID));
+            }
+            disk.header = read_header(&buf)?;
+            disk.header_lsn = page_lsn(&buf);
+            // ヘッダーが書き出される前に増えたページは使用中とみなす
+            let num_pages = heap_file_size / PAGE_SIZE as u64;
+            disk.header.next_page_id = disk.header.next_page_id.max(num_pages);
+        }
+        Ok(disk)
+    }
+
+    // ログファイルを使う場合は、開いた時点でログを再生してクラッシュから復旧する
+    pub fn with_wal(heap_file: File, log_file: File) -> Result<Self> {
+        let mut disk = Self::new(heap_file)?;
+        let mut wal = Wal::new(log_file)?;
+        wal.set_next_lsn(disk.header.next_lsn);
+        disk.wal = Some(wal);
+        disk.recover()?;
+        Ok(disk)
     }
 
     pub fn open<S: AsRef<Path>>(heap_file_path: S) -> Result<Self> {
@@ -68,34 +211,334 @@ impl DiskManager {
             .read(true)
             .write(true)
             .create(true)
-            .open(heap_file_path)?; // ? = エラーが帰ったら早期リターン
-        Self::new(heap_file)
+            .truncate(false)
+            .open(&heap_file_path)?; // ? = エラーが帰ったら早期リターン
+        let mut log_file_path = heap_file_path.as_ref().as_os_str().to_owned();
+        log_file_path.push(".wal");
+        let log_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_file_path)?;
+        Self::with_wal(heap_file, log_file)
+    }
+
+    // Commit まで書かれたレコードをページに反映し直す
+    fn recover(&mut self) -> Result<()> {
+        let wal = self.wal.as_mut().unwrap();
+        let records = wal.read_committed()?;
+        let mut num_pages = self.heap_file.metadata()?.len() / PAGE_SIZE as u64;
+        let mut next_lsn = wal.next_lsn();
+        let mut page = vec![0; PAGE_SIZE];
+        for record in records {
+            next_lsn = next_lsn.max(record.lsn() + 1);
+            let (lsn, page_id, runs) = match record {
+                wal::Record::Redo { lsn, page_id, runs } => (lsn, PageId(page_id), runs),
+                wal::Record::Commit { .. } => continue,
+                wal::Record::Undo { txn_id, data, .. } => {
+                    self.transactions.entry(txn_id).or_default().push(data);
+                    continue;
+                }
+                wal::Record::Compensate { txn_id, .. } => {
+                    if let Some(undo_log) = self.transactions.get_mut(&txn_id) {
+                        undo_log.pop();
+                    }
+                    continue;
+                }
+                wal::Record::End { txn_id, .. } => {
+                    self.transactions.remove(&txn_id);
+                    continue;
+                }
+            };
+            if page_id.to_u64() < num_pages {
+                self.read_page_data(page_id, &mut page)?;
+            } else {
+                page.iter_mut().for_each(|b| *b = 0);
+            }
+            // 既に反映済みのレコードは飛ばす
+            if page_lsn(&page) >= lsn {
+                continue;
+            }
+            wal::apply(&mut page, &runs);
+            set_page_lsn(&mut page, lsn);
+            self.write_page_raw(page_id, &mut page)?;
+            if page_id == HEADER_PAGE_ID {
+                self.header = read_header(&page)?;
+                self.header_lsn = lsn;
+            }
+            num_pages = num_pages.max(page_id.to_u64() + 1);
+            self.header.next_page_id = self.header.next_page_id.max(num_pages);
+        }
+        self.wal.as_mut().unwrap().set_next_lsn(next_lsn);
+        self.checkpoint()
+    }
+
+    // 解放されたページがあれば再利用し、なければファイルの末尾に新しいページを割り当てる
+    pub fn allocate_page(&mut self) -> Result<PageId> {
+        if let Some(hook) = &mut self.fault_hook {
+            hook()?;
+        }
+        let page_id = match self.header.free_page_id.valid() {
+            Some(free_page_id) => {
+                let mut buf = vec![0; PAGE_SIZE];
+                self.read_page_data(free_page_id, &mut buf)?;
+                if !verify_page(&buf) {
+                    return Err(corrupted(free_page_id));
+                }
+                let (free_page, _) = LayoutVerified::<_, FreePage>::new_from_prefix(buf.as_slice())
+                    .expect("free page must be aligned");
+                self.header.free_page_id = free_page.next_free_page_id;
+                free_page_id
+            }
+            None => {
+                let page_id = PageId(self.header.next_page_id);
+                self.header.next_page_id += 1;
+                page_id
+            }
+        };
+        self.header_changed()?;
+        Ok(page_id)
+    }
+
+    // ページを解放して、次の allocate_page で再利用できるようにする
+    pub fn deallocate_page(&mut self, page_id: PageId) -> Result<()> {
+        assert_ne!(HEADER_PAGE_ID, page_id, "header page cannot be deallocated");
+        // このページを参照しなくなったことを示すログを先に永続化しておく
+        // 同じミニトランザクションで解放するページは、最初の 1 ページの時にまとめて永続化される
+        // また、それより前のレコードでページが書き戻されないように LSN を進めておく
+        let lsn = match &mut self.wal {
+            Some(wal) => {
+                wal.flush_to(self.commit_lsn)?;
+                wal.next_lsn() - 1
+            }
+            None => 0,
+        };
+        let mut buf = vec![0; PAGE_SIZE];
+        {
+            let (mut free_page, _) =
+                LayoutVerified::<_, FreePage>::new_from_prefix(buf.as_mut_slice())
+                    .expect("free page must be aligned");
+            free_page.header.lsn = lsn;
+            free_page.next_free_page_id = self.header.free_page_id;
+        }
+        self.write_page_raw(page_id, &mut buf)?;
+        self.header.free_page_id = page_id;
+        self.header_changed()
+    }
+
+    // ヘッダーページは書き出さず、変更後のヘッダーだけをログに残す
+    // ログの再生でヘッダーページも戻るので、書き出す前にクラッシュしても割り当ての状態は失われない
+    // 自分だけでコミットするので、割り当てたページを使うミニトランザクションが失敗してもページが漏れるだけで済む
+    // ログがなければ戻せないので、その場でヘッダーページを書き出す
+    fn header_changed(&mut self) -> Result<()> {
+        self.header_dirty = true;
+        match &mut self.wal {
+            Some(wal) => {
+                let runs = vec![wal::Run {
+                    offset: PAGE_HEADER_SIZE as u16,
+                    data: self.header.as_bytes().to_vec(),
+                }];
+                self.header_lsn = wal.append_redo(HEADER_PAGE_ID.to_u64(), runs);
+                wal.append_commit();
+                Ok(())
+            }
+            None => self.write_header(),
+        }
+    }
+
+    // ヘッダーページも含めた、割り当てたことのあるページの数
+    pub fn num_pages(&self) -> u64 {
+        self.header.next_page_id
     }
 
-    pub fn allocate_page(&mut self) -> PageId {
-        let page_id = self.next_page_id;
-        self.next_page_id += 1;
-        PageId(page_id)
+    // 解放されたページの連結リストをたどる。リストが壊れていればエラーを返す
+    pub fn free_page_ids(&mut self) -> Result<Vec<PageId>> {
+        let mut page_ids = vec![];
+        let mut buf = vec![0; PAGE_SIZE];
+        let mut page_id = self.header.free_page_id.valid();
+        while let Some(free_page_id) = page_id {
+            if free_page_id.to_u64() >= self.num_pages()
+                || page_ids.len() as u64 >= self.num_pages()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("free list is broken at page {}", free_page_id.to_u64()),
+                ));
+            }
+            self.read_page_data(free_page_id, &mut buf)?;
+            if !verify_page(&buf) {
+                return Err(corrupted(free_page_id));
+            }
+            let (free_page, _) = LayoutVerified::<_, FreePage>::new_from_prefix(buf.as_slice())
+                .expect("free page must be aligned");
+            page_ids.push(free_page_id);
+            page_id = free_page.next_free_page_id.valid();
+        }
+        Ok(page_ids)
     }
 
+    fn write_header(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.flush_to(self.header_lsn)?;
+            self.header.next_lsn = wal.next_lsn();
+        }
+        self.header_dirty = false;
+        let mut buf = vec![0; PAGE_SIZE];
+        set_page_lsn(&mut buf, self.header_lsn);
+        {
+            let (mut header, _) =
+                LayoutVerified::<_, Header>::new_from_prefix(&mut buf[PAGE_HEADER_SIZE..])
+                    .expect("header page must be aligned");
+            *header = self.header;
+        }
+        self.write_page_raw(HEADER_PAGE_ID, &mut buf)
+    }
+
+    // チェックサムは確かめずにそのまま読む。確かめるのは verify_page
     pub fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> Result<()> {
         let offset = PAGE_SIZE as u64 * page_id.to_u64();
         self.heap_file.seek(SeekFrom::Start(offset))?;
         self.heap_file.read_exact(data)
     }
 
+    // ページに反映されたログレコードが永続化されてから書き込む (Write-Ahead Logging)
     pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.flush_to(page_lsn(data))?;
+        }
+        self.write_page_raw(page_id, &mut data.to_vec())
+    }
+
+    // チェックサムを計算してから書き込む
+    fn write_page_raw(&mut self, page_id: PageId, data: &mut [u8]) -> Result<()> {
+        set_page_checksum(data);
         let offset = PAGE_SIZE as u64 * page_id.to_u64();
         self.heap_file.seek(SeekFrom::Start(offset))?;
         self.heap_file.write_all(data)
     }
 
+    pub fn set_fault_hook(&mut self, hook: FaultHook) {
+        self.fault_hook = Some(hook);
+    }
+
+    pub fn has_wal(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    pub fn wal_size(&self) -> u64 {
+        self.wal.as_ref().map_or(0, Wal::size)
+    }
+
+    // ページの変更をログに追記し、その LSN を返す
+    pub fn log_redo(&mut self, page_id: PageId, runs: Vec<wal::Run>) -> Option<Lsn> {
+        let wal = self.wal.as_mut()?;
+        Some(wal.append_redo(page_id.to_u64(), runs))
+    }
+
+    pub fn log_undo(&mut self, txn_id: u64, data: Vec<u8>) {
+        if let Some(wal) = &mut self.wal {
+            wal.append_undo(txn_id, data.clone());
+            self.transactions.entry(txn_id).or_default().push(data);
+        }
+    }
+
+    pub fn log_compensate(&mut self, txn_id: u64) {
+        if let Some(wal) = &mut self.wal {
+            wal.append_compensate(txn_id);
+            if let Some(undo_log) = self.transactions.get_mut(&txn_id) {
+                undo_log.pop();
+            }
+        }
+    }
+
+    pub fn log_end(&mut self, txn_id: u64) {
+        if let Some(wal) = &mut self.wal {
+            wal.append_end(txn_id);
+            self.transactions.remove(&txn_id);
+        }
+    }
+
+    // 復旧した時点で終わっていなかったトランザクションの UNDO レコードを、古いものから並べて返す
+    pub fn unfinished_transactions(&self) -> Vec<(u64, Vec<Vec<u8>>)> {
+        self.transactions
+            .iter()
+            .map(|(&txn_id, undo_log)| (txn_id, undo_log.clone()))
+            .collect()
+    }
+
+    pub fn log_commit(&mut self) {
+        if let Some(wal) = &mut self.wal {
+            self.commit_lsn = wal.append_commit();
+        }
+    }
+
+    // ログを永続化する
+    pub fn flush_wal(&mut self) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.flush(),
+            None => Ok(()),
+        }
+    }
+
     pub fn sync(&mut self) -> Result<()> {
+        if self.header_dirty {
+            self.write_header()?;
+        }
         // ディスクに書き出させる
         self.heap_file.flush()?;
         // メモリにあるデータを全てディスクへ
         self.heap_file.sync_all()
     }
+
+    // 全ての変更がヒープファイルに書き出された後に呼ぶ
+    // 次の LSN をヘッダーに残してからログを空にする
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.flush()?;
+        }
+        self.write_header()?;
+        self.sync()?;
+        if let Some(wal) = &mut self.wal {
+            wal.truncate()?;
+            // 終わっていないトランザクションは、後で取り消せるように UNDO レコードを残し直す
+            if !self.transactions.is_empty() {
+                for (&txn_id, undo_log) in self.transactions.iter() {
+                    for data in undo_log {
+                        wal.append_undo(txn_id, data.clone());
+                    }
+                }
+                wal.append_commit();
+                wal.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 閉じる時に、まだ書き出していないヘッダーを書き出す
+impl Drop for DiskManager {
+    fn drop(&mut self) {
+        if self.header_dirty {
+            let _ = self.write_header();
+        }
+    }
+}
+
+// 知らない形式のファイルを壊さないよう、マジックと版が一致しなければエラーにする
+fn read_header(page: &[u8]) -> Result<Header> {
+    let (header, _) = LayoutVerified::<_, Header>::new_from_prefix(&page[PAGE_HEADER_SIZE..])
+        .expect("header page must be aligned");
+    if header.magic != MAGIC {
+        return Err(unrecognized("not a heap file".to_string()));
+    }
+    if header.version != FORMAT_VERSION {
+        return Err(unrecognized(format!(
+            "unsupported heap file version {} (expected {})",
+            header.version, FORMAT_VERSION
+        )));
+    }
+    Ok(*header)
 }
 
 #[cfg(test)]
@@ -110,18 +553,18 @@ mod tests {
         let mut disk = DiskManager::new(data_file).unwrap();
 
         // データの書き込み
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
+        let mut hello = vec![0; PAGE_HEADER_SIZE];
         hello.extend_from_slice(b"hello");
         hello.resize(PAGE_SIZE, 0);
-        let hello_page_id = disk.allocate_page();
+        let hello_page_id = disk.allocate_page().unwrap();
         println!("hello_page_id: {:?}", hello_page_id);
         disk.write_page_data(hello_page_id, &hello).unwrap();
 
         // データの書き込み
-        let mut world = Vec::with_capacity(PAGE_SIZE);
+        let mut world = vec![0; PAGE_HEADER_SIZE];
         world.extend_from_slice(b"world");
         world.resize(PAGE_SIZE, 0);
-        let world_page_id = disk.allocate_page();
+        let world_page_id = disk.allocate_page().unwrap();
         println!("hello_page_id: {:?}", world_page_id);
         disk.write_page_data(world_page_id, &world).unwrap();
 
@@ -134,10 +577,115 @@ mod tests {
         // データの読み込み
         let mut buf = vec![0; PAGE_SIZE];
         disk2.read_page_data(hello_page_id, &mut buf).unwrap();
-        assert_eq!(hello, buf);
+        assert!(verify_page(&buf));
+        assert_eq!(hello[PAGE_HEADER_SIZE..], buf[PAGE_HEADER_SIZE..]);
 
         // データの読み込み
         disk2.read_page_data(world_page_id, &mut buf).unwrap();
-        assert_eq!(world, buf);
+        assert_eq!(world[PAGE_HEADER_SIZE..], buf[PAGE_HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn test_free_list() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let page_ids: Vec<_> = (0..4).map(|_| disk.allocate_page().unwrap()).collect();
+        assert!(!page_ids.contains(&HEADER_PAGE_ID));
+
+        // 解放したページは後に解放したものから順に再利用される
+        disk.deallocate_page(page_ids[1]).unwrap();
+        disk.deallocate_page(page_ids[2]).unwrap();
+        assert_eq!(page_ids[2], disk.allocate_page().unwrap());
+        drop(disk);
+
+        // 解放されたページの情報はファイルに残る
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[1], disk2.allocate_page().unwrap());
+        assert_eq!(PageId(page_ids[3].0 + 1), disk2.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_reject_unrecognized_file() {
+        // ヒープファイルでないファイル
+        let (mut data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        data_file.write_all(&[0xab; PAGE_SIZE]).unwrap();
+        drop(data_file);
+        let err = DiskManager::open(&data_file_path).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // 版が違うヒープファイル
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        disk.header.version = FORMAT_VERSION + 1;
+        disk.write_header().unwrap();
+        drop(disk);
+        let err = DiskManager::open(&data_file_path).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_free_list_without_wal() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        disk.deallocate_page(page_ids[2]).unwrap();
+        assert_eq!(page_ids[2], disk.allocate_page().unwrap());
+        // ヘッダーを sync せずにクラッシュさせる
+        std::mem::forget(disk);
+
+        // 貸し出したページは返ってこず、解放したページも漏れない
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        let mut disk2 = DiskManager::new(file).unwrap();
+        assert_eq!(vec![page_ids[1]], disk2.free_page_ids().unwrap());
+        assert_eq!(page_ids[1], disk2.allocate_page().unwrap());
+        assert_eq!(PageId(page_ids[2].0 + 1), disk2.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_header_written_lazily() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        drop(data_file);
+        let mut before = vec![0; PAGE_SIZE];
+        disk.read_page_data(HEADER_PAGE_ID, &mut before).unwrap();
+
+        // 割り当てと解放ではヘッダーページを書き換えない
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(HEADER_PAGE_ID, &mut buf).unwrap();
+        assert_eq!(before, buf);
+
+        // sync で書き出す
+        disk.sync().unwrap();
+        disk.read_page_data(HEADER_PAGE_ID, &mut buf).unwrap();
+        assert_ne!(before, buf);
+    }
+
+    #[test]
+    fn test_recover_header() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        drop(data_file);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.sync().unwrap();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.deallocate_page(page_ids[2]).unwrap();
+        // ヘッダーを書き出さずにクラッシュさせる
+        disk.flush_wal().unwrap();
+        std::mem::forget(disk);
+
+        // ログからヘッダーが戻る
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[1], page_id);
+        assert_eq!(vec![page_ids[2]], disk2.free_page_ids().unwrap());
+        assert_eq!(page_ids[2], disk2.allocate_page().unwrap());
+        assert_eq!(PageId(page_ids[2].0 + 1), disk2.allocate_page().unwrap());
     }
 }
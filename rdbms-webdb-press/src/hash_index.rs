@@ -0,0 +1,644 @@
+use std::mem;
+
+use thiserror::Error;
+use zerocopy::ByteSlice;
+
+use crate::btree::{self, Pair};
+use crate::buffer::{self, BufferPoolManager, PageLatch};
+use crate::disk::PageId;
+use crate::transaction::UndoRecord;
+
+mod bucket;
+mod directory;
+mod meta;
+
+use directory::ENTRIES_PER_PAGE;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("duplicate key")]
+    DuplicateKey,
+    #[error("key not found")]
+    KeyNotFound,
+    #[error("pair too large: {0} bytes")]
+    PairTooLarge(usize),
+    #[error(transparent)]
+    Buffer(#[from] buffer::Error),
+}
+
+// UNDO レコードを適用する時に、B+Tree と同じエラーとして扱う
+impl From<Error> for btree::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::DuplicateKey => btree::Error::DuplicateKey,
+            Error::KeyNotFound => btree::Error::KeyNotFound,
+            Error::PairTooLarge(size) => btree::Error::KeyTooLarge(size),
+            Error::Buffer(err) => btree::Error::Buffer(err),
+        }
+    }
+}
+
+// 1つのバケットに少なくとも4つのペアが入るようにする
+const MAX_PAIR_SIZE: usize = bucket::CAPACITY / 4;
+
+// バケットの使用量の平均がこの割合 (%) を超えたら、バケットを1つ分割する
+const MAX_LOAD_PERCENT: u64 = 75;
+
+// キーのハッシュ値。FNV-1a の下位ビットは偏るので、最後にかき混ぜる
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+// 線形ハッシュ法のバケット番号。分割済みのバケットに当たったら、1ビット多く使って振り分ける
+fn bucket_idx(hash: u64, level: u64, next_split: u64) -> u64 {
+    let idx = hash & ((1 << level) - 1);
+    if idx < next_split {
+        hash & ((1 << (level + 1)) - 1)
+    } else {
+        idx
+    }
+}
+
+impl<B: ByteSlice> meta::Meta<B> {
+    fn is_overloaded(&self) -> bool {
+        self.header.num_bytes * 100
+            > self.num_buckets() * bucket::CAPACITY as u64 * MAX_LOAD_PERCENT
+    }
+
+    // 次の分割で作るバケットをディレクトリに書けるかどうか
+    // 書けなければ、以降はオーバーフローバケットの鎖が伸びる
+    fn can_split(&self) -> bool {
+        let new_idx = self.header.next_split + (1 << self.header.level);
+        (new_idx as usize / ENTRIES_PER_PAGE) < meta::MAX_DIRECTORY_PAGES
+    }
+}
+
+// バケット番号から、そのバケットの先頭ページのIDを引く
+fn directory_entry(
+    bufmgr: &BufferPoolManager,
+    meta: &meta::Meta<impl ByteSlice>,
+    idx: u64,
+) -> Result<PageId, Error> {
+    let idx = idx as usize;
+    let latch = bufmgr.fetch_page_shared(meta.directory_page_ids[idx / ENTRIES_PER_PAGE])?;
+    let body = latch.body();
+    Ok(directory::Directory::new(&body[..]).entries[idx % ENTRIES_PER_PAGE])
+}
+
+fn set_directory_entry(
+    bufmgr: &BufferPoolManager,
+    meta: &meta::Meta<impl ByteSlice>,
+    idx: u64,
+    page_id: PageId,
+) -> Result<(), Error> {
+    let idx = idx as usize;
+    let latch = bufmgr.fetch_page_exclusive(meta.directory_page_ids[idx / ENTRIES_PER_PAGE])?;
+    let mut body = latch.body_mut();
+    directory::Directory::new(&mut body[..]).entries[idx % ENTRIES_PER_PAGE] = page_id;
+    bufmgr.mark_dirty(&latch);
+    Ok(())
+}
+
+fn bucket_page_id(
+    bufmgr: &BufferPoolManager,
+    meta: &meta::Meta<impl ByteSlice>,
+    key: &[u8],
+) -> Result<PageId, Error> {
+    let idx = bucket_idx(hash(key), meta.header.level, meta.header.next_split);
+    directory_entry(bufmgr, meta, idx)
+}
+
+// 鎖をたどってキーの値を探す
+fn search(
+    bufmgr: &BufferPoolManager,
+    head_page_id: PageId,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut page_id = Some(head_page_id);
+    while let Some(current_page_id) = page_id {
+        let latch = bufmgr.fetch_page_shared(current_page_id)?;
+        let body = latch.body();
+        let bucket = bucket::Bucket::new(&body[..]);
+        if let Some(slot_id) = bucket.search_slot_id(key) {
+            return Ok(Some(bucket.pair_at(slot_id).value.to_vec()));
+        }
+        page_id = bucket.next_page_id();
+    }
+    Ok(None)
+}
+
+// 鎖の中で空きのあるページにペアを入れる。どこにも入らなければ末尾にオーバーフローバケットをつなぐ
+fn append(
+    bufmgr: &BufferPoolManager,
+    head_page_id: PageId,
+    pair_bytes: &[u8],
+) -> Result<(), Error> {
+    let mut latch = bufmgr.fetch_page_exclusive(head_page_id)?;
+    loop {
+        let next_page_id = {
+            let mut body = latch.body_mut();
+            let mut bucket = bucket::Bucket::new(&mut body[..]);
+            if bucket.insert(pair_bytes).is_some() {
+                bufmgr.mark_dirty(&latch);
+                return Ok(());
+            }
+            bucket.next_page_id()
+        };
+        match next_page_id {
+            Some(next_page_id) => latch = bufmgr.fetch_page_exclusive(next_page_id)?,
+            None => break,
+        }
+    }
+    let new_buffer = bufmgr.create_page()?;
+    {
+        let mut body = new_buffer.body_mut();
+        let mut bucket = bucket::Bucket::new(&mut body[..]);
+        bucket.initialize();
+        bucket
+            .insert(pair_bytes)
+            .expect("new bucket must have room for a pair");
+    }
+    let mut body = latch.body_mut();
+    bucket::Bucket::new(&mut body[..]).set_next_page_id(Some(new_buffer.page_id));
+    bufmgr.mark_dirty(&latch);
+    Ok(())
+}
+
+// 線形ハッシュ法によるハッシュインデックス
+// バケットは1つずつ順に分割するので、ディレクトリを倍にする時のように多くのページを一度に書き換えない
+// 書き込みはメタページの排他ラッチを持って1つずつ行い、読み込みはメタページの共有ラッチを持って並行に行う
+// 削除してもバケットは減らさない。トランザクションの中の挿入と削除は UNDO レコードで取り消せる
+pub struct HashIndex {
+    pub meta_page_id: PageId,
+}
+
+impl HashIndex {
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self, Error> {
+        bufmgr.with_mtr(|bufmgr| {
+            let meta_buffer = bufmgr.create_page()?;
+            let directory_buffer = bufmgr.create_page()?;
+            let bucket_buffer = bufmgr.create_page()?;
+            let mut bucket_body = bucket_buffer.body_mut();
+            bucket::Bucket::new(&mut bucket_body[..]).initialize();
+            let mut directory_body = directory_buffer.body_mut();
+            let mut directory = directory::Directory::new(&mut directory_body[..]);
+            directory.entries[0] = bucket_buffer.page_id;
+            let mut meta_body = meta_buffer.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            meta.header.level = 0;
+            meta.header.next_split = 0;
+            meta.header.num_bytes = 0;
+            meta.header.num_directory_pages = 1;
+            meta.directory_page_ids[0] = directory_buffer.page_id;
+            Ok(Self::new(meta_buffer.page_id))
+        })
+    }
+
+    pub fn new(meta_page_id: PageId) -> Self {
+        Self { meta_page_id }
+    }
+
+    pub fn lookup(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        // 分割でペアが動かないよう、メタページの共有ラッチを持ったまま読む
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let meta_body = meta_latch.body();
+        let meta = meta::Meta::new(&meta_body[..]);
+        let head_page_id = bucket_page_id(bufmgr, &meta, key)?;
+        search(bufmgr, head_page_id, key)
+    }
+
+    pub fn insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.put(bufmgr, key, value, false)
+    }
+
+    // キーがあれば値を置き換え、なければ挿入する
+    pub fn upsert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.put(bufmgr, key, value, true)
+    }
+
+    // 置き換える時は古いペアを取り除いてから入れ直す。どちらも同じミニトランザクションで行う
+    fn put(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        replace: bool,
+    ) -> Result<(), Error> {
+        let pair_bytes = Pair { key, value }.to_bytes();
+        if pair_bytes.len() > MAX_PAIR_SIZE {
+            return Err(Error::PairTooLarge(pair_bytes.len()));
+        }
+        let _lock = bufmgr.lock_key(self.meta_page_id, key)?;
+        let overloaded = bufmgr.with_mtr(|bufmgr| {
+            let meta_latch = bufmgr.fetch_page_exclusive(self.meta_page_id)?;
+            let mut meta_body = meta_latch.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            let head_page_id = bucket_page_id(bufmgr, &meta, key)?;
+            if search(bufmgr, head_page_id, key)?.is_some() {
+                if !replace {
+                    return Err(Error::DuplicateKey);
+                }
+                meta.header.num_bytes -= self.remove(bufmgr, head_page_id, key)? as u64;
+            }
+            append(bufmgr, head_page_id, &pair_bytes)?;
+            meta.header.num_bytes += bucket::pair_size(&pair_bytes) as u64;
+            bufmgr.mark_dirty(&meta_latch);
+            bufmgr.push_undo(UndoRecord::HashInsert {
+                meta_page_id: self.meta_page_id,
+                key: key.to_vec(),
+            });
+            Ok(meta.is_overloaded() && meta.can_split())
+        })?;
+        // 分割は別のミニトランザクションにして、一度にラッチを持ち続けるページを減らす
+        if overloaded {
+            self.split(bufmgr)?;
+        }
+        Ok(())
+    }
+
+    // 次の番号のバケットを分割して、バケットを1つ増やす
+    fn split(&self, bufmgr: &BufferPoolManager) -> Result<(), Error> {
+        bufmgr.with_mtr(|bufmgr| {
+            let meta_latch = bufmgr.fetch_page_exclusive(self.meta_page_id)?;
+            let mut meta_body = meta_latch.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            if !meta.is_overloaded() || !meta.can_split() {
+                return Ok(());
+            }
+            let level = meta.header.level;
+            let old_idx = meta.header.next_split;
+            let new_idx = old_idx + (1 << level);
+            let directory_idx = new_idx as usize / ENTRIES_PER_PAGE;
+            if directory_idx == meta.header.num_directory_pages as usize {
+                let directory_buffer = bufmgr.create_page()?;
+                meta.directory_page_ids[directory_idx] = directory_buffer.page_id;
+                meta.header.num_directory_pages += 1;
+            }
+
+            // 分割するバケットのペアを全て取り出し、先頭のページだけを残して空にする
+            let old_page_id = directory_entry(bufmgr, &meta, old_idx)?;
+            let old_latch = bufmgr.fetch_page_exclusive(old_page_id)?;
+            let mut pairs = vec![];
+            let mut freed_page_ids = vec![];
+            {
+                let mut body = old_latch.body_mut();
+                let mut bucket = bucket::Bucket::new(&mut body[..]);
+                let mut page_id = bucket.next_page_id();
+                pairs.extend(
+                    (0..bucket.num_pairs()).map(|slot_id| bucket.pair_bytes_at(slot_id).to_vec()),
+                );
+                bucket.initialize();
+                bufmgr.mark_dirty(&old_latch);
+                while let Some(current_page_id) = page_id {
+                    freed_page_ids.push(current_page_id);
+                    let latch = bufmgr.fetch_page_shared(current_page_id)?;
+                    let body = latch.body();
+                    let bucket = bucket::Bucket::new(&body[..]);
+                    pairs.extend(
+                        (0..bucket.num_pairs())
+                            .map(|slot_id| bucket.pair_bytes_at(slot_id).to_vec()),
+                    );
+                    page_id = bucket.next_page_id();
+                }
+            }
+            for page_id in freed_page_ids {
+                bufmgr.delete_page(page_id)?;
+            }
+
+            let new_buffer = bufmgr.create_page()?;
+            {
+                let mut body = new_buffer.body_mut();
+                bucket::Bucket::new(&mut body[..]).initialize();
+            }
+            set_directory_entry(bufmgr, &meta, new_idx, new_buffer.page_id)?;
+
+            // 1ビット多く使って、元のバケットと新しいバケットに振り分ける
+            let mask = (1 << (level + 1)) - 1;
+            for pair_bytes in pairs {
+                let pair = Pair::from_bytes(&pair_bytes);
+                let head_page_id = if hash(pair.key) & mask == old_idx {
+                    old_page_id
+                } else {
+                    new_buffer.page_id
+                };
+                append(bufmgr, head_page_id, &pair_bytes)?;
+            }
+
+            meta.header.next_split += 1;
+            if meta.header.next_split == 1 << level {
+                meta.header.level += 1;
+                meta.header.next_split = 0;
+            }
+            bufmgr.mark_dirty(&meta_latch);
+            Ok(())
+        })
+    }
+
+    pub fn delete(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<(), Error> {
+        let _lock = bufmgr.lock_key(self.meta_page_id, key)?;
+        bufmgr.with_mtr(|bufmgr| {
+            let meta_latch = bufmgr.fetch_page_exclusive(self.meta_page_id)?;
+            let mut meta_body = meta_latch.body_mut();
+            let mut meta = meta::Meta::new(&mut meta_body[..]);
+            let head_page_id = bucket_page_id(bufmgr, &meta, key)?;
+            meta.header.num_bytes -= self.remove(bufmgr, head_page_id, key)? as u64;
+            bufmgr.mark_dirty(&meta_latch);
+            Ok(())
+        })
+    }
+
+    // 鎖からキーのペアを取り除き、その大きさを返す。メタページの排他ラッチを持って呼ぶ
+    fn remove(
+        &self,
+        bufmgr: &BufferPoolManager,
+        head_page_id: PageId,
+        key: &[u8],
+    ) -> Result<usize, Error> {
+        let mut prev_latch: Option<PageLatch> = None;
+        let mut latch = bufmgr.fetch_page_exclusive(head_page_id)?;
+        loop {
+            let next_page_id = {
+                let mut body = latch.body_mut();
+                let mut bucket = bucket::Bucket::new(&mut body[..]);
+                match bucket.search_slot_id(key) {
+                    Some(slot_id) => {
+                        let pair_size = bucket::pair_size(bucket.pair_bytes_at(slot_id));
+                        bufmgr.push_undo(UndoRecord::HashDelete {
+                            meta_page_id: self.meta_page_id,
+                            key: key.to_vec(),
+                            value: bucket.pair_at(slot_id).value.to_vec(),
+                        });
+                        bucket.remove(slot_id);
+                        bufmgr.mark_dirty(&latch);
+                        // 空になったオーバーフローバケットは鎖から外して解放する
+                        if let (0, Some(prev_latch)) = (bucket.num_pairs(), &prev_latch) {
+                            let mut prev_body = prev_latch.body_mut();
+                            bucket::Bucket::new(&mut prev_body[..])
+                                .set_next_page_id(bucket.next_page_id());
+                            bufmgr.mark_dirty(prev_latch);
+                            bufmgr.delete_page(latch.page_id)?;
+                        }
+                        return Ok(pair_size);
+                    }
+                    None => bucket.next_page_id(),
+                }
+            };
+            let next_page_id = next_page_id.ok_or(Error::KeyNotFound)?;
+            let next_latch = bufmgr.fetch_page_exclusive(next_page_id)?;
+            prev_latch = Some(mem::replace(&mut latch, next_latch));
+        }
+    }
+
+    // 全てのペアを順不同で f に渡す
+    // 分割でペアが動かないよう、メタページの共有ラッチを持ったまま読む
+    pub fn for_each<E: From<Error>>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        mut f: impl FnMut(&[u8], &[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let meta_latch = bufmgr
+            .fetch_page_shared(self.meta_page_id)
+            .map_err(Error::from)?;
+        let body = meta_latch.body();
+        let meta = meta::Meta::new(&body[..]);
+        for idx in 0..meta.num_buckets() {
+            let mut page_id = Some(directory_entry(bufmgr, &meta, idx)?);
+            while let Some(current_page_id) = page_id {
+                let latch = bufmgr
+                    .fetch_page_shared(current_page_id)
+                    .map_err(Error::from)?;
+                let body = latch.body();
+                let bucket = bucket::Bucket::new(&body[..]);
+                for slot_id in 0..bucket.num_pairs() {
+                    let pair = bucket.pair_at(slot_id);
+                    f(pair.key, pair.value)?;
+                }
+                page_id = bucket.next_page_id();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn num_buckets(&self, bufmgr: &BufferPoolManager) -> Result<u64, Error> {
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let body = meta_latch.body();
+        Ok(meta::Meta::new(&body[..]).num_buckets())
+    }
+
+    // インデックスを構成する全てのページのID
+    pub fn page_ids(&self, bufmgr: &BufferPoolManager) -> Result<Vec<PageId>, Error> {
+        let mut page_ids = vec![self.meta_page_id];
+        let meta_latch = bufmgr.fetch_page_shared(self.meta_page_id)?;
+        let body = meta_latch.body();
+        let meta = meta::Meta::new(&body[..]);
+        for idx in 0..meta.num_buckets() {
+            page_ids.extend(chain_page_ids(
+                bufmgr,
+                directory_entry(bufmgr, &meta, idx)?,
+            )?);
+        }
+        let num_directory_pages = meta.header.num_directory_pages as usize;
+        page_ids.extend_from_slice(&meta.directory_page_ids[..num_directory_pages]);
+        Ok(page_ids)
+    }
+
+    // インデックスを構成する全てのページを解放する
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<(), Error> {
+        for page_id in self.page_ids(bufmgr)? {
+            bufmgr.delete_page(page_id)?;
+        }
+        Ok(())
+    }
+}
+
+// バケットの鎖を構成するページのID
+fn chain_page_ids(bufmgr: &BufferPoolManager, head_page_id: PageId) -> Result<Vec<PageId>, Error> {
+    let mut page_ids = vec![];
+    let mut page_id = Some(head_page_id);
+    while let Some(current_page_id) = page_id {
+        page_ids.push(current_page_id);
+        let latch = bufmgr.fetch_page_shared(current_page_id)?;
+        let body = latch.body();
+        page_id = bucket::Bucket::new(&body[..]).next_page_id();
+    }
+    Ok(page_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use crate::{buffer::BufferPool, disk::DiskManager};
+
+    use super::*;
+
+    fn bufmgr(pool_size: usize) -> BufferPoolManager {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        BufferPoolManager::new(disk, BufferPool::new(pool_size))
+    }
+
+    // 各バケットの鎖の長さ
+    fn chain_lengths(bufmgr: &BufferPoolManager, index: &HashIndex) -> Vec<usize> {
+        let meta_latch = bufmgr.fetch_page_shared(index.meta_page_id).unwrap();
+        let body = meta_latch.body();
+        let meta = meta::Meta::new(&body[..]);
+        (0..meta.num_buckets())
+            .map(|idx| {
+                let head_page_id = directory_entry(bufmgr, &meta, idx).unwrap();
+                chain_page_ids(bufmgr, head_page_id).unwrap().len()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test() {
+        let bufmgr = bufmgr(10);
+        let index = HashIndex::create(&bufmgr).unwrap();
+        index.insert(&bufmgr, b"Smith", b"z").unwrap();
+        index.insert(&bufmgr, b"Johnson", b"x").unwrap();
+        index.insert(&bufmgr, b"Williams", b"y").unwrap();
+        assert!(matches!(
+            index.insert(&bufmgr, b"Smith", b"w"),
+            Err(Error::DuplicateKey)
+        ));
+
+        assert_eq!(
+            Some(b"z".to_vec()),
+            index.lookup(&bufmgr, b"Smith").unwrap()
+        );
+        assert_eq!(
+            Some(b"y".to_vec()),
+            index.lookup(&bufmgr, b"Williams").unwrap()
+        );
+        assert_eq!(None, index.lookup(&bufmgr, b"Miller").unwrap());
+
+        index.delete(&bufmgr, b"Smith").unwrap();
+        assert_eq!(None, index.lookup(&bufmgr, b"Smith").unwrap());
+        assert!(matches!(
+            index.delete(&bufmgr, b"Smith"),
+            Err(Error::KeyNotFound)
+        ));
+        assert_eq!(
+            Some(b"x".to_vec()),
+            index.lookup(&bufmgr, b"Johnson").unwrap()
+        );
+
+        assert!(matches!(
+            index.insert(&bufmgr, &[0; MAX_PAIR_SIZE], b""),
+            Err(Error::PairTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_upsert() {
+        let bufmgr = bufmgr(10);
+        let index = HashIndex::create(&bufmgr).unwrap();
+        for i in 0..1000u64 {
+            index.upsert(&bufmgr, &i.to_be_bytes(), b"old").unwrap();
+        }
+        // 置き換えても使用量は増えないので、分割は進まない
+        let num_buckets = index.num_buckets(&bufmgr).unwrap();
+        for i in 0..1000u64 {
+            index.upsert(&bufmgr, &i.to_be_bytes(), b"new").unwrap();
+        }
+        assert_eq!(num_buckets, index.num_buckets(&bufmgr).unwrap());
+
+        let mut pairs = vec![];
+        index
+            .for_each(&bufmgr, |key, value| {
+                pairs.push((key.to_vec(), value.to_vec()));
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        pairs.sort();
+        let expected: Vec<_> = (0..1000u64)
+            .map(|i| (i.to_be_bytes().to_vec(), b"new".to_vec()))
+            .collect();
+        assert_eq!(expected, pairs);
+    }
+
+    #[test]
+    fn test_split() {
+        let bufmgr = bufmgr(10);
+        let index = HashIndex::create(&bufmgr).unwrap();
+        let key = |i: u64| i.to_be_bytes();
+        let value = |i: u64| format!("{:0100}", i * 3).into_bytes();
+        for i in 0..20_000u64 {
+            index.insert(&bufmgr, &key(i), &value(i)).unwrap();
+        }
+        // ディレクトリが2ページ目にまたがるまで分割される
+        let num_buckets = index.num_buckets(&bufmgr).unwrap();
+        assert!(num_buckets > ENTRIES_PER_PAGE as u64, "{}", num_buckets);
+        for i in (0..20_000u64).step_by(2) {
+            index.delete(&bufmgr, &key(i)).unwrap();
+        }
+        for i in 0..20_000u64 {
+            let expected = if i % 2 == 1 { Some(value(i)) } else { None };
+            assert_eq!(expected, index.lookup(&bufmgr, &key(i)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_directory_limit() {
+        let bufmgr = bufmgr(10);
+        let index = HashIndex::create(&bufmgr).unwrap();
+        let meta_latch = bufmgr.fetch_page_exclusive(index.meta_page_id).unwrap();
+        let mut body = meta_latch.body_mut();
+        let mut meta = meta::Meta::new(&mut body[..]);
+        assert!(meta.can_split());
+        // 次に作るバケットがディレクトリの最後のエントリまでなら分割できる
+        let max_buckets = (meta::MAX_DIRECTORY_PAGES * ENTRIES_PER_PAGE) as u64;
+        let level = 63 - (max_buckets - 1).leading_zeros() as u64;
+        meta.header.level = level;
+        meta.header.next_split = max_buckets - 1 - (1 << level);
+        assert!(meta.can_split());
+        // それを超えるなら、分割のミニトランザクションを始めない
+        meta.header.next_split += 1;
+        assert!(!meta.can_split());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let bufmgr = bufmgr(10);
+        let index = HashIndex::create(&bufmgr).unwrap();
+        // 1ページに4つしか入らない大きさのペアで、あふれるバケットを作る
+        let value = vec![0xab; MAX_PAIR_SIZE - 32];
+        for i in 0..500u64 {
+            index.insert(&bufmgr, &i.to_be_bytes(), &value).unwrap();
+        }
+        assert!(chain_lengths(&bufmgr, &index).iter().any(|&len| len > 1));
+        for i in 0..500u64 {
+            assert_eq!(
+                Some(value.clone()),
+                index.lookup(&bufmgr, &i.to_be_bytes()).unwrap()
+            );
+        }
+
+        // 空になったオーバーフローバケットは解放され、鎖は先頭のページだけに戻る
+        for i in 0..500u64 {
+            index.delete(&bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        assert!(chain_lengths(&bufmgr, &index).iter().all(|&len| len == 1));
+
+        index.destroy(&bufmgr).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
+
+use crate::btree::Pair;
+use crate::disk::{PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::slotted::{self, Slotted};
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    // 溢れたペアを入れるオーバーフローバケット
+    next_page_id: PageId,
+}
+
+// ペアを順不同に詰めたページ。溢れたら次のページへのリンクでつなぐ
+pub struct Bucket<B> {
+    header: LayoutVerified<B, Header>,
+    body: Slotted<B>,
+}
+
+// 1ページに入るペアとスロットの大きさ
+pub const CAPACITY: usize =
+    PAGE_SIZE - PAGE_HEADER_SIZE - size_of::<Header>() - size_of::<slotted::Header>();
+
+// ペアを置くのに使う大きさ
+pub fn pair_size(pair_bytes: &[u8]) -> usize {
+    pair_bytes.len() + size_of::<slotted::Pointer>()
+}
+
+impl<B: ByteSlice> Bucket<B> {
+    pub fn new(bytes: B) -> Self {
+        let (header, body) =
+            LayoutVerified::new_from_prefix(bytes).expect("bucket header must be aligned");
+        let body = Slotted::new(body);
+        Self { header, body }
+    }
+
+    pub fn next_page_id(&self) -> Option<PageId> {
+        self.header.next_page_id.valid()
+    }
+
+    pub fn num_pairs(&self) -> usize {
+        self.body.num_slots()
+    }
+
+    pub fn pair_at(&self, slot_id: usize) -> Pair<'_> {
+        Pair::from_bytes(&self.body[slot_id])
+    }
+
+    pub fn pair_bytes_at(&self, slot_id: usize) -> &[u8] {
+        &self.body[slot_id]
+    }
+
+    pub fn search_slot_id(&self, key: &[u8]) -> Option<usize> {
+        (0..self.num_pairs()).find(|&slot_id| self.pair_at(slot_id).key == key)
+    }
+}
+
+impl<B: ByteSliceMut> Bucket<B> {
+    pub fn initialize(&mut self) {
+        self.header.next_page_id = PageId::INVALID_PAGE_ID;
+        self.body.initialize();
+    }
+
+    pub fn set_next_page_id(&mut self, next_page_id: Option<PageId>) {
+        self.header.next_page_id = next_page_id.into();
+    }
+
+    #[must_use = "insertion may fail"]
+    pub fn insert(&mut self, pair_bytes: &[u8]) -> Option<()> {
+        let slot_id = self.num_pairs();
+        self.body.insert(slot_id, pair_bytes.len())?;
+        self.body[slot_id].copy_from_slice(pair_bytes);
+        Some(())
+    }
+
+    pub fn remove(&mut self, slot_id: usize) {
+        self.body.remove(slot_id);
+    }
+}
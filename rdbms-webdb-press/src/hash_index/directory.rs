@@ -0,0 +1,21 @@
+use std::mem::size_of;
+
+use zerocopy::{ByteSlice, LayoutVerified};
+
+use crate::disk::{PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+
+// 1ページに並べるバケットのページID
+pub const ENTRIES_PER_PAGE: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) / size_of::<PageId>();
+
+// バケット番号からバケットの先頭ページのIDを引く表
+pub struct Directory<B> {
+    pub entries: LayoutVerified<B, [PageId]>,
+}
+
+impl<B: ByteSlice> Directory<B> {
+    pub fn new(bytes: B) -> Self {
+        let (bytes, _) = bytes.split_at(ENTRIES_PER_PAGE * size_of::<PageId>());
+        let entries = LayoutVerified::new_slice(bytes).expect("directory page must be aligned");
+        Self { entries }
+    }
+}
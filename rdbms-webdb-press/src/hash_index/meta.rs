@@ -0,0 +1,44 @@
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified};
+
+use crate::disk::{PageId, PAGE_HEADER_SIZE, PAGE_SIZE};
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    // バケットの数は 2^level + next_split
+    pub level: u64,
+    // 次に分割するバケットの番号
+    pub next_split: u64,
+    // バケットに入っているペアとスロットの大きさの合計
+    pub num_bytes: u64,
+    pub num_directory_pages: u64,
+}
+
+// ディレクトリページのIDはメタページに並べる
+pub const MAX_DIRECTORY_PAGES: usize =
+    (PAGE_SIZE - PAGE_HEADER_SIZE - size_of::<Header>()) / size_of::<PageId>();
+
+pub struct Meta<B> {
+    pub header: LayoutVerified<B, Header>,
+    pub directory_page_ids: LayoutVerified<B, [PageId]>,
+}
+
+impl<B: ByteSlice> Meta<B> {
+    pub fn new(bytes: B) -> Self {
+        let (header, body) =
+            LayoutVerified::new_from_prefix(bytes).expect("meta page must be aligned");
+        let (body, _) = body.split_at(MAX_DIRECTORY_PAGES * size_of::<PageId>());
+        let directory_page_ids =
+            LayoutVerified::new_slice(body).expect("directory page ids must be aligned");
+        Self {
+            header,
+            directory_page_ids,
+        }
+    }
+
+    pub fn num_buckets(&self) -> u64 {
+        (1 << self.header.level) + self.header.next_split
+    }
+}
@@ -0,0 +1,280 @@
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // 共有ロックを持っているスレッドと、それぞれが重ねて取った数
+    readers: Vec<(ThreadId, usize)>,
+    writer: Option<ThreadId>,
+    // 同じスレッドが重ねて取った排他ロックの数
+    depth: usize,
+    // 排他ロックを外した後も、他のスレッドの排他ロックを待たせているスレッド
+    retainer: Option<ThreadId>,
+    // 排他ロックを待っているスレッドの数。いる間は新しく共有ロックを取らせない
+    waiting_writers: usize,
+}
+
+impl State {
+    fn holds_shared(&self, me: ThreadId) -> bool {
+        self.readers.iter().any(|&(reader, _)| reader == me)
+    }
+
+    // 既に共有ロックを持っているスレッドは、排他ロックを待つスレッドがいても重ねて取れる
+    // 排他ロックを待つスレッドが retainer を待っている間は、共有ロックを後回しにしない
+    fn can_lock(&self, mode: Mode, me: ThreadId) -> bool {
+        match (self.writer, mode) {
+            (Some(writer), _) => writer == me,
+            (None, Mode::Shared) => {
+                self.waiting_writers == 0 || self.retainer.is_some() || self.holds_shared(me)
+            }
+            (None, Mode::Exclusive) => {
+                self.readers.is_empty() && self.retainer.is_none_or(|retainer| retainer == me)
+            }
+        }
+    }
+
+    fn lock_shared(&mut self, me: ThreadId) {
+        match self.readers.iter_mut().find(|(reader, _)| *reader == me) {
+            Some((_, count)) => *count += 1,
+            None => self.readers.push((me, 1)),
+        }
+    }
+
+    fn unlock_shared(&mut self, me: ThreadId) {
+        let i = self
+            .readers
+            .iter()
+            .position(|&(reader, _)| reader == me)
+            .expect("latch must be held shared by this thread");
+        self.readers[i].1 -= 1;
+        if self.readers[i].1 == 0 {
+            self.readers.swap_remove(i);
+        }
+    }
+
+    // 排他ロックを持っているスレッドは、共有ロックを取ろうとしても排他ロックを重ねて取る
+    fn lock(&mut self, mode: Mode, me: ThreadId) -> Mode {
+        if self.writer.is_none() && mode == Mode::Shared {
+            self.lock_shared(me);
+            Mode::Shared
+        } else {
+            self.writer = Some(me);
+            self.depth += 1;
+            Mode::Exclusive
+        }
+    }
+}
+
+// ページ単位の読み書きラッチ
+// ラッチはルートから葉へ向かう順に取るので、待ち合ってデッドロックすることはない
+// 共有ロックを持ったまま排他ロックに取り直すと他のスレッドと待ち合うので、待たずに断る
+// 排他ロックを待っている間は新しい共有ロックを後回しにするので、読み込みが続いても書き込みが飢えない
+#[derive(Debug, Default)]
+pub struct Latch {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Latch {
+    // 共有ロックを持っているスレッドが排他ロックを取ろうとしたら、待たずに None を返す
+    pub fn lock(&self, mode: Mode) -> Option<Mode> {
+        let me = thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        if !state.can_lock(mode, me) {
+            if mode == Mode::Exclusive {
+                if state.holds_shared(me) {
+                    return None;
+                }
+                state.waiting_writers += 1;
+            }
+            while !state.can_lock(mode, me) {
+                state = self.cond.wait(state).unwrap();
+            }
+            if mode == Mode::Exclusive {
+                state.waiting_writers -= 1;
+            }
+        }
+        Some(state.lock(mode, me))
+    }
+
+    pub fn try_lock(&self, mode: Mode) -> Option<Mode> {
+        let me = thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        if !state.can_lock(mode, me) {
+            return None;
+        }
+        Some(state.lock(mode, me))
+    }
+
+    // lock が返したモードを渡す
+    pub fn unlock(&self, mode: Mode) {
+        let me = thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        match mode {
+            Mode::Shared => {
+                state.unlock_shared(me);
+                if !state.readers.is_empty() {
+                    return;
+                }
+            }
+            Mode::Exclusive => {
+                debug_assert_eq!(Some(me), state.writer);
+                state.depth -= 1;
+                if state.depth > 0 {
+                    return;
+                }
+                state.writer = None;
+            }
+        }
+        self.cond.notify_all();
+    }
+
+    // 排他ロックを持っている間に呼ぶ。外した後も release するまで、他のスレッドは共有ロックしか取れない
+    pub fn retain(&self) {
+        let me = thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        debug_assert_eq!(Some(me), state.writer);
+        state.retainer = Some(me);
+    }
+
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        debug_assert_eq!(Some(thread::current().id()), state.retainer);
+        state.retainer = None;
+        self.cond.notify_all();
+    }
+
+    // どのスレッドも排他ロックを持っておらず、retain もされていなければ共有ロックを取る
+    // 自分が排他ロックを持っている場合も失敗する
+    pub fn try_lock_shared_strict(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.writer.is_some() || state.retainer.is_some() {
+            return false;
+        }
+        state.lock_shared(thread::current().id());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test() {
+        let latch = Latch::default();
+        assert_eq!(Some(Mode::Shared), latch.lock(Mode::Shared));
+        assert_eq!(Some(Mode::Shared), latch.try_lock(Mode::Shared));
+        assert_eq!(None, latch.try_lock(Mode::Exclusive));
+        latch.unlock(Mode::Shared);
+        latch.unlock(Mode::Shared);
+
+        // 排他ロックは同じスレッドから重ねて取れる
+        assert_eq!(Some(Mode::Exclusive), latch.lock(Mode::Exclusive));
+        assert_eq!(Some(Mode::Exclusive), latch.lock(Mode::Shared));
+        assert!(!latch.try_lock_shared_strict());
+        let latch = Arc::new(latch);
+        {
+            let latch = latch.clone();
+            let other = thread::spawn(move || latch.try_lock(Mode::Shared));
+            assert_eq!(None, other.join().unwrap());
+        }
+        latch.unlock(Mode::Exclusive);
+        latch.unlock(Mode::Exclusive);
+
+        let other = {
+            let latch = latch.clone();
+            thread::spawn(move || {
+                let mode = latch.lock(Mode::Exclusive).unwrap();
+                latch.unlock(mode);
+            })
+        };
+        other.join().unwrap();
+        assert!(latch.try_lock_shared_strict());
+        latch.unlock(Mode::Shared);
+    }
+
+    #[test]
+    fn test_writer_preference() {
+        let latch = Arc::new(Latch::default());
+        assert_eq!(Some(Mode::Shared), latch.lock(Mode::Shared));
+        let writer = {
+            let latch = latch.clone();
+            thread::spawn(move || {
+                let mode = latch.lock(Mode::Exclusive).unwrap();
+                latch.unlock(mode);
+            })
+        };
+        while latch.state.lock().unwrap().waiting_writers == 0 {
+            thread::yield_now();
+        }
+
+        // 排他ロックを待っているスレッドがいれば、他のスレッドは新しく共有ロックを取れない
+        {
+            let latch = latch.clone();
+            let other = thread::spawn(move || latch.try_lock(Mode::Shared));
+            assert_eq!(None, other.join().unwrap());
+        }
+        // 既に持っているスレッドは重ねて取れる
+        assert_eq!(Some(Mode::Shared), latch.try_lock(Mode::Shared));
+        latch.unlock(Mode::Shared);
+        latch.unlock(Mode::Shared);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let latch = Latch::default();
+        assert_eq!(Some(Mode::Shared), latch.lock(Mode::Shared));
+        // 取り直しは待たずに断り、持っている共有ロックはそのまま残る
+        assert_eq!(None, latch.lock(Mode::Exclusive));
+        assert_eq!(Some(Mode::Shared), latch.lock(Mode::Shared));
+        latch.unlock(Mode::Shared);
+        latch.unlock(Mode::Shared);
+        assert_eq!(Some(Mode::Exclusive), latch.lock(Mode::Exclusive));
+        latch.unlock(Mode::Exclusive);
+    }
+
+    #[test]
+    fn test_retain() {
+        let latch = Arc::new(Latch::default());
+        assert_eq!(Some(Mode::Exclusive), latch.lock(Mode::Exclusive));
+        latch.retain();
+        latch.unlock(Mode::Exclusive);
+        assert!(!latch.try_lock_shared_strict());
+
+        let writer = {
+            let latch = latch.clone();
+            thread::spawn(move || {
+                let mode = latch.lock(Mode::Exclusive).unwrap();
+                latch.unlock(mode);
+            })
+        };
+        while latch.state.lock().unwrap().waiting_writers == 0 {
+            thread::yield_now();
+        }
+        // retain している間、他のスレッドは読めるが書けない
+        {
+            let latch = latch.clone();
+            let other = thread::spawn(move || {
+                let shared = latch.try_lock(Mode::Shared);
+                latch.unlock(Mode::Shared);
+                (shared, latch.try_lock(Mode::Exclusive))
+            });
+            assert_eq!((Some(Mode::Shared), None), other.join().unwrap());
+        }
+        // retain したスレッドは排他ロックを取り直せる
+        assert_eq!(Some(Mode::Exclusive), latch.try_lock(Mode::Exclusive));
+        latch.unlock(Mode::Exclusive);
+        latch.release();
+        writer.join().unwrap();
+    }
+}
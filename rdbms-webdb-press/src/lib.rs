@@ -1,9 +1,20 @@
 mod bsearch;
 pub mod btree;
 pub mod buffer;
+pub mod catalog;
+pub mod check;
 pub mod disk;
+pub mod hash_index;
+mod latch;
 mod memcmpable;
+pub mod mvcc;
 pub mod query;
+pub mod server;
 mod slotted;
+pub mod sql;
+pub mod stats;
 pub mod table;
+pub mod transaction;
 pub mod tuple;
+pub mod value;
+pub mod wal;
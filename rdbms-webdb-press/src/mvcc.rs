@@ -0,0 +1,1119 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use anyhow::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::btree::{self, BTree, SearchMode};
+use crate::buffer::BufferPoolManager;
+use crate::disk::PageId;
+use crate::hash_index::HashIndex;
+
+pub type TxnId = u64;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("transaction {0} conflicts with a concurrent update")]
+    WriteConflict(TxnId),
+}
+
+// コミットログに記録するトランザクションの状態
+const IN_PROGRESS: u8 = 0;
+const COMMITTED: u8 = 1;
+const ABORTED: u8 = 2;
+
+// コミットログの値は、状態の後に版を書いた B+Tree のメタページIDの列を続けたもの
+// 取り消したトランザクションの版がどの B+Tree に残っているか、開き直した後も分かるようにする
+fn encode_status(status: u8, btrees: &HashSet<PageId>) -> Vec<u8> {
+    let btrees: Vec<_> = btrees.iter().copied().collect();
+    let mut value = vec![status];
+    value.extend(bincode::options().serialize(&btrees).unwrap());
+    value
+}
+
+// 状態だけの値は、B+Tree を記録する前に書かれたもので、どこに版を書いたか分からない
+fn decode_status(value: &[u8]) -> Result<(u8, Option<HashSet<PageId>>)> {
+    match value {
+        [status] => Ok((*status, None)),
+        [status, btrees @ ..] => {
+            let btrees: Vec<PageId> = bincode::options().deserialize(btrees)?;
+            Ok((*status, Some(btrees.into_iter().collect())))
+        }
+        [] => Err(anyhow::anyhow!("empty commit log entry")),
+    }
+}
+
+// 行やインデックスのエントリの1つの版
+// xmin は作ったトランザクション、xmax は削除したトランザクション
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub xmin: TxnId,
+    pub xmax: Option<TxnId>,
+    pub data: Vec<u8>,
+}
+
+// 索引の値には、同じキーの版を新しい順に並べたものを入れる
+pub fn encode_versions(versions: &[Version]) -> Vec<u8> {
+    bincode::options().serialize(versions).unwrap()
+}
+
+pub fn decode_versions(bytes: &[u8]) -> Result<Vec<Version>> {
+    Ok(bincode::options().deserialize(bytes)?)
+}
+
+// VersionStore::for_each に渡す、キーと値を受け取る関数
+pub type VisitPair<'a> = dyn FnMut(&[u8], &[u8]) -> Result<()> + 'a;
+
+// 版を付けて書く索引。B+Tree とハッシュインデックスがある
+// メタページIDで区別し、コミットログにも書いた索引をメタページIDで記録する
+// インデックスを作る時は、版を付けないテーブルのエントリもこれを通して入れる
+pub trait VersionStore {
+    fn meta_page_id(&self) -> PageId;
+    fn get(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    // キーがあれば btree::Error::DuplicateKey を返す
+    fn insert(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()>;
+    // キーがあれば値を置き換え、なければ挿入する
+    fn put(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()>;
+    fn remove(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<()>;
+    // 全てのペアを f に渡す。f の中で索引を書き換えてはいけない
+    fn for_each(&self, bufmgr: &BufferPoolManager, f: &mut VisitPair<'_>) -> Result<()>;
+}
+
+impl VersionStore for BTree {
+    fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    fn get(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut iter = self.search(bufmgr, SearchMode::Key(key.to_vec()))?;
+        match iter.next(bufmgr)? {
+            Some((found, value)) if found == key => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    fn insert(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()> {
+        Ok(BTree::insert(self, bufmgr, key, value)?)
+    }
+
+    fn put(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()> {
+        Ok(self.upsert(bufmgr, key, value)?)
+    }
+
+    fn remove(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<()> {
+        self.delete(bufmgr, key)?;
+        Ok(())
+    }
+
+    fn for_each(&self, bufmgr: &BufferPoolManager, f: &mut VisitPair<'_>) -> Result<()> {
+        let mut iter = self.search(bufmgr, SearchMode::Start)?;
+        while let Some((key, value)) = iter.next(bufmgr)? {
+            f(&key, &value)?;
+        }
+        Ok(())
+    }
+}
+
+impl VersionStore for HashIndex {
+    fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    fn get(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(bufmgr, key)?)
+    }
+
+    // 重複したキーも B+Tree と同じエラーにする
+    fn insert(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()> {
+        HashIndex::insert(self, bufmgr, key, value).map_err(btree::Error::from)?;
+        Ok(())
+    }
+
+    fn put(&self, bufmgr: &BufferPoolManager, key: &[u8], value: &[u8]) -> Result<()> {
+        Ok(self.upsert(bufmgr, key, value)?)
+    }
+
+    fn remove(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<()> {
+        Ok(self.delete(bufmgr, key)?)
+    }
+
+    fn for_each(&self, bufmgr: &BufferPoolManager, f: &mut VisitPair<'_>) -> Result<()> {
+        HashIndex::for_each(self, bufmgr, f)
+    }
+}
+
+// ある時点でコミット済みだったトランザクションの集合
+// 持っている間は、見える版を vacuum が取り除かない。drop すると手放す
+pub struct Snapshot {
+    // 自分の変更は常に見える
+    own: Option<TxnId>,
+    // これより前のトランザクションは、active に含まれない
+    xmin: TxnId,
+    // これ以降に始まったトランザクションは見えない
+    xmax: TxnId,
+    active: BTreeSet<TxnId>,
+    aborted: Arc<BTreeSet<TxnId>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .snapshots
+            .entry(self.xmin)
+            .or_default() += 1;
+        Self {
+            own: self.own,
+            xmin: self.xmin,
+            xmax: self.xmax,
+            active: self.active.clone(),
+            aborted: self.aborted.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.release(self.xmin);
+        }
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("own", &self.own)
+            .field("xmin", &self.xmin)
+            .field("xmax", &self.xmax)
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl Snapshot {
+    // txn_id の変更が見えるかどうか
+    pub fn sees(&self, txn_id: TxnId) -> bool {
+        if self.own == Some(txn_id) {
+            return true;
+        }
+        txn_id < self.xmax && !self.active.contains(&txn_id) && !self.aborted.contains(&txn_id)
+    }
+
+    pub fn is_visible(&self, version: &Version) -> bool {
+        self.sees(version.xmin) && !version.xmax.is_some_and(|xmax| self.sees(xmax))
+    }
+
+    // 見える版は多くとも1つ
+    pub fn visible<'v>(&self, versions: &'v [Version]) -> Option<&'v Version> {
+        versions.iter().find(|version| self.is_visible(version))
+    }
+
+    // 取った時点で取り消されていたトランザクション
+    pub fn is_aborted(&self, txn_id: TxnId) -> bool {
+        self.aborted.contains(&txn_id)
+    }
+}
+
+// EXPLAIN で表示する。xmin から xmax の手前までのうち、active のトランザクション以外が見える
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}..{}", self.xmin, self.xmax)?;
+        if !self.active.is_empty() {
+            let active: Vec<_> = self.active.iter().map(ToString::to_string).collect();
+            write!(f, " active {{{}}}", active.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VacuumStats {
+    pub removed_versions: usize,
+    pub removed_entries: usize,
+}
+
+impl std::ops::AddAssign for VacuumStats {
+    fn add_assign(&mut self, other: Self) {
+        self.removed_versions += other.removed_versions;
+        self.removed_entries += other.removed_entries;
+    }
+}
+
+struct State {
+    next_txn_id: TxnId,
+    active: BTreeSet<TxnId>,
+    // 取り消したトランザクション。スナップショットと共有し、変わる時だけ複製する
+    aborted: Arc<BTreeSet<TxnId>>,
+    // 取り消したトランザクションごとの、まだ vacuum していない版を書いた B+Tree
+    // 全て vacuum し終えたら、aborted からもIDを忘れる
+    // None は書いた B+Tree が分からないもので、vacuum_all を終えるまで忘れない
+    unvacuumed: HashMap<TxnId, Option<HashSet<PageId>>>,
+    // 使用中のスナップショットの xmin ごとの数
+    snapshots: BTreeMap<TxnId, usize>,
+}
+
+impl State {
+    fn release(&mut self, xmin: TxnId) {
+        let count = self
+            .snapshots
+            .get_mut(&xmin)
+            .expect("snapshot already released");
+        *count -= 1;
+        if *count == 0 {
+            self.snapshots.remove(&xmin);
+        }
+    }
+
+    // これより前にコミットしたトランザクションの削除は、どのスナップショットからも見える
+    fn horizon(&self) -> TxnId {
+        let oldest_snapshot = self.snapshots.keys().next().copied();
+        let oldest_active = self.active.iter().next().copied();
+        oldest_snapshot
+            .into_iter()
+            .chain(oldest_active)
+            .fold(self.next_txn_id, TxnId::min)
+    }
+}
+
+// 版を読んでから書くまでの間に、他のトランザクションや vacuum が同じキーを書かないようにする
+// 書き終えたら手放すので、トランザクションの終わりまでは持たない
+#[derive(Default)]
+struct KeyLocks {
+    locked: Mutex<HashSet<(PageId, Vec<u8>)>>,
+    unlocked: Condvar,
+}
+
+impl KeyLocks {
+    fn lock(&self, store: &dyn VersionStore, key: &[u8]) -> KeyLock<'_> {
+        let key = (store.meta_page_id(), key.to_vec());
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains(&key) {
+            locked = self.unlocked.wait(locked).unwrap();
+        }
+        locked.insert(key.clone());
+        KeyLock { locks: self, key }
+    }
+}
+
+struct KeyLock<'a> {
+    locks: &'a KeyLocks,
+    key: (PageId, Vec<u8>),
+}
+
+impl Drop for KeyLock<'_> {
+    fn drop(&mut self) {
+        self.locks.locked.lock().unwrap().remove(&self.key);
+        self.locks.unlocked.notify_all();
+    }
+}
+
+// トランザクションIDを配り、それぞれの状態をコミットログ (B+Tree) に記録する
+// 版を書くのはキーごとに排他して行うが、トランザクション全体を排他するわけではないので、
+// 違うキーは並行して書け、読み取りは書き込みを待たず、スナップショットで見える版だけを読む
+// transaction::Transaction の中で使うことは想定していない
+pub struct TxnManager {
+    pub meta_page_id: PageId,
+    // スナップショットも持ち、drop する時に手放す
+    state: Arc<Mutex<State>>,
+    key_locks: KeyLocks,
+}
+
+impl TxnManager {
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self> {
+        let btree = BTree::create(bufmgr)?;
+        Ok(Self::new(btree.meta_page_id, 1, HashMap::new()))
+    }
+
+    // 終わらないまま落ちたトランザクションは取り消したものとして扱う
+    // コミットログから消したトランザクションはコミットしたもの
+    // 版を1つも書いていなければ、取り除くものもないので覚えておかない
+    pub fn open(bufmgr: &BufferPoolManager, meta_page_id: PageId) -> Result<Self> {
+        let btree = BTree::new(meta_page_id);
+        let mut iter = btree.search(bufmgr, SearchMode::Start)?;
+        let mut next_txn_id = 1;
+        let mut unvacuumed = HashMap::new();
+        while let Some((key, value)) = iter.next(bufmgr)? {
+            let txn_id = TxnId::from_be_bytes(key[..].try_into()?);
+            match decode_status(&value)? {
+                (COMMITTED, _) => {}
+                (_, Some(btrees)) if btrees.is_empty() => {}
+                (_, btrees) => {
+                    unvacuumed.insert(txn_id, btrees);
+                }
+            }
+            next_txn_id = txn_id + 1;
+        }
+        Ok(Self::new(meta_page_id, next_txn_id, unvacuumed))
+    }
+
+    fn new(
+        meta_page_id: PageId,
+        next_txn_id: TxnId,
+        unvacuumed: HashMap<TxnId, Option<HashSet<PageId>>>,
+    ) -> Self {
+        Self {
+            meta_page_id,
+            state: Arc::new(Mutex::new(State {
+                next_txn_id,
+                active: BTreeSet::new(),
+                aborted: Arc::new(unvacuumed.keys().copied().collect()),
+                unvacuumed,
+                snapshots: BTreeMap::new(),
+            })),
+            key_locks: KeyLocks::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn take_snapshot(&self, state: &mut State, own: Option<TxnId>) -> Snapshot {
+        let active: BTreeSet<_> = state
+            .active
+            .iter()
+            .copied()
+            .filter(|&txn_id| Some(txn_id) != own)
+            .collect();
+        let xmin = active.iter().next().copied().unwrap_or(state.next_txn_id);
+        *state.snapshots.entry(xmin).or_default() += 1;
+        Snapshot {
+            own,
+            xmin,
+            xmax: state.next_txn_id,
+            active,
+            aborted: state.aborted.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn begin(&self, bufmgr: &BufferPoolManager) -> Result<Txn<'_>> {
+        let (id, snapshot) = {
+            let mut state = self.state();
+            let id = state.next_txn_id;
+            state.next_txn_id += 1;
+            state.active.insert(id);
+            (id, self.take_snapshot(&mut state, Some(id)))
+        };
+        // 記録できなければ、何も書いていないまま drop して取り消す
+        let txn = Txn {
+            txns: self,
+            id,
+            snapshot,
+            written: Mutex::new(HashSet::new()),
+            finished: false,
+        };
+        self.set_status(bufmgr, id, IN_PROGRESS, &HashSet::new())?;
+        Ok(txn)
+    }
+
+    // 読み取りだけのクエリに使うスナップショット
+    pub fn snapshot(&self) -> Snapshot {
+        let mut state = self.state();
+        self.take_snapshot(&mut state, None)
+    }
+
+    fn set_status(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn_id: TxnId,
+        status: u8,
+        btrees: &HashSet<PageId>,
+    ) -> Result<()> {
+        let value = encode_status(status, btrees);
+        BTree::new(self.meta_page_id).upsert(bufmgr, &txn_id.to_be_bytes(), &value)?;
+        Ok(())
+    }
+
+    // 誰からも見えなくなった版を取り除き、版がなくなったエントリを削除する
+    pub fn vacuum(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+    ) -> Result<VacuumStats> {
+        let (horizon, aborted) = {
+            let state = self.state();
+            (state.horizon(), state.aborted.clone())
+        };
+        let stats = self.vacuum_with(bufmgr, store, horizon, &aborted)?;
+        self.trim_commit_log(bufmgr)?;
+        Ok(stats)
+    }
+
+    // 版を付けて書いた全ての索引を vacuum する
+    // その後に作られた索引も含むように、索引は始めてから stores で集める
+    pub fn vacuum_all(
+        &self,
+        bufmgr: &BufferPoolManager,
+        stores: impl FnOnce() -> Result<Vec<Box<dyn VersionStore>>>,
+    ) -> Result<VacuumStats> {
+        let (horizon, aborted) = {
+            let state = self.state();
+            (state.horizon(), state.aborted.clone())
+        };
+        let mut stats = VacuumStats::default();
+        for store in stores()? {
+            stats += self.vacuum_with(bufmgr, store.as_ref(), horizon, &aborted)?;
+        }
+        self.forget_aborted(None, &aborted);
+        self.trim_commit_log(bufmgr)?;
+        Ok(stats)
+    }
+
+    fn vacuum_with(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+        horizon: TxnId,
+        aborted: &BTreeSet<TxnId>,
+    ) -> Result<VacuumStats> {
+        let mut keys = vec![];
+        store.for_each(bufmgr, &mut |key, value| {
+            let versions = decode_versions(value)?;
+            if prune(versions.clone(), horizon, aborted) != versions {
+                keys.push(key.to_vec());
+            }
+            Ok(())
+        })?;
+
+        // 読んでから今までに書き換えられているかもしれないので、読み直してから書く
+        let mut stats = VacuumStats::default();
+        for key in keys {
+            let _lock = self.key_locks.lock(store, &key);
+            let versions = match read_versions(bufmgr, store, &key)? {
+                Some(versions) => versions,
+                None => continue,
+            };
+            let num_versions = versions.len();
+            let versions = prune(versions, horizon, aborted);
+            stats.removed_versions += num_versions - versions.len();
+            if versions.is_empty() {
+                store.remove(bufmgr, &key)?;
+                stats.removed_entries += 1;
+            } else {
+                store.put(bufmgr, &key, &encode_versions(&versions))?;
+            }
+        }
+        self.forget_aborted(Some(store), aborted);
+        Ok(stats)
+    }
+
+    // horizon より前に終わったトランザクションは、覚えている取り消したもの以外は
+    // コミットしたものとして扱えるので、コミットログから消す
+    // 開き直した時に次に配るIDが分かるように、最後のエントリは残す
+    fn trim_commit_log(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        let (end, aborted) = {
+            let state = self.state();
+            let last_txn_id = state.next_txn_id - 1;
+            (state.horizon().min(last_txn_id), state.aborted.clone())
+        };
+        let btree = BTree::new(self.meta_page_id);
+        let mut txn_ids = vec![];
+        let mut iter = btree.search(bufmgr, SearchMode::Start)?;
+        while let Some((key, _)) = iter.next(bufmgr)? {
+            let txn_id = TxnId::from_be_bytes(key[..].try_into()?);
+            if txn_id >= end {
+                break;
+            }
+            if !aborted.contains(&txn_id) {
+                txn_ids.push(txn_id);
+            }
+        }
+        drop(iter);
+        for txn_id in txn_ids {
+            btree.delete(bufmgr, &txn_id.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    // 始めた時点で取り消されていたトランザクションの版は store にもう残っていない
+    // 版を書いた索引を全て vacuum し終えたトランザクションは、IDを忘れる
+    // store が None なら版を付けた全ての索引を vacuum し終えたので、書いた索引が
+    // 分からないトランザクションも忘れる
+    fn forget_aborted(&self, store: Option<&dyn VersionStore>, aborted: &BTreeSet<TxnId>) {
+        let mut state = self.state();
+        let mut forgotten = BTreeSet::new();
+        for txn_id in aborted {
+            let vacuumed = match (state.unvacuumed.get_mut(txn_id), store) {
+                (Some(Some(btrees)), Some(store)) => {
+                    btrees.remove(&store.meta_page_id());
+                    btrees.is_empty()
+                }
+                (Some(None), None) => true,
+                _ => false,
+            };
+            if vacuumed {
+                state.unvacuumed.remove(txn_id);
+                forgotten.insert(*txn_id);
+            }
+        }
+        if !forgotten.is_empty() {
+            Arc::make_mut(&mut state.aborted).retain(|txn_id| !forgotten.contains(txn_id));
+        }
+    }
+}
+
+// 取り消されたトランザクションが作った版と、horizon より前に削除がコミットされた版を取り除く
+// 取り消されたトランザクションによる削除はなかったことにする
+fn prune(versions: Vec<Version>, horizon: TxnId, aborted: &BTreeSet<TxnId>) -> Vec<Version> {
+    versions
+        .into_iter()
+        .filter(|version| !aborted.contains(&version.xmin))
+        .map(|mut version| {
+            if version.xmax.is_some_and(|xmax| aborted.contains(&xmax)) {
+                version.xmax = None;
+            }
+            version
+        })
+        .filter(|version| version.xmax.is_none_or(|xmax| xmax >= horizon))
+        .collect()
+}
+
+fn read_versions(
+    bufmgr: &BufferPoolManager,
+    store: &dyn VersionStore,
+    key: &[u8],
+) -> Result<Option<Vec<Version>>> {
+    store
+        .get(bufmgr, key)?
+        .map(|value| decode_versions(&value))
+        .transpose()
+}
+
+// スナップショット分離のトランザクション
+// 他のトランザクションが書いたキーを書こうとした時、そのトランザクションが終わっていないか、
+// スナップショットを取った後にコミットしていれば WriteConflict になる。その時は abort する
+// commit も abort もせずに drop すると取り消す
+#[must_use = "a transaction must be committed or aborted"]
+pub struct Txn<'a> {
+    txns: &'a TxnManager,
+    pub id: TxnId,
+    snapshot: Snapshot,
+    // 版を書いた索引のメタページID
+    written: Mutex<HashSet<PageId>>,
+    finished: bool,
+}
+
+impl<'a> Txn<'a> {
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    // 状態をログに書いて永続化してから、他のトランザクションに見えるようにする
+    pub fn commit(mut self, bufmgr: &BufferPoolManager) -> Result<()> {
+        self.txns
+            .set_status(bufmgr, self.id, COMMITTED, &HashSet::new())?;
+        bufmgr.flush_wal()?;
+        self.finish(true);
+        Ok(())
+    }
+
+    // 書いた版はそのまま残し、vacuum で取り除く
+    pub fn abort(mut self, bufmgr: &BufferPoolManager) -> Result<()> {
+        let written = self.written.lock().unwrap().clone();
+        let result = self.txns.set_status(bufmgr, self.id, ABORTED, &written);
+        self.finish(false);
+        result
+    }
+
+    // 版を書かずに取り消したトランザクションは、取り除くものがないので覚えておかない
+    fn finish(&mut self, committed: bool) {
+        self.finished = true;
+        let written = std::mem::take(self.written.get_mut().unwrap());
+        let mut state = self.txns.state();
+        state.active.remove(&self.id);
+        if !committed && !written.is_empty() {
+            Arc::make_mut(&mut state.aborted).insert(self.id);
+            state.unvacuumed.insert(self.id, Some(written));
+        }
+    }
+
+    // 版を書く前に、書く索引をコミットログに記録しておく
+    fn add_written(&self, bufmgr: &BufferPoolManager, store: &dyn VersionStore) -> Result<()> {
+        let mut written = self.written.lock().unwrap();
+        if written.insert(store.meta_page_id()) {
+            self.txns
+                .set_status(bufmgr, self.id, IN_PROGRESS, &written)?;
+        }
+        Ok(())
+    }
+
+    // 取り消されていない最新の版を調べ、見えていて削除されていなければその位置を返す
+    fn latest_live(&self, versions: &[Version]) -> Result<Option<usize>, Error> {
+        let state = self.txns.state();
+        let conflict = |txn_id| txn_id != self.id && !self.snapshot.sees(txn_id);
+        let (i, version) = match versions
+            .iter()
+            .enumerate()
+            .find(|(_, version)| !state.aborted.contains(&version.xmin))
+        {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+        if conflict(version.xmin) {
+            return Err(Error::WriteConflict(self.id));
+        }
+        match version.xmax {
+            Some(xmax) if state.aborted.contains(&xmax) => Ok(Some(i)),
+            Some(xmax) if conflict(xmax) => Err(Error::WriteConflict(self.id)),
+            Some(_) => Ok(None),
+            None => Ok(Some(i)),
+        }
+    }
+
+    // キーの版を読んで書き換える。f が返した版を書き、版がなければ挿入する
+    fn modify<T>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+        key: &[u8],
+        f: impl FnOnce(&mut Vec<Version>, Option<usize>) -> Result<T>,
+    ) -> Result<T> {
+        let _lock = self.txns.key_locks.lock(store, key);
+        let mut versions = read_versions(bufmgr, store, key)?.unwrap_or_default();
+        let latest = self.latest_live(&versions)?;
+        let result = f(&mut versions, latest)?;
+        self.add_written(bufmgr, store)?;
+        store.put(bufmgr, key, &encode_versions(&versions))?;
+        Ok(result)
+    }
+
+    pub(crate) fn insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+        key: &[u8],
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.modify(bufmgr, store, key, |versions, latest| {
+            if latest.is_some() {
+                return Err(btree::Error::DuplicateKey.into());
+            }
+            versions.insert(
+                0,
+                Version {
+                    xmin: self.id,
+                    xmax: None,
+                    data,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    // 書き換える前の版の内容を返す
+    pub(crate) fn update(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+        key: &[u8],
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        self.modify(bufmgr, store, key, |versions, latest| {
+            let i = latest.ok_or(btree::Error::KeyNotFound)?;
+            // 自分が作った版なら、他からは見えないのでそのまま書き換える
+            if versions[i].xmin == self.id {
+                return Ok(std::mem::replace(&mut versions[i].data, data));
+            }
+            versions[i].xmax = Some(self.id);
+            let old_data = versions[i].data.clone();
+            versions.insert(
+                0,
+                Version {
+                    xmin: self.id,
+                    xmax: None,
+                    data,
+                },
+            );
+            Ok(old_data)
+        })
+    }
+
+    // 削除した版の内容を返す
+    pub(crate) fn delete(
+        &self,
+        bufmgr: &BufferPoolManager,
+        store: &dyn VersionStore,
+        key: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.modify(bufmgr, store, key, |versions, latest| {
+            let i = latest.ok_or(btree::Error::KeyNotFound)?;
+            versions[i].xmax = Some(self.id);
+            Ok(versions[i].data.clone())
+        })
+    }
+}
+
+impl Drop for Txn<'_> {
+    // コミットログには IN_PROGRESS のまま残るが、開き直した時も取り消したものとして扱う
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use tempfile::{tempdir, tempfile};
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+
+    fn read(bufmgr: &BufferPoolManager, btree: &BTree, snapshot: &Snapshot) -> Vec<Vec<u8>> {
+        let mut iter = btree.search(bufmgr, SearchMode::Start).unwrap();
+        let mut values = vec![];
+        while let Some((_, value)) = iter.next(bufmgr).unwrap() {
+            let versions = decode_versions(&value).unwrap();
+            if let Some(version) = snapshot.visible(&versions) {
+                values.push(version.data.clone());
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::create(&bufmgr).unwrap();
+        let btree = BTree::create(&bufmgr).unwrap();
+
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"a", b"1".to_vec()).unwrap();
+        txn.insert(&bufmgr, &btree, b"b", b"1".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        let before = txns.snapshot();
+        let writer = txns.begin(&bufmgr).unwrap();
+        writer.update(&bufmgr, &btree, b"a", b"2".to_vec()).unwrap();
+        writer.delete(&bufmgr, &btree, b"b").unwrap();
+        writer.insert(&bufmgr, &btree, b"c", b"2".to_vec()).unwrap();
+        // 自分の変更は見えるが、コミットするまで他からは見えない
+        assert_eq!(
+            vec![b"2".to_vec(), b"2".to_vec()],
+            read(&bufmgr, &btree, writer.snapshot())
+        );
+        let during = txns.snapshot();
+        assert_eq!(
+            vec![b"1".to_vec(), b"1".to_vec()],
+            read(&bufmgr, &btree, &during)
+        );
+
+        // 同じキーを書くトランザクションは衝突する
+        let other = txns.begin(&bufmgr).unwrap();
+        let err = other
+            .update(&bufmgr, &btree, b"a", b"3".to_vec())
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::WriteConflict(_))));
+        writer.commit(&bufmgr).unwrap();
+        // コミットした後でも、スナップショットより後の変更とは衝突する
+        assert!(other.update(&bufmgr, &btree, b"a", b"3".to_vec()).is_err());
+        other.abort(&bufmgr).unwrap();
+
+        // 前に取ったスナップショットからは見えないまま
+        assert_eq!(
+            vec![b"1".to_vec(), b"1".to_vec()],
+            read(&bufmgr, &btree, &before)
+        );
+        let after = txns.snapshot();
+        assert_eq!(
+            vec![b"2".to_vec(), b"2".to_vec()],
+            read(&bufmgr, &btree, &after)
+        );
+        drop(during);
+
+        // 取り消したトランザクションの変更は見えない
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"b", b"3".to_vec()).unwrap();
+        txn.abort(&bufmgr).unwrap();
+        let snapshot = txns.snapshot();
+        assert_eq!(
+            read(&bufmgr, &btree, &after),
+            read(&bufmgr, &btree, &snapshot)
+        );
+        drop(snapshot);
+        drop(after);
+
+        // before が見ている版は残す
+        let stats = txns.vacuum(&bufmgr, &btree).unwrap();
+        assert_eq!(
+            VacuumStats {
+                removed_versions: 1,
+                removed_entries: 0,
+            },
+            stats
+        );
+        drop(before);
+        let stats = txns.vacuum(&bufmgr, &btree).unwrap();
+        assert_eq!(
+            VacuumStats {
+                removed_versions: 2,
+                removed_entries: 1,
+            },
+            stats
+        );
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        let mut entries = vec![];
+        while let Some((key, value)) = iter.next(&bufmgr).unwrap() {
+            entries.push((key, decode_versions(&value).unwrap().len()));
+        }
+        assert_eq!(vec![(b"a".to_vec(), 1), (b"c".to_vec(), 1)], entries);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::create(&bufmgr).unwrap();
+        let btree = BTree::create(&bufmgr).unwrap();
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"a", b"0".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        // 全員が始めてから同じキーを書くと、書けるのは1つだけで、他は衝突する
+        // 違うキーは並行して書ける
+        const NUM_THREADS: usize = 8;
+        let barrier = Barrier::new(NUM_THREADS);
+        let num_updated = thread::scope(|s| {
+            let threads: Vec<_> = (0..NUM_THREADS)
+                .map(|i| {
+                    let (bufmgr, txns, btree, barrier) = (&bufmgr, &txns, &btree, &barrier);
+                    s.spawn(move || {
+                        let txn = txns.begin(bufmgr).unwrap();
+                        barrier.wait();
+                        let key = format!("k{}", i);
+                        txn.insert(bufmgr, btree, key.as_bytes(), b"1".to_vec())
+                            .unwrap();
+                        let updated = txn.update(bufmgr, btree, b"a", b"1".to_vec());
+                        barrier.wait();
+                        match updated {
+                            Ok(_) => txn.commit(bufmgr).map(|_| 1).unwrap(),
+                            Err(err) => {
+                                assert!(matches!(
+                                    err.downcast_ref(),
+                                    Some(Error::WriteConflict(_))
+                                ));
+                                txn.abort(bufmgr).map(|_| 0).unwrap()
+                            }
+                        }
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(1, num_updated);
+        let snapshot = txns.snapshot();
+        assert_eq!(2, read(&bufmgr, &btree, &snapshot).len());
+    }
+
+    #[test]
+    fn test_drop() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::create(&bufmgr).unwrap();
+        let btree = BTree::create(&bufmgr).unwrap();
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"a", b"1".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        // 途中で drop したトランザクションは取り消す
+        let snapshot = txns.snapshot();
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.update(&bufmgr, &btree, b"a", b"2".to_vec()).unwrap();
+        drop(txn);
+        let cloned = snapshot.clone();
+        drop(snapshot);
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.update(&bufmgr, &btree, b"a", b"3".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+        assert_eq!(vec![b"1".to_vec()], read(&bufmgr, &btree, &cloned));
+
+        // スナップショットを drop すれば、古い版も取り除ける
+        drop(cloned);
+        assert_eq!(1, txns.state().aborted.len());
+        let stats = txns
+            .vacuum_all(&bufmgr, || {
+                Ok(vec![Box::new(BTree::new(btree.meta_page_id)) as _])
+            })
+            .unwrap();
+        assert_eq!(2, stats.removed_versions);
+        assert!(txns.state().aborted.is_empty());
+        assert!(txns.state().snapshots.is_empty());
+        let snapshot = txns.snapshot();
+        assert_eq!(vec![b"3".to_vec()], read(&bufmgr, &btree, &snapshot));
+    }
+
+    #[test]
+    fn test_forget_aborted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("mvcc.rly");
+        let (meta_page_id, table, index) = {
+            let disk = DiskManager::open(&path).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+            let txns = TxnManager::create(&bufmgr).unwrap();
+            let table = BTree::create(&bufmgr).unwrap();
+            let index = BTree::create(&bufmgr).unwrap();
+
+            // 版を書かずに取り消したトランザクションは覚えておかない
+            txns.begin(&bufmgr).unwrap().abort(&bufmgr).unwrap();
+            assert!(txns.state().aborted.is_empty());
+
+            let txn = txns.begin(&bufmgr).unwrap();
+            txn.insert(&bufmgr, &table, b"a", b"1".to_vec()).unwrap();
+            txn.insert(&bufmgr, &index, b"1", b"a".to_vec()).unwrap();
+            txn.abort(&bufmgr).unwrap();
+
+            // 片方の B+Tree だけを vacuum しても、もう片方に版が残っているので忘れない
+            let stats = txns
+                .vacuum_all(&bufmgr, || {
+                    Ok(vec![Box::new(BTree::new(table.meta_page_id)) as _])
+                })
+                .unwrap();
+            assert_eq!(1, stats.removed_entries);
+            assert_eq!(1, txns.state().aborted.len());
+
+            // B+Tree を記録していなかった頃に書かれた、状態だけの値
+            let txn = txns.begin(&bufmgr).unwrap();
+            txn.insert(&bufmgr, &table, b"b", b"3".to_vec()).unwrap();
+            std::mem::forget(txn);
+            BTree::new(txns.meta_page_id)
+                .upsert(&bufmgr, &3u64.to_be_bytes(), &[IN_PROGRESS])
+                .unwrap();
+            bufmgr.flush().unwrap();
+            (txns.meta_page_id, table, index)
+        };
+
+        // 開き直しても、版が残っている B+Tree はコミットログから分かる
+        let disk = DiskManager::open(&path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::open(&bufmgr, meta_page_id).unwrap();
+        assert_eq!(
+            vec![2, 3],
+            txns.state().aborted.iter().copied().collect::<Vec<_>>()
+        );
+        let snapshot = txns.snapshot();
+        assert!(read(&bufmgr, &table, &snapshot).is_empty());
+        assert!(read(&bufmgr, &index, &snapshot).is_empty());
+        drop(snapshot);
+        txns.vacuum(&bufmgr, &table).unwrap();
+        assert_eq!(2, txns.state().aborted.len());
+        let stats = txns.vacuum(&bufmgr, &index).unwrap();
+        assert_eq!(1, stats.removed_entries);
+        assert_eq!(
+            vec![3],
+            txns.state().aborted.iter().copied().collect::<Vec<_>>()
+        );
+
+        // どこに書いたか分からないものは、全ての B+Tree を vacuum し終えてから忘れる
+        let stats = txns
+            .vacuum_all(&bufmgr, || {
+                Ok(vec![
+                    Box::new(BTree::new(table.meta_page_id)) as _,
+                    Box::new(BTree::new(index.meta_page_id)) as _,
+                ])
+            })
+            .unwrap();
+        assert_eq!(VacuumStats::default(), stats);
+        assert!(txns.state().aborted.is_empty());
+        assert!(txns.state().unvacuumed.is_empty());
+    }
+
+    #[test]
+    fn test_trim_commit_log() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::create(&bufmgr).unwrap();
+        let btree = BTree::create(&bufmgr).unwrap();
+        let other = BTree::create(&bufmgr).unwrap();
+        let commit_log = |bufmgr: &BufferPoolManager| {
+            let commit_log = BTree::new(txns.meta_page_id);
+            let mut iter = commit_log.search(bufmgr, SearchMode::Start).unwrap();
+            let mut txn_ids = vec![];
+            while let Some((key, _)) = iter.next(bufmgr).unwrap() {
+                txn_ids.push(TxnId::from_be_bytes(key[..].try_into().unwrap()));
+            }
+            txn_ids
+        };
+
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"a", b"1".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.insert(&bufmgr, &btree, b"b", b"2".to_vec()).unwrap();
+        txn.abort(&bufmgr).unwrap();
+        let txn = txns.begin(&bufmgr).unwrap();
+        txn.update(&bufmgr, &btree, b"a", b"3".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        // 進行中のトランザクションと、版が残っている取り消したトランザクションは残す
+        let txn = txns.begin(&bufmgr).unwrap();
+        txns.vacuum(&bufmgr, &other).unwrap();
+        assert_eq!(vec![2, 4], commit_log(&bufmgr));
+        txn.commit(&bufmgr).unwrap();
+        txns.vacuum(&bufmgr, &btree).unwrap();
+        assert_eq!(vec![4], commit_log(&bufmgr));
+
+        // 消したトランザクションはコミットしたものとして開き直す
+        let txns = TxnManager::open(&bufmgr, txns.meta_page_id).unwrap();
+        assert!(txns.state().aborted.is_empty());
+        let snapshot = txns.snapshot();
+        assert_eq!(vec![b"3".to_vec()], read(&bufmgr, &btree, &snapshot));
+        drop(snapshot);
+        let txn = txns.begin(&bufmgr).unwrap();
+        assert_eq!(5, txn.id);
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_recovery() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("mvcc.rly");
+        let (meta_page_id, btree) = {
+            let disk = DiskManager::open(&path).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+            let txns = TxnManager::create(&bufmgr).unwrap();
+            let btree = BTree::create(&bufmgr).unwrap();
+            let txn = txns.begin(&bufmgr).unwrap();
+            txn.insert(&bufmgr, &btree, b"a", b"1".to_vec()).unwrap();
+            txn.commit(&bufmgr).unwrap();
+
+            // コミットしないまま落ちる
+            let txn = txns.begin(&bufmgr).unwrap();
+            txn.insert(&bufmgr, &btree, b"b", b"2".to_vec()).unwrap();
+            bufmgr.flush().unwrap();
+            std::mem::forget(txn);
+            (txns.meta_page_id, btree)
+        };
+
+        let disk = DiskManager::open(&path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::open(&bufmgr, meta_page_id).unwrap();
+        let snapshot = txns.snapshot();
+        assert_eq!(vec![b"1".to_vec()], read(&bufmgr, &btree, &snapshot));
+        drop(snapshot);
+
+        // 落ちたトランザクションのIDは使い回さず、そのキーにも書ける
+        let txn = txns.begin(&bufmgr).unwrap();
+        assert_eq!(3, txn.id);
+        txn.insert(&bufmgr, &btree, b"b", b"3".to_vec()).unwrap();
+        txn.commit(&bufmgr).unwrap();
+        let stats = txns.vacuum(&bufmgr, &btree).unwrap();
+        assert_eq!(1, stats.removed_versions);
+    }
+}
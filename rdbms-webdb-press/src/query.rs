@@ -1,19 +1,71 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
+use thiserror::Error;
 
-use crate::btree::{self, BTree, SearchMode};
-use crate::buffer::BufferPoolManager;
+use crate::btree::{self, overflow, BTree, SearchMode};
+use crate::buffer::{self, BufferPoolManager};
 use crate::disk::PageId;
+use crate::hash_index::HashIndex;
+use crate::mvcc::{self, Snapshot};
 use crate::tuple;
+use crate::value::Value;
+
+pub type Tuple = Vec<Value>;
+pub type TupleSlice<'a> = &'a [Value];
+
+// 行を受け取って条件を満たすかどうかを返す関数と、EXPLAIN で表示するその説明
+pub struct Predicate {
+    pub description: String,
+    f: Box<dyn Fn(TupleSlice) -> bool>,
+}
+
+impl Predicate {
+    pub fn new(description: impl Into<String>, f: impl Fn(TupleSlice) -> bool + 'static) -> Self {
+        Self {
+            description: description.into(),
+            f: Box::new(f),
+        }
+    }
+
+    // どの行も満たす条件
+    pub fn always() -> Self {
+        Self::new("true", |_| true)
+    }
+
+    pub fn eval(&self, tuple: TupleSlice) -> bool {
+        (self.f)(tuple)
+    }
+}
 
-pub type Tuple = Vec<Vec<u8>>;
-pub type TupleSlice<'a> = &'a [Vec<u8>];
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot compute {function} of {value}")]
+    InvalidAggregate {
+        function: &'static str,
+        value: Value,
+    },
+    #[error("integer overflow in SUM")]
+    SumOverflow,
+    #[error("index entry points to a missing row")]
+    DanglingIndexEntry,
+}
 
-pub enum TupleSearchMode<'a> {
+// End なら範囲の末尾から逆順に読む
+pub enum TupleSearchMode {
     Start,
-    Key(&'a [&'a [u8]]),
+    Key(Tuple),
+    End,
 }
 
-impl<'a> TupleSearchMode<'a> {
+impl TupleSearchMode {
     fn encode(&self) -> SearchMode {
         match self {
             TupleSearchMode::Start => SearchMode::Start,
@@ -22,72 +74,333 @@ impl<'a> TupleSearchMode<'a> {
                 tuple::encode(tuple.iter(), &mut key);
                 SearchMode::Key(key)
             }
+            TupleSearchMode::End => SearchMode::End,
+        }
+    }
+
+    fn is_reverse(&self) -> bool {
+        matches!(self, TupleSearchMode::End)
+    }
+}
+
+impl fmt::Display for TupleSearchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TupleSearchMode::Start => f.write_str("start"),
+            TupleSearchMode::Key(tuple) => write!(f, "key {}", format_tuple(tuple)),
+            TupleSearchMode::End => f.write_str("end"),
+        }
+    }
+}
+
+// 読むキーの範囲。境界にはキーの先頭の列だけを渡してもよく、
+// Included(prefix) はその列で始まるキーを全て範囲に含め、Excluded(prefix) は全て除く
+pub struct TupleRange {
+    pub lower: Bound<Tuple>,
+    pub upper: Bound<Tuple>,
+}
+
+impl TupleRange {
+    pub fn all() -> Self {
+        Self {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        }
+    }
+
+    // 先頭の列が prefix に一致するキー全て
+    pub fn prefix(prefix: Tuple) -> Self {
+        Self {
+            lower: Bound::Included(prefix.clone()),
+            upper: Bound::Included(prefix),
+        }
+    }
+
+    fn search(
+        &self,
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        search_mode: &TupleSearchMode,
+    ) -> Result<btree::Iter> {
+        let encode = |tuple: &Tuple| {
+            let mut key = vec![];
+            tuple::encode(tuple.iter(), &mut key);
+            key
+        };
+        // エンコードした値は 0xFF より小さい型のタグで始まるので、
+        // 後ろに 0xFF を付けると、prefix で始まるどのキーよりも後ろになる
+        let after_prefix = |tuple: &Tuple| {
+            let mut key = encode(tuple);
+            key.push(0xFF);
+            key
+        };
+        let lower = match &self.lower {
+            Bound::Unbounded => Bound::Unbounded,
+            Bound::Included(tuple) => Bound::Included(encode(tuple)),
+            Bound::Excluded(tuple) => Bound::Excluded(after_prefix(tuple)),
+        };
+        let upper = match &self.upper {
+            Bound::Unbounded => Bound::Unbounded,
+            Bound::Included(tuple) => Bound::Included(after_prefix(tuple)),
+            Bound::Excluded(tuple) => Bound::Excluded(encode(tuple)),
+        };
+        Ok(btree.range(bufmgr, search_mode.encode(), lower, upper)?)
+    }
+}
+
+impl fmt::Display for TupleRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lower = match &self.lower {
+            Bound::Unbounded => None,
+            Bound::Included(tuple) => Some(format!(">= {}", format_tuple(tuple))),
+            Bound::Excluded(tuple) => Some(format!("> {}", format_tuple(tuple))),
+        };
+        let upper = match &self.upper {
+            Bound::Unbounded => None,
+            Bound::Included(tuple) => Some(format!("<= {}", format_tuple(tuple))),
+            Bound::Excluded(tuple) => Some(format!("< {}", format_tuple(tuple))),
+        };
+        let bounds: Vec<_> = lower.into_iter().chain(upper).collect();
+        if bounds.is_empty() {
+            f.write_str("all")
+        } else {
+            f.write_str(&bounds.join(" AND "))
         }
     }
 }
 
 pub trait Executor {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>>;
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>>;
+}
+// 逆順なら prev で読む
+#[allow(clippy::type_complexity)]
+fn step(
+    iter: &mut btree::Iter,
+    bufmgr: &BufferPoolManager,
+    reverse: bool,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    if reverse {
+        Ok(iter.prev(bufmgr)?)
+    } else {
+        Ok(iter.next(bufmgr)?)
+    }
 }
+
 pub struct ExecSeqScan<'a> {
     table_iter: btree::Iter,
-    while_cond: &'a dyn Fn(TupleSlice) -> bool,
+    reverse: bool,
+    snapshot: Option<&'a Snapshot>,
 }
 
 // クエリエクスキュータ
 impl<'a> Executor for ExecSeqScan<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
-        let (pkey_bytes, tuple_bytes) = match self.table_iter.next(bufmgr)? {
-            Some(pair) => pair,
-            None => return Ok(None),
-        };
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            let (pkey_bytes, tuple_bytes) = match step(&mut self.table_iter, bufmgr, self.reverse)?
+            {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
+            let tuple_bytes = match visible_data(self.snapshot, tuple_bytes)? {
+                Some(tuple_bytes) => tuple_bytes,
+                None => continue,
+            };
 
-        let mut pkey = vec![];
-        tuple::decode(&pkey_bytes, &mut pkey);
-        if !(self.while_cond)(&pkey) {
-            return Ok(None);
+            let mut tuple = vec![];
+            tuple::decode(&pkey_bytes, &mut tuple);
+            tuple::decode(&tuple_bytes, &mut tuple);
+            return Ok(Some(tuple));
         }
-        let mut tuple = pkey;
-        tuple::decode(&tuple_bytes, &mut tuple);
-        Ok(Some(tuple))
     }
 }
 
+// スナップショットがあれば、値は版を並べたものなので、見える版の内容を取り出す
+pub(crate) fn visible_data(snapshot: Option<&Snapshot>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return Ok(Some(value)),
+    };
+    let versions = mvcc::decode_versions(&value)?;
+    Ok(snapshot
+        .visible(&versions)
+        .map(|version| version.data.clone()))
+}
+
 pub type BoxExecutor<'a> = Box<dyn Executor + 'a>;
 
+// エクスキュータは実行計画と BufferPoolManager を借りたまま動く
 pub trait PlanNode {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor>;
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>>;
+
+    // EXPLAIN で表示する、このノードと子ノードの木
+    fn explain(&self) -> Explain;
+
+    // 子ノード。Instrumented で包む時に差し替える
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![]
+    }
+}
+
+// 実行計画の 1 つのノードの説明。EXPLAIN ANALYZE なら実行した時の計測値も持つ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explain {
+    pub node_type: &'static str,
+    pub properties: Vec<(&'static str, String)>,
+    pub children: Vec<Explain>,
+    pub stats: Option<NodeStats>,
+}
+
+impl Explain {
+    pub fn new(node_type: &'static str) -> Self {
+        Self {
+            node_type,
+            properties: vec![],
+            children: vec![],
+            stats: None,
+        }
+    }
+
+    pub fn property(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.properties.push((name, value.to_string()));
+        self
+    }
+
+    pub fn child(mut self, child: &dyn PlanNode) -> Self {
+        self.children.push(child.explain());
+        self
+    }
+
+    // 1 行に 1 つのノードを、子ノードを字下げして並べる
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        self.push_lines(0, &mut lines);
+        lines
+    }
+
+    fn push_lines(&self, depth: usize, lines: &mut Vec<String>) {
+        let mut line = String::new();
+        if depth > 0 {
+            line.push_str(&"  ".repeat(depth - 1));
+            line.push_str("-> ");
+        }
+        line.push_str(self.node_type);
+        if !self.properties.is_empty() {
+            let properties: Vec<_> = self
+                .properties
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect();
+            line.push_str(&format!(" ({})", properties.join(", ")));
+        }
+        if let Some(stats) = &self.stats {
+            line.push_str(&format!(" [{}]", stats));
+        }
+        lines.push(line);
+        for child in &self.children {
+            child.push_lines(depth + 1, lines);
+        }
+    }
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.lines().join("\n"))
+    }
+}
+
+// ノードの実行を計測した値。子ノードの分も含む
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NodeStats {
+    // start を呼んだ回数。NestedLoopJoin の内側は外側の行の数だけ始める
+    pub loops: u64,
+    pub rows: u64,
+    // BufferPoolManager から取り出したページの数
+    pub pages: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for NodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "loops: {}, rows: {}, pages: {}, time: {:.3} ms",
+            self.loops,
+            self.rows,
+            self.pages,
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+// EXPLAIN で表示する値。文字列は SQL と同じく引用符で囲む
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::Text(text) => format!("'{}'", text.replace('\'', "''")),
+        value => value.to_string(),
+    }
+}
+
+fn format_tuple(tuple: TupleSlice) -> String {
+    let values: Vec<_> = tuple.iter().map(format_value).collect();
+    format!("({})", values.join(", "))
+}
+
+fn format_columns(columns: &[usize]) -> String {
+    let columns: Vec<_> = columns
+        .iter()
+        .map(|column| format!("#{}", column))
+        .collect();
+    columns.join(", ")
 }
-pub struct SeqScan<'a> {
+pub struct SeqScan {
     pub table_meta_page_id: PageId,
-    pub search_mode: TupleSearchMode<'a>,
-    pub while_cond: &'a dyn Fn(TupleSlice) -> bool,
+    pub search_mode: TupleSearchMode,
+    pub range: TupleRange,
+    // MVCC で書いたテーブルなら、このスナップショットで見える版だけを読む
+    pub snapshot: Option<Snapshot>,
 }
 
 // 実行計画
-impl<'a> PlanNode for SeqScan<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+impl PlanNode for SeqScan {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
         let btree = BTree::new(self.table_meta_page_id);
-        let table_iter = btree.search(bufmgr, self.search_mode.encode())?;
+        let table_iter = self.range.search(bufmgr, &btree, &self.search_mode)?;
         Ok(Box::new(ExecSeqScan {
             table_iter,
-            while_cond: self.while_cond,
+            reverse: self.search_mode.is_reverse(),
+            snapshot: self.snapshot.as_ref(),
         }))
     }
+
+    fn explain(&self) -> Explain {
+        let explain = Explain::new("SeqScan")
+            .property("table", self.table_meta_page_id.to_u64())
+            .property("mode", &self.search_mode)
+            .property("range", &self.range);
+        explain_snapshot(explain, self.snapshot.as_ref())
+    }
+}
+
+// スナップショットで読むなら、その範囲を表示する
+fn explain_snapshot(explain: Explain, snapshot: Option<&Snapshot>) -> Explain {
+    match snapshot {
+        Some(snapshot) => explain.property("snapshot", snapshot),
+        None => explain,
+    }
 }
 
 pub struct ExecFilter<'a> {
     inner_iter: BoxExecutor<'a>,
-    cond: &'a dyn Fn(TupleSlice) -> bool,
+    cond: &'a Predicate,
 }
 
 // クエリエクスキュータ
 impl<'a> Executor for ExecFilter<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
         loop {
             match self.inner_iter.next(bufmgr)? {
                 Some(tuple) => {
-                    if (self.cond)(&tuple) {
+                    if self.cond.eval(&tuple) {
                         return Ok(Some(tuple));
                     }
                 }
@@ -97,70 +410,1715 @@ impl<'a> Executor for ExecFilter<'a> {
     }
 }
 
-pub struct Filter<'a> {
-    pub inner_plan: &'a dyn PlanNode,
-    pub cond: &'a dyn Fn(TupleSlice) -> bool,
+pub struct Filter {
+    pub inner_plan: Box<dyn PlanNode>,
+    pub cond: Predicate,
 }
 
 // 実行計画
-impl<'a> PlanNode for Filter<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+impl PlanNode for Filter {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
         let inner_iter = self.inner_plan.start(bufmgr)?;
         Ok(Box::new(ExecFilter {
             inner_iter,
-            cond: self.cond,
+            cond: &self.cond,
         }))
     }
+
+    fn explain(&self) -> Explain {
+        Explain::new("Filter")
+            .property("cond", &self.cond.description)
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.inner_plan]
+    }
 }
 
 pub struct ExecIndexScan<'a> {
     table_btree: BTree,      // B+Treeの検索で使う
     index_iter: btree::Iter, // セカンダリインデックスでの検索に使う
-    while_cond: &'a dyn Fn(TupleSlice) -> bool,
+    reverse: bool,
+    snapshot: Option<&'a Snapshot>,
 }
 
 // クエリエクスキュータ
 impl<'a> Executor for ExecIndexScan<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
-        let (skey_bytes, pkey_bytes) = match self.index_iter.next(bufmgr)? {
-            Some(pair) => pair,
-            None => return Ok(None),
-        };
-        let mut skey = vec![];
-        tuple::decode(&skey_bytes, &mut skey);
-
-        // クロージャにセカンダリキーを渡している
-        if !(self.while_cond)(&skey) {
-            return Ok(None);
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            let (_, index_value) = match step(&mut self.index_iter, bufmgr, self.reverse)? {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
+            let snapshot = match self.snapshot {
+                Some(snapshot) => snapshot,
+                None => return Ok(Some(fetch_record(&self.table_btree, bufmgr, index_value)?)),
+            };
+            if let Some(tuple) =
+                fetch_visible_record(&self.table_btree, bufmgr, index_value, snapshot)?
+            {
+                return Ok(Some(tuple));
+            }
         }
+    }
+}
 
-        let mut table_iter = self
-            .table_btree
-            .search(bufmgr, SearchMode::Key(pkey_bytes))?;
-        let (pkey_bytes, tuple_bytes) = table_iter.next(bufmgr)?.unwrap();
+// 版を付けたインデックスのエントリの値から、snapshot から見える行を読む
+// インデックスのエントリにも版があり、見えるエントリが指す行には見える版がある
+fn fetch_visible_record(
+    table_btree: &BTree,
+    bufmgr: &BufferPoolManager,
+    index_value: Vec<u8>,
+    snapshot: &Snapshot,
+) -> Result<Option<Tuple>> {
+    let pkey_bytes = match visible_data(Some(snapshot), index_value)? {
+        Some(pkey_bytes) => pkey_bytes,
+        None => return Ok(None),
+    };
+    let mut table_iter = table_btree.search(bufmgr, SearchMode::Key(pkey_bytes.clone()))?;
+    // vacuum で版が全て取り除かれた行は、このスナップショットからも見えない
+    let value = match table_iter.next(bufmgr)? {
+        Some((found, value)) if found == pkey_bytes => value,
+        _ => return Ok(None),
+    };
+    Ok(visible_data(Some(snapshot), value)?.map(|tuple_bytes| {
         let mut tuple = vec![];
         tuple::decode(&pkey_bytes, &mut tuple);
         tuple::decode(&tuple_bytes, &mut tuple);
-        Ok(Some(tuple))
-    }
+        tuple
+    }))
+}
+
+// セカンダリインデックスから引いた主キーで、テーブルの行を読む
+// 版を付けないインデックスのエントリは、いつもテーブルにある行を指していなければならない
+fn fetch_record(
+    table_btree: &BTree,
+    bufmgr: &BufferPoolManager,
+    pkey_bytes: Vec<u8>,
+) -> Result<Tuple> {
+    let mut table_iter = table_btree.search(bufmgr, SearchMode::Key(pkey_bytes.clone()))?;
+    let tuple_bytes = match table_iter.next(bufmgr)? {
+        Some((found, tuple_bytes)) if found == pkey_bytes => tuple_bytes,
+        _ => return Err(Error::DanglingIndexEntry.into()),
+    };
+    let mut tuple = vec![];
+    tuple::decode(&pkey_bytes, &mut tuple);
+    tuple::decode(&tuple_bytes, &mut tuple);
+    Ok(tuple)
 }
 
-pub struct IndexScan<'a> {
+pub struct IndexScan {
     pub table_meta_page_id: PageId,
     pub index_meta_page_id: PageId,
-    pub search_mode: TupleSearchMode<'a>,
-    pub while_cond: &'a dyn Fn(TupleSlice) -> bool,
+    pub search_mode: TupleSearchMode,
+    pub range: TupleRange,
+    pub snapshot: Option<Snapshot>,
 }
 
-impl<'a> PlanNode for IndexScan<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+impl PlanNode for IndexScan {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
         let table_btree = BTree::new(self.table_meta_page_id);
         let index_btree = BTree::new(self.index_meta_page_id);
-        let index_iter = index_btree.search(bufmgr, self.search_mode.encode())?;
+        let index_iter = self.range.search(bufmgr, &index_btree, &self.search_mode)?;
         Ok(Box::new(ExecIndexScan {
             table_btree,
             index_iter,
-            while_cond: self.while_cond,
+            reverse: self.search_mode.is_reverse(),
+            snapshot: self.snapshot.as_ref(),
         }))
     }
+
+    fn explain(&self) -> Explain {
+        let explain = Explain::new("IndexScan")
+            .property("table", self.table_meta_page_id.to_u64())
+            .property("index", self.index_meta_page_id.to_u64())
+            .property("mode", &self.search_mode)
+            .property("range", &self.range);
+        explain_snapshot(explain, self.snapshot.as_ref())
+    }
+}
+
+pub struct ExecHashIndexScan<'a> {
+    table_btree: BTree,
+    // ハッシュインデックスから引いた値。返したら None にする
+    index_value: Option<Vec<u8>>,
+    snapshot: Option<&'a Snapshot>,
+}
+
+impl<'a> Executor for ExecHashIndexScan<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let index_value = match self.index_value.take() {
+            Some(index_value) => index_value,
+            None => return Ok(None),
+        };
+        match self.snapshot {
+            Some(snapshot) => {
+                fetch_visible_record(&self.table_btree, bufmgr, index_value, snapshot)
+            }
+            None => Ok(Some(fetch_record(&self.table_btree, bufmgr, index_value)?)),
+        }
+    }
+}
+
+// セカンダリキーが key に等しい行を、ハッシュインデックスで引く
+// インデックスは一意なので、返す行は高々1つ。範囲の検索はできない
+pub struct HashIndexScan {
+    pub table_meta_page_id: PageId,
+    pub index_meta_page_id: PageId,
+    pub key: Tuple,
+    pub snapshot: Option<Snapshot>,
+}
+
+impl PlanNode for HashIndexScan {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        // NULL はどの値とも等しくない
+        let index_value = if self.key.iter().any(Value::is_null) {
+            None
+        } else {
+            let mut key = vec![];
+            tuple::encode(self.key.iter(), &mut key);
+            HashIndex::new(self.index_meta_page_id).lookup(bufmgr, &key)?
+        };
+        Ok(Box::new(ExecHashIndexScan {
+            table_btree: BTree::new(self.table_meta_page_id),
+            index_value,
+            snapshot: self.snapshot.as_ref(),
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        let explain = Explain::new("HashIndexScan")
+            .property("table", self.table_meta_page_id.to_u64())
+            .property("index", self.index_meta_page_id.to_u64())
+            .property("key", format_tuple(&self.key));
+        explain_snapshot(explain, self.snapshot.as_ref())
+    }
+}
+
+// 結合した行は、左 (外側) の行の後ろに右 (内側) の行を並べたものになる
+fn concat(left: TupleSlice, right: TupleSlice) -> Tuple {
+    left.iter().chain(right).cloned().collect()
+}
+
+// 等結合に使う列をエンコードしたもの。NULL はどの値とも等しくないので None を返す
+fn join_key(tuple: TupleSlice, columns: &[usize]) -> Option<Vec<u8>> {
+    if columns.iter().any(|&index| tuple[index].is_null()) {
+        return None;
+    }
+    let mut key = vec![];
+    tuple::encode(columns.iter().map(|&index| &tuple[index]), &mut key);
+    Some(key)
+}
+
+pub struct ExecNestedLoopJoin<'a> {
+    bufmgr: &'a BufferPoolManager,
+    outer_iter: BoxExecutor<'a>,
+    inner_plan: &'a dyn PlanNode,
+    // 外側の今の行と、それに対する内側の走査
+    current: Option<(Tuple, BoxExecutor<'a>)>,
+    cond: &'a Predicate,
+}
+
+impl<'a> Executor for ExecNestedLoopJoin<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            let (outer, inner_iter) = match &mut self.current {
+                Some(current) => current,
+                None => match self.outer_iter.next(bufmgr)? {
+                    Some(outer) => self
+                        .current
+                        .insert((outer, self.inner_plan.start(self.bufmgr)?)),
+                    None => return Ok(None),
+                },
+            };
+            match inner_iter.next(bufmgr)? {
+                Some(inner) => {
+                    let tuple = concat(outer, &inner);
+                    if self.cond.eval(&tuple) {
+                        return Ok(Some(tuple));
+                    }
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+// 外側の行ごとに内側の実行計画を最初から実行し、条件を満たす組を返す
+pub struct NestedLoopJoin {
+    pub outer_plan: Box<dyn PlanNode>,
+    pub inner_plan: Box<dyn PlanNode>,
+    pub cond: Predicate,
+}
+
+impl PlanNode for NestedLoopJoin {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let outer_iter = self.outer_plan.start(bufmgr)?;
+        Ok(Box::new(ExecNestedLoopJoin {
+            bufmgr,
+            outer_iter,
+            inner_plan: &*self.inner_plan,
+            current: None,
+            cond: &self.cond,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        Explain::new("NestedLoopJoin")
+            .property("cond", &self.cond.description)
+            .child(&*self.outer_plan)
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.outer_plan, &mut self.inner_plan]
+    }
+}
+
+pub struct ExecIndexNestedLoopJoin<'a> {
+    outer_iter: BoxExecutor<'a>,
+    table_btree: BTree,
+    index_btree: BTree,
+    outer_key: &'a [usize],
+    // 外側の今の行と、それに一致するインデックスのエントリの走査
+    current: Option<(Tuple, btree::Iter)>,
+    cond: &'a Predicate,
+}
+
+impl<'a> Executor for ExecIndexNestedLoopJoin<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            let (outer, index_iter) = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let outer = match self.outer_iter.next(bufmgr)? {
+                        Some(outer) => outer,
+                        None => return Ok(None),
+                    };
+                    let skey: Tuple = self
+                        .outer_key
+                        .iter()
+                        .map(|&index| outer[index].clone())
+                        .collect();
+                    // NULL はどの値とも等しくない
+                    if skey.iter().any(Value::is_null) {
+                        continue;
+                    }
+                    // 一意でないインデックスでは、セカンダリキーで始まるエントリが複数ある
+                    let index_iter = TupleRange::prefix(skey).search(
+                        bufmgr,
+                        &self.index_btree,
+                        &TupleSearchMode::Start,
+                    )?;
+                    self.current.insert((outer, index_iter))
+                }
+            };
+            match index_iter.next(bufmgr)? {
+                Some((_, pkey_bytes)) => {
+                    let inner = fetch_record(&self.table_btree, bufmgr, pkey_bytes)?;
+                    let tuple = concat(outer, &inner);
+                    if self.cond.eval(&tuple) {
+                        return Ok(Some(tuple));
+                    }
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+// 外側の行の outer_key の列の値で内側のテーブルのインデックスを引く
+// outer_key はインデックスの skey と同じ順に並べる。一意でないインデックスなら一致する行を全て返す
+pub struct IndexNestedLoopJoin {
+    pub outer_plan: Box<dyn PlanNode>,
+    pub table_meta_page_id: PageId,
+    pub index_meta_page_id: PageId,
+    pub outer_key: Vec<usize>,
+    pub cond: Predicate,
+}
+
+impl PlanNode for IndexNestedLoopJoin {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let outer_iter = self.outer_plan.start(bufmgr)?;
+        Ok(Box::new(ExecIndexNestedLoopJoin {
+            outer_iter,
+            table_btree: BTree::new(self.table_meta_page_id),
+            index_btree: BTree::new(self.index_meta_page_id),
+            outer_key: &self.outer_key,
+            current: None,
+            cond: &self.cond,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        Explain::new("IndexNestedLoopJoin")
+            .property("table", self.table_meta_page_id.to_u64())
+            .property("index", self.index_meta_page_id.to_u64())
+            .property("outer_key", format_columns(&self.outer_key))
+            .property("cond", &self.cond.description)
+            .child(&*self.outer_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.outer_plan]
+    }
+}
+
+pub struct ExecHashJoin<'a> {
+    left_iter: BoxExecutor<'a>,
+    // 右の行を結合キーごとにまとめたハッシュ表
+    right_rows: HashMap<Vec<u8>, Vec<Tuple>>,
+    left_key: &'a [usize],
+    // 左の今の行と結合した、まだ返していない行
+    pending: VecDeque<Tuple>,
+    cond: &'a Predicate,
+}
+
+impl<'a> Executor for ExecHashJoin<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(tuple) = self.pending.pop_front() {
+                return Ok(Some(tuple));
+            }
+            let left = match self.left_iter.next(bufmgr)? {
+                Some(left) => left,
+                None => return Ok(None),
+            };
+            let right_rows = &self.right_rows;
+            let rights = join_key(&left, self.left_key).and_then(|key| right_rows.get(&key));
+            for right in rights.into_iter().flatten() {
+                let tuple = concat(&left, right);
+                if self.cond.eval(&tuple) {
+                    self.pending.push_back(tuple);
+                }
+            }
+        }
+    }
+}
+
+// 右の行を全て読んでハッシュ表を作り、左の行で引く
+// left_key と right_key の列の値が等しい組のうち、cond を満たすものを返す
+pub struct HashJoin {
+    pub left_plan: Box<dyn PlanNode>,
+    pub right_plan: Box<dyn PlanNode>,
+    pub left_key: Vec<usize>,
+    pub right_key: Vec<usize>,
+    pub cond: Predicate,
+}
+
+impl PlanNode for HashJoin {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let mut right_rows: HashMap<_, Vec<_>> = HashMap::new();
+        let mut right_iter = self.right_plan.start(bufmgr)?;
+        while let Some(right) = right_iter.next(bufmgr)? {
+            if let Some(key) = join_key(&right, &self.right_key) {
+                right_rows.entry(key).or_default().push(right);
+            }
+        }
+        let left_iter = self.left_plan.start(bufmgr)?;
+        Ok(Box::new(ExecHashJoin {
+            left_iter,
+            right_rows,
+            left_key: &self.left_key,
+            pending: VecDeque::new(),
+            cond: &self.cond,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        explain_join("HashJoin", &self.left_key, &self.right_key, &self.cond)
+            .child(&*self.left_plan)
+            .child(&*self.right_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.left_plan, &mut self.right_plan]
+    }
+}
+
+fn explain_join(
+    node_type: &'static str,
+    left_key: &[usize],
+    right_key: &[usize],
+    cond: &Predicate,
+) -> Explain {
+    Explain::new(node_type)
+        .property("left_key", format_columns(left_key))
+        .property("right_key", format_columns(right_key))
+        .property("cond", &cond.description)
+}
+
+pub struct ExecMergeJoin<'a> {
+    // 結合キーの順に並べた行
+    left_rows: Vec<(Vec<u8>, Tuple)>,
+    right_rows: Vec<(Vec<u8>, Tuple)>,
+    left_pos: usize,
+    right_pos: usize,
+    pending: VecDeque<Tuple>,
+    cond: &'a Predicate,
+}
+
+impl<'a> Executor for ExecMergeJoin<'a> {
+    fn next(&mut self, _bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(tuple) = self.pending.pop_front() {
+                return Ok(Some(tuple));
+            }
+            let (left_key, right_key) = match (
+                self.left_rows.get(self.left_pos),
+                self.right_rows.get(self.right_pos),
+            ) {
+                (Some((left_key, _)), Some((right_key, _))) => (left_key, right_key),
+                _ => return Ok(None),
+            };
+            match left_key.cmp(right_key) {
+                Ordering::Less => self.left_pos += 1,
+                Ordering::Greater => self.right_pos += 1,
+                Ordering::Equal => {
+                    // 同じキーの行の全ての組を作る
+                    let same_key = |rows: &[(Vec<u8>, Tuple)], pos: usize| {
+                        let key = &rows[pos].0;
+                        pos + rows[pos..].iter().take_while(|(k, _)| k == key).count()
+                    };
+                    let left_end = same_key(&self.left_rows, self.left_pos);
+                    let right_end = same_key(&self.right_rows, self.right_pos);
+                    for (_, left) in &self.left_rows[self.left_pos..left_end] {
+                        for (_, right) in &self.right_rows[self.right_pos..right_end] {
+                            let tuple = concat(left, right);
+                            if self.cond.eval(&tuple) {
+                                self.pending.push_back(tuple);
+                            }
+                        }
+                    }
+                    self.left_pos = left_end;
+                    self.right_pos = right_end;
+                }
+            }
+        }
+    }
+}
+
+// 左右の行を結合キーで並べ替えてから、先頭から順に突き合わせる
+// 並べ替えはメモリ上で行う。結果は結合キーの順に並ぶ
+pub struct MergeJoin {
+    pub left_plan: Box<dyn PlanNode>,
+    pub right_plan: Box<dyn PlanNode>,
+    pub left_key: Vec<usize>,
+    pub right_key: Vec<usize>,
+    pub cond: Predicate,
+}
+
+impl MergeJoin {
+    fn sorted_rows(
+        bufmgr: &BufferPoolManager,
+        plan: &dyn PlanNode,
+        columns: &[usize],
+    ) -> Result<Vec<(Vec<u8>, Tuple)>> {
+        let mut rows = vec![];
+        let mut iter = plan.start(bufmgr)?;
+        while let Some(tuple) = iter.next(bufmgr)? {
+            if let Some(key) = join_key(&tuple, columns) {
+                rows.push((key, tuple));
+            }
+        }
+        // エンコードしたキーのバイト列の順は、値の順と同じ
+        rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(rows)
+    }
+}
+
+impl PlanNode for MergeJoin {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let left_rows = Self::sorted_rows(bufmgr, &*self.left_plan, &self.left_key)?;
+        let right_rows = Self::sorted_rows(bufmgr, &*self.right_plan, &self.right_key)?;
+        Ok(Box::new(ExecMergeJoin {
+            left_rows,
+            right_rows,
+            left_pos: 0,
+            right_pos: 0,
+            pending: VecDeque::new(),
+            cond: &self.cond,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        explain_join("MergeJoin", &self.left_key, &self.right_key, &self.cond)
+            .child(&*self.left_plan)
+            .child(&*self.right_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.left_plan, &mut self.right_plan]
+    }
+}
+
+pub struct ExecProject<'a> {
+    inner_iter: BoxExecutor<'a>,
+    columns: &'a [usize],
+}
+
+impl<'a> Executor for ExecProject<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let tuple = match self.inner_iter.next(bufmgr)? {
+            Some(tuple) => tuple,
+            None => return Ok(None),
+        };
+        Ok(Some(
+            self.columns
+                .iter()
+                .map(|&index| tuple[index].clone())
+                .collect(),
+        ))
+    }
+}
+
+// columns の列だけを、その順に並べる
+pub struct Project {
+    pub inner_plan: Box<dyn PlanNode>,
+    pub columns: Vec<usize>,
+}
+
+impl PlanNode for Project {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let inner_iter = self.inner_plan.start(bufmgr)?;
+        Ok(Box::new(ExecProject {
+            inner_iter,
+            columns: &self.columns,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        Explain::new("Project")
+            .property("columns", format_columns(&self.columns))
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.inner_plan]
+    }
+}
+
+pub struct ExecLimit<'a> {
+    inner_iter: BoxExecutor<'a>,
+    // 読み飛ばす残りの行数と、返してよい残りの行数
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl<'a> Executor for ExecLimit<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        while self.offset > 0 {
+            if self.inner_iter.next(bufmgr)?.is_none() {
+                return Ok(None);
+            }
+            self.offset -= 1;
+        }
+        match &mut self.limit {
+            Some(0) => return Ok(None),
+            Some(limit) => *limit -= 1,
+            None => {}
+        }
+        self.inner_iter.next(bufmgr)
+    }
+}
+
+// 先頭の offset 行を読み飛ばし、その後の limit 行だけを返す
+pub struct Limit {
+    pub inner_plan: Box<dyn PlanNode>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl PlanNode for Limit {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let inner_iter = self.inner_plan.start(bufmgr)?;
+        Ok(Box::new(ExecLimit {
+            inner_iter,
+            offset: self.offset,
+            limit: self.limit,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        let mut explain = Explain::new("Limit");
+        if let Some(limit) = self.limit {
+            explain = explain.property("limit", limit);
+        }
+        explain
+            .property("offset", self.offset)
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.inner_plan]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortKey {
+    pub column: usize,
+    pub descending: bool,
+}
+
+fn compare(keys: &[SortKey], a: TupleSlice, b: TupleSlice) -> Ordering {
+    keys.iter()
+        .map(|key| {
+            let ordering = a[key.column].cmp(&b[key.column]);
+            if key.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+// 並べ替えた行をオーバーフローページの連鎖に書き出したもの
+// 各行は長さ (u32) とエンコードした行を並べる
+struct Run {
+    next_page_id: Option<PageId>,
+    // 読み込んだが、まだ行として返していないバイト列
+    buf: Vec<u8>,
+}
+
+impl Run {
+    fn write(bufmgr: &BufferPoolManager, tuples: &[Tuple]) -> Result<Self> {
+        let mut bytes = vec![];
+        let mut encoded = vec![];
+        for tuple in tuples {
+            encoded.clear();
+            tuple::encode(tuple.iter(), &mut encoded);
+            bytes.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(Self {
+            next_page_id: Some(overflow::write(bufmgr, &bytes)?),
+            buf: vec![],
+        })
+    }
+
+    // 読み終えたページはすぐに解放する
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            if self.buf.len() >= 4 {
+                let len = u32::from_le_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                if self.buf.len() >= 4 + len {
+                    let mut tuple = vec![];
+                    tuple::decode(&self.buf[4..4 + len], &mut tuple);
+                    self.buf.drain(..4 + len);
+                    return Ok(Some(tuple));
+                }
+            }
+            let page_id = match self.next_page_id {
+                Some(page_id) => page_id,
+                None => return Ok(None),
+            };
+            {
+                let latch = bufmgr.fetch_page_shared(page_id)?;
+                let body = latch.body();
+                let page = overflow::Overflow::new(&body[..]);
+                self.buf.extend_from_slice(page.data());
+                self.next_page_id = page.next_page_id();
+            }
+            bufmgr.delete_page(page_id)?;
+        }
+    }
+
+    fn free(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
+        if let Some(page_id) = self.next_page_id.take() {
+            for page_id in overflow::page_ids(bufmgr, page_id)? {
+                bufmgr.delete_page(page_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Sorted {
+    Memory(std::vec::IntoIter<Tuple>),
+    // 書き出した並びと、それぞれの先頭の行
+    Runs(Vec<(Run, Option<Tuple>)>),
+}
+
+pub struct ExecSort<'a> {
+    bufmgr: &'a BufferPoolManager,
+    keys: &'a [SortKey],
+    sorted: Sorted,
+}
+
+impl<'a> Executor for ExecSort<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let keys = self.keys;
+        let runs = match &mut self.sorted {
+            Sorted::Memory(iter) => return Ok(iter.next()),
+            Sorted::Runs(runs) => runs,
+        };
+        // 先頭の行が最も小さい並びから取る。同じなら先に書き出した並びを選ぶので、元の順序を保つ
+        let mut min: Option<usize> = None;
+        for (i, (_, head)) in runs.iter().enumerate() {
+            if let Some(head) = head {
+                let is_less = min.is_none_or(|min| {
+                    let min_head = runs[min].1.as_ref().unwrap();
+                    compare(keys, head, min_head).is_lt()
+                });
+                if is_less {
+                    min = Some(i);
+                }
+            }
+        }
+        let (run, head) = match min {
+            Some(min) => &mut runs[min],
+            None => return Ok(None),
+        };
+        let next = run.next(bufmgr)?;
+        Ok(std::mem::replace(head, next))
+    }
+}
+
+impl<'a> Drop for ExecSort<'a> {
+    // 読み終える前に捨てられた並びのページを解放する
+    fn drop(&mut self) {
+        if let Sorted::Runs(runs) = &mut self.sorted {
+            for (run, _) in runs {
+                let _ = run.free(self.bufmgr);
+            }
+        }
+    }
+}
+
+// keys の順に並べ替える。同じ順位の行は元の順に並ぶ
+// エンコードした行の大きさの合計が memory_budget を超えたら、そこまでを並べ替えてページに書き出し、
+// 最後に書き出した並びをマージする
+pub struct Sort {
+    pub inner_plan: Box<dyn PlanNode>,
+    pub keys: Vec<SortKey>,
+    pub memory_budget: usize,
+}
+
+impl PlanNode for Sort {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        // 途中で失敗しても、書き出した並びは exec を drop する時に解放する
+        let mut exec = ExecSort {
+            bufmgr,
+            keys: &self.keys,
+            sorted: Sorted::Runs(vec![]),
+        };
+        let runs = match &mut exec.sorted {
+            Sorted::Runs(runs) => runs,
+            Sorted::Memory(_) => unreachable!(),
+        };
+        let mut tuples = vec![];
+        let mut size = 0;
+        let mut encoded = vec![];
+        let mut inner_iter = self.inner_plan.start(bufmgr)?;
+        while let Some(tuple) = inner_iter.next(bufmgr)? {
+            encoded.clear();
+            tuple::encode(tuple.iter(), &mut encoded);
+            size += encoded.len();
+            tuples.push(tuple);
+            if size > self.memory_budget {
+                tuples.sort_by(|a, b| compare(&self.keys, a, b));
+                runs.push((Run::write(bufmgr, &tuples)?, None));
+                tuples.clear();
+                size = 0;
+            }
+        }
+        tuples.sort_by(|a, b| compare(&self.keys, a, b));
+        if runs.is_empty() {
+            exec.sorted = Sorted::Memory(tuples.into_iter());
+            return Ok(Box::new(exec));
+        }
+        if !tuples.is_empty() {
+            runs.push((Run::write(bufmgr, &tuples)?, None));
+        }
+        for (run, head) in runs.iter_mut() {
+            *head = run.next(bufmgr)?;
+        }
+        Ok(Box::new(exec))
+    }
+
+    fn explain(&self) -> Explain {
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let order = if key.descending { "DESC" } else { "ASC" };
+                format!("#{} {}", key.column, order)
+            })
+            .collect();
+        Explain::new("Sort")
+            .property("keys", keys.join(", "))
+            .property("memory_budget", self.memory_budget)
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.inner_plan]
+    }
+}
+
+// 集約関数。列の NULL は数えない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    // COUNT(*)
+    CountAll,
+    Count(usize),
+    Sum(usize),
+    Min(usize),
+    Max(usize),
+    Avg(usize),
+}
+
+impl Aggregate {
+    fn column(self) -> Option<usize> {
+        match self {
+            Aggregate::CountAll => None,
+            Aggregate::Count(column)
+            | Aggregate::Sum(column)
+            | Aggregate::Min(column)
+            | Aggregate::Max(column)
+            | Aggregate::Avg(column) => Some(column),
+        }
+    }
+
+    fn init(self) -> Accumulator {
+        match self {
+            Aggregate::CountAll | Aggregate::Count(_) => Accumulator::Count(0),
+            Aggregate::Sum(_) => Accumulator::Sum(Value::Null),
+            Aggregate::Min(_) => Accumulator::Min(Value::Null),
+            Aggregate::Max(_) => Accumulator::Max(Value::Null),
+            Aggregate::Avg(_) => Accumulator::Avg(0.0, 0),
+        }
+    }
+}
+
+// 集約の途中の値
+enum Accumulator {
+    Count(i64),
+    // 整数だけなら整数、浮動小数点数が混ざれば浮動小数点数で足す
+    Sum(Value),
+    Min(Value),
+    Max(Value),
+    Avg(f64, i64),
+}
+
+impl Accumulator {
+    fn update(&mut self, value: &Value) -> Result<()> {
+        if value.is_null() {
+            return Ok(());
+        }
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Sum(sum) => {
+                *sum = match (&*sum, value) {
+                    (Value::Null, Value::Int64(_)) | (Value::Null, Value::Float64(_)) => {
+                        value.clone()
+                    }
+                    (Value::Int64(a), Value::Int64(b)) => {
+                        Value::Int64(a.checked_add(*b).ok_or(Error::SumOverflow)?)
+                    }
+                    (Value::Int64(a), Value::Float64(b)) => Value::Float64(*a as f64 + b),
+                    (Value::Float64(a), Value::Int64(b)) => Value::Float64(a + *b as f64),
+                    (Value::Float64(a), Value::Float64(b)) => Value::Float64(a + b),
+                    _ => return Err(invalid_aggregate("SUM", value)),
+                }
+            }
+            Accumulator::Min(min) => {
+                if min.is_null() || value < min {
+                    *min = value.clone();
+                }
+            }
+            Accumulator::Max(max) => {
+                if max.is_null() || value > max {
+                    *max = value.clone();
+                }
+            }
+            Accumulator::Avg(sum, count) => {
+                *sum += match value {
+                    Value::Int64(i) => *i as f64,
+                    Value::Float64(f) => *f,
+                    _ => return Err(invalid_aggregate("AVG", value)),
+                };
+                *count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // 値が 1 つもなければ、COUNT 以外は NULL になる
+    fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(count) => Value::Int64(count),
+            Accumulator::Sum(value) | Accumulator::Min(value) | Accumulator::Max(value) => value,
+            Accumulator::Avg(_, 0) => Value::Null,
+            Accumulator::Avg(sum, count) => Value::Float64(sum / count as f64),
+        }
+    }
+}
+
+fn invalid_aggregate(function: &'static str, value: &Value) -> anyhow::Error {
+    Error::InvalidAggregate {
+        function,
+        value: value.clone(),
+    }
+    .into()
+}
+
+pub struct ExecHashAggregate {
+    rows: std::vec::IntoIter<Tuple>,
+}
+
+impl Executor for ExecHashAggregate {
+    fn next(&mut self, _bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        Ok(self.rows.next())
+    }
+}
+
+// group_by の列の値が等しい行をまとめ、グループごとに group_by の列の値と aggregates の結果を並べた行を返す
+// グループは最初に現れた順に並ぶ。group_by が空なら、行がなくても 1 行を返す
+pub struct HashAggregate {
+    pub inner_plan: Box<dyn PlanNode>,
+    pub group_by: Vec<usize>,
+    pub aggregates: Vec<Aggregate>,
+}
+
+impl PlanNode for HashAggregate {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let init = || -> Vec<_> { self.aggregates.iter().map(|agg| agg.init()).collect() };
+        let mut groups = vec![];
+        let mut group_ids = HashMap::new();
+        if self.group_by.is_empty() {
+            groups.push((vec![], init()));
+            group_ids.insert(vec![], 0);
+        }
+        let mut inner_iter = self.inner_plan.start(bufmgr)?;
+        while let Some(tuple) = inner_iter.next(bufmgr)? {
+            // NULL も 1 つのグループにまとめる
+            let mut key = vec![];
+            tuple::encode(self.group_by.iter().map(|&index| &tuple[index]), &mut key);
+            let group_id = *group_ids.entry(key).or_insert_with(|| {
+                let group = self
+                    .group_by
+                    .iter()
+                    .map(|&index| tuple[index].clone())
+                    .collect();
+                groups.push((group, init()));
+                groups.len() - 1
+            });
+            let accumulators = &mut groups[group_id].1;
+            for (agg, accumulator) in self.aggregates.iter().zip(accumulators) {
+                match agg.column() {
+                    Some(column) => accumulator.update(&tuple[column])?,
+                    None => accumulator.update(&Value::Bool(true))?,
+                }
+            }
+        }
+        let rows: Vec<Tuple> = groups
+            .into_iter()
+            .map(|(mut group, accumulators)| {
+                group.extend(accumulators.into_iter().map(Accumulator::finish));
+                group
+            })
+            .collect();
+        Ok(Box::new(ExecHashAggregate {
+            rows: rows.into_iter(),
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        let aggregates: Vec<_> = self.aggregates.iter().map(ToString::to_string).collect();
+        Explain::new("HashAggregate")
+            .property("group_by", format_columns(&self.group_by))
+            .property("aggregates", aggregates.join(", "))
+            .child(&*self.inner_plan)
+    }
+
+    fn children_mut(&mut self) -> Vec<&mut Box<dyn PlanNode>> {
+        vec![&mut self.inner_plan]
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregate::CountAll => f.write_str("COUNT(*)"),
+            Aggregate::Count(column) => write!(f, "COUNT(#{})", column),
+            Aggregate::Sum(column) => write!(f, "SUM(#{})", column),
+            Aggregate::Min(column) => write!(f, "MIN(#{})", column),
+            Aggregate::Max(column) => write!(f, "MAX(#{})", column),
+            Aggregate::Avg(column) => write!(f, "AVG(#{})", column),
+        }
+    }
+}
+
+pub struct ExecInstrumented<'a> {
+    inner_iter: BoxExecutor<'a>,
+    stats: &'a Cell<NodeStats>,
+}
+
+impl<'a> Executor for ExecInstrumented<'a> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let inner_iter = &mut self.inner_iter;
+        let tuple = measure(self.stats, || inner_iter.next(bufmgr))?;
+        if tuple.is_some() {
+            let mut stats = self.stats.get();
+            stats.rows += 1;
+            self.stats.set(stats);
+        }
+        Ok(tuple)
+    }
+}
+
+// f にかかった時間と、その間にこのスレッドが取り出したページの数を足す
+fn measure<T>(stats: &Cell<NodeStats>, f: impl FnOnce() -> T) -> T {
+    let fetches = buffer::thread_fetches();
+    let started = Instant::now();
+    let result = f();
+    let mut total = stats.get();
+    total.elapsed += started.elapsed();
+    total.pages += buffer::thread_fetches() - fetches;
+    stats.set(total);
+    result
+}
+
+// 包んだノードの実行を計測する。EXPLAIN ANALYZE で使う
+pub struct Instrumented {
+    pub inner_plan: Box<dyn PlanNode>,
+    stats: Cell<NodeStats>,
+}
+
+impl Instrumented {
+    // plan の全てのノードを包む
+    pub fn wrap(mut plan: Box<dyn PlanNode>) -> Box<dyn PlanNode> {
+        for child in plan.children_mut() {
+            let inner_plan = mem::replace(child, Box::new(Detached));
+            *child = Self::wrap(inner_plan);
+        }
+        Box::new(Self {
+            inner_plan: plan,
+            stats: Cell::default(),
+        })
+    }
+
+    pub fn stats(&self) -> NodeStats {
+        self.stats.get()
+    }
+}
+
+impl PlanNode for Instrumented {
+    fn start<'a>(&'a self, bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        let inner_iter = measure(&self.stats, || self.inner_plan.start(bufmgr))?;
+        let mut stats = self.stats.get();
+        stats.loops += 1;
+        self.stats.set(stats);
+        Ok(Box::new(ExecInstrumented {
+            inner_iter,
+            stats: &self.stats,
+        }))
+    }
+
+    fn explain(&self) -> Explain {
+        let mut explain = self.inner_plan.explain();
+        explain.stats = Some(self.stats.get());
+        explain
+    }
+}
+
+// Instrumented::wrap が子ノードを取り出している間だけ置いておく
+struct Detached;
+
+impl PlanNode for Detached {
+    fn start<'a>(&'a self, _bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+        unreachable!("detached plan node")
+    }
+
+    fn explain(&self) -> Explain {
+        Explain::new("Detached")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempfile, NamedTempFile};
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+    use crate::mvcc::TxnManager;
+    use crate::table::{NonUniqueIndex, Table, UniqueIndex};
+
+    // メモリ上の行を返す実行計画
+    struct Values(Vec<Tuple>);
+
+    impl PlanNode for Values {
+        fn start<'a>(&'a self, _bufmgr: &'a BufferPoolManager) -> Result<BoxExecutor<'a>> {
+            Ok(Box::new(ExecValues(self.0.iter())))
+        }
+
+        fn explain(&self) -> Explain {
+            Explain::new("Values").property("rows", self.0.len())
+        }
+    }
+
+    struct ExecValues<'a>(std::slice::Iter<'a, Tuple>);
+
+    impl<'a> Executor for ExecValues<'a> {
+        fn next(&mut self, _bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+            Ok(self.0.next().cloned())
+        }
+    }
+
+    fn collect(bufmgr: &BufferPoolManager, plan: &dyn PlanNode) -> Vec<Tuple> {
+        let mut exec = plan.start(bufmgr).unwrap();
+        let mut tuples = vec![];
+        while let Some(tuple) = exec.next(bufmgr).unwrap() {
+            tuples.push(tuple);
+        }
+        tuples
+    }
+
+    #[test]
+    fn test_join() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        // users (id, email, name) と、email で users を指す orders (id, email, item)
+        let mut users = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        users.create(&bufmgr).unwrap();
+        for (id, email, name) in [(1, "a@x", "Alice"), (2, "b@x", "Bob"), (3, "c@x", "Carol")] {
+            let record = [Value::from(id as i64), email.into(), name.into()];
+            users.insert(&bufmgr, &record).unwrap();
+        }
+        let mut orders = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![NonUniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            hash_indices: vec![],
+        };
+        orders.create(&bufmgr).unwrap();
+        let order_rows = [
+            (10, Value::from("b@x"), "pen"),
+            (11, Value::from("a@x"), "ink"),
+            (12, Value::from("b@x"), "cup"),
+            (13, Value::from("z@x"), "mug"),
+            (14, Value::Null, "box"),
+        ];
+        for (id, email, item) in order_rows.iter().cloned() {
+            let record = [Value::from(id as i64), email, item.into()];
+            orders.insert(&bufmgr, &record).unwrap();
+        }
+
+        let scan = |table: &Table| -> Box<dyn PlanNode> {
+            Box::new(SeqScan {
+                table_meta_page_id: table.meta_page_id,
+                search_mode: TupleSearchMode::Start,
+                range: TupleRange::all(),
+                snapshot: None,
+            })
+        };
+        let row = |order: (i64, &str, &str), user: (i64, &str, &str)| -> Tuple {
+            vec![
+                order.0.into(),
+                order.1.into(),
+                order.2.into(),
+                user.0.into(),
+                user.1.into(),
+                user.2.into(),
+            ]
+        };
+        let expected = vec![
+            row((10, "b@x", "pen"), (2, "b@x", "Bob")),
+            row((11, "a@x", "ink"), (1, "a@x", "Alice")),
+            row((12, "b@x", "cup"), (2, "b@x", "Bob")),
+        ];
+
+        // orders.email = users.email
+        let eq = || {
+            Predicate::new("#1 = #4", |tuple| {
+                !tuple[1].is_null() && tuple[1] == tuple[4]
+            })
+        };
+        let nested_loop = NestedLoopJoin {
+            outer_plan: scan(&orders),
+            inner_plan: scan(&users),
+            cond: eq(),
+        };
+        assert_eq!(expected, collect(&bufmgr, &nested_loop));
+        let index_nested_loop = IndexNestedLoopJoin {
+            outer_plan: scan(&orders),
+            table_meta_page_id: users.meta_page_id,
+            index_meta_page_id: users.unique_indices[0].meta_page_id,
+            outer_key: vec![1],
+            cond: Predicate::always(),
+        };
+        assert_eq!(expected, collect(&bufmgr, &index_nested_loop));
+        // 一意でないインデックスなら、外側の1行に一致する行を全て返す
+        let index_nested_loop = IndexNestedLoopJoin {
+            outer_plan: scan(&users),
+            table_meta_page_id: orders.meta_page_id,
+            index_meta_page_id: orders.non_unique_indices[0].meta_page_id,
+            outer_key: vec![1],
+            cond: Predicate::always(),
+        };
+        let swap = |tuple: &Tuple| [&tuple[3..], &tuple[..3]].concat();
+        assert_eq!(
+            vec![swap(&expected[1]), swap(&expected[0]), swap(&expected[2])],
+            collect(&bufmgr, &index_nested_loop)
+        );
+        let hash = HashJoin {
+            left_plan: scan(&orders),
+            right_plan: scan(&users),
+            left_key: vec![1],
+            right_key: vec![1],
+            cond: Predicate::always(),
+        };
+        assert_eq!(expected, collect(&bufmgr, &hash));
+        // 結合キーの順に並ぶ
+        let merge = MergeJoin {
+            left_plan: scan(&orders),
+            right_plan: scan(&users),
+            left_key: vec![1],
+            right_key: vec![1],
+            cond: Predicate::always(),
+        };
+        let mut by_email = expected.clone();
+        by_email.swap(0, 1);
+        assert_eq!(by_email, collect(&bufmgr, &merge));
+
+        // 結合キー以外の条件も使える
+        let hash = HashJoin {
+            left_plan: scan(&orders),
+            right_plan: scan(&users),
+            left_key: vec![1],
+            right_key: vec![1],
+            cond: Predicate::new("#2 <> 'cup'", |tuple| tuple[2] != Value::from("cup")),
+        };
+        assert_eq!(expected[..2].to_vec(), collect(&bufmgr, &hash));
+        let nested_loop = NestedLoopJoin {
+            outer_plan: scan(&users),
+            inner_plan: scan(&users),
+            cond: Predicate::new("#0 < #3", |tuple| tuple[0] < tuple[3]),
+        };
+        assert_eq!(3, collect(&bufmgr, &nested_loop).len());
+
+        // セカンダリキーが一致する行を全て、主キーの順に返す
+        let index_scan = |search_mode, range| IndexScan {
+            table_meta_page_id: orders.meta_page_id,
+            index_meta_page_id: orders.non_unique_indices[0].meta_page_id,
+            search_mode,
+            range,
+            snapshot: None,
+        };
+        let ids = |tuples: Vec<Tuple>| -> Vec<Value> {
+            tuples.into_iter().map(|tuple| tuple[0].clone()).collect()
+        };
+        let prefix = TupleRange::prefix(vec!["b@x".into()]);
+        let plan = index_scan(TupleSearchMode::Start, prefix);
+        assert_eq!(
+            vec![Value::from(10i64), 12i64.into()],
+            ids(collect(&bufmgr, &plan))
+        );
+        let prefix = TupleRange::prefix(vec!["b@x".into()]);
+        let plan = index_scan(TupleSearchMode::End, prefix);
+        assert_eq!(
+            vec![Value::from(12i64), 10i64.into()],
+            ids(collect(&bufmgr, &plan))
+        );
+        let range = TupleRange {
+            lower: Bound::Excluded(vec!["a@x".into()]),
+            upper: Bound::Unbounded,
+        };
+        let plan = index_scan(TupleSearchMode::Start, range);
+        assert_eq!(
+            vec![Value::from(10i64), 12i64.into(), 13i64.into()],
+            ids(collect(&bufmgr, &plan))
+        );
+
+        // テーブルから消えた行を指すエントリは、次の行を返さずにエラーにする
+        let mut pkey = vec![];
+        tuple::encode([Value::from(12i64)].iter(), &mut pkey);
+        BTree::new(orders.meta_page_id)
+            .delete(&bufmgr, &pkey)
+            .unwrap();
+        let plan = index_scan(TupleSearchMode::Start, TupleRange::all());
+        let mut exec = plan.start(&bufmgr).unwrap();
+        let err = loop {
+            match exec.next(&bufmgr) {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("dangling index entry was not reported"),
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::DanglingIndexEntry)
+        ));
+    }
+
+    #[test]
+    fn test_hash_index_scan() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let mut users = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        users.create(&bufmgr).unwrap();
+        // email から主キーを引くハッシュインデックスを、B+Tree のインデックスと同じ内容で作る
+        let index = HashIndex::create(&bufmgr).unwrap();
+        for id in 0..1000i64 {
+            let email = format!("user{}@x", id);
+            users
+                .insert(&bufmgr, &[id.into(), email.as_str().into()])
+                .unwrap();
+            let (mut skey, mut pkey) = (vec![], vec![]);
+            tuple::encode([Value::from(email.as_str())].iter(), &mut skey);
+            tuple::encode([Value::from(id)].iter(), &mut pkey);
+            index.insert(&bufmgr, &skey, &pkey).unwrap();
+        }
+
+        let hash_index_scan = |key: Value| HashIndexScan {
+            table_meta_page_id: users.meta_page_id,
+            index_meta_page_id: index.meta_page_id,
+            key: vec![key],
+            snapshot: None,
+        };
+        for id in [0i64, 123, 999] {
+            let email = format!("user{}@x", id);
+            let plan = hash_index_scan(email.as_str().into());
+            let index_scan = IndexScan {
+                table_meta_page_id: users.meta_page_id,
+                index_meta_page_id: users.unique_indices[0].meta_page_id,
+                search_mode: TupleSearchMode::Start,
+                range: TupleRange::prefix(vec![email.as_str().into()]),
+                snapshot: None,
+            };
+            assert_eq!(collect(&bufmgr, &index_scan), collect(&bufmgr, &plan));
+            assert_eq!(
+                vec![vec![Value::from(id), email.as_str().into()]],
+                collect(&bufmgr, &plan)
+            );
+        }
+        assert!(collect(&bufmgr, &hash_index_scan("nobody@x".into())).is_empty());
+        assert!(collect(&bufmgr, &hash_index_scan(Value::Null)).is_empty());
+
+        let plan = hash_index_scan("user1@x".into());
+        assert_eq!(
+            vec![format!(
+                "HashIndexScan (table: {}, index: {}, key: ('user1@x'))",
+                users.meta_page_id.to_u64(),
+                index.meta_page_id.to_u64()
+            )],
+            plan.explain().lines()
+        );
+    }
+
+    #[test]
+    fn test_project_limit() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let rows = (0..10i64)
+            .map(|i| vec![i.into(), format!("row{}", i).into()])
+            .collect();
+        let plan = Limit {
+            inner_plan: Box::new(Project {
+                inner_plan: Box::new(Values(rows)),
+                columns: vec![1, 0],
+            }),
+            limit: Some(3),
+            offset: 8,
+        };
+        let expected: Vec<Tuple> = vec![
+            vec!["row8".into(), 8i64.into()],
+            vec!["row9".into(), 9i64.into()],
+        ];
+        assert_eq!(expected, collect(&bufmgr, &plan));
+    }
+
+    #[test]
+    fn test_explain() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        for i in 0..100i64 {
+            table.insert(&bufmgr, &[i.into(), "x".into()]).unwrap();
+        }
+        let outer = vec![vec![Value::from(3i64)], vec![Value::from(5i64)]];
+        let plan = Limit {
+            inner_plan: Box::new(NestedLoopJoin {
+                outer_plan: Box::new(Values(outer)),
+                inner_plan: Box::new(Filter {
+                    inner_plan: Box::new(SeqScan {
+                        table_meta_page_id: table.meta_page_id,
+                        search_mode: TupleSearchMode::Start,
+                        range: TupleRange {
+                            lower: Bound::Included(vec![Value::from(10i64)]),
+                            upper: Bound::Unbounded,
+                        },
+                        snapshot: None,
+                    }),
+                    cond: Predicate::new("#1 = 'x'", |tuple| tuple[1] == Value::from("x")),
+                }),
+                cond: Predicate::new("#1 < 20", |tuple| tuple[1] < Value::from(20i64)),
+            }),
+            limit: Some(15),
+            offset: 0,
+        };
+        let table_id = table.meta_page_id.to_u64();
+        assert_eq!(
+            vec![
+                "Limit (limit: 15, offset: 0)".to_string(),
+                "-> NestedLoopJoin (cond: #1 < 20)".to_string(),
+                "  -> Values (rows: 2)".to_string(),
+                "  -> Filter (cond: #1 = 'x')".to_string(),
+                format!(
+                    "    -> SeqScan (table: {}, mode: start, range: >= (10))",
+                    table_id
+                ),
+            ],
+            plan.explain().lines()
+        );
+
+        let plan = Instrumented::wrap(Box::new(plan));
+        assert_eq!(15, collect(&bufmgr, &*plan).len());
+        let explain = plan.explain();
+        let limit = explain.stats.unwrap();
+        assert_eq!((1, 15), (limit.loops, limit.rows));
+        let join = &explain.children[0];
+        assert_eq!(15, join.stats.unwrap().rows);
+        // 内側は外側の行ごとに始める。1 回目は末尾まで読み、2 回目は 5 行で打ち切る
+        let filter = join.children[1].stats.unwrap();
+        assert_eq!((2, 90 + 5), (filter.loops, filter.rows));
+        let scan = join.children[1].children[0].stats.unwrap();
+        assert_eq!(filter.rows, scan.rows);
+        assert!(scan.pages > 0);
+        assert!(scan.pages <= filter.pages && filter.pages <= limit.pages);
+        assert!(scan.elapsed <= limit.elapsed);
+        assert!(explain.lines()[0]
+            .starts_with("Limit (limit: 15, offset: 0) [loops: 1, rows: 15, pages: "));
+    }
+
+    #[test]
+    fn test_sort() {
+        let (file, path) = NamedTempFile::new().unwrap().into_parts();
+        let disk = DiskManager::new(file).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        // (i % 7, i) を i % 7 の降順に並べる。i % 7 が同じ行は元の順のまま
+        let rows: Vec<Tuple> = (0..2000i64)
+            .map(|i| vec![(i % 7).into(), i.into(), "x".repeat(20).into()])
+            .collect();
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| b[0].cmp(&a[0]));
+        let sort = |memory_budget| Sort {
+            inner_plan: Box::new(Values(rows.clone())),
+            keys: vec![SortKey {
+                column: 0,
+                descending: true,
+            }],
+            memory_budget,
+        };
+        assert_eq!(expected, collect(&bufmgr, &sort(usize::MAX)));
+        // 40 行ほどごとに書き出す
+        let spilled = sort(1000);
+        assert_eq!(expected, collect(&bufmgr, &spilled));
+        // 読み終える前に捨てる
+        let limit = Limit {
+            inner_plan: Box::new(spilled),
+            limit: Some(1),
+            offset: 0,
+        };
+        assert_eq!(expected[..1].to_vec(), collect(&bufmgr, &limit));
+
+        // 書き出したページは全て解放されている
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut disk = DiskManager::new(file).unwrap();
+        assert!(disk.num_pages() > 10);
+        assert_eq!(
+            disk.num_pages() - 1,
+            disk.free_page_ids().unwrap().len() as u64
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let rows: Vec<Tuple> = vec![
+            vec!["a".into(), 1i64.into(), 1.5.into()],
+            vec!["b".into(), 2i64.into(), Value::Null],
+            vec!["a".into(), Value::Null, 2.5.into()],
+            vec![Value::Null, 4i64.into(), 0.5.into()],
+            vec!["a".into(), 3i64.into(), 0.5.into()],
+        ];
+        let aggregates = vec![
+            Aggregate::CountAll,
+            Aggregate::Count(1),
+            Aggregate::Sum(1),
+            Aggregate::Min(2),
+            Aggregate::Max(1),
+            Aggregate::Avg(2),
+        ];
+        let plan = HashAggregate {
+            inner_plan: Box::new(Values(rows.clone())),
+            group_by: vec![0],
+            aggregates: aggregates.clone(),
+        };
+        let expected: Vec<Tuple> = vec![
+            vec![
+                "a".into(),
+                3i64.into(),
+                2i64.into(),
+                4i64.into(),
+                0.5.into(),
+                3i64.into(),
+                1.5.into(),
+            ],
+            vec![
+                "b".into(),
+                1i64.into(),
+                1i64.into(),
+                2i64.into(),
+                Value::Null,
+                2i64.into(),
+                Value::Null,
+            ],
+            vec![
+                Value::Null,
+                1i64.into(),
+                1i64.into(),
+                4i64.into(),
+                0.5.into(),
+                4i64.into(),
+                0.5.into(),
+            ],
+        ];
+        assert_eq!(expected, collect(&bufmgr, &plan));
+
+        // GROUP BY がなければ、行がなくても 1 行を返す
+        let plan = HashAggregate {
+            inner_plan: Box::new(Values(vec![])),
+            group_by: vec![],
+            aggregates,
+        };
+        let expected: Vec<Tuple> = vec![vec![
+            0i64.into(),
+            0i64.into(),
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+        ]];
+        assert_eq!(expected, collect(&bufmgr, &plan));
+
+        let plan = HashAggregate {
+            inner_plan: Box::new(Values(rows)),
+            group_by: vec![],
+            aggregates: vec![Aggregate::Sum(0)],
+        };
+        assert!(plan.start(&bufmgr).is_err());
+    }
+
+    #[test]
+    fn test_mvcc_scan() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txns = TxnManager::create(&bufmgr).unwrap();
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        let row = |id: i64, name: &str| -> Tuple { vec![id.into(), name.into()] };
+        let txn = txns.begin(&bufmgr).unwrap();
+        for (id, name) in [(1, "Alice"), (2, "Bob"), (3, "Carol")] {
+            table.insert_version(&bufmgr, &txn, &row(id, name)).unwrap();
+        }
+        txn.commit(&bufmgr).unwrap();
+
+        let before = txns.snapshot();
+        let txn = txns.begin(&bufmgr).unwrap();
+        table
+            .update_version(&bufmgr, &txn, &row(1, "Dave"))
+            .unwrap();
+        table.delete_version(&bufmgr, &txn, &[2i64.into()]).unwrap();
+        // Bob を消したので、同じトランザクションで Bob を使える
+        table.insert_version(&bufmgr, &txn, &row(4, "Bob")).unwrap();
+        txn.commit(&bufmgr).unwrap();
+        let after = txns.snapshot();
+
+        let seq_scan = |snapshot: &Snapshot| SeqScan {
+            table_meta_page_id: table.meta_page_id,
+            search_mode: TupleSearchMode::Start,
+            range: TupleRange::all(),
+            snapshot: Some(snapshot.clone()),
+        };
+        let index_scan = |snapshot: &Snapshot| IndexScan {
+            table_meta_page_id: table.meta_page_id,
+            index_meta_page_id: table.unique_indices[0].meta_page_id,
+            search_mode: TupleSearchMode::Start,
+            range: TupleRange::all(),
+            snapshot: Some(snapshot.clone()),
+        };
+        let old_rows = vec![row(1, "Alice"), row(2, "Bob"), row(3, "Carol")];
+        assert_eq!(old_rows, collect(&bufmgr, &seq_scan(&before)));
+        assert_eq!(old_rows, collect(&bufmgr, &index_scan(&before)));
+        assert_eq!(
+            vec![row(1, "Dave"), row(3, "Carol"), row(4, "Bob")],
+            collect(&bufmgr, &seq_scan(&after))
+        );
+        assert_eq!(
+            vec![row(4, "Bob"), row(3, "Carol"), row(1, "Dave")],
+            collect(&bufmgr, &index_scan(&after))
+        );
+
+        // before が残っている間は、古い版を消さない
+        assert_eq!(0, table.vacuum(&bufmgr, &txns).unwrap().removed_versions);
+        drop(before);
+        let stats = table.vacuum(&bufmgr, &txns).unwrap();
+        assert_eq!(2, stats.removed_entries);
+        assert_eq!(
+            vec![row(1, "Dave"), row(3, "Carol"), row(4, "Bob")],
+            collect(&bufmgr, &seq_scan(&after))
+        );
+        drop(after);
+    }
 }
@@ -0,0 +1,498 @@
+use std::convert::TryInto;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::btree;
+use crate::buffer::BufferPoolManager;
+use crate::catalog;
+use crate::mvcc;
+use crate::sql::{self, ast::Statement, Database, QueryResult};
+use crate::value::{DataType, Value};
+
+// 起動メッセージの先頭に入るプロトコルのバージョンと、特別な要求のコード
+const PROTOCOL_VERSION: i32 = 3 << 16;
+const SSL_REQUEST_CODE: i32 = 80877103;
+const GSSENC_REQUEST_CODE: i32 = 80877104;
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+
+// 起動メッセージと通常のメッセージの長さの上限
+const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+// 接続を受け付けられなかった時に、次に受け付けるまで待つ時間
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// 列の型の OID (PostgreSQL の pg_type)
+fn type_oid(data_type: DataType) -> i32 {
+    match data_type {
+        DataType::Bool => 16,
+        DataType::Bytes => 17,
+        DataType::Int64 => 20,
+        DataType::Text => 25,
+        DataType::Float64 => 701,
+    }
+}
+
+// 値のテキスト形式。NULL は None
+fn text_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(true) => Some("t".into()),
+        Value::Bool(false) => Some("f".into()),
+        value => Some(value.to_string()),
+    }
+}
+
+// エラーに対応する SQLSTATE
+fn sqlstate(err: &anyhow::Error) -> &'static str {
+    let catalog_error =
+        err.downcast_ref::<catalog::Error>()
+            .or_else(|| match err.downcast_ref::<sql::Error>() {
+                Some(sql::Error::Catalog(err)) => Some(err),
+                _ => None,
+            });
+    if let Some(err) = catalog_error {
+        return match err {
+            catalog::Error::TableNotFound(_) => "42P01",
+            catalog::Error::TableExists(_) | catalog::Error::IndexExists(_) => "42P07",
+            catalog::Error::ColumnNotFound(_) => "42703",
+            catalog::Error::TypeMismatch { .. } => "42804",
+            catalog::Error::NullPrimaryKey(_) => "23502",
+            catalog::Error::InvalidSchema(_) => "42P16",
+            catalog::Error::NotEmpty | catalog::Error::SnapshotRequired(_) => "XX000",
+            catalog::Error::UnsupportedFormat(_) | catalog::Error::Corrupted => "XX001",
+        };
+    }
+    if let Some(mvcc::Error::WriteConflict(_)) = err.downcast_ref::<mvcc::Error>() {
+        return "40001";
+    }
+    match (
+        err.downcast_ref::<sql::Error>(),
+        err.downcast_ref::<btree::Error>(),
+    ) {
+        (Some(sql::Error::Syntax { .. }), _) | (Some(sql::Error::ValueCount { .. }), _) => "42601",
+        (Some(sql::Error::Unsupported(_)), _) => "0A000",
+        (_, Some(btree::Error::DuplicateKey)) => "23505",
+        _ => "XX000",
+    }
+}
+
+// 種類を表す1バイトと、自身を含む長さ (i32) の後に本体が続くメッセージ
+struct Message {
+    tag: u8,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn new(tag: u8) -> Self {
+        Self { tag, body: vec![] }
+    }
+
+    fn i16(mut self, n: i16) -> Self {
+        self.body.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn i32(mut self, n: i32) -> Self {
+        self.body.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn byte(mut self, b: u8) -> Self {
+        self.body.push(b);
+        self
+    }
+
+    fn cstr(mut self, s: &str) -> Self {
+        self.body.extend_from_slice(s.as_bytes());
+        self.body.push(0);
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.body.extend_from_slice(bytes);
+        self
+    }
+
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&[self.tag])?;
+        w.write_all(&(self.body.len() as i32 + 4).to_be_bytes())?;
+        w.write_all(&self.body)
+    }
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+fn read_body(r: &mut impl Read, len: i32) -> io::Result<Vec<u8>> {
+    let len = (len as usize)
+        .checked_sub(4)
+        .filter(|&len| len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid message length"))?;
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// 接続が閉じられていたら None
+fn read_message(r: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut tag = [0];
+    match r.read_exact(&mut tag) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = read_i32(r)?;
+    Ok(Some((tag[0], read_body(r, len)?)))
+}
+
+fn error_response(code: &str, message: &str) -> Message {
+    Message::new(b'E')
+        .byte(b'S')
+        .cstr("ERROR")
+        .byte(b'V')
+        .cstr("ERROR")
+        .byte(b'C')
+        .cstr(code)
+        .byte(b'M')
+        .cstr(message)
+        .byte(0)
+}
+
+fn ready_for_query() -> Message {
+    // 各文は1つのトランザクションとしてコミットか取り消しまで終えるので、文の間は常にアイドル
+    // BEGIN で始めるトランザクションのブロックは受け付けない
+    Message::new(b'Z').byte(b'I')
+}
+
+// 結果を返した文の種類を表すコマンドタグ
+fn command_tag(statement: &Statement, result: &QueryResult) -> String {
+    match result {
+        QueryResult::Created => match statement {
+            Statement::CreateIndex(_) => "CREATE INDEX".into(),
+            _ => "CREATE TABLE".into(),
+        },
+        QueryResult::Inserted(n) => format!("INSERT 0 {}", n),
+        QueryResult::Updated(n) => format!("UPDATE {}", n),
+        QueryResult::Deleted(n) => format!("DELETE {}", n),
+        QueryResult::Analyzed => "ANALYZE".into(),
+        QueryResult::Vacuumed => "VACUUM".into(),
+        QueryResult::Rows { .. } if matches!(statement, Statement::Explain(_)) => "EXPLAIN".into(),
+        QueryResult::Rows { rows, .. } => format!("SELECT {}", rows.len()),
+    }
+}
+
+// PostgreSQL のフロントエンド/バックエンドプロトコル (バージョン 3.0) のうち、
+// 起動と簡易問い合わせだけを話すサーバー。認証はせず、SSL の要求は断る
+// 各文はそれぞれの接続のスレッドで Database::execute と同じように実行する
+pub struct Server<'a> {
+    bufmgr: &'a BufferPoolManager,
+    db: Database,
+    next_process_id: AtomicU32,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(bufmgr: &'a BufferPoolManager, db: Database) -> Self {
+        Self {
+            bufmgr,
+            db,
+            next_process_id: AtomicU32::new(1),
+        }
+    }
+
+    // 接続ごとにスレッドを作って処理する。戻らない
+    // ファイル記述子が足りないなど、受け付けられなかった接続はログに書いて次を待つ
+    pub fn serve(&self, listener: &TcpListener) -> ! {
+        thread::scope(|s| loop {
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("accept: {}", err);
+                    thread::sleep(ACCEPT_RETRY_INTERVAL);
+                    continue;
+                }
+            };
+            s.spawn(move || {
+                if let Err(err) = self.handle(stream) {
+                    eprintln!("{}: {:#}", addr, err);
+                }
+            });
+        })
+    }
+
+    // 1つの接続を、クライアントが終了するか切断するまで処理する
+    pub fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        if !self.startup(&mut reader, &mut writer)? {
+            return Ok(());
+        }
+
+        // 拡張問い合わせでエラーを返した後は、Sync まで読み捨てる
+        let mut skip_until_sync = false;
+        while let Some((tag, body)) = read_message(&mut reader)? {
+            match tag {
+                b'Q' => {
+                    let query = body.strip_suffix(&[0]).unwrap_or(&body);
+                    self.simple_query(&mut writer, &String::from_utf8_lossy(query))?;
+                    ready_for_query().write_to(&mut writer)?;
+                }
+                b'X' => break,
+                b'S' => {
+                    skip_until_sync = false;
+                    ready_for_query().write_to(&mut writer)?;
+                }
+                _ if skip_until_sync => {}
+                b'P' | b'B' | b'D' | b'E' | b'C' | b'H' | b'F' => {
+                    error_response("0A000", "extended query protocol is not supported")
+                        .write_to(&mut writer)?;
+                    skip_until_sync = true;
+                }
+                tag => {
+                    error_response("08P01", &format!("unexpected message {:?}", tag as char))
+                        .write_to(&mut writer)?;
+                    writer.flush()?;
+                    break;
+                }
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // 起動メッセージを受け取って認証済みと返す。問い合わせを受け付けないなら false
+    fn startup(&self, reader: &mut impl Read, writer: &mut impl Write) -> Result<bool> {
+        let body = loop {
+            let len = read_i32(reader)?;
+            let body = read_body(reader, len)?;
+            let code = match body.get(..4) {
+                Some(code) => i32::from_be_bytes(code.try_into().unwrap()),
+                None => return Ok(false),
+            };
+            match code {
+                SSL_REQUEST_CODE | GSSENC_REQUEST_CODE => {
+                    writer.write_all(b"N")?;
+                    writer.flush()?;
+                }
+                CANCEL_REQUEST_CODE => return Ok(false),
+                PROTOCOL_VERSION => break body,
+                _ => {
+                    error_response("0A000", "unsupported frontend protocol").write_to(writer)?;
+                    writer.flush()?;
+                    return Ok(false);
+                }
+            }
+        };
+        // user や database などのパラメーターは受け取るだけで使わない
+        let _params = &body[4..];
+
+        Message::new(b'R').i32(0).write_to(writer)?;
+        for (name, value) in [
+            ("server_version", "13.0"),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            Message::new(b'S').cstr(name).cstr(value).write_to(writer)?;
+        }
+        let process_id = self.next_process_id.fetch_add(1, Ordering::Relaxed);
+        Message::new(b'K')
+            .i32(process_id as i32)
+            .i32(0)
+            .write_to(writer)?;
+        ready_for_query().write_to(writer)?;
+        writer.flush()?;
+        Ok(true)
+    }
+
+    // 文を1つ実行して結果を送る。ReadyForQuery は呼び出し側で送る
+    fn simple_query(&self, writer: &mut impl Write, query: &str) -> Result<()> {
+        if query.trim().trim_end_matches(';').trim().is_empty() {
+            Message::new(b'I').write_to(writer)?;
+            return Ok(());
+        }
+        let result = sql::parse(query)
+            .map_err(anyhow::Error::from)
+            .and_then(|statement| {
+                let result = self.db.execute_statement(self.bufmgr, statement.clone())?;
+                // 実行した変更は、応答を返す前に永続化する
+                self.bufmgr.flush_wal()?;
+                Ok((statement, result))
+            });
+        let (statement, result) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                error_response(sqlstate(&err), &format!("{:#}", err)).write_to(writer)?;
+                return Ok(());
+            }
+        };
+        if let QueryResult::Rows { columns, rows } = &result {
+            let mut description = Message::new(b'T').i16(columns.len() as i16);
+            for column in columns {
+                // テーブルの OID と列番号は 0、型の長さは可変長 (-1)、修飾子なし、テキスト形式
+                description = description
+                    .cstr(&column.name)
+                    .i32(0)
+                    .i16(0)
+                    .i32(type_oid(column.data_type))
+                    .i16(-1)
+                    .i32(-1)
+                    .i16(0);
+            }
+            description.write_to(writer)?;
+            for row in rows {
+                let mut data_row = Message::new(b'D').i16(row.len() as i16);
+                for value in row {
+                    data_row = match text_value(value) {
+                        Some(text) => data_row.i32(text.len() as i32).bytes(text.as_bytes()),
+                        None => data_row.i32(-1),
+                    };
+                }
+                data_row.write_to(writer)?;
+            }
+        }
+        Message::new(b'C')
+            .cstr(&command_tag(&statement, &result))
+            .write_to(writer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+
+    // テスト用のクライアント。受け取ったメッセージを ReadyForQuery まで集める
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(listener: &TcpListener) -> Self {
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            // SSL を断られたら平文で続ける
+            stream.write_all(&8i32.to_be_bytes()).unwrap();
+            stream.write_all(&SSL_REQUEST_CODE.to_be_bytes()).unwrap();
+            let mut answer = [0];
+            stream.read_exact(&mut answer).unwrap();
+            assert_eq!(b'N', answer[0]);
+
+            let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+            body.extend_from_slice(b"user\0alice\0\0");
+            stream
+                .write_all(&(body.len() as i32 + 4).to_be_bytes())
+                .unwrap();
+            stream.write_all(&body).unwrap();
+            let mut client = Self { stream };
+            let messages = client.until_ready();
+            assert_eq!((b'R', 0i32.to_be_bytes().to_vec()), messages[0]);
+            client
+        }
+
+        fn until_ready(&mut self) -> Vec<(u8, Vec<u8>)> {
+            let mut messages = vec![];
+            loop {
+                let message = read_message(&mut self.stream).unwrap().unwrap();
+                if message.0 == b'Z' {
+                    return messages;
+                }
+                messages.push(message);
+            }
+        }
+
+        fn query(&mut self, sql: &str) -> Vec<(u8, Vec<u8>)> {
+            Message::new(b'Q')
+                .cstr(sql)
+                .write_to(&mut self.stream)
+                .unwrap();
+            self.until_ready()
+        }
+    }
+
+    // DataRow の列の値
+    fn columns(body: &[u8]) -> Vec<Option<String>> {
+        let mut rest = &body[2..];
+        let mut values = vec![];
+        while !rest.is_empty() {
+            let len = i32::from_be_bytes(rest[..4].try_into().unwrap());
+            rest = &rest[4..];
+            if len < 0 {
+                values.push(None);
+                continue;
+            }
+            let (value, next) = rest.split_at(len as usize);
+            values.push(Some(String::from_utf8(value.to_vec()).unwrap()));
+            rest = next;
+        }
+        values
+    }
+
+    #[test]
+    fn test() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let server = Server::new(&bufmgr, Database::create(&bufmgr).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                server.handle(stream).unwrap();
+            });
+            let mut client = Client::connect(&listener);
+
+            let messages = client
+                .query("CREATE TABLE users (id BIGINT PRIMARY KEY, name TEXT, admin BOOLEAN)");
+            assert_eq!(vec![(b'C', b"CREATE TABLE\0".to_vec())], messages);
+            let messages =
+                client.query("INSERT INTO users VALUES (1, 'Alice', TRUE), (2, NULL, FALSE);");
+            assert_eq!(vec![(b'C', b"INSERT 0 2\0".to_vec())], messages);
+
+            let messages = client.query("SELECT * FROM users");
+            let tags: Vec<_> = messages.iter().map(|(tag, _)| *tag).collect();
+            assert_eq!(b"TDDC".to_vec(), tags);
+            // 列名の後に、テーブルの OID、列番号、型の OID が続く
+            let description = &messages[0].1;
+            assert_eq!(3, i16::from_be_bytes(description[..2].try_into().unwrap()));
+            assert_eq!(b"id\0", &description[2..5]);
+            assert_eq!(
+                20,
+                i32::from_be_bytes(description[11..15].try_into().unwrap())
+            );
+            assert_eq!(
+                vec![Some("1".into()), Some("Alice".into()), Some("t".into())],
+                columns(&messages[1].1)
+            );
+            assert_eq!(
+                vec![Some("2".into()), None, Some("f".into())],
+                columns(&messages[2].1)
+            );
+            assert_eq!((b'C', b"SELECT 2\0".to_vec()), messages[3]);
+            let messages = client.query("EXPLAIN SELECT * FROM users");
+            assert_eq!(b'T', messages[0].0);
+            assert_eq!((b'C', b"EXPLAIN\0".to_vec()), messages[messages.len() - 1]);
+
+            // エラーを返しても接続は続く
+            let messages = client.query("SELECT * FROM orders");
+            assert_eq!(b'E', messages[0].0);
+            assert!(messages[0].1.windows(6).any(|w| w == b"C42P01"));
+            let messages = client.query("INSERT INTO users VALUES (1, 'Bob', FALSE)");
+            assert!(messages[0].1.windows(6).any(|w| w == b"C23505"));
+            assert_eq!(vec![(b'I', vec![])], client.query(";"));
+
+            Message::new(b'X').write_to(&mut client.stream).unwrap();
+        });
+    }
+}
@@ -63,6 +63,45 @@ impl<B: ByteSlice> Slotted<B> {
     fn data(&self, pointer: Pointer) -> &[u8] {
         &self.body[pointer.range()]
     }
+
+    // ポインタが本体に収まり、データが重ならずに空き領域の後ろに隙間なく詰まっているかを調べる
+    pub fn check(&self) -> Result<(), String> {
+        let capacity = self.capacity();
+        let free_space_offset = self.header.free_space_offset as usize;
+        if free_space_offset > capacity {
+            return Err(format!(
+                "free space offset {} exceeds capacity {}",
+                free_space_offset, capacity
+            ));
+        }
+        if self.pointers_size() > free_space_offset {
+            return Err(format!(
+                "{} slot pointers overlap the data at offset {}",
+                self.num_slots(),
+                free_space_offset
+            ));
+        }
+        let mut ranges: Vec<_> = self.pointers().iter().map(Pointer::range).collect();
+        ranges.sort_by_key(|range| range.start);
+        if let Some(range) = ranges
+            .iter()
+            .find(|range| range.start < free_space_offset || range.end > capacity)
+        {
+            return Err(format!("slot data {:?} is outside the data area", range));
+        }
+        if let Some(pair) = ranges.windows(2).find(|pair| pair[0].end > pair[1].start) {
+            return Err(format!("slot data {:?} and {:?} overlap", pair[0], pair[1]));
+        }
+        let used: usize = ranges.iter().map(|range| range.len()).sum();
+        if used != capacity - free_space_offset {
+            return Err(format!(
+                "slots hold {} bytes but {} bytes are in use",
+                used,
+                capacity - free_space_offset
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl<B: ByteSliceMut> Slotted<B> {
@@ -162,7 +201,7 @@ mod tests {
             slotted[index].copy_from_slice(buf);
         };
         let push = |slotted: &mut Slotted<&mut [u8]>, buf: &[u8]| {
-            let index = slotted.num_slots() as usize;
+            let index = slotted.num_slots();
             insert(slotted, index, buf);
         };
         slotted.initialize();
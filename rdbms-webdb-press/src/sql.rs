@@ -0,0 +1,1193 @@
+use std::sync::RwLock;
+
+use anyhow::Result;
+
+use crate::buffer::BufferPoolManager;
+use crate::catalog::{self, Catalog, Column, TableSchema};
+use crate::mvcc::{self, Snapshot, Txn, TxnManager};
+use crate::query::{Instrumented, PlanNode, Project, Tuple};
+use crate::value::DataType;
+
+pub mod ast;
+mod lexer;
+mod parser;
+pub mod planner;
+
+pub use parser::parse;
+
+use ast::{Expr, Projection, Statement};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("syntax error at {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("expected {expected} values, got {actual}")]
+    ValueCount { expected: usize, actual: usize },
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    #[error("column reference {0:?} is ambiguous")]
+    AmbiguousColumn(String),
+    #[error(transparent)]
+    Catalog(#[from] catalog::Error),
+}
+
+impl Error {
+    fn syntax(position: usize, message: impl Into<String>) -> Self {
+        Error::Syntax {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueryResult {
+    Created,
+    Inserted(usize),
+    Updated(usize),
+    Deleted(usize),
+    Analyzed,
+    Vacuumed,
+    Rows {
+        columns: Vec<Column>,
+        rows: Vec<Tuple>,
+    },
+}
+
+// SQL 文を受け取って実行する
+// CREATE TABLE で作るテーブルには版を付け、各文はそれぞれのスナップショットで読む
+// 書く文は1つのトランザクションで実行し、途中で失敗したらその文の変更を全て取り消す
+// 版を付けずに作られたテーブルは、途中で失敗した文の変更を取り消せないので読むだけにする
+pub struct Database {
+    catalog: Catalog,
+    txns: TxnManager,
+    // 行を書く文は共有で取り、インデックスを作る間と VACUUM の間は排他で取る
+    // インデックスに入れる行を読んでからカタログを書き換えるまでに、行が書かれないようにする
+    write_lock: RwLock<()>,
+}
+
+impl Database {
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self> {
+        let catalog = Catalog::create(bufmgr)?;
+        let txns = TxnManager::create(bufmgr)?;
+        catalog.set_commit_log(bufmgr, txns.meta_page_id)?;
+        Ok(Self::new(catalog, txns))
+    }
+
+    // コミットログがないヒープファイルなら作る
+    pub fn open(bufmgr: &BufferPoolManager) -> Result<Self> {
+        let catalog = Catalog::open();
+        let txns = match catalog.commit_log(bufmgr)? {
+            Some(meta_page_id) => TxnManager::open(bufmgr, meta_page_id)?,
+            None => {
+                let txns = TxnManager::create(bufmgr)?;
+                catalog.set_commit_log(bufmgr, txns.meta_page_id)?;
+                txns
+            }
+        };
+        Ok(Self::new(catalog, txns))
+    }
+
+    fn new(catalog: Catalog, txns: TxnManager) -> Self {
+        Self {
+            catalog,
+            txns,
+            write_lock: RwLock::new(()),
+        }
+    }
+
+    pub fn catalog(&self) -> &Catalog {
+        &self.catalog
+    }
+
+    pub fn txns(&self) -> &TxnManager {
+        &self.txns
+    }
+
+    pub fn execute(&self, bufmgr: &BufferPoolManager, sql: &str) -> Result<QueryResult> {
+        self.execute_statement(bufmgr, parse(sql)?)
+    }
+
+    pub fn execute_statement(
+        &self,
+        bufmgr: &BufferPoolManager,
+        statement: Statement,
+    ) -> Result<QueryResult> {
+        match statement {
+            Statement::CreateTable(create) => self.create_table(bufmgr, create),
+            Statement::CreateIndex(create) => self.create_index(bufmgr, create),
+            Statement::Insert(insert) => self.insert(bufmgr, insert),
+            Statement::Select(select) => self.select(bufmgr, select),
+            Statement::Update(update) => self.update(bufmgr, update),
+            Statement::Delete(delete) => self.delete(bufmgr, delete),
+            Statement::Explain(explain) => self.explain(bufmgr, explain),
+            Statement::Analyze(analyze) => self.analyze(bufmgr, analyze),
+            Statement::Vacuum => {
+                self.vacuum(bufmgr)?;
+                Ok(QueryResult::Vacuumed)
+            }
+        }
+    }
+
+    // テーブルの定義を読み直し、トランザクションの中で f を呼ぶ。失敗したら取り消す
+    fn write<T>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        table_name: &str,
+        f: impl FnOnce(&TableSchema, &Txn) -> Result<T>,
+    ) -> Result<T> {
+        let _guard = self.write_lock.read().unwrap();
+        let schema = self.catalog.table_schema(bufmgr, table_name)?;
+        if !schema.versioned {
+            return Err(Error::Unsupported(format!(
+                "writing to table {:?} created without versions",
+                schema.name
+            ))
+            .into());
+        }
+        let txn = self.txns.begin(bufmgr)?;
+        match f(&schema, &txn) {
+            Ok(result) => {
+                txn.commit(bufmgr)?;
+                Ok(result)
+            }
+            Err(err) => {
+                // 記録できなくても、drop したトランザクションと同じく取り消したものとして扱う
+                let _ = txn.abort(bufmgr);
+                Err(err)
+            }
+        }
+    }
+
+    // 版を付けた全てのテーブルとインデックスから、どのスナップショットからも見えない版を取り除く
+    pub fn vacuum(&self, bufmgr: &BufferPoolManager) -> Result<mvcc::VacuumStats> {
+        let _guard = self.write_lock.write().unwrap();
+        self.txns.vacuum_all(bufmgr, || {
+            let mut stores = vec![];
+            for schema in self.catalog.tables(bufmgr)? {
+                if schema.versioned {
+                    stores.extend(schema.table.version_stores());
+                }
+            }
+            Ok(stores)
+        })
+    }
+
+    fn create_table(
+        &self,
+        bufmgr: &BufferPoolManager,
+        create: ast::CreateTable,
+    ) -> Result<QueryResult> {
+        // 主キーは B+Tree のキーになるので、先頭から順に並んだ列でなければならない
+        let leading_columns = create.columns.iter().map(|column| &column.name);
+        if !create
+            .primary_key
+            .iter()
+            .eq(leading_columns.take(create.primary_key.len()))
+        {
+            return Err(
+                Error::Unsupported("primary key other than the leading columns".into()).into(),
+            );
+        }
+        self.catalog.create_versioned_table(
+            bufmgr,
+            &create.name,
+            create.columns,
+            create.primary_key.len(),
+        )?;
+        Ok(QueryResult::Created)
+    }
+
+    fn create_index(
+        &self,
+        bufmgr: &BufferPoolManager,
+        create: ast::CreateIndex,
+    ) -> Result<QueryResult> {
+        let _guard = self.write_lock.write().unwrap();
+        let snapshot = self.txns.snapshot();
+        if create.hash {
+            // ハッシュインデックスは一意なものしかない
+            if !create.unique {
+                return Err(Error::Unsupported("non-unique hash index".into()).into());
+            }
+            self.catalog.create_hash_index(
+                bufmgr,
+                &create.table,
+                &create.name,
+                &create.columns,
+                Some(&snapshot),
+            )?;
+        } else if create.unique {
+            self.catalog.create_index(
+                bufmgr,
+                &create.table,
+                &create.name,
+                &create.columns,
+                Some(&snapshot),
+            )?;
+        } else {
+            self.catalog.create_non_unique_index(
+                bufmgr,
+                &create.table,
+                &create.name,
+                &create.columns,
+                Some(&snapshot),
+            )?;
+        }
+        Ok(QueryResult::Created)
+    }
+
+    fn insert(&self, bufmgr: &BufferPoolManager, insert: ast::Insert) -> Result<QueryResult> {
+        self.write(bufmgr, &insert.table, |schema, txn| {
+            // 列を指定された場合は、CREATE TABLE の順に並べ替える
+            let positions = match &insert.columns {
+                Some(columns) => {
+                    let indices = schema.column_indices(columns)?;
+                    (0..schema.columns.len())
+                        .map(|i| {
+                            indices.iter().position(|&index| index == i).ok_or_else(|| {
+                                Error::Unsupported(format!(
+                                    "omitting column {:?}",
+                                    schema.columns[i].name
+                                ))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?
+                }
+                None => (0..schema.columns.len()).collect(),
+            };
+            for row in &insert.rows {
+                if row.len() != positions.len() {
+                    return Err(Error::ValueCount {
+                        expected: positions.len(),
+                        actual: row.len(),
+                    }
+                    .into());
+                }
+            }
+            for row in &insert.rows {
+                let values = row
+                    .iter()
+                    .map(|expr| match expr {
+                        Expr::Literal(value) => Ok(value.clone()),
+                        _ => Err(Error::Unsupported("non-literal values in INSERT".into())),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let record = positions
+                    .iter()
+                    .map(|&position| values[position].clone())
+                    .collect();
+                let record = schema.check_record(record)?;
+                schema.table.insert_version(bufmgr, txn, &record)?;
+            }
+            Ok(QueryResult::Inserted(insert.rows.len()))
+        })
+    }
+
+    fn select(&self, bufmgr: &BufferPoolManager, select: ast::Select) -> Result<QueryResult> {
+        let snapshot = self.txns.snapshot();
+        let (columns, plan) = self.select_plan(bufmgr, &select, &snapshot)?;
+        let rows = collect(bufmgr, &*plan)?;
+        Ok(QueryResult::Rows { columns, rows })
+    }
+
+    // 結果の列と実行計画
+    fn select_plan(
+        &self,
+        bufmgr: &BufferPoolManager,
+        select: &ast::Select,
+        snapshot: &Snapshot,
+    ) -> Result<(Vec<Column>, Box<dyn PlanNode>)> {
+        if !select.joins.is_empty() {
+            return self.join_plan(bufmgr, select, snapshot);
+        }
+        let schema = self.catalog.table_schema(bufmgr, &select.from)?;
+        let projection: Vec<_> = match &select.projection {
+            Projection::Wildcard => (0..schema.columns.len()).collect(),
+            Projection::Columns(columns) => columns
+                .iter()
+                .map(|column| planner::bind_column(column, &schema))
+                .collect::<Result<_, _>>()?,
+        };
+        let snapshot = schema.snapshot(Some(snapshot))?;
+        let plan = planner::plan_scan(&schema, select.selection.as_ref(), &select.order_by)?
+            .into_plan_node(&schema, snapshot);
+        let columns = projection
+            .iter()
+            .map(|&i| schema.columns[i].clone())
+            .collect();
+        let plan = Box::new(Project {
+            inner_plan: plan,
+            columns: projection,
+        });
+        Ok((columns, plan))
+    }
+
+    // FROM に並べた表を結合する SELECT の結果の列と実行計画
+    // 結合順は統計情報から決めるので、ORDER BY には対応しない
+    fn join_plan(
+        &self,
+        bufmgr: &BufferPoolManager,
+        select: &ast::Select,
+        snapshot: &Snapshot,
+    ) -> Result<(Vec<Column>, Box<dyn PlanNode>)> {
+        if !select.order_by.is_empty() {
+            return Err(Error::Unsupported("ORDER BY with joins".into()).into());
+        }
+        let names: Vec<_> = std::iter::once(&select.from)
+            .chain(select.joins.iter().map(|join| &join.table))
+            .collect();
+        if (1..names.len()).any(|i| names[..i].contains(&names[i])) {
+            return Err(Error::Unsupported("joining a table with itself".into()).into());
+        }
+        let schemas = names
+            .iter()
+            .map(|name| self.catalog.table_schema(bufmgr, name))
+            .collect::<Result<Vec<_>>>()?;
+        let schemas: Vec<_> = schemas.iter().collect();
+        let inputs = schemas
+            .iter()
+            .map(|&schema| Ok((schema, schema.snapshot(Some(snapshot))?)))
+            .collect::<Result<Vec<_>>>()?;
+        let conds: Vec<_> = select
+            .joins
+            .iter()
+            .filter_map(|join| join.on.as_ref())
+            .chain(&select.selection)
+            .collect();
+        let plan = planner::plan_select_join(&inputs, &conds)?;
+
+        // 結合した行の中での、各表の先頭の列の位置
+        let offsets: Vec<_> = schemas
+            .iter()
+            .scan(0, |offset, schema| {
+                let start = *offset;
+                *offset += schema.columns.len();
+                Some(start)
+            })
+            .collect();
+        let projection: Vec<(usize, usize)> = match &select.projection {
+            Projection::Wildcard => schemas
+                .iter()
+                .enumerate()
+                .flat_map(|(input, schema)| (0..schema.columns.len()).map(move |i| (input, i)))
+                .collect(),
+            Projection::Columns(columns) => columns
+                .iter()
+                .map(|column| planner::bind_join_column(column, &schemas))
+                .collect::<Result<_, _>>()?,
+        };
+        let columns = projection
+            .iter()
+            .map(|&(input, i)| schemas[input].columns[i].clone())
+            .collect();
+        let plan = Box::new(Project {
+            inner_plan: plan,
+            columns: projection
+                .iter()
+                .map(|&(input, i)| offsets[input] + i)
+                .collect(),
+        });
+        Ok((columns, plan))
+    }
+
+    // 実行計画を 1 行に 1 ノードずつ返す。ANALYZE なら実行して、結果の行は捨てる
+    fn explain(&self, bufmgr: &BufferPoolManager, explain: ast::Explain) -> Result<QueryResult> {
+        let snapshot = self.txns.snapshot();
+        let (_, mut plan) = self.select_plan(bufmgr, &explain.select, &snapshot)?;
+        if explain.analyze {
+            plan = Instrumented::wrap(plan);
+            let mut exec = plan.start(bufmgr)?;
+            while exec.next(bufmgr)?.is_some() {}
+        }
+        let rows = plan
+            .explain()
+            .lines()
+            .into_iter()
+            .map(|line| vec![line.into()])
+            .collect();
+        let columns = vec![Column {
+            name: "QUERY PLAN".into(),
+            data_type: DataType::Text,
+        }];
+        Ok(QueryResult::Rows { columns, rows })
+    }
+
+    fn analyze(&self, bufmgr: &BufferPoolManager, analyze: ast::Analyze) -> Result<QueryResult> {
+        let tables = match analyze.table {
+            Some(table) => vec![table],
+            None => self
+                .catalog
+                .tables(bufmgr)?
+                .into_iter()
+                .map(|schema| schema.name)
+                .collect(),
+        };
+        let snapshot = self.txns.snapshot();
+        for table in &tables {
+            self.catalog.analyze(bufmgr, table, Some(&snapshot))?;
+        }
+        Ok(QueryResult::Analyzed)
+    }
+
+    fn update(&self, bufmgr: &BufferPoolManager, update: ast::Update) -> Result<QueryResult> {
+        self.write(bufmgr, &update.table, |schema, txn| {
+            let assignments = update
+                .assignments
+                .iter()
+                .map(|(column, expr)| {
+                    Ok((
+                        schema.column_index(column).map_err(Error::from)?,
+                        planner::bind_operand(expr, schema)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            let scan = planner::plan_scan(schema, update.selection.as_ref(), &[])?;
+            let plan = scan.into_plan_node(schema, Some(txn.snapshot()));
+            // 読みながら書き換えると同じ行を何度も読むことがあるので、先に全て読んでおく
+            let records = collect(bufmgr, &*plan)?;
+            let num_key_elems = schema.table.num_key_elems;
+            for old_record in &records {
+                let mut record = old_record.clone();
+                for (index, value) in &assignments {
+                    record[*index] = value.eval(old_record).clone();
+                }
+                let record = schema.check_record(record)?;
+                if record[..num_key_elems] == old_record[..num_key_elems] {
+                    schema.table.update_version(bufmgr, txn, &record)?;
+                } else {
+                    // 主キーが変わる場合は行を移す
+                    let pkey = &old_record[..num_key_elems];
+                    schema.table.delete_version(bufmgr, txn, pkey)?;
+                    schema.table.insert_version(bufmgr, txn, &record)?;
+                }
+            }
+            Ok(QueryResult::Updated(records.len()))
+        })
+    }
+
+    fn delete(&self, bufmgr: &BufferPoolManager, delete: ast::Delete) -> Result<QueryResult> {
+        self.write(bufmgr, &delete.table, |schema, txn| {
+            let scan = planner::plan_scan(schema, delete.selection.as_ref(), &[])?;
+            let plan = scan.into_plan_node(schema, Some(txn.snapshot()));
+            let records = collect(bufmgr, &*plan)?;
+            let num_key_elems = schema.table.num_key_elems;
+            for record in &records {
+                let pkey = &record[..num_key_elems];
+                schema.table.delete_version(bufmgr, txn, pkey)?;
+            }
+            Ok(QueryResult::Deleted(records.len()))
+        })
+    }
+}
+
+fn collect(bufmgr: &BufferPoolManager, plan: &dyn PlanNode) -> Result<Vec<Tuple>> {
+    let mut exec = plan.start(bufmgr)?;
+    let mut records = vec![];
+    while let Some(record) = exec.next(bufmgr)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempfile, NamedTempFile};
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+    use crate::hash_index::HashIndex;
+    use crate::query::{IndexScan, TupleRange, TupleSearchMode};
+    use crate::tuple;
+    use crate::value::Value;
+
+    fn rows(result: QueryResult) -> Vec<Vec<String>> {
+        match result {
+            QueryResult::Rows { rows, .. } => rows
+                .into_iter()
+                .map(|row| row.iter().map(|elem| elem.to_string()).collect())
+                .collect(),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE users (id TEXT PRIMARY KEY, first_name TEXT, last_name TEXT)",
+        )
+        .unwrap();
+        assert_eq!(
+            QueryResult::Inserted(3),
+            db.execute(
+                &bufmgr,
+                "INSERT INTO users VALUES ('z', 'Alice', 'Smith'), ('x', 'Bob', 'Johnson'), ('y', 'Charlie', 'Williams')",
+            )
+            .unwrap()
+        );
+        db.execute(
+            &bufmgr,
+            "CREATE UNIQUE INDEX users_last_name ON users (last_name)",
+        )
+        .unwrap();
+
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT last_name, id FROM users ORDER BY last_name",
+            )
+            .unwrap();
+        assert_eq!(
+            vec![
+                vec!["Johnson", "x"],
+                vec!["Smith", "z"],
+                vec!["Williams", "y"]
+            ],
+            rows(result)
+        );
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT * FROM users WHERE id >= 'y' AND first_name <> 'Alice'",
+            )
+            .unwrap();
+        assert_eq!(vec![vec!["y", "Charlie", "Williams"]], rows(result));
+
+        // 主キーを変える更新と、変えない更新
+        assert_eq!(
+            QueryResult::Updated(1),
+            db.execute(
+                &bufmgr,
+                "UPDATE users SET id = 'w' WHERE last_name = 'Smith'"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            QueryResult::Updated(2),
+            db.execute(
+                &bufmgr,
+                "UPDATE users SET first_name = last_name WHERE id < 'y'"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            QueryResult::Deleted(1),
+            db.execute(
+                &bufmgr,
+                "DELETE FROM users WHERE first_name = 'Williams' OR id = 'y'"
+            )
+            .unwrap()
+        );
+        let result = db.execute(&bufmgr, "SELECT * FROM users").unwrap();
+        assert_eq!(
+            vec![vec!["w", "Smith", "Smith"], vec!["x", "Johnson", "Johnson"]],
+            rows(result)
+        );
+
+        // インデックスの重複
+        assert!(db
+            .execute(&bufmgr, "INSERT INTO users VALUES ('v', 'Eve', 'Smith')")
+            .is_err());
+        assert!(db
+            .execute(
+                &bufmgr,
+                "CREATE UNIQUE INDEX users_first_name ON users (first_name)"
+            )
+            .is_ok());
+        assert!(matches!(
+            db.execute(&bufmgr, "SELECT * FROM posts")
+                .unwrap_err()
+                .downcast_ref::<catalog::Error>(),
+            Some(catalog::Error::TableNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_non_unique_index() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE tasks (id BIGINT PRIMARY KEY, status TEXT, owner TEXT)",
+        )
+        .unwrap();
+        db.execute(
+            &bufmgr,
+            "INSERT INTO tasks VALUES (1, 'open', 'alice'), (2, 'done', 'bob'), (3, 'open', 'bob')",
+        )
+        .unwrap();
+        db.execute(&bufmgr, "CREATE INDEX tasks_status ON tasks (status)")
+            .unwrap();
+        db.execute(
+            &bufmgr,
+            "INSERT INTO tasks VALUES (4, 'open', 'carol'), (5, NULL, 'dave')",
+        )
+        .unwrap();
+        db.execute(&bufmgr, "UPDATE tasks SET status = 'open' WHERE id = 2")
+            .unwrap();
+        db.execute(&bufmgr, "DELETE FROM tasks WHERE id = 1")
+            .unwrap();
+
+        let result = db
+            .execute(&bufmgr, "SELECT id, owner FROM tasks WHERE status = 'open'")
+            .unwrap();
+        assert_eq!(
+            vec![vec!["2", "bob"], vec!["3", "bob"], vec!["4", "carol"]],
+            rows(result)
+        );
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT id FROM tasks WHERE status = 'open' AND id >= 3 ORDER BY id DESC",
+            )
+            .unwrap();
+        assert_eq!(vec![vec!["4"], vec!["3"]], rows(result));
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM tasks WHERE status IS NULL")
+            .unwrap();
+        assert_eq!(vec![vec!["5"]], rows(result));
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, email TEXT, name TEXT)",
+        )
+        .unwrap();
+        db.execute(
+            &bufmgr,
+            "INSERT INTO users VALUES (1, 'alice@x', 'Alice'), (2, 'bob@x', 'Bob')",
+        )
+        .unwrap();
+        db.execute(&bufmgr, "UPDATE users SET email = 'bobby@x' WHERE id = 2")
+            .unwrap();
+        // 既にある行は、作る時のスナップショットから見える版で入る
+        db.execute(
+            &bufmgr,
+            "CREATE UNIQUE INDEX users_email ON users USING HASH (email)",
+        )
+        .unwrap();
+        assert!(matches!(
+            db.execute(
+                &bufmgr,
+                "CREATE INDEX users_name ON users USING HASH (name)"
+            )
+            .unwrap_err()
+            .downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+
+        let select = |email: &str| {
+            let sql = format!("SELECT id, name FROM users WHERE email = '{}'", email);
+            rows(db.execute(&bufmgr, &sql).unwrap())
+        };
+        let result = db
+            .execute(
+                &bufmgr,
+                "EXPLAIN SELECT id, name FROM users WHERE email = 'alice@x'",
+            )
+            .unwrap();
+        let lines: Vec<_> = rows(result).into_iter().map(|row| row.concat()).collect();
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("-> HashIndexScan (") && line.contains("snapshot: ")),
+            "{:?}",
+            lines
+        );
+        assert_eq!(vec![vec!["1", "Alice"]], select("alice@x"));
+        assert!(select("bob@x").is_empty());
+        assert_eq!(vec![vec!["2", "Bob"]], select("bobby@x"));
+
+        // 取り消した文が書いたエントリは見えない
+        assert!(db
+            .execute(
+                &bufmgr,
+                "INSERT INTO users VALUES (3, 'carol@x', 'Carol'), (1, 'dave@x', 'Dave')"
+            )
+            .is_err());
+        assert!(select("carol@x").is_empty());
+        assert!(db
+            .execute(&bufmgr, "INSERT INTO users VALUES (4, 'alice@x', 'Eve')")
+            .is_err());
+
+        // 読んでいる間に書き換えられても、スナップショットを取った時点の行を読む
+        let snapshot = db.txns().snapshot();
+        let select_alice = parse("SELECT name FROM users WHERE email = 'alice@x'").unwrap();
+        let plan = match select_alice {
+            Statement::Select(select) => db.select_plan(&bufmgr, &select, &snapshot).unwrap().1,
+            _ => unreachable!(),
+        };
+        db.execute(&bufmgr, "UPDATE users SET email = 'alicia@x' WHERE id = 1")
+            .unwrap();
+        db.execute(&bufmgr, "DELETE FROM users WHERE id = 2")
+            .unwrap();
+        let names: Vec<_> = collect(&bufmgr, &*plan)
+            .unwrap()
+            .into_iter()
+            .map(|row| row[0].to_string())
+            .collect();
+        assert_eq!(vec!["Alice"], names);
+        drop(plan);
+        drop(snapshot);
+        assert!(select("alice@x").is_empty());
+        assert_eq!(vec![vec!["1", "Alice"]], select("alicia@x"));
+        assert!(select("bobby@x").is_empty());
+
+        // 使わなくなったメールアドレスは、別の行が使える
+        db.execute(
+            &bufmgr,
+            "INSERT INTO users VALUES (3, 'carol@x', 'Carol'), (5, 'alice@x', 'Alice')",
+        )
+        .unwrap();
+        assert_eq!(vec![vec!["3", "Carol"]], select("carol@x"));
+        assert_eq!(vec![vec!["5", "Alice"]], select("alice@x"));
+
+        // ハッシュインデックスからも、どこからも見えない版を取り除く
+        assert!(db.vacuum(&bufmgr).unwrap().removed_versions > 0);
+        assert_eq!(0, db.vacuum(&bufmgr).unwrap().removed_versions);
+        assert_eq!(vec![vec!["5", "Alice"]], select("alice@x"));
+        assert!(select("bobby@x").is_empty());
+        let index = &db
+            .catalog()
+            .table_schema(&bufmgr, "users")
+            .unwrap()
+            .table
+            .hash_indices[0];
+        let mut key = vec![];
+        tuple::encode([Value::from("bobby@x")].iter(), &mut key);
+        assert_eq!(
+            None,
+            HashIndex::new(index.meta_page_id)
+                .lookup(&bufmgr, &key)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE tasks (id BIGINT PRIMARY KEY, status TEXT, owner TEXT)",
+        )
+        .unwrap();
+        db.execute(
+            &bufmgr,
+            "INSERT INTO tasks VALUES (1, 'open', 'alice'), (2, 'done', 'bob'), (3, 'open', 'bob')",
+        )
+        .unwrap();
+        db.execute(&bufmgr, "CREATE INDEX tasks_status ON tasks (status)")
+            .unwrap();
+
+        let sql = "SELECT owner FROM tasks WHERE status = 'open' AND owner <> 'alice'";
+        let plan = vec![
+            "Project (columns: #2)",
+            "-> Filter (cond: status = 'open' AND owner <> 'alice')",
+            "  -> IndexScan (table: 5, index: 7, mode: start, range: >= ('open') AND <= ('open'), snapshot: 2..2)",
+        ];
+        let result = db.execute(&bufmgr, &format!("EXPLAIN {}", sql)).unwrap();
+        match &result {
+            QueryResult::Rows { columns, .. } => assert_eq!("QUERY PLAN", columns[0].name),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let lines: Vec<_> = rows(result).into_iter().map(|row| row.concat()).collect();
+        assert_eq!(plan, lines);
+
+        // 計測した値が後ろに付く
+        let result = db
+            .execute(&bufmgr, &format!("EXPLAIN ANALYZE {}", sql))
+            .unwrap();
+        let lines: Vec<_> = rows(result).into_iter().map(|row| row.concat()).collect();
+        assert_eq!(plan.len(), lines.len());
+        for ((line, node), rows) in lines.iter().zip(&plan).zip([1, 1, 2]) {
+            let stats = format!(" [loops: 1, rows: {}, pages: ", rows);
+            assert!(line.starts_with(&format!("{}{}", node, stats)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE tasks (id BIGINT PRIMARY KEY, status TEXT, owner TEXT)",
+        )
+        .unwrap();
+        db.execute(&bufmgr, "CREATE INDEX tasks_status ON tasks (status)")
+            .unwrap();
+        db.execute(&bufmgr, "CREATE INDEX tasks_owner ON tasks (owner)")
+            .unwrap();
+        // ほとんどの行は 'done' で、owner は行ごとに異なる
+        for start in (0..2000).step_by(100) {
+            let values: Vec<_> = (start..start + 100)
+                .map(|id| {
+                    let status = if id % 500 == 0 { "open" } else { "done" };
+                    format!("({}, '{}', 'user{}')", id, status, id)
+                })
+                .collect();
+            let sql = format!("INSERT INTO tasks VALUES {}", values.join(", "));
+            db.execute(&bufmgr, &sql).unwrap();
+        }
+        let scan = |cond: &str| -> String {
+            let sql = format!("EXPLAIN SELECT id FROM tasks WHERE {}", cond);
+            let lines = rows(db.execute(&bufmgr, &sql).unwrap());
+            lines.last().unwrap()[0]
+                .trim_start_matches([' ', '-', '>'])
+                .to_string()
+        };
+        assert!(scan("status = 'done'").starts_with("IndexScan"));
+
+        assert_eq!(
+            QueryResult::Analyzed,
+            db.execute(&bufmgr, "ANALYZE").unwrap()
+        );
+        let stats = db.catalog().table_schema(&bufmgr, "tasks").unwrap().stats;
+        assert_eq!(2000, stats.unwrap().row_count);
+        // 統計情報があれば、ほとんどの行に一致する値はテーブルを順に読む
+        assert!(scan("status = 'done'").starts_with("SeqScan"));
+        assert!(scan("owner = 'user7'").starts_with("IndexScan"));
+        // 両方で絞れるなら、少ない行を読む方を使う
+        let plan = scan("status = 'done' AND owner = 'user7'");
+        assert!(
+            plan.contains("range: >= ('user7') AND <= ('user7')"),
+            "{}",
+            plan
+        );
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM tasks WHERE status = 'open'")
+            .unwrap();
+        assert_eq!(4, rows(result).len());
+        assert!(db.execute(&bufmgr, "ANALYZE posts").is_err());
+    }
+
+    #[test]
+    fn test_join() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        for sql in [
+            "CREATE TABLE orders (id BIGINT PRIMARY KEY, user_id BIGINT)",
+            "CREATE TABLE users (id BIGINT PRIMARY KEY, status_id BIGINT)",
+            "CREATE TABLE statuses (id BIGINT PRIMARY KEY, name TEXT)",
+            "INSERT INTO statuses VALUES (0, 'a'), (1, 'b'), (2, 'c'), (3, 'd'), (4, 'e')",
+        ] {
+            db.execute(&bufmgr, sql).unwrap();
+        }
+        let insert = |table: &str, rows: i64, modulo: i64| {
+            for start in (0..rows).step_by(100) {
+                let values: Vec<_> = (start..start + 100)
+                    .map(|id| format!("({}, {})", id, id % modulo))
+                    .collect();
+                let sql = format!("INSERT INTO {} VALUES {}", table, values.join(", "));
+                db.execute(&bufmgr, &sql).unwrap();
+            }
+        };
+        insert("orders", 2000, 500);
+        insert("users", 500, 5);
+        db.execute(&bufmgr, "ANALYZE").unwrap();
+
+        let schemas: Vec<_> = ["orders", "users", "statuses"]
+            .iter()
+            .map(|name| db.catalog().table_schema(&bufmgr, name).unwrap())
+            .collect();
+        let sql = "SELECT * FROM orders JOIN users ON orders.user_id = users.id, statuses \
+                   WHERE users.status_id = statuses.id";
+        // 小さい statuses と users を先に結合し、大きい orders を最後に足す
+        let lines: Vec<_> = rows(db.execute(&bufmgr, &format!("EXPLAIN {}", sql)).unwrap())
+            .into_iter()
+            .map(|row| row.concat())
+            .collect();
+        assert!(lines[0].starts_with("Project"), "{:?}", lines);
+        assert!(
+            lines.iter().any(|line| line.contains("HashJoin")),
+            "{:?}",
+            lines
+        );
+        let table = |line: &str, schema: &TableSchema| {
+            line.contains(&format!("table: {},", schema.table.meta_page_id.to_u64()))
+        };
+        let scans: Vec<_> = lines
+            .iter()
+            .filter(|line| line.contains("SeqScan"))
+            .collect();
+        assert!(table(scans[0], &schemas[2]), "{:?}", lines);
+        assert!(table(scans[2], &schemas[0]), "{:?}", lines);
+
+        // 結合した行は orders, users, statuses の順に列が並ぶ
+        let joined = rows(db.execute(&bufmgr, sql).unwrap());
+        assert_eq!(2000, joined.len());
+        for row in &joined {
+            assert_eq!(6, row.len());
+            assert_eq!(row[1], row[2]);
+            assert_eq!(row[3], row[4]);
+        }
+
+        // 1つの表だけの条件はその表を読む時に絞り、名前が一意な列は表の名前を省ける
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT orders.id, name FROM orders, users, statuses \
+                 WHERE user_id = users.id AND status_id = statuses.id \
+                 AND orders.id < 3 AND name <> 'b'",
+            )
+            .unwrap();
+        assert_eq!(vec![vec!["0", "a"], vec!["2", "c"]], rows(result));
+        assert!(matches!(
+            db.execute(
+                &bufmgr,
+                "SELECT id FROM users JOIN statuses ON status_id = statuses.id"
+            )
+            .unwrap_err()
+            .downcast_ref::<Error>(),
+            Some(Error::AmbiguousColumn(_))
+        ));
+        assert!(matches!(
+            db.execute(
+                &bufmgr,
+                "SELECT * FROM users JOIN statuses ON status_id < statuses.id"
+            )
+            .unwrap_err()
+            .downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+        assert!(db.execute(&bufmgr, "SELECT * FROM users, users").is_err());
+    }
+
+    #[test]
+    fn test_transaction() {
+        let file = NamedTempFile::new().unwrap();
+        {
+            let disk = DiskManager::open(file.path()).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+            let db = Database::create(&bufmgr).unwrap();
+            db.execute(
+                &bufmgr,
+                "CREATE TABLE tasks (id BIGINT PRIMARY KEY, status TEXT, owner TEXT)",
+            )
+            .unwrap();
+            db.execute(
+                &bufmgr,
+                "INSERT INTO tasks VALUES (1, 'open', 'alice'), (2, 'done', 'bob')",
+            )
+            .unwrap();
+            // 途中の行で失敗した文は、それまでの行も取り消す
+            assert!(db
+                .execute(
+                    &bufmgr,
+                    "INSERT INTO tasks VALUES (3, 'open', 'carol'), (1, 'done', 'dave')"
+                )
+                .is_err());
+            db.execute(&bufmgr, "UPDATE tasks SET id = 4 WHERE id = 2")
+                .unwrap();
+
+            // 読んでいる間に書き換えられても、スナップショットを取った時点の行を読む
+            let snapshot = db.txns().snapshot();
+            let select = parse("SELECT id FROM tasks").unwrap();
+            let plan = match select {
+                Statement::Select(select) => db.select_plan(&bufmgr, &select, &snapshot).unwrap().1,
+                _ => unreachable!(),
+            };
+            db.execute(&bufmgr, "DELETE FROM tasks WHERE id = 1")
+                .unwrap();
+            let ids: Vec<_> = collect(&bufmgr, &*plan)
+                .unwrap()
+                .into_iter()
+                .map(|row| row[0].to_string())
+                .collect();
+            assert_eq!(vec!["1", "4"], ids);
+            drop(plan);
+            drop(snapshot);
+
+            // 取り消された行も削除された行もインデックスには見えない
+            db.execute(&bufmgr, "CREATE UNIQUE INDEX tasks_owner ON tasks (owner)")
+                .unwrap();
+            let result = db
+                .execute(&bufmgr, "SELECT id FROM tasks WHERE owner >= 'a'")
+                .unwrap();
+            assert_eq!(vec![vec!["4"]], rows(result));
+            assert_eq!(
+                QueryResult::Vacuumed,
+                db.execute(&bufmgr, "VACUUM").unwrap()
+            );
+            assert_eq!(0, db.vacuum(&bufmgr).unwrap().removed_versions);
+            bufmgr.flush().unwrap();
+        }
+
+        // 開き直してもコミットした行だけが見える
+        let disk = DiskManager::open(file.path()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let db = Database::open(&bufmgr).unwrap();
+        db.execute(&bufmgr, "INSERT INTO tasks VALUES (1, 'open', 'alice')")
+            .unwrap();
+        let result = db
+            .execute(&bufmgr, "SELECT id, owner FROM tasks WHERE owner >= 'a'")
+            .unwrap();
+        assert_eq!(vec![vec!["1", "alice"], vec!["4", "bob"]], rows(result));
+
+        // 版を付けずに作ったテーブルは読めるが、書けない
+        let columns = vec![Column {
+            name: "id".into(),
+            data_type: DataType::Int64,
+        }];
+        let schema = db
+            .catalog()
+            .create_table(&bufmgr, "plain", columns, 1)
+            .unwrap();
+        schema.table.insert(&bufmgr, &[1.into()]).unwrap();
+        let result = db.execute(&bufmgr, "SELECT * FROM plain").unwrap();
+        assert_eq!(vec![vec!["1"]], rows(result));
+        assert!(matches!(
+            db.execute(&bufmgr, "DELETE FROM plain")
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_ddl() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(100));
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE tasks (id BIGINT PRIMARY KEY, status TEXT, owner TEXT)",
+        )
+        .unwrap();
+        // インデックスを作る間に入れた行も、作った後に入れた行も、インデックスから引ける
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (db, bufmgr) = (&db, &bufmgr);
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let id = t * 100 + i;
+                        let sql =
+                            format!("INSERT INTO tasks VALUES ({}, 'open', 'user{}')", id, id);
+                        db.execute(bufmgr, &sql).unwrap();
+                    }
+                });
+            }
+            for i in 0..4 {
+                let sql = format!("CREATE INDEX tasks_status{} ON tasks (status)", i);
+                db.execute(&bufmgr, &sql).unwrap();
+                db.execute(&bufmgr, "ANALYZE tasks").unwrap();
+            }
+        });
+        let schema = db.catalog().table_schema(&bufmgr, "tasks").unwrap();
+        assert_eq!(4, schema.non_unique_index_names.len());
+        for index in &schema.table.non_unique_indices {
+            let plan = IndexScan {
+                table_meta_page_id: schema.table.meta_page_id,
+                index_meta_page_id: index.meta_page_id,
+                search_mode: TupleSearchMode::Start,
+                range: TupleRange::prefix(vec!["open".into()]),
+                snapshot: Some(db.txns().snapshot()),
+            };
+            assert_eq!(200, collect(&bufmgr, &plan).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_types() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let db = Database::create(&bufmgr).unwrap();
+        db.execute(
+            &bufmgr,
+            "CREATE TABLE items (id BIGINT PRIMARY KEY, price DOUBLE, name TEXT, sold BOOLEAN)",
+        )
+        .unwrap();
+        db.execute(
+            &bufmgr,
+            "INSERT INTO items VALUES (10, 1.5, 'pen', FALSE), (-2, 3, 'ink', TRUE), (9, -0.5, NULL, NULL)",
+        )
+        .unwrap();
+
+        // 整数は文字列としてではなく数値として並ぶ
+        let result = db.execute(&bufmgr, "SELECT id FROM items").unwrap();
+        assert_eq!(vec![vec!["-2"], vec!["9"], vec!["10"]], rows(result));
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM items ORDER BY id DESC")
+            .unwrap();
+        assert_eq!(vec![vec!["10"], vec!["9"], vec!["-2"]], rows(result));
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT id FROM items WHERE id BETWEEN -2 AND 9 ORDER BY id DESC",
+            )
+            .unwrap();
+        assert_eq!(vec![vec!["9"], vec!["-2"]], rows(result));
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM items WHERE id NOT BETWEEN 0 AND 9")
+            .unwrap();
+        assert_eq!(vec![vec!["-2"], vec!["10"]], rows(result));
+        let result = db
+            .execute(
+                &bufmgr,
+                "SELECT id, price FROM items WHERE id > -2 AND price < 2",
+            )
+            .unwrap();
+        assert_eq!(vec![vec!["9", "-0.5"], vec!["10", "1.5"]], rows(result));
+
+        // NULL との比較は真にならない
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM items WHERE name <> 'pen'")
+            .unwrap();
+        assert_eq!(vec![vec!["-2"]], rows(result));
+        let result = db
+            .execute(&bufmgr, "SELECT id FROM items WHERE NOT sold = TRUE")
+            .unwrap();
+        assert_eq!(vec![vec!["10"]], rows(result));
+        let result = db
+            .execute(&bufmgr, "SELECT id, name FROM items WHERE sold IS NULL")
+            .unwrap();
+        assert_eq!(vec![vec!["9", "NULL"]], rows(result));
+
+        assert!(matches!(
+            db.execute(&bufmgr, "INSERT INTO items VALUES ('a', 1, 'a', TRUE)")
+                .unwrap_err()
+                .downcast_ref::<catalog::Error>(),
+            Some(catalog::Error::TypeMismatch { .. })
+        ));
+        assert!(db
+            .execute(&bufmgr, "SELECT * FROM items WHERE price = 'a'")
+            .is_err());
+        assert!(db
+            .execute(&bufmgr, "INSERT INTO items VALUES (NULL, 1, 'a', TRUE)")
+            .is_err());
+
+        // ページに収まらない値はオーバーフローページに置かれる
+        let name = "abc".repeat(5000);
+        db.execute(
+            &bufmgr,
+            &format!("INSERT INTO items VALUES (1, 0, '{}', TRUE)", name),
+        )
+        .unwrap();
+        let result = db
+            .execute(&bufmgr, "SELECT name FROM items WHERE id = 1")
+            .unwrap();
+        assert_eq!(vec![vec![name.as_str()]], rows(result));
+    }
+}
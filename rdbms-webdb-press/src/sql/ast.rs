@@ -0,0 +1,154 @@
+use crate::catalog::Column;
+use crate::value::Value;
+
+// 構文解析の結果。名前はまだ解決していない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    Insert(Insert),
+    Select(Select),
+    Update(Update),
+    Delete(Delete),
+    Explain(Explain),
+    Analyze(Analyze),
+    // 全てのテーブルから、どのスナップショットからも見えない版を取り除く
+    Vacuum,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub primary_key: Vec<String>,
+}
+
+// CREATE [UNIQUE] INDEX name ON table [USING {BTREE | HASH}] (columns)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateIndex {
+    pub unique: bool,
+    // USING HASH ならハッシュインデックスを作る
+    pub hash: bool,
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insert {
+    pub table: String,
+    // 省略された場合は CREATE TABLE の順
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<Expr>>,
+}
+
+// 列の参照。表を結合する時は table.column と表の名前を付けられる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+impl From<&str> for ColumnRef {
+    fn from(name: &str) -> Self {
+        Self {
+            table: None,
+            name: name.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.table {
+            Some(table) => write!(f, "{}.{}", table, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Projection {
+    Wildcard,
+    Columns(Vec<ColumnRef>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderBy {
+    pub column: ColumnRef,
+    pub asc: bool,
+}
+
+// FROM の表に続けて結合する表
+// [INNER] JOIN table ON expr なら on があり、カンマで並べた表には on がない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Join {
+    pub table: String,
+    pub on: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Select {
+    pub projection: Projection,
+    pub from: String,
+    pub joins: Vec<Join>,
+    pub selection: Option<Expr>,
+    pub order_by: Vec<OrderBy>,
+}
+
+// EXPLAIN [ANALYZE] SELECT ...
+// ANALYZE なら実行して、ノードごとに計測した値も表示する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explain {
+    pub analyze: bool,
+    pub select: Select,
+}
+
+// ANALYZE [table]
+// テーブルを省略すると全てのテーブルの統計情報を集める
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analyze {
+    pub table: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub selection: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delete {
+    pub table: String,
+    pub selection: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Column(ColumnRef),
+    Literal(Value),
+    Not(Box<Expr>),
+    // expr IS [NOT] NULL
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
@@ -0,0 +1,159 @@
+use super::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    // キーワードも識別子として読み、パーサーが大文字小文字を区別せずに比べる
+    Ident(String),
+    String(String),
+    // 符号は含まない。整数か小数かはパーサーが決める
+    Number(String),
+    LParen,
+    RParen,
+    Comma,
+    Dot,
+    Semicolon,
+    Asterisk,
+    Minus,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+// SQL 文をトークンの列に分ける。各トークンには文中の位置 (バイト) を添える
+pub fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '-' if input[pos..].starts_with("--") => {
+                // 行末までコメント
+                while matches!(chars.peek(), Some(&(_, c)) if c != '\n') {
+                    chars.next();
+                }
+                continue;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                Token::Ident(ident)
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !(c.is_ascii_digit() || c == '.') {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                Token::Number(number)
+            }
+            '\'' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        // '' は ' そのものを表す
+                        Some((_, '\'')) if matches!(chars.peek(), Some(&(_, '\''))) => {
+                            chars.next();
+                            string.push('\'');
+                        }
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => string.push(c),
+                        None => return Err(Error::syntax(pos, "unterminated string literal")),
+                    }
+                }
+                Token::String(string)
+            }
+            _ => {
+                chars.next();
+                let next = chars.peek().map(|&(_, c)| c);
+                let token = match (c, next) {
+                    ('(', _) => Token::LParen,
+                    (')', _) => Token::RParen,
+                    (',', _) => Token::Comma,
+                    ('.', _) => Token::Dot,
+                    (';', _) => Token::Semicolon,
+                    ('*', _) => Token::Asterisk,
+                    ('-', _) => Token::Minus,
+                    ('=', _) => Token::Eq,
+                    ('<', Some('=')) => Token::LtEq,
+                    ('<', Some('>')) | ('!', Some('=')) => Token::NotEq,
+                    ('<', _) => Token::Lt,
+                    ('>', Some('=')) => Token::GtEq,
+                    ('>', _) => Token::Gt,
+                    _ => return Err(Error::syntax(pos, format!("unexpected character {:?}", c))),
+                };
+                if matches!(token, Token::LtEq | Token::NotEq | Token::GtEq) {
+                    chars.next();
+                }
+                token
+            }
+        };
+        tokens.push((pos, token));
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let tokens: Vec<_> =
+            tokenize("SELECT * FROM t WHERE name <> 'O''Brien' AND age >= -1.5 -- comment\n;")
+                .unwrap()
+                .into_iter()
+                .map(|(_, token)| token)
+                .collect();
+        assert_eq!(
+            vec![
+                Token::Ident("SELECT".into()),
+                Token::Asterisk,
+                Token::Ident("FROM".into()),
+                Token::Ident("t".into()),
+                Token::Ident("WHERE".into()),
+                Token::Ident("name".into()),
+                Token::NotEq,
+                Token::String("O'Brien".into()),
+                Token::Ident("AND".into()),
+                Token::Ident("age".into()),
+                Token::GtEq,
+                Token::Minus,
+                Token::Number("1.5".into()),
+                Token::Semicolon,
+            ],
+            tokens
+        );
+        let tokens: Vec<_> = tokenize("users.id=1.5")
+            .unwrap()
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect();
+        assert_eq!(
+            vec![
+                Token::Ident("users".into()),
+                Token::Dot,
+                Token::Ident("id".into()),
+                Token::Eq,
+                Token::Number("1.5".into()),
+            ],
+            tokens
+        );
+        assert!(tokenize("SELECT 'abc").is_err());
+    }
+}
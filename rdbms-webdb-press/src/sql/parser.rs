@@ -0,0 +1,787 @@
+use crate::catalog::Column;
+use crate::value::{DataType, Value};
+
+use super::ast::*;
+use super::lexer::{self, Token};
+use super::Error;
+
+// 再帰下降パーサー
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    // 入力の終わりの位置。エラーの位置として使う
+    end: usize,
+}
+
+pub fn parse(input: &str) -> Result<Statement, Error> {
+    let mut parser = Parser {
+        tokens: lexer::tokenize(input)?,
+        pos: 0,
+        end: input.len(),
+    };
+    let statement = parser.statement()?;
+    parser.consume(&Token::Semicolon);
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error("expected end of statement"));
+    }
+    Ok(statement)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let position = self.tokens.get(self.pos).map_or(self.end, |&(pos, _)| pos);
+        Error::syntax(position, message)
+    }
+
+    fn consume(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), Error> {
+        if self.consume(&token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", token)))
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        if self.consume_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", keyword)))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.clone();
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => Err(self.error("expected identifier")),
+        }
+    }
+
+    // column か table.column
+    fn column_ref(&mut self) -> Result<ColumnRef, Error> {
+        let name = self.ident()?;
+        if self.consume(&Token::Dot) {
+            Ok(ColumnRef {
+                table: Some(name),
+                name: self.ident()?,
+            })
+        } else {
+            Ok(ColumnRef { table: None, name })
+        }
+    }
+
+    // ( a, b, ... )
+    fn ident_list(&mut self) -> Result<Vec<String>, Error> {
+        self.expect(Token::LParen)?;
+        let mut idents = vec![self.ident()?];
+        while self.consume(&Token::Comma) {
+            idents.push(self.ident()?);
+        }
+        self.expect(Token::RParen)?;
+        Ok(idents)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Error> {
+        if self.consume_keyword("CREATE") {
+            if self.consume_keyword("TABLE") {
+                self.create_table().map(Statement::CreateTable)
+            } else if self.consume_keyword("UNIQUE") {
+                self.expect_keyword("INDEX")?;
+                self.create_index(true).map(Statement::CreateIndex)
+            } else if self.consume_keyword("INDEX") {
+                self.create_index(false).map(Statement::CreateIndex)
+            } else {
+                Err(self.error("expected TABLE, INDEX or UNIQUE INDEX"))
+            }
+        } else if self.consume_keyword("INSERT") {
+            self.insert().map(Statement::Insert)
+        } else if self.consume_keyword("SELECT") {
+            self.select().map(Statement::Select)
+        } else if self.consume_keyword("UPDATE") {
+            self.update().map(Statement::Update)
+        } else if self.consume_keyword("DELETE") {
+            self.delete().map(Statement::Delete)
+        } else if self.consume_keyword("EXPLAIN") {
+            let analyze = self.consume_keyword("ANALYZE");
+            self.expect_keyword("SELECT")?;
+            let select = self.select()?;
+            Ok(Statement::Explain(Explain { analyze, select }))
+        } else if self.consume_keyword("ANALYZE") {
+            let table = match self.peek() {
+                Some(Token::Ident(_)) => Some(self.ident()?),
+                _ => None,
+            };
+            Ok(Statement::Analyze(Analyze { table }))
+        } else if self.consume_keyword("VACUUM") {
+            Ok(Statement::Vacuum)
+        } else {
+            Err(self.error("expected statement"))
+        }
+    }
+
+    fn create_table(&mut self) -> Result<CreateTable, Error> {
+        let name = self.ident()?;
+        self.expect(Token::LParen)?;
+        let mut columns = vec![];
+        let mut primary_key = vec![];
+        loop {
+            if self.consume_keyword("PRIMARY") {
+                self.expect_keyword("KEY")?;
+                primary_key = self.ident_list()?;
+            } else {
+                let name = self.ident()?;
+                let data_type = self.data_type()?;
+                if self.consume_keyword("PRIMARY") {
+                    self.expect_keyword("KEY")?;
+                    primary_key = vec![name.clone()];
+                }
+                columns.push(Column { name, data_type });
+            }
+            if !self.consume(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RParen)?;
+        Ok(CreateTable {
+            name,
+            columns,
+            primary_key,
+        })
+    }
+
+    fn data_type(&mut self) -> Result<DataType, Error> {
+        let names: &[(&[&str], DataType)] = &[
+            (&["TEXT", "VARCHAR"], DataType::Text),
+            (&["BIGINT", "INTEGER", "INT"], DataType::Int64),
+            (&["DOUBLE", "FLOAT", "REAL"], DataType::Float64),
+            (&["BYTEA", "BLOB"], DataType::Bytes),
+            (&["BOOLEAN", "BOOL"], DataType::Bool),
+        ];
+        for (keywords, data_type) in names {
+            if keywords.iter().any(|keyword| self.consume_keyword(keyword)) {
+                if *data_type == DataType::Float64 {
+                    self.consume_keyword("PRECISION");
+                }
+                return Ok(*data_type);
+            }
+        }
+        Err(self.error("expected data type"))
+    }
+
+    fn create_index(&mut self, unique: bool) -> Result<CreateIndex, Error> {
+        let name = self.ident()?;
+        self.expect_keyword("ON")?;
+        let table = self.ident()?;
+        let hash = if self.consume_keyword("USING") {
+            if self.consume_keyword("HASH") {
+                true
+            } else {
+                self.expect_keyword("BTREE")?;
+                false
+            }
+        } else {
+            false
+        };
+        let columns = self.ident_list()?;
+        Ok(CreateIndex {
+            unique,
+            hash,
+            name,
+            table,
+            columns,
+        })
+    }
+
+    fn insert(&mut self) -> Result<Insert, Error> {
+        self.expect_keyword("INTO")?;
+        let table = self.ident()?;
+        let columns = if self.peek() == Some(&Token::LParen) {
+            Some(self.ident_list()?)
+        } else {
+            None
+        };
+        self.expect_keyword("VALUES")?;
+        let mut rows = vec![];
+        loop {
+            self.expect(Token::LParen)?;
+            let mut row = vec![self.expr()?];
+            while self.consume(&Token::Comma) {
+                row.push(self.expr()?);
+            }
+            self.expect(Token::RParen)?;
+            rows.push(row);
+            if !self.consume(&Token::Comma) {
+                break;
+            }
+        }
+        Ok(Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn select(&mut self) -> Result<Select, Error> {
+        let projection = if self.consume(&Token::Asterisk) {
+            Projection::Wildcard
+        } else {
+            let mut columns = vec![self.column_ref()?];
+            while self.consume(&Token::Comma) {
+                columns.push(self.column_ref()?);
+            }
+            Projection::Columns(columns)
+        };
+        self.expect_keyword("FROM")?;
+        let from = self.ident()?;
+        let mut joins = vec![];
+        loop {
+            if self.consume(&Token::Comma) {
+                let table = self.ident()?;
+                joins.push(Join { table, on: None });
+            } else if self.is_keyword("JOIN") || self.is_keyword("INNER") {
+                self.consume_keyword("INNER");
+                self.expect_keyword("JOIN")?;
+                let table = self.ident()?;
+                self.expect_keyword("ON")?;
+                let on = Some(self.expr()?);
+                joins.push(Join { table, on });
+            } else {
+                break;
+            }
+        }
+        let selection = self.selection()?;
+        let mut order_by = vec![];
+        if self.consume_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let column = self.column_ref()?;
+                let asc = if self.consume_keyword("DESC") {
+                    false
+                } else {
+                    self.consume_keyword("ASC");
+                    true
+                };
+                order_by.push(OrderBy { column, asc });
+                if !self.consume(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        Ok(Select {
+            projection,
+            from,
+            joins,
+            selection,
+            order_by,
+        })
+    }
+
+    fn update(&mut self) -> Result<Update, Error> {
+        let table = self.ident()?;
+        self.expect_keyword("SET")?;
+        let mut assignments = vec![];
+        loop {
+            let column = self.ident()?;
+            self.expect(Token::Eq)?;
+            assignments.push((column, self.expr()?));
+            if !self.consume(&Token::Comma) {
+                break;
+            }
+        }
+        let selection = self.selection()?;
+        Ok(Update {
+            table,
+            assignments,
+            selection,
+        })
+    }
+
+    fn delete(&mut self) -> Result<Delete, Error> {
+        self.expect_keyword("FROM")?;
+        let table = self.ident()?;
+        let selection = self.selection()?;
+        Ok(Delete { table, selection })
+    }
+
+    fn selection(&mut self) -> Result<Option<Expr>, Error> {
+        if self.consume_keyword("WHERE") {
+            self.expr().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // 優先順位の低いものから OR, AND, NOT, 比較
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let mut left = self.and_expr()?;
+        while self.consume_keyword("OR") {
+            let right = self.and_expr()?;
+            left = binary(BinaryOp::Or, left, right);
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, Error> {
+        let mut left = self.not_expr()?;
+        while self.consume_keyword("AND") {
+            let right = self.not_expr()?;
+            left = binary(BinaryOp::And, left, right);
+        }
+        Ok(left)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr, Error> {
+        if self.consume_keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.not_expr()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, Error> {
+        let left = self.primary()?;
+        if self.consume_keyword("IS") {
+            let negated = self.consume_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Expr::IsNull {
+                expr: Box::new(left),
+                negated,
+            });
+        }
+        // a BETWEEN x AND y は a >= x AND a <= y と同じ
+        let negated = self.consume_keyword("NOT");
+        if negated || self.is_keyword("BETWEEN") {
+            self.expect_keyword("BETWEEN")?;
+            let low = self.primary()?;
+            self.expect_keyword("AND")?;
+            let high = self.primary()?;
+            let between = binary(
+                BinaryOp::And,
+                binary(BinaryOp::GtEq, left.clone(), low),
+                binary(BinaryOp::LtEq, left, high),
+            );
+            return Ok(if negated {
+                Expr::Not(Box::new(between))
+            } else {
+                between
+            });
+        }
+        let op = match self.peek() {
+            Some(Token::Eq) => BinaryOp::Eq,
+            Some(Token::NotEq) => BinaryOp::NotEq,
+            Some(Token::Lt) => BinaryOp::Lt,
+            Some(Token::LtEq) => BinaryOp::LtEq,
+            Some(Token::Gt) => BinaryOp::Gt,
+            Some(Token::GtEq) => BinaryOp::GtEq,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.primary()?;
+        Ok(binary(op, left, right))
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        match self.peek() {
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::String(string)) => {
+                let string = string.clone();
+                self.pos += 1;
+                Ok(Expr::Literal(Value::Text(string)))
+            }
+            Some(Token::Number(_)) => self.number(false).map(Expr::Literal),
+            Some(Token::Minus) => {
+                self.pos += 1;
+                self.number(true).map(Expr::Literal)
+            }
+            Some(Token::Ident(_)) => {
+                if self.consume_keyword("NULL") {
+                    Ok(Expr::Literal(Value::Null))
+                } else if self.consume_keyword("TRUE") {
+                    Ok(Expr::Literal(Value::Bool(true)))
+                } else if self.consume_keyword("FALSE") {
+                    Ok(Expr::Literal(Value::Bool(false)))
+                } else {
+                    self.column_ref().map(Expr::Column)
+                }
+            }
+            _ => Err(self.error("expected expression")),
+        }
+    }
+
+    // 小数点を含めば Float64、含まなければ Int64
+    fn number(&mut self, negative: bool) -> Result<Value, Error> {
+        let number = match self.peek() {
+            Some(Token::Number(number)) => number.clone(),
+            _ => return Err(self.error("expected number")),
+        };
+        let sign = if negative { "-" } else { "" };
+        let literal = format!("{}{}", sign, number);
+        let value = if number.contains('.') {
+            literal.parse().ok().map(Value::Float64)
+        } else {
+            literal.parse().ok().map(Value::Int64)
+        };
+        let value = value.ok_or_else(|| self.error("invalid number"))?;
+        self.pos += 1;
+        Ok(value)
+    }
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+    Expr::Binary {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str) -> Box<Expr> {
+        Box::new(Expr::Column(name.into()))
+    }
+
+    fn string(s: &str) -> Box<Expr> {
+        Box::new(Expr::Literal(s.into()))
+    }
+
+    #[test]
+    fn test_create() {
+        assert_eq!(
+            Statement::CreateTable(CreateTable {
+                name: "users".into(),
+                columns: vec![
+                    Column {
+                        name: "id".into(),
+                        data_type: DataType::Text,
+                    },
+                    Column {
+                        name: "last_name".into(),
+                        data_type: DataType::Text,
+                    },
+                ],
+                primary_key: vec!["id".into()],
+            }),
+            parse("create table users (id text, last_name varchar, primary key (id));").unwrap()
+        );
+        assert_eq!(
+            Statement::CreateTable(CreateTable {
+                name: "t".into(),
+                columns: vec![
+                    Column {
+                        name: "a".into(),
+                        data_type: DataType::Int64,
+                    },
+                    Column {
+                        name: "b".into(),
+                        data_type: DataType::Float64,
+                    },
+                    Column {
+                        name: "c".into(),
+                        data_type: DataType::Bool,
+                    },
+                    Column {
+                        name: "d".into(),
+                        data_type: DataType::Bytes,
+                    },
+                ],
+                primary_key: vec!["a".into()],
+            }),
+            parse("CREATE TABLE t (a INT PRIMARY KEY, b DOUBLE PRECISION, c BOOLEAN, d BYTEA)")
+                .unwrap()
+        );
+        assert_eq!(
+            Statement::CreateIndex(CreateIndex {
+                unique: true,
+                hash: false,
+                name: "users_last_name".into(),
+                table: "users".into(),
+                columns: vec!["last_name".into()],
+            }),
+            parse("CREATE UNIQUE INDEX users_last_name ON users (last_name)").unwrap()
+        );
+        assert_eq!(
+            Statement::CreateIndex(CreateIndex {
+                unique: false,
+                hash: false,
+                name: "users_status".into(),
+                table: "users".into(),
+                columns: vec!["status".into()],
+            }),
+            parse("CREATE INDEX users_status ON users (status)").unwrap()
+        );
+        assert_eq!(
+            Statement::CreateIndex(CreateIndex {
+                unique: true,
+                hash: true,
+                name: "users_email".into(),
+                table: "users".into(),
+                columns: vec!["email".into()],
+            }),
+            parse("CREATE UNIQUE INDEX users_email ON users USING HASH (email)").unwrap()
+        );
+        assert!(parse("CREATE INDEX users_email ON users USING GIST (email)").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(
+            Statement::Select(Select {
+                projection: Projection::Columns(vec!["id".into(), "first_name".into()]),
+                from: "users".into(),
+                joins: vec![],
+                selection: Some(Expr::Binary {
+                    op: BinaryOp::Or,
+                    left: Box::new(Expr::Binary {
+                        op: BinaryOp::And,
+                        left: Box::new(Expr::Binary {
+                            op: BinaryOp::GtEq,
+                            left: column("id"),
+                            right: string("b"),
+                        }),
+                        right: Box::new(Expr::Not(Box::new(Expr::Binary {
+                            op: BinaryOp::Eq,
+                            left: column("first_name"),
+                            right: string("Bob"),
+                        }))),
+                    }),
+                    right: Box::new(Expr::Binary {
+                        op: BinaryOp::Lt,
+                        left: string("a"),
+                        right: column("id"),
+                    }),
+                }),
+                order_by: vec![
+                    OrderBy {
+                        column: "id".into(),
+                        asc: true,
+                    },
+                    OrderBy {
+                        column: "first_name".into(),
+                        asc: false,
+                    },
+                ],
+            }),
+            parse(
+                "SELECT id, first_name FROM users \
+                 WHERE id >= 'b' AND NOT first_name = 'Bob' OR 'a' < id \
+                 ORDER BY id, first_name DESC"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_join() {
+        let qualified = |table: &str, name: &str| ColumnRef {
+            table: Some(table.into()),
+            name: name.into(),
+        };
+        assert_eq!(
+            Statement::Select(Select {
+                projection: Projection::Columns(vec![qualified("users", "id"), "name".into()]),
+                from: "users".into(),
+                joins: vec![
+                    Join {
+                        table: "orders".into(),
+                        on: Some(Expr::Binary {
+                            op: BinaryOp::Eq,
+                            left: Box::new(Expr::Column(qualified("users", "id"))),
+                            right: Box::new(Expr::Column(qualified("orders", "user_id"))),
+                        }),
+                    },
+                    Join {
+                        table: "statuses".into(),
+                        on: None,
+                    },
+                    Join {
+                        table: "items".into(),
+                        on: Some(Expr::Binary {
+                            op: BinaryOp::Eq,
+                            left: column("item_id"),
+                            right: Box::new(Expr::Column(qualified("items", "id"))),
+                        }),
+                    },
+                ],
+                selection: Some(Expr::Binary {
+                    op: BinaryOp::Eq,
+                    left: column("status_id"),
+                    right: Box::new(Expr::Column(qualified("statuses", "id"))),
+                }),
+                order_by: vec![],
+            }),
+            parse(
+                "SELECT users.id, name FROM users JOIN orders ON users.id = orders.user_id, \
+                 statuses INNER JOIN items ON item_id = items.id \
+                 WHERE status_id = statuses.id"
+            )
+            .unwrap()
+        );
+        assert!(matches!(
+            parse("SELECT * FROM users JOIN orders"),
+            Err(Error::Syntax { position: 31, .. })
+        ));
+        assert!(matches!(
+            parse("SELECT * FROM users INNER orders"),
+            Err(Error::Syntax { position: 26, .. })
+        ));
+    }
+
+    #[test]
+    fn test_explain() {
+        let select = Select {
+            projection: Projection::Wildcard,
+            from: "users".into(),
+            joins: vec![],
+            selection: None,
+            order_by: vec![],
+        };
+        assert_eq!(
+            Statement::Explain(Explain {
+                analyze: false,
+                select: select.clone(),
+            }),
+            parse("EXPLAIN SELECT * FROM users").unwrap()
+        );
+        assert_eq!(
+            Statement::Explain(Explain {
+                analyze: true,
+                select,
+            }),
+            parse("explain analyze select * from users;").unwrap()
+        );
+        assert_eq!(
+            Statement::Analyze(Analyze {
+                table: Some("users".into())
+            }),
+            parse("ANALYZE users").unwrap()
+        );
+        assert_eq!(
+            Statement::Analyze(Analyze { table: None }),
+            parse("ANALYZE;").unwrap()
+        );
+        // 説明できるのは SELECT だけ
+        assert!(matches!(
+            parse("EXPLAIN DELETE FROM users"),
+            Err(Error::Syntax { position: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn test_vacuum() {
+        assert_eq!(Statement::Vacuum, parse("vacuum;").unwrap());
+    }
+
+    #[test]
+    fn test_error() {
+        assert!(matches!(
+            parse("SELECT * FROM users WHERE"),
+            Err(Error::Syntax { position: 25, .. })
+        ));
+        assert!(matches!(
+            parse("SELECT * FROM users users"),
+            Err(Error::Syntax { position: 20, .. })
+        ));
+        assert!(parse("UPDATE users SET first_name = 'Bob' WHERE id = 'x'").is_ok());
+        assert!(parse("DELETE FROM users WHERE id = 'x';").is_ok());
+        assert!(parse("INSERT INTO users VALUES ('x', 'Bob'), ('y', 'Eve')").is_ok());
+        assert!(matches!(
+            parse("SELECT * FROM t WHERE a = 1.2.3"),
+            Err(Error::Syntax { position: 26, .. })
+        ));
+    }
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(
+            Statement::Insert(Insert {
+                table: "t".into(),
+                columns: None,
+                rows: vec![vec![
+                    Expr::Literal(Value::Int64(-42)),
+                    Expr::Literal(Value::Float64(1.5)),
+                    Expr::Literal(Value::Bool(true)),
+                    Expr::Literal(Value::Null),
+                ]],
+            }),
+            parse("INSERT INTO t VALUES (-42, 1.5, TRUE, null)").unwrap()
+        );
+        assert_eq!(
+            Statement::Delete(Delete {
+                table: "t".into(),
+                selection: Some(Expr::Not(Box::new(Expr::IsNull {
+                    expr: column("a"),
+                    negated: true,
+                }))),
+            }),
+            parse("DELETE FROM t WHERE NOT a IS NOT NULL").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_between() {
+        let between = |op, value| {
+            Box::new(Expr::Binary {
+                op,
+                left: column("a"),
+                right: Box::new(Expr::Literal(Value::Int64(value))),
+            })
+        };
+        let expected = Expr::Binary {
+            op: BinaryOp::And,
+            left: between(BinaryOp::GtEq, 1),
+            right: between(BinaryOp::LtEq, 3),
+        };
+        assert_eq!(
+            Statement::Delete(Delete {
+                table: "t".into(),
+                selection: Some(Expr::Binary {
+                    op: BinaryOp::Or,
+                    left: Box::new(expected.clone()),
+                    right: Box::new(Expr::Not(Box::new(expected))),
+                }),
+            }),
+            parse("DELETE FROM t WHERE a BETWEEN 1 AND 3 OR a NOT BETWEEN 1 AND 3").unwrap()
+        );
+        assert!(matches!(
+            parse("DELETE FROM t WHERE a NOT 1"),
+            Err(Error::Syntax { position: 26, .. })
+        ));
+    }
+}
@@ -0,0 +1,962 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use crate::catalog::{self, TableSchema};
+use crate::mvcc::Snapshot;
+use crate::query::{
+    self, Filter, HashIndexScan, HashJoin, IndexScan, NestedLoopJoin, PlanNode, Predicate, Project,
+    SeqScan, Tuple, TupleRange, TupleSearchMode, TupleSlice,
+};
+use crate::value::Value;
+
+use super::ast::{BinaryOp, ColumnRef, Expr, OrderBy};
+use super::Error;
+
+pub mod cost;
+
+pub use cost::Estimate;
+
+// 列名を行の中の位置に解決した値
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Column(usize),
+    Value(Value),
+}
+
+impl Operand {
+    pub fn eval<'a>(&'a self, tuple: TupleSlice<'a>) -> &'a Value {
+        match self {
+            Operand::Column(index) => &tuple[*index],
+            Operand::Value(value) => value,
+        }
+    }
+
+    fn describe(&self, schema: &TableSchema) -> String {
+        match self {
+            Operand::Column(index) => schema.columns[*index].name.clone(),
+            Operand::Value(value) => query::format_value(value),
+        }
+    }
+}
+
+// 列名を解決した WHERE 句
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cond {
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+    Not(Box<Cond>),
+    Compare(BinaryOp, Operand, Operand),
+    IsNull(Operand, bool),
+}
+
+impl Cond {
+    // NULL との比較は真でも偽でもない (None)。WHERE 句は Some(true) になる行だけを返す
+    pub fn eval(&self, tuple: TupleSlice) -> Option<bool> {
+        match self {
+            Cond::And(left, right) => match (left.eval(tuple), right.eval(tuple)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Cond::Or(left, right) => match (left.eval(tuple), right.eval(tuple)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Cond::Not(cond) => cond.eval(tuple).map(|b| !b),
+            Cond::Compare(op, left, right) => {
+                let (left, right) = (left.eval(tuple), right.eval(tuple));
+                if left.is_null() || right.is_null() {
+                    return None;
+                }
+                Some(compare(*op, left.cmp(right)))
+            }
+            Cond::IsNull(operand, negated) => Some(operand.eval(tuple).is_null() != *negated),
+        }
+    }
+
+    // EXPLAIN で表示する、列名で書いた条件
+    pub fn describe(&self, schema: &TableSchema) -> String {
+        match self {
+            Cond::And(left, right) => format!(
+                "{} AND {}",
+                left.describe_nested(schema),
+                right.describe_nested(schema)
+            ),
+            Cond::Or(left, right) => format!(
+                "{} OR {}",
+                left.describe_nested(schema),
+                right.describe_nested(schema)
+            ),
+            Cond::Not(cond) => format!("NOT {}", cond.describe_nested(schema)),
+            Cond::Compare(op, left, right) => format!(
+                "{} {} {}",
+                left.describe(schema),
+                symbol(*op),
+                right.describe(schema)
+            ),
+            Cond::IsNull(operand, negated) => format!(
+                "{} IS {}NULL",
+                operand.describe(schema),
+                if *negated { "NOT " } else { "" }
+            ),
+        }
+    }
+
+    // AND と OR は括弧で囲む
+    fn describe_nested(&self, schema: &TableSchema) -> String {
+        match self {
+            Cond::And(..) | Cond::Or(..) => format!("({})", self.describe(schema)),
+            cond => cond.describe(schema),
+        }
+    }
+
+    // AND でつながった条件を1つずつに分ける
+    fn conjuncts<'a>(&'a self, conjuncts: &mut Vec<&'a Cond>) {
+        match self {
+            Cond::And(left, right) => {
+                left.conjuncts(conjuncts);
+                right.conjuncts(conjuncts);
+            }
+            cond => conjuncts.push(cond),
+        }
+    }
+}
+
+fn symbol(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Eq => "=",
+        BinaryOp::NotEq => "<>",
+        BinaryOp::Lt => "<",
+        BinaryOp::LtEq => "<=",
+        BinaryOp::Gt => ">",
+        BinaryOp::GtEq => ">=",
+        BinaryOp::And => "AND",
+        BinaryOp::Or => "OR",
+    }
+}
+
+fn compare(op: BinaryOp, ordering: Ordering) -> bool {
+    match op {
+        BinaryOp::Eq => ordering == Ordering::Equal,
+        BinaryOp::NotEq => ordering != Ordering::Equal,
+        BinaryOp::Lt => ordering == Ordering::Less,
+        BinaryOp::LtEq => ordering != Ordering::Greater,
+        BinaryOp::Gt => ordering == Ordering::Greater,
+        BinaryOp::GtEq => ordering != Ordering::Less,
+        BinaryOp::And | BinaryOp::Or => unreachable!(),
+    }
+}
+
+// 表の名前が付いていれば、schema の表の名前と同じでなければならない
+pub fn bind_column(column: &ColumnRef, schema: &TableSchema) -> Result<usize, Error> {
+    match &column.table {
+        Some(table) if *table != schema.name => {
+            Err(catalog::Error::ColumnNotFound(column.to_string()).into())
+        }
+        _ => Ok(schema.column_index(&column.name)?),
+    }
+}
+
+pub fn bind_operand(expr: &Expr, schema: &TableSchema) -> Result<Operand, Error> {
+    match expr {
+        Expr::Column(column) => Ok(Operand::Column(bind_column(column, schema)?)),
+        Expr::Literal(value) => Ok(Operand::Value(value.clone())),
+        _ => Err(Error::Unsupported("boolean expressions as values".into())),
+    }
+}
+
+// 列と比べる定数を列の型に合わせる
+// キーの検索にも使うので、エンコードした結果が列の値と同じ順に並ぶようにしておく
+fn cast_operand(operand: Operand, other: &Operand, schema: &TableSchema) -> Result<Operand, Error> {
+    match (operand, other) {
+        (Operand::Value(value), Operand::Column(index)) => {
+            let column = &schema.columns[*index];
+            match value.clone().cast(column.data_type) {
+                Some(value) => Ok(Operand::Value(value)),
+                None => Err(catalog::Error::TypeMismatch {
+                    column: column.name.clone(),
+                    data_type: column.data_type,
+                    value,
+                }
+                .into()),
+            }
+        }
+        (operand, _) => Ok(operand),
+    }
+}
+
+pub fn bind_cond(expr: &Expr, schema: &TableSchema) -> Result<Cond, Error> {
+    match expr {
+        Expr::Not(expr) => Ok(Cond::Not(Box::new(bind_cond(expr, schema)?))),
+        Expr::Binary { op, left, right } => {
+            let cond = match op {
+                BinaryOp::And => Cond::And(
+                    Box::new(bind_cond(left, schema)?),
+                    Box::new(bind_cond(right, schema)?),
+                ),
+                BinaryOp::Or => Cond::Or(
+                    Box::new(bind_cond(left, schema)?),
+                    Box::new(bind_cond(right, schema)?),
+                ),
+                op => {
+                    let left = bind_operand(left, schema)?;
+                    let right = bind_operand(right, schema)?;
+                    Cond::Compare(
+                        *op,
+                        cast_operand(left.clone(), &right, schema)?,
+                        cast_operand(right, &left, schema)?,
+                    )
+                }
+            };
+            Ok(cond)
+        }
+        Expr::IsNull { expr, negated } => Ok(Cond::IsNull(bind_operand(expr, schema)?, *negated)),
+        _ => Err(Error::Unsupported(
+            "non-boolean expressions in WHERE".into(),
+        )),
+    }
+}
+
+// 列と定数の比較。列が右辺にあれば左右を入れ替える
+fn column_comparison(cond: &Cond) -> Option<(usize, BinaryOp, &Value)> {
+    let flip = |op| match op {
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::LtEq => BinaryOp::GtEq,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::GtEq => BinaryOp::LtEq,
+        op => op,
+    };
+    match cond {
+        Cond::Compare(op, Operand::Column(column), Operand::Value(value)) => {
+            Some((*column, *op, value))
+        }
+        Cond::Compare(op, Operand::Value(value), Operand::Column(column)) => {
+            Some((*column, flip(*op), value))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPath {
+    // テーブルの B+Tree を主キーの順に読む
+    SeqScan,
+    // n 番目のユニークインデックスをセカンダリキーの順に読む
+    IndexScan(usize),
+    // n 番目の一意でないインデックスを、セカンダリキーと主キーの順に読む
+    NonUniqueIndexScan(usize),
+    // n 番目のハッシュインデックスで、セカンダリキーの全ての列が等号で決まる行を引く
+    HashIndexScan(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScanPlan {
+    pub access_path: AccessPath,
+    // キーの先頭から、等号で値が決まる列の値
+    pub key_prefix: Tuple,
+    // その次の列の下限と上限。bool は境界を含むかどうか
+    pub lower: Option<(Value, bool)>,
+    pub upper: Option<(Value, bool)>,
+    // キーの大きい方から読む
+    pub reverse: bool,
+    // 読んだ行にかける WHERE 句全体
+    pub filter: Option<Cond>,
+}
+
+impl ScanPlan {
+    fn new(access_path: AccessPath, key_columns: &[usize], conjuncts: &[&Cond]) -> Self {
+        let comparisons: Vec<_> = conjuncts
+            .iter()
+            .filter_map(|cond| column_comparison(cond))
+            .collect();
+        let bound = |column, ops: &[BinaryOp]| {
+            comparisons
+                .iter()
+                .find(|(c, op, _)| *c == column && ops.contains(op))
+                .map(|(_, op, value)| (*op, (*value).clone()))
+        };
+        let mut key_prefix = vec![];
+        for &column in key_columns {
+            match bound(column, &[BinaryOp::Eq]) {
+                Some((_, value)) => key_prefix.push(value),
+                None => break,
+            }
+        }
+        let (mut lower, mut upper) = (None, None);
+        if let Some(&column) = key_columns.get(key_prefix.len()) {
+            lower = bound(column, &[BinaryOp::Gt, BinaryOp::GtEq])
+                .map(|(op, value)| (value, op == BinaryOp::GtEq));
+            upper = bound(column, &[BinaryOp::Lt, BinaryOp::LtEq])
+                .map(|(op, value)| (value, op == BinaryOp::LtEq));
+        }
+        Self {
+            access_path,
+            key_prefix,
+            lower,
+            upper,
+            reverse: false,
+            filter: None,
+        }
+    }
+
+    // 統計情報があれば、返す行の数と読むコストを見積もる
+    pub fn estimate(&self, schema: &TableSchema) -> Option<Estimate> {
+        let stats = schema.stats.as_ref()?;
+        let key_columns = key_columns(schema, self.access_path);
+        Some(cost::estimate_scan(self, &key_columns, schema, stats))
+    }
+
+    // 大きいほど読む範囲が狭い
+    fn score(&self, key_columns: &[usize]) -> (bool, usize, bool) {
+        (
+            self.key_prefix.len() == key_columns.len(),
+            self.key_prefix.len(),
+            self.lower.is_some() || self.upper.is_some(),
+        )
+    }
+
+    // スナップショットがあれば、そこから見える版を読む
+    pub fn into_plan_node(
+        self,
+        schema: &TableSchema,
+        snapshot: Option<&Snapshot>,
+    ) -> Box<dyn PlanNode> {
+        let bound = |bound: Option<(Value, bool)>| match bound {
+            Some((value, inclusive)) => {
+                let mut tuple = self.key_prefix.clone();
+                tuple.push(value);
+                if inclusive {
+                    Bound::Included(tuple)
+                } else {
+                    Bound::Excluded(tuple)
+                }
+            }
+            None if self.key_prefix.is_empty() => Bound::Unbounded,
+            None => Bound::Included(self.key_prefix.clone()),
+        };
+        let range = TupleRange {
+            lower: bound(self.lower.clone()),
+            upper: bound(self.upper.clone()),
+        };
+        let search_mode = if self.reverse {
+            TupleSearchMode::End
+        } else {
+            TupleSearchMode::Start
+        };
+        let table_meta_page_id = schema.table.meta_page_id;
+        let scan: Box<dyn PlanNode> = match self.access_path {
+            AccessPath::SeqScan => Box::new(SeqScan {
+                table_meta_page_id,
+                search_mode,
+                range,
+                snapshot: snapshot.cloned(),
+            }),
+            AccessPath::IndexScan(index) => Box::new(IndexScan {
+                table_meta_page_id,
+                index_meta_page_id: schema.table.unique_indices[index].meta_page_id,
+                search_mode,
+                range,
+                snapshot: snapshot.cloned(),
+            }),
+            AccessPath::NonUniqueIndexScan(index) => Box::new(IndexScan {
+                table_meta_page_id,
+                index_meta_page_id: schema.table.non_unique_indices[index].meta_page_id,
+                search_mode,
+                range,
+                snapshot: snapshot.cloned(),
+            }),
+            AccessPath::HashIndexScan(index) => Box::new(HashIndexScan {
+                table_meta_page_id,
+                index_meta_page_id: schema.table.hash_indices[index].meta_page_id,
+                key: self.key_prefix,
+                snapshot: snapshot.cloned(),
+            }),
+        };
+        match self.filter {
+            Some(filter) => Box::new(Filter {
+                inner_plan: scan,
+                cond: Predicate::new(filter.describe(schema), move |tuple| {
+                    filter.eval(tuple) == Some(true)
+                }),
+            }),
+            None => scan,
+        }
+    }
+}
+
+// キーの順に読み出した行が ORDER BY の順に並ぶかどうか
+// 等号で値が決まる列は並び順に影響しないので飛ばす
+fn satisfies_order(key_columns: &[usize], fixed_columns: &[usize], order_by: &[usize]) -> bool {
+    let mut rest = key_columns
+        .iter()
+        .filter(|column| !fixed_columns.contains(column));
+    for column in order_by
+        .iter()
+        .filter(|column| !fixed_columns.contains(column))
+    {
+        match rest.next() {
+            Some(key_column) if key_column == column => continue,
+            Some(_) => return false,
+            // キーは一意なので、キーの列を並べ終えたら残りの列の順番は関係ない
+            None => return true,
+        }
+    }
+    true
+}
+
+// アクセスパスで読むインデックスのキーの列
+// 一意でないインデックスのキーは、セカンダリキーの後ろに主キーが続く
+fn key_columns(schema: &TableSchema, access_path: AccessPath) -> Vec<usize> {
+    let pkey_columns = 0..schema.table.num_key_elems;
+    match access_path {
+        AccessPath::SeqScan => pkey_columns.collect(),
+        AccessPath::IndexScan(index) => schema.table.unique_indices[index].skey.clone(),
+        AccessPath::NonUniqueIndexScan(index) => {
+            let skey = &schema.table.non_unique_indices[index].skey;
+            skey.iter().copied().chain(pkey_columns).collect()
+        }
+        AccessPath::HashIndexScan(index) => schema.table.hash_indices[index].skey.clone(),
+    }
+}
+
+// テーブルの読み方を決める
+// ANALYZE した統計情報があれば、主キーかインデックスのうちコストの見積もりが最も小さいものを選ぶ
+// なければ WHERE 句の条件で読む範囲を最も絞れるものを選ぶ
+#[allow(clippy::type_complexity)]
+pub fn plan_scan(
+    schema: &TableSchema,
+    selection: Option<&Expr>,
+    order_by: &[OrderBy],
+) -> Result<ScanPlan, Error> {
+    let filter = selection.map(|expr| bind_cond(expr, schema)).transpose()?;
+    let mut conjuncts = vec![];
+    if let Some(filter) = &filter {
+        filter.conjuncts(&mut conjuncts);
+    }
+    // 全て DESC ならキーを逆順に読む
+    let reverse = order_by.first().is_some_and(|order_by| !order_by.asc);
+    if order_by.iter().any(|order_by| order_by.asc == reverse) {
+        return Err(Error::Unsupported("ORDER BY mixing ASC and DESC".into()));
+    }
+    let order_by = order_by
+        .iter()
+        .map(|order_by| bind_column(&order_by.column, schema))
+        .collect::<Result<Vec<_>, _>>()?;
+    let fixed_columns: Vec<_> = conjuncts
+        .iter()
+        .filter_map(|cond| column_comparison(cond))
+        .filter(|(_, op, _)| *op == BinaryOp::Eq)
+        .map(|(column, _, _)| column)
+        .collect();
+
+    let candidates = std::iter::once(AccessPath::SeqScan)
+        .chain((0..schema.table.unique_indices.len()).map(AccessPath::IndexScan))
+        .chain((0..schema.table.non_unique_indices.len()).map(AccessPath::NonUniqueIndexScan))
+        .chain((0..schema.table.hash_indices.len()).map(AccessPath::HashIndexScan));
+    let mut best: Option<(ScanPlan, (bool, usize, bool), Option<f64>)> = None;
+    for access_path in candidates {
+        let key_columns = key_columns(schema, access_path);
+        let plan = ScanPlan::new(access_path, &key_columns, &conjuncts);
+        let usable = match access_path {
+            // ハッシュインデックスは範囲を読めない。キーが全て決まれば高々1行なので、並び順は関係ない
+            AccessPath::HashIndexScan(_) => plan.key_prefix.len() == key_columns.len(),
+            _ => satisfies_order(&key_columns, &fixed_columns, &order_by),
+        };
+        if !usable {
+            continue;
+        }
+        let score = plan.score(&key_columns);
+        let cost = plan.estimate(schema).map(|estimate| estimate.cost);
+        // 同じ程度なら主キーを使う
+        let is_better = match &best {
+            None => true,
+            Some((_, _, Some(best_cost))) => cost.is_some_and(|cost| cost < *best_cost),
+            Some((_, best_score, None)) => score > *best_score,
+        };
+        if is_better {
+            best = Some((plan, score, cost));
+        }
+    }
+    let (mut plan, _, _) = best.ok_or_else(|| {
+        Error::Unsupported("ORDER BY columns other than the primary key or an index".into())
+    })?;
+    plan.reverse = reverse;
+    plan.filter = filter;
+    Ok(plan)
+}
+
+// 結合する表と、その表の読み方
+pub struct JoinInput<'a> {
+    pub schema: &'a TableSchema,
+    pub scan: ScanPlan,
+    // 版を付けた表を読むスナップショット
+    pub snapshot: Option<&'a Snapshot>,
+}
+
+// inputs[left] の left_column 列と inputs[right] の right_column 列の等結合
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinColumns {
+    pub left: usize,
+    pub left_column: usize,
+    pub right: usize,
+    pub right_column: usize,
+}
+
+// 入力を結合する左深の結合木を作る
+// 統計情報から見積もった行数で結合順を決め、結合条件のある入力は HashJoin で、ない入力は直積で足す
+// 結果の行は、inputs の順に各表の列を並べたもの
+pub fn plan_join(inputs: Vec<JoinInput>, conds: &[JoinColumns]) -> Box<dyn PlanNode> {
+    let rows: Vec<_> = inputs
+        .iter()
+        .map(|input| {
+            input
+                .scan
+                .estimate(input.schema)
+                .map_or(cost::DEFAULT_ROWS, |estimate| estimate.rows)
+        })
+        .collect();
+    let column_stats = |input: usize, column: usize| {
+        let stats = inputs[input].schema.stats.as_ref()?;
+        stats.columns.get(column)
+    };
+    let edges: Vec<_> = conds
+        .iter()
+        .map(|cond| cost::JoinEdge {
+            left: cond.left,
+            right: cond.right,
+            selectivity: cost::join_selectivity(
+                column_stats(cond.left, cond.left_column),
+                column_stats(cond.right, cond.right_column),
+            ),
+        })
+        .collect();
+    let order = cost::join_order(&rows, &edges);
+
+    let num_columns: Vec<_> = inputs
+        .iter()
+        .map(|input| input.schema.columns.len())
+        .collect();
+    let mut nodes: Vec<_> = inputs
+        .into_iter()
+        .map(|input| Some(input.scan.into_plan_node(input.schema, input.snapshot)))
+        .collect();
+    // 結合した行の中での、各入力の先頭の列の位置
+    let mut offsets = vec![None; nodes.len()];
+    let mut plan = nodes[order[0]].take().unwrap();
+    offsets[order[0]] = Some(0);
+    let mut width = num_columns[order[0]];
+    for &i in &order[1..] {
+        let (mut left_key, mut right_key) = (vec![], vec![]);
+        for cond in conds {
+            let sides = [
+                (cond.left, cond.left_column, cond.right, cond.right_column),
+                (cond.right, cond.right_column, cond.left, cond.left_column),
+            ];
+            for (joined, joined_column, next, next_column) in sides {
+                if next != i {
+                    continue;
+                }
+                if let Some(offset) = offsets[joined] {
+                    left_key.push(offset + joined_column);
+                    right_key.push(next_column);
+                }
+            }
+        }
+        let right_plan = nodes[i].take().unwrap();
+        plan = if left_key.is_empty() {
+            Box::new(NestedLoopJoin {
+                outer_plan: plan,
+                inner_plan: right_plan,
+                cond: Predicate::always(),
+            })
+        } else {
+            Box::new(HashJoin {
+                left_plan: plan,
+                right_plan,
+                left_key,
+                right_key,
+                cond: Predicate::always(),
+            })
+        };
+        offsets[i] = Some(width);
+        width += num_columns[i];
+    }
+    if order.iter().copied().eq(0..order.len()) {
+        return plan;
+    }
+    let columns = offsets
+        .iter()
+        .zip(&num_columns)
+        .flat_map(|(offset, &n)| (0..n).map(move |column| offset.unwrap() + column))
+        .collect();
+    Box::new(Project {
+        inner_plan: plan,
+        columns,
+    })
+}
+
+// FROM に並べた表の中から列を探し、何番目の表の何番目の列かを返す
+// 表の名前が付いていなければ、その名前の列を持つ表は1つだけでなければならない
+pub fn bind_join_column(
+    column: &ColumnRef,
+    schemas: &[&TableSchema],
+) -> Result<(usize, usize), Error> {
+    let mut found = None;
+    for (input, schema) in schemas.iter().enumerate() {
+        if column
+            .table
+            .as_ref()
+            .is_some_and(|table| *table != schema.name)
+        {
+            continue;
+        }
+        if let Ok(index) = schema.column_index(&column.name) {
+            if found.is_some() {
+                return Err(Error::AmbiguousColumn(column.to_string()));
+            }
+            found = Some((input, index));
+        }
+    }
+    found.ok_or_else(|| catalog::Error::ColumnNotFound(column.to_string()).into())
+}
+
+// AND でつながった式を1つずつに分ける
+fn expr_conjuncts<'a>(expr: &'a Expr, conjuncts: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::And,
+            left,
+            right,
+        } => {
+            expr_conjuncts(left, conjuncts);
+            expr_conjuncts(right, conjuncts);
+        }
+        expr => conjuncts.push(expr),
+    }
+}
+
+// 式が使う列を持つ表
+fn referenced_inputs(
+    expr: &Expr,
+    schemas: &[&TableSchema],
+    inputs: &mut Vec<usize>,
+) -> Result<(), Error> {
+    match expr {
+        Expr::Column(column) => {
+            let (input, _) = bind_join_column(column, schemas)?;
+            if !inputs.contains(&input) {
+                inputs.push(input);
+            }
+        }
+        Expr::Literal(_) => {}
+        Expr::Not(expr) | Expr::IsNull { expr, .. } => referenced_inputs(expr, schemas, inputs)?,
+        Expr::Binary { left, right, .. } => {
+            referenced_inputs(left, schemas, inputs)?;
+            referenced_inputs(right, schemas, inputs)?;
+        }
+    }
+    Ok(())
+}
+
+// FROM に並べた表を結合する
+// 1つの表の列だけを使う条件はその表を読む時に絞り込み、2つの表の列の等号を結合条件にする
+// 結果の行は、inputs の順に各表の列を並べたもの
+pub fn plan_select_join(
+    inputs: &[(&TableSchema, Option<&Snapshot>)],
+    conds: &[&Expr],
+) -> Result<Box<dyn PlanNode>, Error> {
+    let schemas: Vec<_> = inputs.iter().map(|(schema, _)| *schema).collect();
+    let mut conjuncts = vec![];
+    for cond in conds {
+        expr_conjuncts(cond, &mut conjuncts);
+    }
+    let mut filters: Vec<Vec<&Expr>> = vec![vec![]; inputs.len()];
+    let mut join_columns = vec![];
+    for conjunct in conjuncts {
+        let mut referenced = vec![];
+        referenced_inputs(conjunct, &schemas, &mut referenced)?;
+        let columns = match conjunct {
+            Expr::Binary {
+                op: BinaryOp::Eq,
+                left,
+                right,
+            } => match (&**left, &**right) {
+                (Expr::Column(left), Expr::Column(right)) => Some((left, right)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match (&referenced[..], columns) {
+            // 列を使わない条件は、最初の表を読む時に調べる
+            ([], _) => filters[0].push(conjunct),
+            ([input], _) => filters[*input].push(conjunct),
+            ([_, _], Some((left, right))) => {
+                let (left, left_column) = bind_join_column(left, &schemas)?;
+                let (right, right_column) = bind_join_column(right, &schemas)?;
+                join_columns.push(JoinColumns {
+                    left,
+                    left_column,
+                    right,
+                    right_column,
+                });
+            }
+            _ => {
+                return Err(Error::Unsupported(
+                    "join conditions other than equality of two columns".into(),
+                ))
+            }
+        }
+    }
+    let inputs = inputs
+        .iter()
+        .zip(filters)
+        .map(|(&(schema, snapshot), filters)| {
+            let selection = filters
+                .into_iter()
+                .cloned()
+                .reduce(|left, right| Expr::Binary {
+                    op: BinaryOp::And,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                });
+            Ok(JoinInput {
+                schema,
+                scan: plan_scan(schema, selection.as_ref(), &[])?,
+                snapshot,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(plan_join(inputs, &join_columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ast::Statement;
+    use super::super::parse;
+    use super::*;
+    use crate::catalog::Column;
+    use crate::disk::PageId;
+    use crate::stats::{ColumnStats, TableStats};
+    use crate::table::{NonUniqueIndex, Table, UniqueHashIndex, UniqueIndex};
+    use crate::value::DataType;
+
+    fn schema() -> TableSchema {
+        let column = |name: &str| Column {
+            name: name.into(),
+            data_type: DataType::Text,
+        };
+        TableSchema {
+            name: "users".into(),
+            columns: vec![
+                column("id"),
+                column("first_name"),
+                column("last_name"),
+                column("status"),
+            ],
+            table: Table {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                num_key_elems: 1,
+                unique_indices: vec![UniqueIndex {
+                    meta_page_id: PageId::INVALID_PAGE_ID,
+                    skey: vec![2, 1],
+                }],
+                non_unique_indices: vec![NonUniqueIndex {
+                    meta_page_id: PageId::INVALID_PAGE_ID,
+                    skey: vec![3],
+                }],
+                hash_indices: vec![],
+            },
+            index_names: vec!["users_name".into()],
+            non_unique_index_names: vec!["users_status".into()],
+            versioned: false,
+            stats: None,
+            hash_index_names: vec![],
+        }
+    }
+
+    fn plan_select(sql: &str) -> Result<ScanPlan, Error> {
+        plan_select_in(&schema(), sql)
+    }
+
+    fn plan_select_in(schema: &TableSchema, sql: &str) -> Result<ScanPlan, Error> {
+        match parse(sql).unwrap() {
+            Statement::Select(select) => {
+                plan_scan(schema, select.selection.as_ref(), &select.order_by)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_access_path() {
+        let plan = plan_select("SELECT * FROM users WHERE last_name = 'Smith'").unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+        assert_eq!(vec![Value::from("Smith")], plan.key_prefix);
+        assert!(plan.filter.is_some());
+
+        let plan =
+            plan_select("SELECT * FROM users WHERE 'Alice' <= first_name AND 'Smith' = last_name")
+                .unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+        assert_eq!(Some((Value::from("Alice"), true)), plan.lower);
+
+        // 同じように絞れるなら主キーを使う
+        let plan =
+            plan_select("SELECT * FROM users WHERE id = 'x' AND last_name = 'Smith'").unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        let plan =
+            plan_select("SELECT * FROM users WHERE id < 'x' OR last_name = 'Smith'").unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        assert_eq!(None, plan.upper);
+    }
+
+    #[test]
+    fn test_order_by() {
+        let plan = plan_select("SELECT * FROM users ORDER BY last_name, first_name").unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+        // 等号で決まる列は並び順に関係しない
+        let plan =
+            plan_select("SELECT * FROM users WHERE last_name = 'Smith' ORDER BY first_name, id")
+                .unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+        let plan =
+            plan_select("SELECT * FROM users WHERE last_name = 'Smith' ORDER BY id").unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        assert!(matches!(
+            plan_select("SELECT * FROM users ORDER BY first_name"),
+            Err(Error::Unsupported(_))
+        ));
+        let plan =
+            plan_select("SELECT * FROM users WHERE last_name = 'Smith' ORDER BY first_name DESC")
+                .unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+        assert!(plan.reverse);
+        assert!(matches!(
+            plan_select("SELECT * FROM users ORDER BY last_name DESC, first_name"),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_between() {
+        let plan = plan_select("SELECT * FROM users WHERE id BETWEEN 'b' AND 'd' ORDER BY id DESC")
+            .unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        assert_eq!(Some((Value::from("b"), true)), plan.lower);
+        assert_eq!(Some((Value::from("d"), true)), plan.upper);
+        assert!(plan.reverse);
+    }
+
+    #[test]
+    fn test_non_unique_index() {
+        let plan = plan_select("SELECT * FROM users WHERE status = 'open'").unwrap();
+        assert_eq!(AccessPath::NonUniqueIndexScan(0), plan.access_path);
+        assert_eq!(vec![Value::from("open")], plan.key_prefix);
+        // セカンダリキーの後ろの主キーでも絞れて、その順に並ぶ
+        let plan =
+            plan_select("SELECT * FROM users WHERE status = 'open' AND id > 'm' ORDER BY id")
+                .unwrap();
+        assert_eq!(AccessPath::NonUniqueIndexScan(0), plan.access_path);
+        assert_eq!(Some((Value::from("m"), false)), plan.lower);
+        // ユニークインデックスで1行に決まるならそちらを使う
+        let plan = plan_select(
+            "SELECT * FROM users WHERE status = 'open' AND last_name = 'Smith' AND first_name = 'Alice'",
+        )
+        .unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let mut schema = schema();
+        schema.table.hash_indices.push(UniqueHashIndex {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            skey: vec![1],
+        });
+        schema.hash_index_names.push("users_first_name".into());
+        let plan =
+            plan_select_in(&schema, "SELECT * FROM users WHERE first_name = 'Alice'").unwrap();
+        assert_eq!(AccessPath::HashIndexScan(0), plan.access_path);
+        assert_eq!(vec![Value::from("Alice")], plan.key_prefix);
+        // 高々1行なので、どの順に並べても使える
+        let plan = plan_select_in(
+            &schema,
+            "SELECT * FROM users WHERE first_name = 'Alice' ORDER BY status DESC",
+        )
+        .unwrap();
+        assert_eq!(AccessPath::HashIndexScan(0), plan.access_path);
+        // 範囲は引けない
+        let plan =
+            plan_select_in(&schema, "SELECT * FROM users WHERE first_name > 'Alice'").unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        // 同じように絞れるなら主キーを使う
+        let plan = plan_select_in(
+            &schema,
+            "SELECT * FROM users WHERE id = 'x' AND first_name = 'Alice'",
+        )
+        .unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+    }
+
+    #[test]
+    fn test_cost_based() {
+        let column = |distinct_count, histogram: Vec<String>| ColumnStats {
+            null_count: 0,
+            distinct_count,
+            avg_width: 10.0,
+            histogram: histogram.into_iter().map(Value::from).collect(),
+        };
+        // status の 9 割近くは 'open' で、残りは多くの種類の値に分かれる
+        let mut statuses = vec!["archived".to_string()];
+        statuses.extend(std::iter::repeat_n("closed".to_string(), 10));
+        statuses.extend(std::iter::repeat_n("open".to_string(), 90));
+        let mut analyzed = schema();
+        analyzed.stats = Some(TableStats {
+            row_count: 100000,
+            columns: vec![
+                column(
+                    100000,
+                    (0..=100).map(|i| format!("u{:05}", i * 1000)).collect(),
+                ),
+                column(1000, vec!["a".into(), "z".into()]),
+                column(5000, vec!["a".into(), "z".into()]),
+                column(1000, statuses),
+            ],
+        });
+
+        // 統計情報がなければインデックスを使うが、ほとんどの行を読むなら順に読む方が安い
+        let sql = "SELECT * FROM users WHERE status = 'open'";
+        assert_eq!(
+            AccessPath::NonUniqueIndexScan(0),
+            plan_select(sql).unwrap().access_path
+        );
+        let plan = plan_select_in(&analyzed, sql).unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        let estimate = plan.estimate(&analyzed).unwrap();
+        assert!((estimate.rows - 89000.0).abs() < 1.0, "{:?}", estimate);
+        assert!(plan_select(sql).unwrap().estimate(&schema()).is_none());
+
+        // 少ない値ならインデックスを使う
+        let plan =
+            plan_select_in(&analyzed, "SELECT * FROM users WHERE status = 'blocked'").unwrap();
+        assert_eq!(AccessPath::NonUniqueIndexScan(0), plan.access_path);
+        let plan =
+            plan_select_in(&analyzed, "SELECT * FROM users WHERE last_name = 'Smith'").unwrap();
+        assert_eq!(AccessPath::IndexScan(0), plan.access_path);
+
+        // 主キーの範囲が狭ければ、インデックスで絞れても主キーの範囲を読む
+        let plan = plan_select_in(
+            &analyzed,
+            "SELECT * FROM users WHERE id >= 'u99990' AND status = 'blocked'",
+        )
+        .unwrap();
+        assert_eq!(AccessPath::SeqScan, plan.access_path);
+        assert_eq!(
+            AccessPath::NonUniqueIndexScan(0),
+            plan_select("SELECT * FROM users WHERE id >= 'u99990' AND status = 'blocked'")
+                .unwrap()
+                .access_path
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::catalog::TableSchema;
+use crate::disk::PAGE_SIZE;
+use crate::stats::{ColumnStats, TableStats};
+use crate::value::Value;
+
+use super::super::ast::BinaryOp;
+use super::{column_comparison, AccessPath, Cond, Operand, ScanPlan};
+
+// コストの単位は、ページを 1 つ順に読む手間
+const SEQ_PAGE_COST: f64 = 1.0;
+// インデックスから引いた行ごとに、テーブルの B+Tree を根から辿る
+const RANDOM_PAGE_COST: f64 = 4.0;
+// 1 行を読んで条件を調べる手間
+const CPU_ROW_COST: f64 = 0.01;
+// 葉のページのうち、ペアが使っている割合
+const FILL_FACTOR: f64 = 0.7;
+// ペアごとのスロットと長さの分
+const PAIR_OVERHEAD: f64 = 8.0;
+// 統計情報がない時に使う値
+const DEFAULT_EQ_SELECTIVITY: f64 = 0.005;
+const DEFAULT_RANGE_SELECTIVITY: f64 = 0.33;
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+const DEFAULT_WIDTH: f64 = 16.0;
+pub const DEFAULT_ROWS: f64 = 1000.0;
+// 結合順を動的計画法で探す入力の数の上限。2^n 個の部分集合を調べる
+const MAX_DP_INPUTS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    // 返す行の数
+    pub rows: f64,
+    pub cost: f64,
+}
+
+// key_columns は plan のアクセスパスで読むインデックスのキーの列
+pub fn estimate_scan(
+    plan: &ScanPlan,
+    key_columns: &[usize],
+    schema: &TableSchema,
+    stats: &TableStats,
+) -> Estimate {
+    let row_count = stats.row_count;
+    let column = |index: usize| stats.columns.get(index);
+    let mut selectivity = 1.0;
+    for (&index, value) in key_columns.iter().zip(&plan.key_prefix) {
+        selectivity *= column(index).map_or(DEFAULT_EQ_SELECTIVITY, |column| {
+            column.eq_selectivity(row_count, value)
+        });
+    }
+    if plan.lower.is_some() || plan.upper.is_some() {
+        let index = key_columns[plan.key_prefix.len()];
+        selectivity *= column(index).map_or(DEFAULT_RANGE_SELECTIVITY, |column| {
+            column.range_selectivity(row_count, bound(&plan.lower), bound(&plan.upper))
+        });
+    }
+    let mut scanned = row_count as f64 * selectivity;
+    // キーは一意
+    if plan.key_prefix.len() == key_columns.len() {
+        scanned = scanned.min(1.0);
+    }
+
+    let width = |columns: &[usize]| -> f64 {
+        let width: f64 = columns
+            .iter()
+            .map(|&index| column(index).map_or(DEFAULT_WIDTH, |column| column.avg_width))
+            .sum();
+        width + PAIR_OVERHEAD
+    };
+    let pages = |rows: f64, width: f64| {
+        (rows * width / (PAGE_SIZE as f64 * FILL_FACTOR))
+            .ceil()
+            .max(1.0)
+    };
+    let cost = match plan.access_path {
+        AccessPath::SeqScan => {
+            let columns: Vec<_> = (0..schema.columns.len()).collect();
+            pages(scanned, width(&columns)) * SEQ_PAGE_COST + scanned * CPU_ROW_COST
+        }
+        AccessPath::IndexScan(_)
+        | AccessPath::NonUniqueIndexScan(_)
+        | AccessPath::HashIndexScan(_) => {
+            // インデックスのエントリはセカンダリキーと主キーを持つ
+            let mut columns = key_columns.to_vec();
+            columns.extend((0..schema.table.num_key_elems).filter(|c| !key_columns.contains(c)));
+            pages(scanned, width(&columns)) * SEQ_PAGE_COST
+                + scanned * (RANDOM_PAGE_COST + CPU_ROW_COST)
+        }
+    };
+    let rows = match &plan.filter {
+        Some(filter) => (row_count as f64 * cond_selectivity(filter, stats)).min(scanned),
+        None => scanned,
+    };
+    Estimate { rows, cost }
+}
+
+fn bound(bound: &Option<(Value, bool)>) -> Option<(&Value, bool)> {
+    bound.as_ref().map(|(value, inclusive)| (value, *inclusive))
+}
+
+// 条件を満たす行の割合。列の値は互いに独立とみなす
+pub fn cond_selectivity(cond: &Cond, stats: &TableStats) -> f64 {
+    let row_count = stats.row_count;
+    match cond {
+        Cond::And(left, right) => cond_selectivity(left, stats) * cond_selectivity(right, stats),
+        Cond::Or(left, right) => {
+            let (left, right) = (
+                cond_selectivity(left, stats),
+                cond_selectivity(right, stats),
+            );
+            left + right - left * right
+        }
+        Cond::Not(cond) => 1.0 - cond_selectivity(cond, stats),
+        Cond::IsNull(Operand::Column(index), negated) => {
+            let selectivity = stats
+                .columns
+                .get(*index)
+                .map_or(DEFAULT_EQ_SELECTIVITY, |column| {
+                    column.null_selectivity(row_count)
+                });
+            if *negated {
+                1.0 - selectivity
+            } else {
+                selectivity
+            }
+        }
+        Cond::IsNull(Operand::Value(value), negated) => {
+            if value.is_null() != *negated {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        Cond::Compare(..) => {
+            let (index, op, value) = match column_comparison(cond) {
+                Some(comparison) => comparison,
+                None => return DEFAULT_SELECTIVITY,
+            };
+            // NULL との比較は真にならない
+            if value.is_null() {
+                return 0.0;
+            }
+            let column = match stats.columns.get(index) {
+                Some(column) => column,
+                None if op == BinaryOp::Eq => return DEFAULT_EQ_SELECTIVITY,
+                None => return DEFAULT_RANGE_SELECTIVITY,
+            };
+            let range = |lower, upper| column.range_selectivity(row_count, lower, upper);
+            match op {
+                BinaryOp::Eq => column.eq_selectivity(row_count, value),
+                BinaryOp::NotEq => (1.0
+                    - column.null_selectivity(row_count)
+                    - column.eq_selectivity(row_count, value))
+                .max(0.0),
+                BinaryOp::Lt => range(None, Some((value, false))),
+                BinaryOp::LtEq => range(None, Some((value, true))),
+                BinaryOp::Gt => range(Some((value, false)), None),
+                BinaryOp::GtEq => range(Some((value, true)), None),
+                BinaryOp::And | BinaryOp::Or => DEFAULT_SELECTIVITY,
+            }
+        }
+    }
+}
+
+// 2 つの入力の結合条件と、それを満たす組の割合
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JoinEdge {
+    pub left: usize,
+    pub right: usize,
+    pub selectivity: f64,
+}
+
+// 列の等結合を満たす組の割合
+// 異なる値の数が少ない方の値は全て、多い方にも現れるとみなす
+pub fn join_selectivity(left: Option<&ColumnStats>, right: Option<&ColumnStats>) -> f64 {
+    match left
+        .into_iter()
+        .chain(right)
+        .map(|c| c.distinct_count)
+        .max()
+    {
+        Some(0) => 0.0,
+        Some(distinct) => 1.0 / distinct as f64,
+        None => DEFAULT_EQ_SELECTIVITY,
+    }
+}
+
+// i を結合済みの入力の集合 joined に結合する時に、組にかかる割合
+fn edge_selectivity(edges: &[JoinEdge], i: usize, joined: impl Fn(usize) -> bool) -> f64 {
+    edges
+        .iter()
+        .filter(|edge| {
+            (edge.left == i && joined(edge.right)) || (edge.right == i && joined(edge.left))
+        })
+        .map(|edge| edge.selectivity)
+        .product()
+}
+
+// 左深の結合木で入力を結合する順番。rows はそれぞれの入力の行数の見積もり
+// 途中の結合結果の行数の合計が最も小さい順番を、入力の部分集合ごとの動的計画法で探す
+// 入力の数について指数関数的な時間がかかるので、MAX_DP_INPUTS より多ければ貪欲法で決める
+pub fn join_order(rows: &[f64], edges: &[JoinEdge]) -> Vec<usize> {
+    let n = rows.len();
+    if n == 0 {
+        return vec![];
+    }
+    if n > MAX_DP_INPUTS {
+        return greedy_join_order(rows, edges);
+    }
+    // 部分集合ごとに、途中の行数の合計、結合結果の行数と、結合する順番
+    let mut best: Vec<Option<(f64, f64, Vec<usize>)>> = vec![None; 1 << n];
+    for (i, &rows) in rows.iter().enumerate() {
+        best[1 << i] = Some((0.0, rows, vec![i]));
+    }
+    for set in 1..best.len() {
+        if set.count_ones() < 2 {
+            continue;
+        }
+        let mut candidate: Option<(f64, f64, Vec<usize>)> = None;
+        // 最後に i を結合する
+        for i in (0..n).filter(|i| set & (1 << i) != 0) {
+            let rest = set & !(1 << i);
+            let (rest_cost, rest_rows, order) = best[rest].as_ref().unwrap();
+            let selectivity = edge_selectivity(edges, i, |j| rest & (1 << j) != 0);
+            let joined = rest_rows * rows[i] * selectivity;
+            let cost = rest_cost + joined;
+            if candidate
+                .as_ref()
+                .is_none_or(|(best_cost, _, _)| cost < *best_cost)
+            {
+                let mut order = order.clone();
+                order.push(i);
+                candidate = Some((cost, joined, order));
+            }
+        }
+        best[set] = candidate;
+    }
+    best.pop().unwrap().unwrap().2
+}
+
+// 最も小さい入力から始めて、結合結果が最も小さくなる入力を 1 つずつ足していく
+fn greedy_join_order(rows: &[f64], edges: &[JoinEdge]) -> Vec<usize> {
+    let mut joined = vec![false; rows.len()];
+    let mut order: Vec<usize> = vec![];
+    let mut joined_rows = 1.0;
+    while order.len() < rows.len() {
+        let (i, next_rows) = (0..rows.len())
+            .filter(|&i| !joined[i])
+            .map(|i| {
+                let selectivity = if order.is_empty() {
+                    1.0
+                } else {
+                    edge_selectivity(edges, i, |j| joined[j])
+                };
+                (i, joined_rows * rows[i] * selectivity)
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        joined[i] = true;
+        order.push(i);
+        joined_rows = next_rows;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_order() {
+        // 大きな 2 つの表は、小さな表を介してしか結合できない
+        let rows = [100000.0, 10.0, 50000.0];
+        let edges = [
+            JoinEdge {
+                left: 0,
+                right: 1,
+                selectivity: 0.1,
+            },
+            JoinEdge {
+                left: 1,
+                right: 2,
+                selectivity: 0.1,
+            },
+        ];
+        // 途中の結果が小さくなる 1 と 2 を先に結合する
+        let order = join_order(&rows, &edges);
+        assert_eq!(0, order[2]);
+        // 結合条件がなくても、大きな表を最後に回す
+        assert_eq!(0, join_order(&rows, &[])[2]);
+        // 1 と 2 を結合すると、2 と 0 を直積で結合するより小さくなる
+        let edges = [JoinEdge {
+            left: 1,
+            right: 2,
+            selectivity: 0.0001,
+        }];
+        let order = join_order(&[100.0, 1000.0, 1000.0], &edges);
+        assert_eq!(0, order[2]);
+        assert_eq!(vec![0], join_order(&[5.0], &[]));
+        assert!(join_order(&[], &[]).is_empty());
+
+        let column = |distinct_count| ColumnStats {
+            null_count: 0,
+            distinct_count,
+            avg_width: 8.0,
+            histogram: vec![],
+        };
+        assert_eq!(
+            0.01,
+            join_selectivity(Some(&column(10)), Some(&column(100)))
+        );
+        assert_eq!(DEFAULT_EQ_SELECTIVITY, join_selectivity(None, None));
+    }
+
+    #[test]
+    fn test_greedy_join_order() {
+        // 動的計画法では部分集合が多すぎる数の入力を、鎖の形に結合する
+        let n = 70;
+        let rows: Vec<f64> = (0..n).map(|i| 1000.0 + i as f64).collect();
+        let edges: Vec<_> = (1..n)
+            .map(|i| JoinEdge {
+                left: i - 1,
+                right: i,
+                selectivity: 0.001,
+            })
+            .collect();
+        let order = join_order(&rows, &edges);
+        // 最も小さい入力から、結合条件で繋がった入力を順に足す
+        assert_eq!((0..n).collect::<Vec<_>>(), order);
+
+        // 結合条件のない入力は、途中の結果を大きくするので最後に回す
+        let mut rows = vec![1000.0; MAX_DP_INPUTS + 1];
+        rows[0] = 10.0;
+        let edges: Vec<_> = (1..MAX_DP_INPUTS)
+            .map(|i| JoinEdge {
+                left: 0,
+                right: i,
+                selectivity: 0.0001,
+            })
+            .collect();
+        let order = join_order(&rows, &edges);
+        assert_eq!(0, order[0]);
+        assert_eq!(MAX_DP_INPUTS, order[MAX_DP_INPUTS]);
+    }
+}
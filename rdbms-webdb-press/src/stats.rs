@@ -0,0 +1,416 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::iter;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::btree::{BTree, SearchMode};
+use crate::buffer::BufferPoolManager;
+use crate::disk::PageId;
+use crate::mvcc::Snapshot;
+use crate::query;
+use crate::table::Table;
+use crate::tuple;
+use crate::value::Value;
+
+// 統計情報を作るのに読むリーフの数。これより大きなテーブルはランダムに選んだリーフの行で代表する
+pub const SAMPLE_PAGES: usize = 100;
+// ヒストグラムのバケットの数
+pub const NUM_BUCKETS: usize = 100;
+
+// ANALYZE で集めたテーブルの統計情報。カタログに保存し、実行計画のコストの見積もりに使う
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub row_count: u64,
+    // 列の順に並ぶ
+    pub columns: Vec<ColumnStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub null_count: u64,
+    // NULL 以外の異なる値の数
+    pub distinct_count: u64,
+    // エンコードした値の大きさの平均
+    pub avg_width: f64,
+    // 等深ヒストグラムの境界。NULL 以外の値を小さい順に並べ、同じ数ずつのバケットに分ける
+    // 先頭は最小値、末尾は最大値。多く現れる値は複数の境界に現れる
+    pub histogram: Vec<Value>,
+}
+
+impl ColumnStats {
+    fn non_null_fraction(&self, row_count: u64) -> f64 {
+        if row_count == 0 {
+            return 0.0;
+        }
+        1.0 - self.null_count as f64 / row_count as f64
+    }
+
+    pub fn null_selectivity(&self, row_count: u64) -> f64 {
+        if row_count == 0 {
+            return 0.0;
+        }
+        self.null_count as f64 / row_count as f64
+    }
+
+    // 列 = value を満たす行の割合
+    // 2 つ以上の境界に現れる値は多く現れる値としてその分だけあるとし、
+    // 残りの行には、それ以外の異なる値が同じ数ずつあるとする
+    pub fn eq_selectivity(&self, row_count: u64, value: &Value) -> f64 {
+        let (min, max) = match (self.histogram.first(), self.histogram.last()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return 0.0,
+        };
+        if value < min || value > max {
+            return 0.0;
+        }
+        let mut frequent_fraction = 0.0;
+        let mut num_frequent = 0;
+        let mut start = 0;
+        while start < self.histogram.len() {
+            let bound = &self.histogram[start];
+            let bounds = self.histogram[start..]
+                .iter()
+                .take_while(|b| *b == bound)
+                .count();
+            if bounds >= 2 {
+                let fraction = (bounds - 1) as f64 / self.num_buckets();
+                if bound == value {
+                    return fraction * self.non_null_fraction(row_count);
+                }
+                frequent_fraction += fraction;
+                num_frequent += 1;
+            }
+            start += bounds;
+        }
+        let rest = self.distinct_count.saturating_sub(num_frequent).max(1);
+        (1.0 - frequent_fraction).max(0.0) / rest as f64 * self.non_null_fraction(row_count)
+    }
+
+    // 下限と上限の間にある行の割合。bool は境界を含むかどうか
+    pub fn range_selectivity(
+        &self,
+        row_count: u64,
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+    ) -> f64 {
+        let low = lower.map_or(0.0, |(value, inclusive)| {
+            self.fraction_below(value, !inclusive)
+        });
+        let high = upper.map_or(1.0, |(value, inclusive)| {
+            self.fraction_below(value, inclusive)
+        });
+        (high - low).max(0.0) * self.non_null_fraction(row_count)
+    }
+
+    fn num_buckets(&self) -> f64 {
+        self.histogram.len().saturating_sub(1).max(1) as f64
+    }
+
+    // NULL 以外の値のうち、value より小さい (or_equal なら value 以下の) 値の割合
+    // バケットの中では、数値なら線形に補間し、それ以外は半分とする
+    fn fraction_below(&self, value: &Value, or_equal: bool) -> f64 {
+        let bounds = &self.histogram;
+        let k = bounds
+            .iter()
+            .take_while(|&b| if or_equal { b <= value } else { b < value })
+            .count();
+        if k == 0 {
+            return 0.0;
+        }
+        if k == bounds.len() {
+            return 1.0;
+        }
+        let within = match (&bounds[k - 1], &bounds[k], value) {
+            (Value::Int64(low), Value::Int64(high), Value::Int64(v)) => {
+                interpolate(*low as f64, *high as f64, *v as f64)
+            }
+            (Value::Float64(low), Value::Float64(high), Value::Float64(v)) => {
+                interpolate(*low, *high, *v)
+            }
+            _ => 0.5,
+        };
+        ((k - 1) as f64 + within) / self.num_buckets()
+    }
+}
+
+fn interpolate(low: f64, high: f64, value: f64) -> f64 {
+    if high > low {
+        ((value - low) / (high - low)).clamp(0.0, 1.0)
+    } else {
+        0.5
+    }
+}
+
+// 木を SAMPLE_PAGES 個の区間に分け、区間の中ほどの位置に向かって根からリーフまで降りる
+// たどり着いたリーフの行を標本にするので、読むページの数はテーブルの大きさによらない
+// 行数は、リーフの行数に通ったブランチの子の数の積をかけたものの平均で見積もる (Knuth の見積もり)
+// リーフが SAMPLE_PAGES より少なければ全ての行を読む
+// スナップショットがあれば、値は版を並べたものなので、見える行だけを数える
+pub fn analyze(
+    bufmgr: &BufferPoolManager,
+    table: &Table,
+    num_columns: usize,
+    snapshot: Option<&Snapshot>,
+) -> Result<TableStats> {
+    let btree = BTree::new(table.meta_page_id);
+    // リーフごとに、通ったブランチの子の数の積と見える行
+    let mut leaves: HashMap<PageId, (f64, Vec<Vec<Value>>)> = HashMap::new();
+    let (mut total_rows, mut total_leaves, mut descents) = (0.0, 0.0, 0);
+    while descents < SAMPLE_PAGES {
+        // 木の中の位置を 0 以上 1 未満で表し、子の数で分けた区間のうち位置を含む子を選ぶ
+        let mut position = (descents as f64 + 0.5) / SAMPLE_PAGES as f64;
+        let (page_id, weight, pairs) = btree.sample_leaf(bufmgr, |n| {
+            let scaled = position * n as f64;
+            let child = (scaled as usize).min(n - 1);
+            position = scaled - child as f64;
+            child
+        })?;
+        let (_, records) = match leaves.entry(page_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert((weight, visible_records(pairs, snapshot)?)),
+        };
+        total_rows += weight * records.len() as f64;
+        total_leaves += weight;
+        descents += 1;
+        // 根がリーフなら、どう降りても同じ
+        if weight == 1.0 {
+            break;
+        }
+    }
+
+    let (sample, row_count) = if total_leaves / descents as f64 <= SAMPLE_PAGES as f64 {
+        let mut iter = btree.search(bufmgr, SearchMode::Start)?;
+        let mut pairs = vec![];
+        while let Some(pair) = iter.next(bufmgr)? {
+            pairs.push(pair);
+        }
+        let sample = visible_records(pairs, snapshot)?;
+        let row_count = sample.len() as u64;
+        (sample, row_count)
+    } else {
+        let row_count = (total_rows / descents as f64).round() as u64;
+        // 子の少ないブランチの下のリーフほど選ばれやすいので、
+        // 子の数の積が最も大きいリーフに合わせて、他のリーフの行を間引く
+        let max_weight = leaves
+            .values()
+            .map(|(weight, _)| *weight)
+            .fold(1.0, f64::max);
+        let sample = leaves
+            .into_values()
+            .flat_map(|(weight, records)| {
+                let fraction = weight / max_weight;
+                records
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(move |(i, record)| {
+                        let kept = |i: usize| (i as f64 * fraction).floor();
+                        (kept(i + 1) > kept(i)).then_some(record)
+                    })
+            })
+            .collect();
+        (sample, row_count)
+    };
+    let columns = (0..num_columns)
+        .map(|index| {
+            let values = sample.iter().map(|record| record[index].clone()).collect();
+            column_stats(values, row_count)
+        })
+        .collect();
+    Ok(TableStats { row_count, columns })
+}
+
+// B+Tree のペアのうち、スナップショットから見える行の列の値
+fn visible_records(
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    snapshot: Option<&Snapshot>,
+) -> Result<Vec<Vec<Value>>> {
+    let mut records = vec![];
+    for (pkey_bytes, value) in pairs {
+        if let Some(tuple_bytes) = query::visible_data(snapshot, value)? {
+            let mut record = vec![];
+            tuple::decode(&pkey_bytes, &mut record);
+            tuple::decode(&tuple_bytes, &mut record);
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+fn column_stats(values: Vec<Value>, row_count: u64) -> ColumnStats {
+    let sample_size = values.len();
+    if sample_size == 0 {
+        return ColumnStats {
+            null_count: 0,
+            distinct_count: 0,
+            avg_width: 0.0,
+            histogram: vec![],
+        };
+    }
+    let scale = row_count as f64 / sample_size as f64;
+    let mut width = 0;
+    let mut encoded = vec![];
+    for value in &values {
+        encoded.clear();
+        tuple::encode(iter::once(value), &mut encoded);
+        width += encoded.len();
+    }
+    let mut non_null: Vec<_> = values
+        .into_iter()
+        .filter(|value| !value.is_null())
+        .collect();
+    non_null.sort();
+    let null_count = ((sample_size - non_null.len()) as f64 * scale).round() as u64;
+
+    // 標本の中の異なる値の数と、1 回だけ現れた値の数
+    let mut distinct = 0;
+    let mut singletons = 0;
+    let mut start = 0;
+    while start < non_null.len() {
+        let end = start
+            + non_null[start..]
+                .iter()
+                .take_while(|v| **v == non_null[start])
+                .count();
+        distinct += 1;
+        if end - start == 1 {
+            singletons += 1;
+        }
+        start = end;
+    }
+    let distinct_count = if sample_size as u64 == row_count {
+        distinct as u64
+    } else {
+        estimate_distinct(non_null.len(), distinct, singletons, scale)
+    };
+
+    let histogram = match non_null.len() {
+        0 => vec![],
+        n => {
+            let buckets = NUM_BUCKETS.min(n - 1).max(1);
+            (0..=buckets)
+                .map(|i| non_null[i * (n - 1) / buckets].clone())
+                .collect()
+        }
+    };
+    ColumnStats {
+        null_count,
+        distinct_count,
+        avg_width: width as f64 / sample_size as f64,
+        histogram,
+    }
+}
+
+// 標本から表全体の異なる値の数を見積もる (Haas と Stokes の Duj1)
+// 標本で 1 回だけ現れた値が多いほど、標本に現れなかった値も多いとみなす
+fn estimate_distinct(n: usize, distinct: usize, singletons: usize, scale: f64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let (n, d, f1) = (n as f64, distinct as f64, singletons as f64);
+    let total = n * scale;
+    let estimate = n * d / (n - f1 + f1 / scale);
+    estimate.clamp(d, total).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::buffer::{BufferPool, BufferPoolManager};
+    use crate::disk::{DiskManager, PageId};
+
+    #[test]
+    fn test_analyze() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        // (id, id % 10 か NULL, 半分の行は 'hot')
+        let num_rows = 30000i64;
+        for id in 0..num_rows {
+            let modulo = if id % 5 == 0 {
+                Value::Null
+            } else {
+                Value::from(id % 10)
+            };
+            let status = if id % 2 == 0 {
+                "hot".to_string()
+            } else {
+                format!("cold{}", id)
+            };
+            table
+                .insert(&bufmgr, &[id.into(), modulo, status.into()])
+                .unwrap();
+        }
+        let before = bufmgr.stats();
+        let stats = analyze(&bufmgr, &table, 3, None).unwrap();
+        let after = bufmgr.stats();
+        // 全てのリーフではなく、選んだリーフとそこまでのブランチだけを読む
+        let fetches = after.hits + after.misses - before.hits - before.misses;
+        assert!(fetches <= (SAMPLE_PAGES * 4) as u64, "{}", fetches);
+        let rows = stats.row_count;
+        assert!(
+            (rows as f64 / num_rows as f64 - 1.0).abs() < 0.1,
+            "{}",
+            rows
+        );
+        let [id, modulo, status] = <[ColumnStats; 3]>::try_from(stats.columns).unwrap();
+
+        // 全ての値が異なる列は、標本の外にも同じだけ異なる値があるとみなす
+        assert_eq!(0, id.null_count);
+        assert_eq!(rows, id.distinct_count);
+        assert_eq!(NUM_BUCKETS + 1, id.histogram.len());
+        let below_half = id.range_selectivity(rows, None, Some((&Value::from(15000i64), false)));
+        assert!((below_half - 0.5).abs() < 0.05, "{}", below_half);
+        let between = id.range_selectivity(
+            rows,
+            Some((&Value::from(3000i64), true)),
+            Some((&Value::from(6000i64), true)),
+        );
+        assert!((between - 0.1).abs() < 0.02, "{}", between);
+        assert_eq!(0.0, id.eq_selectivity(rows, &Value::from(-1i64)));
+
+        // 2 割が NULL で、残りは 8 種類の値
+        assert!((modulo.null_selectivity(rows) - 0.2).abs() < 0.02);
+        assert_eq!(8, modulo.distinct_count);
+        let eq = modulo.eq_selectivity(rows, &Value::from(3i64));
+        assert!((eq - 0.1).abs() < 0.02, "{}", eq);
+
+        // 多く現れる値は、異なる値の数によらずその分だけあるとみなす
+        let hot = status.eq_selectivity(rows, &"hot".into());
+        assert!((hot - 0.5).abs() < 0.05, "{}", hot);
+        let cold = status.eq_selectivity(rows, &"cold1".into());
+        assert!(cold < 0.001, "{}", cold);
+        // 標本に 1 回だけ現れた値が多いので、標本の異なる値の数より多く見積もる
+        assert!(status.distinct_count > 3000, "{}", status.distinct_count);
+    }
+
+    #[test]
+    fn test_analyze_empty() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        let stats = analyze(&bufmgr, &table, 2, None).unwrap();
+        assert_eq!(0, stats.row_count);
+        assert_eq!(0.0, stats.columns[0].eq_selectivity(0, &Value::from(1i64)));
+        assert_eq!(0.0, stats.columns[1].range_selectivity(0, None, None));
+    }
+}
@@ -1,9 +1,13 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-use crate::btree::BTree;
+use crate::btree::{self, BTree, SearchMode};
 use crate::buffer::BufferPoolManager;
 use crate::disk::PageId;
+use crate::hash_index::HashIndex;
+use crate::mvcc::{Txn, TxnManager, VacuumStats, VersionStore};
 use crate::tuple;
+use crate::value::Value;
 
 #[derive(Debug)]
 pub struct SimpleTable {
@@ -12,13 +16,13 @@ pub struct SimpleTable {
 }
 
 impl SimpleTable {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         Ok(())
     }
 
-    pub fn insert(&self, bufmgr: &mut BufferPoolManager, record: &[&[u8]]) -> Result<()> {
+    pub fn insert(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
         let btree = BTree::new(self.meta_page_id);
         let mut key = vec![];
         tuple::encode(record[..self.num_key_elems].iter(), &mut key);
@@ -28,59 +32,288 @@ impl SimpleTable {
         btree.insert(bufmgr, &key, &value)?;
         Ok(())
     }
+
+    // 主キーが一致する行の内容を書き換える
+    pub fn update(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let (key, value) = encode_record(record, self.num_key_elems);
+        btree.update(bufmgr, &key, &value)?;
+        Ok(())
+    }
+
+    pub fn upsert(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let (key, value) = encode_record(record, self.num_key_elems);
+        btree.upsert(bufmgr, &key, &value)?;
+        Ok(())
+    }
+
+    // 主キーが一致する行を削除する
+    pub fn delete(&self, bufmgr: &BufferPoolManager, pkey: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let mut key = vec![];
+        tuple::encode(pkey.iter(), &mut key);
+        btree.delete(bufmgr, &key)?;
+        Ok(())
+    }
+
+    // テーブルを削除して、使っていたページを解放する
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        BTree::new(self.meta_page_id).destroy(bufmgr)?;
+        Ok(())
+    }
+}
+
+fn encode_record(record: &[Value], num_key_elems: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut key = vec![];
+    tuple::encode(record[..num_key_elems].iter(), &mut key);
+    let mut value = vec![];
+    tuple::encode(record[num_key_elems..].iter(), &mut value);
+    (key, value)
+}
+
+// 主キーに対応する行を取り出す
+fn fetch_record(
+    bufmgr: &BufferPoolManager,
+    btree: &BTree,
+    key: &[u8],
+) -> Result<Option<Vec<Value>>> {
+    let mut iter = btree.search(bufmgr, SearchMode::Key(key.to_vec()))?;
+    match iter.next(bufmgr)? {
+        Some((pkey_bytes, tuple_bytes)) if pkey_bytes == key => {
+            let mut record = vec![];
+            tuple::decode(&pkey_bytes, &mut record);
+            tuple::decode(&tuple_bytes, &mut record);
+            Ok(Some(record))
+        }
+        _ => Ok(None),
+    }
 }
 
 // セカンダリインデックス用のテーブル
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct UniqueIndex {
     pub meta_page_id: PageId, // セカンダリインデックス用のテーブルの内容が入っているB+TreeのメタページのID
     pub skey: Vec<usize>,     // セカンダリキーに含める列を指定するフィールド
 }
 
 impl UniqueIndex {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         Ok(())
     }
 
-    pub fn insert(
-        &self,
-        bufmgr: &mut BufferPoolManager,
-        pkey: &[u8],
-        record: &[impl AsRef<[u8]>],
-    ) -> Result<()> {
+    pub fn insert(&self, bufmgr: &BufferPoolManager, pkey: &[u8], record: &[Value]) -> Result<()> {
         let btree = BTree::new(self.meta_page_id);
+        let skey = self.encode_skey(record);
+        btree.insert(bufmgr, &skey, pkey)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let skey = self.encode_skey(record);
+        btree.delete(bufmgr, &skey)?;
+        Ok(())
+    }
+
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        BTree::new(self.meta_page_id).destroy(bufmgr)?;
+        Ok(())
+    }
+
+    // セカンダリキーのエンコード
+    pub(crate) fn encode_skey(&self, record: &[Value]) -> Vec<u8> {
         let mut skey = vec![];
+        tuple::encode(self.skey.iter().map(|&index| &record[index]), &mut skey);
+        skey
+    }
+}
 
-        // セカンダリキーのエンコード
-        tuple::encode(
-            self.skey.iter().map(|&index| record[index].as_ref()),
-            &mut skey,
-        );
-        btree.insert(bufmgr, &skey, pkey)?;
+// 一意でないセカンダリインデックス
+// キーはセカンダリキーの後ろに主キーを付けたものなので、セカンダリキーが同じ行があっても重複しない
+// 値には UniqueIndex と同じく主キーを入れるので、IndexScan でそのまま読める
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NonUniqueIndex {
+    pub meta_page_id: PageId,
+    pub skey: Vec<usize>,
+}
+
+impl NonUniqueIndex {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
+        let btree = BTree::create(bufmgr)?;
+        self.meta_page_id = btree.meta_page_id;
+        Ok(())
+    }
+
+    pub fn insert(&self, bufmgr: &BufferPoolManager, pkey: &[u8], record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let key = self.encode_key(pkey, record);
+        btree.insert(bufmgr, &key, pkey)?;
         Ok(())
     }
+
+    pub fn delete(&self, bufmgr: &BufferPoolManager, pkey: &[u8], record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let key = self.encode_key(pkey, record);
+        btree.delete(bufmgr, &key)?;
+        Ok(())
+    }
+
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        BTree::new(self.meta_page_id).destroy(bufmgr)?;
+        Ok(())
+    }
+
+    // エンコードした値をつなげたものは、値を並べたタプルをエンコードしたものと同じになる
+    // そのため、セカンダリキーの値を先頭に持つ TupleRange で全てのエントリを引ける
+    pub(crate) fn encode_key(&self, pkey: &[u8], record: &[Value]) -> Vec<u8> {
+        let mut key = vec![];
+        tuple::encode(self.skey.iter().map(|&index| &record[index]), &mut key);
+        key.extend_from_slice(pkey);
+        key
+    }
 }
 
-#[derive(Debug)]
+// ハッシュインデックスによるユニークインデックス。等号でしか引けない
+// キーはセカンダリキーで、値には UniqueIndex と同じく主キーを入れる
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UniqueHashIndex {
+    pub meta_page_id: PageId,
+    pub skey: Vec<usize>,
+}
+
+impl UniqueHashIndex {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
+        let index = HashIndex::create(bufmgr)?;
+        self.meta_page_id = index.meta_page_id;
+        Ok(())
+    }
+
+    pub fn insert(&self, bufmgr: &BufferPoolManager, pkey: &[u8], record: &[Value]) -> Result<()> {
+        let index = HashIndex::new(self.meta_page_id);
+        index.insert(bufmgr, &self.encode_skey(record), pkey)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let index = HashIndex::new(self.meta_page_id);
+        index.delete(bufmgr, &self.encode_skey(record))?;
+        Ok(())
+    }
+
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        HashIndex::new(self.meta_page_id).destroy(bufmgr)?;
+        Ok(())
+    }
+
+    pub(crate) fn encode_skey(&self, record: &[Value]) -> Vec<u8> {
+        let mut skey = vec![];
+        tuple::encode(self.skey.iter().map(|&index| &record[index]), &mut skey);
+        skey
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Table {
     pub meta_page_id: PageId, // テーブルの内容が入っているB+TreeのメタページのID
     pub num_key_elems: usize, // 主キーの位置
     pub unique_indices: Vec<UniqueIndex>,
+    pub non_unique_indices: Vec<NonUniqueIndex>,
+    pub hash_indices: Vec<UniqueHashIndex>,
 }
 
 impl Table {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         for unique_index in &mut self.unique_indices {
             unique_index.create(bufmgr)?;
         }
+        for non_unique_index in &mut self.non_unique_indices {
+            non_unique_index.create(bufmgr)?;
+        }
+        for hash_index in &mut self.hash_indices {
+            hash_index.create(bufmgr)?;
+        }
+        Ok(())
+    }
+
+    // 主キーの昇順に並んだ行から、create の代わりにテーブルとインデックスをまとめて作る
+    // 行を一度読む間にテーブルの B+Tree を作り、インデックスのエントリは集めてからキーの順に並べ替えて作る
+    // ハッシュインデックスには順序がないので、集めたエントリを 1 つずつ挿入する
+    pub fn bulk_load(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        records: impl IntoIterator<Item = Vec<Value>>,
+        fill_factor: f64,
+    ) -> Result<()> {
+        let num_key_elems = self.num_key_elems;
+        let unique_indices = &self.unique_indices;
+        let non_unique_indices = &self.non_unique_indices;
+        let hash_indices = &self.hash_indices;
+        let mut unique_entries = vec![vec![]; unique_indices.len()];
+        let mut non_unique_entries = vec![vec![]; non_unique_indices.len()];
+        let mut hash_entries = vec![vec![]; hash_indices.len()];
+        let pairs = records.into_iter().map(|record| {
+            let (key, value) = encode_record(&record, num_key_elems);
+            for (unique_index, entries) in unique_indices.iter().zip(&mut unique_entries) {
+                entries.push((unique_index.encode_skey(&record), key.clone()));
+            }
+            for (non_unique_index, entries) in
+                non_unique_indices.iter().zip(&mut non_unique_entries)
+            {
+                entries.push((non_unique_index.encode_key(&key, &record), key.clone()));
+            }
+            for (hash_index, entries) in hash_indices.iter().zip(&mut hash_entries) {
+                entries.push((hash_index.encode_skey(&record), key.clone()));
+            }
+            (key, value)
+        });
+        let mut btrees = vec![BTree::bulk_load(bufmgr, pairs, fill_factor)?];
+        let mut hashes = vec![];
+        // セカンダリキーが重複していたら、作った木とハッシュインデックスを全て捨てる
+        let result = (|| -> Result<()> {
+            for mut entries in unique_entries.into_iter().chain(non_unique_entries) {
+                entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                btrees.push(BTree::bulk_load(bufmgr, entries, fill_factor)?);
+            }
+            for entries in hash_entries {
+                let index = HashIndex::create(bufmgr)?;
+                let meta_page_id = index.meta_page_id;
+                hashes.push(meta_page_id);
+                for (skey, pkey) in entries {
+                    index.insert(bufmgr, &skey, &pkey)?;
+                }
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            for btree in btrees {
+                btree.destroy(bufmgr)?;
+            }
+            for meta_page_id in hashes {
+                HashIndex::new(meta_page_id).destroy(bufmgr)?;
+            }
+            return Err(err);
+        }
+
+        let mut meta_page_ids = btrees.into_iter().map(|btree| btree.meta_page_id);
+        self.meta_page_id = meta_page_ids.next().unwrap();
+        for unique_index in &mut self.unique_indices {
+            unique_index.meta_page_id = meta_page_ids.next().unwrap();
+        }
+        for non_unique_index in &mut self.non_unique_indices {
+            non_unique_index.meta_page_id = meta_page_ids.next().unwrap();
+        }
+        for (hash_index, meta_page_id) in self.hash_indices.iter_mut().zip(hashes) {
+            hash_index.meta_page_id = meta_page_id;
+        }
         Ok(())
     }
 
-    pub fn insert(&self, bufmgr: &mut BufferPoolManager, record: &[&[u8]]) -> Result<()> {
+    pub fn insert(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
         let btree = BTree::new(self.meta_page_id);
         let mut key = vec![];
         tuple::encode(record[..self.num_key_elems].iter(), &mut key);
@@ -92,6 +325,480 @@ impl Table {
         for unique_index in &self.unique_indices {
             unique_index.insert(bufmgr, &key, record)?;
         }
+        for non_unique_index in &self.non_unique_indices {
+            non_unique_index.insert(bufmgr, &key, record)?;
+        }
+        for hash_index in &self.hash_indices {
+            hash_index.insert(bufmgr, &key, record)?;
+        }
+        Ok(())
+    }
+
+    // 主キーが一致する行を書き換え、セカンダリキーが変わったインデックスを付け替える
+    pub fn update(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let (key, value) = encode_record(record, self.num_key_elems);
+        let old_record = fetch_record(bufmgr, &btree, &key)?.ok_or(btree::Error::KeyNotFound)?;
+
+        // 途中で失敗してインデックスが食い違わないように、先に重複を調べておく
+        let mut changed_indices = vec![];
+        for unique_index in &self.unique_indices {
+            let old_skey = unique_index.encode_skey(&old_record);
+            let new_skey = unique_index.encode_skey(record);
+            if old_skey == new_skey {
+                continue;
+            }
+            let index_btree = BTree::new(unique_index.meta_page_id);
+            let mut iter = index_btree.search(bufmgr, SearchMode::Key(new_skey.clone()))?;
+            if matches!(iter.next(bufmgr)?, Some((skey, _)) if skey == new_skey) {
+                return Err(btree::Error::DuplicateKey.into());
+            }
+            changed_indices.push(unique_index);
+        }
+        let mut changed_hash_indices = vec![];
+        for hash_index in &self.hash_indices {
+            let old_skey = hash_index.encode_skey(&old_record);
+            let new_skey = hash_index.encode_skey(record);
+            if old_skey == new_skey {
+                continue;
+            }
+            let index = HashIndex::new(hash_index.meta_page_id);
+            if index.lookup(bufmgr, &new_skey)?.is_some() {
+                return Err(btree::Error::DuplicateKey.into());
+            }
+            changed_hash_indices.push(hash_index);
+        }
+
+        btree.update(bufmgr, &key, &value)?;
+        for unique_index in changed_indices {
+            unique_index.delete(bufmgr, &old_record)?;
+            unique_index.insert(bufmgr, &key, record)?;
+        }
+        for hash_index in changed_hash_indices {
+            hash_index.delete(bufmgr, &old_record)?;
+            hash_index.insert(bufmgr, &key, record)?;
+        }
+        for non_unique_index in &self.non_unique_indices {
+            if non_unique_index.encode_key(&key, &old_record)
+                != non_unique_index.encode_key(&key, record)
+            {
+                non_unique_index.delete(bufmgr, &key, &old_record)?;
+                non_unique_index.insert(bufmgr, &key, record)?;
+            }
+        }
         Ok(())
     }
+
+    pub fn upsert(&self, bufmgr: &BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let (key, _) = encode_record(record, self.num_key_elems);
+        if fetch_record(bufmgr, &btree, &key)?.is_some() {
+            self.update(bufmgr, record)
+        } else {
+            self.insert(bufmgr, record)
+        }
+    }
+
+    // 主キーが一致する行と、それを指すインデックスのエントリを削除する
+    pub fn delete(&self, bufmgr: &BufferPoolManager, pkey: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let mut key = vec![];
+        tuple::encode(pkey.iter(), &mut key);
+        let old_record = fetch_record(bufmgr, &btree, &key)?.ok_or(btree::Error::KeyNotFound)?;
+        btree.delete(bufmgr, &key)?;
+        for unique_index in &self.unique_indices {
+            unique_index.delete(bufmgr, &old_record)?;
+        }
+        for non_unique_index in &self.non_unique_indices {
+            non_unique_index.delete(bufmgr, &key, &old_record)?;
+        }
+        for hash_index in &self.hash_indices {
+            hash_index.delete(bufmgr, &old_record)?;
+        }
+        Ok(())
+    }
+
+    // テーブルとセカンダリインデックスを削除して、使っていたページを解放する
+    pub fn destroy(&self, bufmgr: &BufferPoolManager) -> Result<()> {
+        BTree::new(self.meta_page_id).destroy(bufmgr)?;
+        for unique_index in &self.unique_indices {
+            unique_index.destroy(bufmgr)?;
+        }
+        for non_unique_index in &self.non_unique_indices {
+            non_unique_index.destroy(bufmgr)?;
+        }
+        for hash_index in &self.hash_indices {
+            hash_index.destroy(bufmgr)?;
+        }
+        Ok(())
+    }
+}
+
+// MVCC で版を付けて書く操作
+// テーブルとインデックス (ハッシュインデックスも) の値には mvcc::Version を並べたものが入るので、
+// insert などと混ぜて使ってはいけない。インデックスのエントリにも版を付け、値は主キーになる
+// 途中で失敗したら、書きかけの版が残らないようにトランザクションを abort する
+impl Table {
+    pub fn insert_version(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &Txn,
+        record: &[Value],
+    ) -> Result<()> {
+        let (key, value) = encode_record(record, self.num_key_elems);
+        txn.insert(bufmgr, &BTree::new(self.meta_page_id), &key, value)?;
+        for unique_index in &self.unique_indices {
+            let index_btree = BTree::new(unique_index.meta_page_id);
+            txn.insert(
+                bufmgr,
+                &index_btree,
+                &unique_index.encode_skey(record),
+                key.clone(),
+            )?;
+        }
+        for non_unique_index in &self.non_unique_indices {
+            let index_btree = BTree::new(non_unique_index.meta_page_id);
+            let index_key = non_unique_index.encode_key(&key, record);
+            txn.insert(bufmgr, &index_btree, &index_key, key.clone())?;
+        }
+        for hash_index in &self.hash_indices {
+            let index = HashIndex::new(hash_index.meta_page_id);
+            txn.insert(bufmgr, &index, &hash_index.encode_skey(record), key.clone())?;
+        }
+        Ok(())
+    }
+
+    // 新しい版を作り、セカンダリキーが変わったインデックスのエントリを付け替える
+    pub fn update_version(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &Txn,
+        record: &[Value],
+    ) -> Result<()> {
+        let (key, value) = encode_record(record, self.num_key_elems);
+        let old_value = txn.update(bufmgr, &BTree::new(self.meta_page_id), &key, value)?;
+        let mut old_record = vec![];
+        tuple::decode(&key, &mut old_record);
+        tuple::decode(&old_value, &mut old_record);
+        for unique_index in &self.unique_indices {
+            let old_skey = unique_index.encode_skey(&old_record);
+            let new_skey = unique_index.encode_skey(record);
+            if old_skey != new_skey {
+                let index_btree = BTree::new(unique_index.meta_page_id);
+                txn.delete(bufmgr, &index_btree, &old_skey)?;
+                txn.insert(bufmgr, &index_btree, &new_skey, key.clone())?;
+            }
+        }
+        for non_unique_index in &self.non_unique_indices {
+            let old_key = non_unique_index.encode_key(&key, &old_record);
+            let new_key = non_unique_index.encode_key(&key, record);
+            if old_key != new_key {
+                let index_btree = BTree::new(non_unique_index.meta_page_id);
+                txn.delete(bufmgr, &index_btree, &old_key)?;
+                txn.insert(bufmgr, &index_btree, &new_key, key.clone())?;
+            }
+        }
+        for hash_index in &self.hash_indices {
+            let old_skey = hash_index.encode_skey(&old_record);
+            let new_skey = hash_index.encode_skey(record);
+            if old_skey != new_skey {
+                let index = HashIndex::new(hash_index.meta_page_id);
+                txn.delete(bufmgr, &index, &old_skey)?;
+                txn.insert(bufmgr, &index, &new_skey, key.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_version(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &Txn,
+        pkey: &[Value],
+    ) -> Result<()> {
+        let mut key = vec![];
+        tuple::encode(pkey.iter(), &mut key);
+        let old_value = txn.delete(bufmgr, &BTree::new(self.meta_page_id), &key)?;
+        let mut old_record = vec![];
+        tuple::decode(&key, &mut old_record);
+        tuple::decode(&old_value, &mut old_record);
+        for unique_index in &self.unique_indices {
+            let index_btree = BTree::new(unique_index.meta_page_id);
+            txn.delete(bufmgr, &index_btree, &unique_index.encode_skey(&old_record))?;
+        }
+        for non_unique_index in &self.non_unique_indices {
+            let index_btree = BTree::new(non_unique_index.meta_page_id);
+            let index_key = non_unique_index.encode_key(&key, &old_record);
+            txn.delete(bufmgr, &index_btree, &index_key)?;
+        }
+        for hash_index in &self.hash_indices {
+            let index = HashIndex::new(hash_index.meta_page_id);
+            txn.delete(bufmgr, &index, &hash_index.encode_skey(&old_record))?;
+        }
+        Ok(())
+    }
+
+    // 版を付けて書くテーブルとインデックスの索引
+    pub fn version_stores(&self) -> Vec<Box<dyn VersionStore>> {
+        let mut stores: Vec<Box<dyn VersionStore>> = vec![Box::new(BTree::new(self.meta_page_id))];
+        for unique_index in &self.unique_indices {
+            stores.push(Box::new(BTree::new(unique_index.meta_page_id)));
+        }
+        for non_unique_index in &self.non_unique_indices {
+            stores.push(Box::new(BTree::new(non_unique_index.meta_page_id)));
+        }
+        for hash_index in &self.hash_indices {
+            stores.push(Box::new(HashIndex::new(hash_index.meta_page_id)));
+        }
+        stores
+    }
+
+    // テーブルとインデックスから、どのスナップショットからも見えない版を取り除く
+    pub fn vacuum(&self, bufmgr: &BufferPoolManager, txns: &TxnManager) -> Result<VacuumStats> {
+        let mut stats = VacuumStats::default();
+        for store in self.version_stores() {
+            stats += txns.vacuum(bufmgr, store.as_ref())?;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+
+    fn text(elems: &[&str]) -> Vec<Value> {
+        elems.iter().map(|&elem| elem.into()).collect()
+    }
+
+    #[test]
+    fn test_update() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &text(&["z", "Alice", "Smith"]))
+            .unwrap();
+        table
+            .insert(&bufmgr, &text(&["x", "Bob", "Johnson"]))
+            .unwrap();
+
+        // セカンダリキーが重複する更新は失敗し、何も変わらない
+        assert!(table
+            .update(&bufmgr, &text(&["z", "Alice", "Johnson"]))
+            .is_err());
+        table
+            .update(&bufmgr, &text(&["z", "Alice", "Williams"]))
+            .unwrap();
+        table
+            .upsert(&bufmgr, &text(&["y", "Eve", "Smith"]))
+            .unwrap();
+
+        let btree = BTree::new(table.meta_page_id);
+        let mut key = vec![];
+        tuple::encode(text(&["z"]).iter(), &mut key);
+        let record = fetch_record(&bufmgr, &btree, &key).unwrap().unwrap();
+        assert_eq!(text(&["z", "Alice", "Williams"]), record);
+
+        let index_btree = BTree::new(table.unique_indices[0].meta_page_id);
+        let mut iter = index_btree.search(&bufmgr, SearchMode::Start).unwrap();
+        let mut entries = vec![];
+        while let Some((skey_bytes, pkey_bytes)) = iter.next(&bufmgr).unwrap() {
+            let mut entry = vec![];
+            tuple::decode(&skey_bytes, &mut entry);
+            tuple::decode(&pkey_bytes, &mut entry);
+            entries.push(entry);
+        }
+        assert_eq!(
+            vec![
+                text(&["Johnson", "x"]),
+                text(&["Smith", "y"]),
+                text(&["Williams", "z"]),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_non_unique_index() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![NonUniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            hash_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        table.insert(&bufmgr, &text(&["a", "open", "x"])).unwrap();
+        table.insert(&bufmgr, &text(&["b", "closed", "y"])).unwrap();
+        table.insert(&bufmgr, &text(&["c", "open", "z"])).unwrap();
+        table.update(&bufmgr, &text(&["b", "open", "y"])).unwrap();
+        table.update(&bufmgr, &text(&["b", "open", "w"])).unwrap();
+        table.delete(&bufmgr, &text(&["a"])).unwrap();
+
+        let index_btree = BTree::new(table.non_unique_indices[0].meta_page_id);
+        let mut iter = index_btree.search(&bufmgr, SearchMode::Start).unwrap();
+        let mut entries = vec![];
+        while let Some((key_bytes, pkey_bytes)) = iter.next(&bufmgr).unwrap() {
+            let mut entry = vec![];
+            tuple::decode(&key_bytes, &mut entry);
+            tuple::decode(&pkey_bytes, &mut entry);
+            entries.push(entry);
+        }
+        // キーはセカンダリキーと主キー、値は主キー
+        assert_eq!(
+            vec![text(&["open", "b", "b"]), text(&["open", "c", "c"])],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let new_table = || Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+            non_unique_indices: vec![NonUniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![1],
+            }],
+            hash_indices: vec![UniqueHashIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+        };
+        let records = || {
+            (0..1000).map(|i| {
+                let id = format!("{:04}", i);
+                let status = if i % 3 == 0 { "open" } else { "done" };
+                let name = format!("name{}", 999 - i);
+                text(&[&id, status, &name])
+            })
+        };
+        let mut table = new_table();
+        table.bulk_load(&bufmgr, records(), 1.0).unwrap();
+
+        let scan = |meta_page_id| {
+            let btree = BTree::new(meta_page_id);
+            let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+            let mut pkeys = vec![];
+            while let Some((_, value)) = iter.next(&bufmgr).unwrap() {
+                pkeys.push(value);
+            }
+            pkeys
+        };
+        assert_eq!(1000, scan(table.meta_page_id).len());
+        // セカンダリキーの順に並ぶ
+        let pkeys = scan(table.unique_indices[0].meta_page_id);
+        let mut key = vec![];
+        tuple::encode(text(&["0999"]).iter(), &mut key);
+        assert_eq!(key, pkeys[0]);
+        let pkeys = scan(table.non_unique_indices[0].meta_page_id);
+        assert_eq!(1000, pkeys.len());
+        let mut key = vec![];
+        tuple::encode(text(&["0001"]).iter(), &mut key);
+        assert_eq!(key, pkeys[0]);
+        let hash_index = &table.hash_indices[0];
+        let index = HashIndex::new(hash_index.meta_page_id);
+        let skey = hash_index.encode_skey(&text(&["", "", "name999"]));
+        let mut key = vec![];
+        tuple::encode(text(&["0000"]).iter(), &mut key);
+        assert_eq!(Some(key), index.lookup(&bufmgr, &skey).unwrap());
+
+        // 作った後は普通のテーブルとして使える
+        assert!(table
+            .insert(&bufmgr, &text(&["1000", "open", "name0"]))
+            .is_err());
+        table
+            .update(&bufmgr, &text(&["0001", "open", "name998"]))
+            .unwrap();
+        assert_eq!(1000, scan(table.non_unique_indices[0].meta_page_id).len());
+
+        // セカンダリキーが重複していれば失敗する
+        let mut table = new_table();
+        let duplicated = records().chain(std::iter::once(text(&["1000", "open", "name0"])));
+        assert!(table.bulk_load(&bufmgr, duplicated, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![UniqueHashIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+        };
+        table.create(&bufmgr).unwrap();
+        let hash_index = &table.hash_indices[0];
+        let index = HashIndex::new(hash_index.meta_page_id);
+        // last_name から引いた主キー
+        let lookup = |last_name: &str| {
+            let skey = hash_index.encode_skey(&text(&["", "", last_name]));
+            index.lookup(&bufmgr, &skey).unwrap().map(|pkey_bytes| {
+                let mut pkey = vec![];
+                tuple::decode(&pkey_bytes, &mut pkey);
+                pkey
+            })
+        };
+
+        table
+            .insert(&bufmgr, &text(&["z", "Alice", "Smith"]))
+            .unwrap();
+        table
+            .insert(&bufmgr, &text(&["x", "Bob", "Johnson"]))
+            .unwrap();
+        assert_eq!(Some(text(&["z"])), lookup("Smith"));
+        assert!(table
+            .insert(&bufmgr, &text(&["y", "Eve", "Smith"]))
+            .is_err());
+
+        // セカンダリキーが重複する更新は失敗し、エントリは変わらない
+        assert!(table
+            .update(&bufmgr, &text(&["z", "Alice", "Johnson"]))
+            .is_err());
+        assert_eq!(Some(text(&["x"])), lookup("Johnson"));
+        table
+            .update(&bufmgr, &text(&["z", "Alice", "Williams"]))
+            .unwrap();
+        assert_eq!(None, lookup("Smith"));
+        assert_eq!(Some(text(&["z"])), lookup("Williams"));
+
+        table.delete(&bufmgr, &text(&["x"])).unwrap();
+        assert_eq!(None, lookup("Johnson"));
+        table.destroy(&bufmgr).unwrap();
+    }
 }
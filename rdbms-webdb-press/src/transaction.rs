@@ -0,0 +1,470 @@
+use std::marker::PhantomData;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::btree::{self, BTree};
+use crate::buffer::BufferPoolManager;
+use crate::disk::PageId;
+use crate::hash_index::HashIndex;
+
+// トランザクション中の変更を取り消すための記録
+// B+Tree とハッシュインデックスへの論理的な操作の逆を覚えておく
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UndoRecord {
+    // 挿入したキーを削除する
+    Insert {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+    },
+    // 書き換える前の値に戻す
+    Update {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // 削除したペアを挿入し直す
+    Delete {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // ハッシュインデックスに挿入したキーを削除する
+    HashInsert {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+    },
+    // ハッシュインデックスから削除したペアを挿入し直す
+    HashDelete {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+impl UndoRecord {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::options().serialize(self).unwrap()
+    }
+
+    // ログに残したものを読むので、読めなければログが壊れている
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::options()
+            .deserialize(bytes)
+            .expect("undo record is corrupted")
+    }
+
+    fn undo(self, bufmgr: &BufferPoolManager) -> Result<(), btree::Error> {
+        match self {
+            UndoRecord::Insert { meta_page_id, key } => {
+                BTree::new(meta_page_id).delete(bufmgr, &key)?;
+                Ok(())
+            }
+            UndoRecord::Update {
+                meta_page_id,
+                key,
+                value,
+            } => BTree::new(meta_page_id).update(bufmgr, &key, &value),
+            UndoRecord::Delete {
+                meta_page_id,
+                key,
+                value,
+            } => BTree::new(meta_page_id).insert(bufmgr, &key, &value),
+            UndoRecord::HashInsert { meta_page_id, key } => {
+                Ok(HashIndex::new(meta_page_id).delete(bufmgr, &key)?)
+            }
+            UndoRecord::HashDelete {
+                meta_page_id,
+                key,
+                value,
+            } => Ok(HashIndex::new(meta_page_id).insert(bufmgr, &key, &value)?),
+        }
+    }
+}
+
+// begin から commit または rollback までの BTree (と Table) への挿入・更新・削除をひとまとまりにする
+// 操作ごとにミニトランザクションとしてログに残してラッチを外し、取り消すための UNDO レコードだけをコミットまで持っておく
+// UNDO レコードもログに残すので、終わらないまま落ちたトランザクションは次に開いた時に取り消される
+// 書き換えたキーは終わるまでロックしておき、他のスレッドからの書き換えは buffer::Error::KeyLocked で断る
+// そのため、ロールバックで他の変更を上書きすることはない
+// commit も rollback もせずに手放すとロールバックする
+// 状態はスレッドごとに管理しているので、begin したスレッドで commit または rollback する
+#[must_use = "a transaction must be committed or rolled back"]
+pub struct Transaction<'a> {
+    bufmgr: &'a BufferPoolManager,
+    finished: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a> Transaction<'a> {
+    // このスレッドで既にトランザクションを実行中ならエラーになる
+    pub fn begin(bufmgr: &'a BufferPoolManager) -> Result<Self, btree::Error> {
+        bufmgr.begin_transaction()?;
+        Ok(Self {
+            bufmgr,
+            finished: false,
+            _not_send: PhantomData,
+        })
+    }
+
+    // 終わったことをログに書き、ここまでの変更と一緒に永続化する
+    pub fn commit(mut self) -> Result<(), btree::Error> {
+        self.finished = true;
+        self.bufmgr.end_transaction()?;
+        Ok(())
+    }
+
+    // begin した時点の状態に戻す
+    pub fn rollback(mut self) -> Result<(), btree::Error> {
+        self.finished = true;
+        rollback_current(self.bufmgr)
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            // 取り消せなかった分は、次に開いた時にログから取り消す
+            let _ = rollback_current(self.bufmgr);
+        }
+    }
+}
+
+// このスレッドのトランザクションを取り消して終える
+pub(crate) fn rollback_current(bufmgr: &BufferPoolManager) -> Result<(), btree::Error> {
+    bufmgr.begin_rollback();
+    match undo_all(bufmgr) {
+        Ok(()) => Ok(bufmgr.end_transaction()?),
+        Err(err) => {
+            bufmgr.abandon_transaction();
+            Err(err)
+        }
+    }
+}
+
+// UNDO レコードを新しい方から適用する
+// 1つ取り消すごとにそのことを同じミニトランザクションでログに残すので、途中で落ちても続きから取り消せる
+fn undo_all(bufmgr: &BufferPoolManager) -> Result<(), btree::Error> {
+    while let Some(record) = bufmgr.last_undo() {
+        bufmgr.with_mtr(|bufmgr| -> Result<(), btree::Error> {
+            record.undo(bufmgr)?;
+            bufmgr.compensate();
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::btree::SearchMode;
+    use crate::buffer::{self, BufferPool};
+    use crate::disk::DiskManager;
+    use crate::table::{Table, UniqueHashIndex, UniqueIndex};
+    use crate::tuple;
+    use crate::value::Value;
+
+    fn text(elems: &[&str]) -> Vec<Value> {
+        elems.iter().map(|&elem| elem.into()).collect()
+    }
+
+    fn scan(bufmgr: &BufferPoolManager, meta_page_id: PageId) -> Vec<Vec<Value>> {
+        let btree = BTree::new(meta_page_id);
+        let mut iter = btree.search(bufmgr, SearchMode::Start).unwrap();
+        let mut records = vec![];
+        while let Some((key, value)) = iter.next(bufmgr).unwrap() {
+            let mut record = vec![];
+            tuple::decode(&key, &mut record);
+            tuple::decode(&value, &mut record);
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("table.rly");
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+            non_unique_indices: vec![],
+            hash_indices: vec![],
+        };
+        let (rows, entries) = {
+            let disk = DiskManager::open(&path).unwrap();
+            let pool = BufferPool::new(10);
+            let bufmgr = BufferPoolManager::new(disk, pool);
+            table.create(&bufmgr).unwrap();
+
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .insert(&bufmgr, &text(&["z", "Alice", "Smith"]))
+                .unwrap();
+            table
+                .insert(&bufmgr, &text(&["x", "Bob", "Johnson"]))
+                .unwrap();
+            txn.commit().unwrap();
+            let rows = scan(&bufmgr, table.meta_page_id);
+            let entries = scan(&bufmgr, table.unique_indices[0].meta_page_id);
+
+            // 行の挿入には成功しても、インデックスが重複したらまとめて取り消す
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .update(&bufmgr, &text(&["z", "Alice", "Williams"]))
+                .unwrap();
+            table.delete(&bufmgr, &text(&["x"])).unwrap();
+            assert!(table
+                .insert(&bufmgr, &text(&["y", "Charlie", "Williams"]))
+                .is_err());
+            txn.rollback().unwrap();
+            assert_eq!(rows, scan(&bufmgr, table.meta_page_id));
+            assert_eq!(entries, scan(&bufmgr, table.unique_indices[0].meta_page_id));
+
+            // コミットしないまま落ちる
+            // 変更したページもログも書き出してあるので、UNDO レコードを使って取り消すしかない
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .insert(&bufmgr, &text(&["w", "Dave", "Miller"]))
+                .unwrap();
+            bufmgr.flush().unwrap();
+            std::mem::forget(txn);
+            (rows, entries)
+        };
+
+        // コミットしたものだけがログから復旧される
+        let disk = DiskManager::open(&path).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        assert_eq!(rows, scan(&bufmgr, table.meta_page_id));
+        assert_eq!(entries, scan(&bufmgr, table.unique_indices[0].meta_page_id));
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("table.rly");
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+            non_unique_indices: vec![],
+            hash_indices: vec![UniqueHashIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+        };
+        // 各 last_name をハッシュインデックスで引いた結果
+        let lookup = |bufmgr: &BufferPoolManager, table: &Table| -> Vec<Option<Vec<u8>>> {
+            let hash_index = &table.hash_indices[0];
+            let index = HashIndex::new(hash_index.meta_page_id);
+            ["Smith", "Johnson", "Williams", "Miller"]
+                .iter()
+                .map(|&last_name| {
+                    let skey = hash_index.encode_skey(&text(&["", "", last_name]));
+                    index.lookup(bufmgr, &skey).unwrap()
+                })
+                .collect()
+        };
+        let entries = {
+            let disk = DiskManager::open(&path).unwrap();
+            let pool = BufferPool::new(10);
+            let bufmgr = BufferPoolManager::new(disk, pool);
+            table.create(&bufmgr).unwrap();
+
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .insert(&bufmgr, &text(&["z", "Alice", "Smith"]))
+                .unwrap();
+            table
+                .insert(&bufmgr, &text(&["x", "Bob", "Johnson"]))
+                .unwrap();
+            txn.commit().unwrap();
+            let entries = lookup(&bufmgr, &table);
+
+            // ハッシュインデックスのエントリの付け替えと削除も取り消す
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .update(&bufmgr, &text(&["z", "Alice", "Williams"]))
+                .unwrap();
+            table.delete(&bufmgr, &text(&["x"])).unwrap();
+            txn.rollback().unwrap();
+            assert_eq!(entries, lookup(&bufmgr, &table));
+
+            // コミットしないまま落ちる
+            let txn = Transaction::begin(&bufmgr).unwrap();
+            table
+                .insert(&bufmgr, &text(&["w", "Dave", "Miller"]))
+                .unwrap();
+            bufmgr.flush().unwrap();
+            std::mem::forget(txn);
+            entries
+        };
+
+        let disk = DiskManager::open(&path).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        assert_eq!(entries, lookup(&bufmgr, &table));
+    }
+
+    #[test]
+    fn test_rollback_large() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("btree.rly")).unwrap();
+        let pool = BufferPool::new(32);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..100 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0x01; 100])
+                .unwrap();
+        }
+
+        // 分割や併合を伴う変更も元に戻る
+        let txn = Transaction::begin(&bufmgr).unwrap();
+        for i in 100u64..200 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0x02; 100])
+                .unwrap();
+        }
+        for i in (0u64..100).step_by(3) {
+            btree
+                .update(&bufmgr, &i.to_be_bytes(), &[0x03; 300])
+                .unwrap();
+        }
+        for i in (0u64..200).step_by(2) {
+            btree.delete(&bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        txn.rollback().unwrap();
+
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..100 {
+            let (key, value) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), key.as_slice());
+            assert_eq!(vec![0x01; 100], value);
+        }
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_drop() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("btree.rly")).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        btree.insert(&bufmgr, b"a", b"1").unwrap();
+
+        let insert_then_fail = || -> Result<(), btree::Error> {
+            let txn = Transaction::begin(&bufmgr)?;
+            // 同じスレッドでは入れ子にできない
+            assert!(Transaction::begin(&bufmgr).is_err());
+            btree.update(&bufmgr, b"a", b"2")?;
+            btree.insert(&bufmgr, b"b", b"2")?;
+            btree.insert(&bufmgr, b"a", b"3")?;
+            txn.commit()
+        };
+        assert!(matches!(
+            insert_then_fail(),
+            Err(btree::Error::DuplicateKey)
+        ));
+
+        // 途中で抜けたトランザクションは取り消され、次のトランザクションを始められる
+        let txn = Transaction::begin(&bufmgr).unwrap();
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        assert_eq!(
+            Some((b"a".to_vec(), b"1".to_vec())),
+            iter.next(&bufmgr).unwrap()
+        );
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_key_locked() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("btree.rly")).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+        btree.insert(&bufmgr, b"a", b"1").unwrap();
+
+        let txn = Transaction::begin(&bufmgr).unwrap();
+        btree.update(&bufmgr, b"a", b"2").unwrap();
+        btree.insert(&bufmgr, b"b", b"2").unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // 書き換え中のキーは、他のトランザクションからもトランザクションの外からも書き換えられない
+                let locked = |result: Result<(), btree::Error>| {
+                    matches!(result, Err(btree::Error::Buffer(buffer::Error::KeyLocked)))
+                };
+                assert!(locked(btree.update(&bufmgr, b"a", b"3")));
+                assert!(locked(btree.delete(&bufmgr, b"b").map(|_| ())));
+                let other = Transaction::begin(&bufmgr).unwrap();
+                assert!(locked(btree.upsert(&bufmgr, b"b", b"3")));
+                btree.insert(&bufmgr, b"c", b"3").unwrap();
+                other.commit().unwrap();
+            })
+            .join()
+            .unwrap();
+        });
+        txn.rollback().unwrap();
+
+        // ロールバックしても他のスレッドの変更は残り、ロックも外れる
+        std::thread::scope(|s| {
+            s.spawn(|| btree.update(&bufmgr, b"a", b"4").unwrap())
+                .join()
+                .unwrap();
+        });
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        assert_eq!(
+            Some((b"a".to_vec(), b"4".to_vec())),
+            iter.next(&bufmgr).unwrap()
+        );
+        assert_eq!(
+            Some((b"c".to_vec(), b"3".to_vec())),
+            iter.next(&bufmgr).unwrap()
+        );
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_larger_than_pool() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("btree.rly")).unwrap();
+        let pool = BufferPool::new(10);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&bufmgr).unwrap();
+
+        // 触るページがバッファプールに収まらなくても、操作ごとにラッチを外すので実行できる
+        let txn = Transaction::begin(&bufmgr).unwrap();
+        for i in 0u64..2000 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0x01; 100])
+                .unwrap();
+        }
+        txn.rollback().unwrap();
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        assert!(iter.next(&bufmgr).unwrap().is_none());
+
+        let txn = Transaction::begin(&bufmgr).unwrap();
+        for i in 0u64..2000 {
+            btree
+                .insert(&bufmgr, &i.to_be_bytes(), &[0x01; 100])
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..2000 {
+            let (key, _) = iter.next(&bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), key.as_slice());
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memcmpable;
+
+// 列の型。カタログに保存するので、並びを変えずに末尾に足していく
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataType {
+    Text,
+    Int64,
+    Float64,
+    Bytes,
+    Bool,
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DataType::Text => "TEXT",
+            DataType::Int64 => "BIGINT",
+            DataType::Float64 => "DOUBLE",
+            DataType::Bytes => "BYTEA",
+            DataType::Bool => "BOOLEAN",
+        };
+        f.write_str(name)
+    }
+}
+
+// 行の1つの列の値
+// 比較の結果とエンコードしたバイト列の比較の結果は一致する
+// 統計情報としてカタログにも保存するので、並びを変えずに末尾に足していく
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int64(i64),
+    Float64(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+// エンコードした値の先頭に置く型のタグ。NULL は全ての値より小さい
+const TAG_NULL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_INT64: u8 = 2;
+const TAG_FLOAT64: u8 = 3;
+const TAG_TEXT: u8 = 4;
+const TAG_BYTES: u8 = 5;
+
+impl Value {
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::Null => None,
+            Value::Bool(_) => Some(DataType::Bool),
+            Value::Int64(_) => Some(DataType::Int64),
+            Value::Float64(_) => Some(DataType::Float64),
+            Value::Text(_) => Some(DataType::Text),
+            Value::Bytes(_) => Some(DataType::Bytes),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    // 値を列の型に合わせる。NULL はどの型の列にも入れられる
+    pub fn cast(self, data_type: DataType) -> Option<Value> {
+        match (self, data_type) {
+            (Value::Null, _) => Some(Value::Null),
+            (Value::Int64(i), DataType::Float64) => Some(Value::Float64(i as f64)),
+            (Value::Text(s), DataType::Bytes) => Some(Value::Bytes(s.into_bytes())),
+            (value, data_type) if value.data_type() == Some(data_type) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Value::Null => TAG_NULL,
+            Value::Bool(_) => TAG_BOOL,
+            Value::Int64(_) => TAG_INT64,
+            Value::Float64(_) => TAG_FLOAT64,
+            Value::Text(_) => TAG_TEXT,
+            Value::Bytes(_) => TAG_BYTES,
+        }
+    }
+
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        dst.push(self.tag());
+        match self {
+            Value::Null => {}
+            Value::Bool(b) => dst.push(*b as u8),
+            // 符号ビットを反転させると、負の数が正の数より前に並ぶ
+            Value::Int64(i) => dst.extend_from_slice(&((*i as u64) ^ (1 << 63)).to_be_bytes()),
+            // 正の数は符号ビットを、負の数は全てのビットを反転させる
+            Value::Float64(f) => {
+                let bits = f.to_bits();
+                let bits = if bits >> 63 == 0 {
+                    bits ^ (1 << 63)
+                } else {
+                    !bits
+                };
+                dst.extend_from_slice(&bits.to_be_bytes());
+            }
+            Value::Text(s) => encode_bytes(s.as_bytes(), dst),
+            Value::Bytes(bytes) => encode_bytes(bytes, dst),
+        }
+    }
+
+    pub fn decode(src: &mut &[u8]) -> Value {
+        let tag = src[0];
+        *src = &src[1..];
+        match tag {
+            TAG_NULL => Value::Null,
+            TAG_BOOL => {
+                let b = src[0] != 0;
+                *src = &src[1..];
+                Value::Bool(b)
+            }
+            TAG_INT64 => Value::Int64((decode_u64(src) ^ (1 << 63)) as i64),
+            TAG_FLOAT64 => {
+                let bits = decode_u64(src);
+                let bits = if bits >> 63 == 1 {
+                    bits ^ (1 << 63)
+                } else {
+                    !bits
+                };
+                Value::Float64(f64::from_bits(bits))
+            }
+            TAG_TEXT => {
+                let mut bytes = vec![];
+                memcmpable::decode(src, &mut bytes);
+                Value::Text(String::from_utf8(bytes).expect("text must be valid UTF-8"))
+            }
+            TAG_BYTES => {
+                let mut bytes = vec![];
+                memcmpable::decode(src, &mut bytes);
+                Value::Bytes(bytes)
+            }
+            _ => panic!("unknown value tag: {}", tag),
+        }
+    }
+}
+
+fn decode_u64(src: &mut &[u8]) -> u64 {
+    let bits = u64::from_be_bytes(src[..8].try_into().unwrap());
+    *src = &src[8..];
+    bits
+}
+
+fn encode_bytes(bytes: &[u8], dst: &mut Vec<u8>) {
+    dst.reserve(memcmpable::encoded_size(bytes.len()));
+    memcmpable::encode(bytes, dst);
+}
+
+// エンコードした結果と同じ順序。浮動小数点数は -0.0 < 0.0 とし、NaN も他の値と比べられる
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int64(a), Value::Int64(b)) => a.cmp(b),
+            (Value::Float64(a), Value::Float64(b)) => a.total_cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (a, b) => a.tag().cmp(&b.tag()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("NULL"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int64(i) => write!(f, "{}", i),
+            Value::Float64(x) => write!(f, "{:?}", x),
+            Value::Text(s) => f.write_str(s),
+            Value::Bytes(bytes) => {
+                f.write_str("\\x")?;
+                bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int64(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float64(f)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut bytes = vec![];
+        value.encode(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_order() {
+        let values = vec![
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Int64(i64::MIN),
+            Value::Int64(-1),
+            Value::Int64(0),
+            Value::Int64(1),
+            Value::Int64(256),
+            Value::Int64(i64::MAX),
+            Value::Float64(f64::NEG_INFINITY),
+            Value::Float64(-1.5),
+            Value::Float64(-0.0),
+            Value::Float64(0.0),
+            Value::Float64(f64::MIN_POSITIVE),
+            Value::Float64(2.5),
+            Value::Float64(f64::INFINITY),
+            Value::Text("".into()),
+            Value::Text("a".into()),
+            Value::Text("abcdefghij".into()),
+            Value::Text("b".into()),
+            Value::Bytes(vec![]),
+            Value::Bytes(vec![0]),
+            Value::Bytes(vec![0xff; 20]),
+        ];
+        for (i, a) in values.iter().enumerate() {
+            let mut rest = &encode(a)[..];
+            assert_eq!(*a, Value::decode(&mut rest));
+            assert!(rest.is_empty());
+            for b in &values[i + 1..] {
+                assert!(a < b, "{:?} < {:?}", a, b);
+                assert!(encode(a) < encode(b), "{:?} < {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cast() {
+        assert_eq!(
+            Some(Value::Float64(3.0)),
+            Value::Int64(3).cast(DataType::Float64)
+        );
+        assert_eq!(
+            Some(Value::Bytes(b"ab".to_vec())),
+            Value::from("ab").cast(DataType::Bytes)
+        );
+        assert_eq!(Some(Value::Null), Value::Null.cast(DataType::Int64));
+        assert_eq!(None, Value::from("1").cast(DataType::Int64));
+    }
+}
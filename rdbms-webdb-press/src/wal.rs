@@ -0,0 +1,302 @@
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{Read, Result, Seek, SeekFrom, Write},
+};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+pub type Lsn = u64;
+
+// 差分がこのバイト数より近ければ、1つの範囲にまとめて記録する
+const MERGE_GAP: usize = 16;
+
+// ページの中で書き換わった範囲
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    pub offset: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Record {
+    // ページ単位の REDO レコード
+    Redo {
+        lsn: Lsn,
+        page_id: u64,
+        runs: Vec<Run>,
+    },
+    // ここまでのレコードをまとめて反映してよいことを示す
+    Commit {
+        lsn: Lsn,
+    },
+    // トランザクションの操作を取り消すための UNDO レコード (transaction::UndoRecord)
+    Undo {
+        lsn: Lsn,
+        txn_id: u64,
+        data: Vec<u8>,
+    },
+    // トランザクションの最後の UNDO レコードを適用し終えたことを示す
+    Compensate {
+        lsn: Lsn,
+        txn_id: u64,
+    },
+    // トランザクションがコミットまたはロールバックし終えたことを示す
+    End {
+        lsn: Lsn,
+        txn_id: u64,
+    },
+}
+
+impl Record {
+    pub fn lsn(&self) -> Lsn {
+        match self {
+            Record::Redo { lsn, .. }
+            | Record::Commit { lsn }
+            | Record::Undo { lsn, .. }
+            | Record::Compensate { lsn, .. }
+            | Record::End { lsn, .. } => *lsn,
+        }
+    }
+}
+
+// 書き換え前後のページを比べて、変わった範囲を取り出す
+pub fn diff(before: &[u8], after: &[u8]) -> Vec<Run> {
+    let mut runs: Vec<Run> = vec![];
+    let mut i = 0;
+    while i < after.len() {
+        if before[i] == after[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < after.len() && before[i] != after[i] {
+            i += 1;
+        }
+        match runs.last_mut() {
+            Some(last) if start - (last.offset as usize + last.data.len()) < MERGE_GAP => {
+                let last_start = last.offset as usize;
+                last.data = after[last_start..i].to_vec();
+            }
+            _ => runs.push(Run {
+                offset: start as u16,
+                data: after[start..i].to_vec(),
+            }),
+        }
+    }
+    runs
+}
+
+pub fn apply(page: &mut [u8], runs: &[Run]) {
+    for run in runs {
+        let start = run.offset as usize;
+        page[start..start + run.data.len()].copy_from_slice(&run.data);
+    }
+}
+
+// 追記のみのログファイル
+// 各レコードは [長さ (u32)][CRC32C (u32)][bincode でシリアライズした Record] の形で並ぶ
+pub struct Wal {
+    log_file: File,
+    // まだファイルに書き出していないレコード
+    buffer: Vec<u8>,
+    next_lsn: Lsn,
+    // この LSN より前のレコードは永続化されている
+    flushed_lsn: Lsn,
+    file_size: u64,
+}
+
+impl Wal {
+    pub fn new(mut log_file: File) -> Result<Self> {
+        let file_size = log_file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            log_file,
+            buffer: vec![],
+            next_lsn: 1,
+            flushed_lsn: 1,
+            file_size,
+        })
+    }
+
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+
+    // リカバリ後など、LSN がページに書かれたものより小さくならないようにする
+    pub fn set_next_lsn(&mut self, lsn: Lsn) {
+        self.next_lsn = lsn;
+        self.flushed_lsn = lsn;
+    }
+
+    // ログの大きさ (書き出していない分を含む)
+    pub fn size(&self) -> u64 {
+        self.file_size + self.buffer.len() as u64
+    }
+
+    pub fn append_redo(&mut self, page_id: u64, runs: Vec<Run>) -> Lsn {
+        let lsn = self.next_lsn;
+        self.append(&Record::Redo { lsn, page_id, runs });
+        lsn
+    }
+
+    pub fn append_commit(&mut self) -> Lsn {
+        let lsn = self.next_lsn;
+        self.append(&Record::Commit { lsn });
+        lsn
+    }
+
+    pub fn append_undo(&mut self, txn_id: u64, data: Vec<u8>) -> Lsn {
+        let lsn = self.next_lsn;
+        self.append(&Record::Undo { lsn, txn_id, data });
+        lsn
+    }
+
+    pub fn append_compensate(&mut self, txn_id: u64) -> Lsn {
+        let lsn = self.next_lsn;
+        self.append(&Record::Compensate { lsn, txn_id });
+        lsn
+    }
+
+    pub fn append_end(&mut self, txn_id: u64) -> Lsn {
+        let lsn = self.next_lsn;
+        self.append(&Record::End { lsn, txn_id });
+        lsn
+    }
+
+    fn append(&mut self, record: &Record) {
+        let bytes = bincode::options().serialize(record).unwrap();
+        self.buffer
+            .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.buffer
+            .extend_from_slice(&crc32c::crc32c(&bytes).to_le_bytes());
+        self.buffer.extend_from_slice(&bytes);
+        self.next_lsn += 1;
+    }
+
+    // lsn までのレコードが永続化されていることを保証する
+    pub fn flush_to(&mut self, lsn: Lsn) -> Result<()> {
+        if lsn < self.flushed_lsn {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.log_file.write_all(&self.buffer)?;
+        self.log_file.sync_data()?;
+        self.file_size += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.flushed_lsn = self.next_lsn;
+        Ok(())
+    }
+
+    // Commit まで書き切られたレコードを先頭から読む
+    // 途中で壊れている (書き込み中にクラッシュした) レコード以降は捨てる
+    pub fn read_committed(&mut self) -> Result<Vec<Record>> {
+        self.log_file.seek(SeekFrom::Start(0))?;
+        let mut bytes = vec![];
+        self.log_file.read_to_end(&mut bytes)?;
+
+        let mut records = vec![];
+        let mut num_committed = 0;
+        let mut rest = bytes.as_slice();
+        while rest.len() >= 8 {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+            if rest.len() < 8 + len {
+                break;
+            }
+            let body = &rest[8..8 + len];
+            if crc32c::crc32c(body) != checksum {
+                break;
+            }
+            let record: Record = match bincode::options().deserialize(body) {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+            if let Record::Commit { .. } = record {
+                num_committed = records.len() + 1;
+            }
+            records.push(record);
+            rest = &rest[8 + len..];
+        }
+        records.truncate(num_committed);
+        Ok(records)
+    }
+
+    // 全ての変更がヒープファイルに反映された後に、ログを空にする
+    pub fn truncate(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.log_file.set_len(0)?;
+        self.log_file.seek(SeekFrom::Start(0))?;
+        self.log_file.sync_all()?;
+        self.file_size = 0;
+        self.flushed_lsn = self.next_lsn;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_diff() {
+        let before = vec![0u8; 64];
+        let mut after = before.clone();
+        after[3] = 1;
+        after[10] = 2;
+        after[40..44].copy_from_slice(b"wxyz");
+        let runs = diff(&before, &after);
+        assert_eq!(2, runs.len());
+        assert_eq!(3, runs[0].offset);
+        assert_eq!(40, runs[1].offset);
+
+        let mut page = before.clone();
+        apply(&mut page, &runs);
+        assert_eq!(after, page);
+        assert!(diff(&after, &page).is_empty());
+    }
+
+    #[test]
+    fn test_read_committed() {
+        let (log_file, log_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut wal = Wal::new(log_file).unwrap();
+        let runs = vec![Run {
+            offset: 8,
+            data: b"hello".to_vec(),
+        }];
+        wal.append_redo(1, runs.clone());
+        wal.append_commit();
+        wal.append_redo(2, runs.clone());
+        wal.flush().unwrap();
+        drop(wal);
+
+        // Commit の後ろにあるレコードは読まれない
+        let mut log_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&log_file_path)
+            .unwrap();
+        log_file.seek(SeekFrom::End(0)).unwrap();
+        // 書きかけのレコード
+        log_file.write_all(&[0xff; 6]).unwrap();
+        let mut wal = Wal::new(log_file).unwrap();
+        assert_eq!(
+            vec![
+                Record::Redo {
+                    lsn: 1,
+                    page_id: 1,
+                    runs
+                },
+                Record::Commit { lsn: 2 },
+            ],
+            wal.read_committed().unwrap()
+        );
+    }
+}